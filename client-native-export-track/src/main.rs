@@ -6,9 +6,10 @@
 
 use clap::Parser;
 use libkeks::{
-    instance::Instance,
+    instance::{Instance, REJOIN_TIMEOUT},
     peer::{Peer, TransportChannel},
-    protocol::ProvideInfo,
+    protocol::{ProvideInfo, DEFAULT_ROOM},
+    room::REKEY_GRACE,
     webrtc::{
         rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication,
         rtp::{codecs::h264::H264Packet, packetizer::Depacketizer},
@@ -38,6 +39,7 @@ fn main() {
         .build()
         .unwrap()
         .block_on(run())
+        .unwrap();
 }
 
 #[derive(Parser, Clone)]
@@ -57,27 +59,37 @@ pub struct Args {
     pli_interval: Option<f64>,
 }
 
-async fn run() {
+async fn run() -> anyhow::Result<()> {
     let args = Args::parse();
 
     let inst = Instance::new(
         Config {
             signaling_uri: args.signaling_uri.clone(),
             username: args.username.clone(),
+            ice_servers: None,
+            moderator_secret: None,
+            private_relay: false,
+            identity: None,
+            known_identities: None,
+            key_cache: None,
+            rekey: false,
+            rekey_grace: REKEY_GRACE,
+            rejoin_timeout: REJOIN_TIMEOUT,
         },
         Arc::new(Handler {
             _args: Arc::new(args.clone()),
             requested_track: Arc::new(AtomicBool::new(false)),
         }),
     )
-    .await;
+    .await?;
 
-    inst.join(Some(&args.secret)).await;
+    inst.join(DEFAULT_ROOM, Some(&args.secret)).await?;
     inst.spawn_ping().await;
     inst.receive_loop().await;
 
     tokio::signal::ctrl_c().await.unwrap();
     error!("interrupt received, exiting");
+    Ok(())
 }
 
 #[derive(Clone)]
@@ -106,7 +118,9 @@ impl EventHandler for Handler {
             if info.kind == "track" {
                 info!("track of interest is provided, requesting");
                 if !r.swap(true, Ordering::Relaxed) {
-                    peer.request_resource(id).await;
+                    if let Err(e) = peer.request_resource(id).await {
+                        error!("cannot request track: {e}")
+                    }
                 }
             }
         })
@@ -155,7 +169,9 @@ impl EventHandler for Handler {
                         error!("export failed: {e}")
                     }
                     info!("stopping, telling the remote to stop too.");
-                    peer.request_stop_resource(track.stream_id()).await;
+                    if let Err(e) = peer.request_stop_resource(track.stream_id()).await {
+                        warn!("{e}")
+                    }
                 }
                 TransportChannel::DataChannel(_) => warn!("wrong type"),
             }
@@ -5,11 +5,12 @@
 */
 use crate::GuiPeer;
 use async_std::task::block_on;
+use egui::{Key, ScrollArea, TextEdit, Ui};
 use libkeks::{
     instance::Instance,
-    protocol::{ChatMesssage, RelayMessage},
+    protocol::{ChatMessage, RelayMessage, DEFAULT_ROOM},
 };
-use egui::{Key, ScrollArea, TextEdit, Ui};
+use log::warn;
 use std::{
     collections::VecDeque,
     sync::{Arc, RwLock},
@@ -18,7 +19,7 @@ use std::{
 pub struct Chat {
     instance: Arc<Instance>,
     input_line: String,
-    pub history: VecDeque<(Option<Arc<RwLock<GuiPeer>>>, ChatMesssage)>,
+    pub history: VecDeque<(Option<Arc<RwLock<GuiPeer>>>, ChatMessage)>,
 }
 
 impl Chat {
@@ -38,27 +39,33 @@ impl Chat {
                     .map(|s| s.read().unwrap().display_name())
                     .unwrap_or(String::from("Me"));
                 match message {
-                    ChatMesssage::Text(s) => {
+                    ChatMessage::Text(s) => {
                         ui.label(&format!("{}: {}", sender, s));
                     }
-                    ChatMesssage::Image(_) => {
+                    ChatMessage::Image(_) => {
                         ui.label("<image here>");
                     }
                 };
             }
             let r = TextEdit::singleline(&mut self.input_line).show(ui).response;
             if r.lost_focus() && r.ctx.input(|i| i.key_down(Key::Enter)) {
-                self.send(ChatMesssage::Text(self.input_line.to_owned()));
+                self.send(ChatMessage::Text(self.input_line.to_owned()));
                 self.input_line = "".into();
                 r.request_focus();
             }
         });
     }
-    pub fn add(&mut self, sender: Option<Arc<RwLock<GuiPeer>>>, message: ChatMesssage) {
+    pub fn add(&mut self, sender: Option<Arc<RwLock<GuiPeer>>>, message: ChatMessage) {
         self.history.push_back((sender, message));
     }
-    pub fn send(&mut self, message: ChatMesssage) {
+    pub fn send(&mut self, message: ChatMessage) {
         self.add(None, message.clone());
-        block_on(self.instance.send_relay(None, RelayMessage::Chat(message)));
+        if let Err(e) = block_on(self.instance.send_relay(
+            DEFAULT_ROOM,
+            None,
+            RelayMessage::Chat(message),
+        )) {
+            warn!("cannot send chat message: {e}")
+        }
     }
 }
@@ -6,16 +6,20 @@
 
 pub mod chat;
 
-use anyhow::bail;
+use anyhow::{anyhow, bail};
 use async_std::task::block_on;
 use chat::Chat;
 use clap::Parser;
 use crossbeam_channel::Sender;
 use egui::{ScrollArea, Ui, Visuals};
 use libkeks::{
-    instance::Instance,
+    identity::Identity,
+    instance::{Instance, REJOIN_TIMEOUT},
+    lan::{self, LanServer},
     peer::Peer,
-    protocol::{ProvideInfo, RelayMessage},
+    protocol::{ClientId, ProvideInfo, RelayMessage, DEFAULT_ROOM},
+    room::REKEY_GRACE,
+    sas::Sas,
     webrtc::{
         rtcp::payload_feedbacks::picture_loss_indication::PictureLossIndication,
         rtp::{
@@ -31,6 +35,7 @@ use std::{
     collections::{HashMap, VecDeque},
     fs::File,
     io::Write,
+    path::PathBuf,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc, RwLock,
@@ -48,6 +53,18 @@ struct Args {
     default_room_secret: String,
     #[arg(short = 'U', long, default_value = "alice")]
     default_username: String,
+    /// File holding your identity key; created if missing
+    #[arg(long)]
+    identity: Option<PathBuf>,
+    /// File remembering the identity keys of other users
+    #[arg(long)]
+    known_identities: Option<PathBuf>,
+    /// File remembering keys derived from room secrets, so joining again is faster
+    #[arg(long)]
+    key_cache: Option<PathBuf>,
+    /// Hand out a fresh room key whenever someone leaves
+    #[arg(long)]
+    rekey: bool,
 }
 
 #[tokio::main]
@@ -75,12 +92,32 @@ async fn main() {
     .unwrap();
 }
 
-enum App {
-    Prejoin(String, String),
-    Joining(Option<JoinHandle<Inroom>>),
+struct App {
+    identity: Option<PathBuf>,
+    known_identities: Option<PathBuf>,
+    key_cache: Option<PathBuf>,
+    rekey: bool,
+    state: State,
+}
+
+enum State {
+    Prejoin(String, String, Network),
+    Joining(
+        Option<JoinHandle<anyhow::Result<Inroom>>>,
+        String,
+        String,
+        Network,
+    ),
     Inroom(Inroom),
 }
 
+#[derive(Clone, Copy, PartialEq)]
+enum Network {
+    Server,
+    HostLan,
+    FindLan,
+}
+
 #[derive(Clone)]
 // TODO
 #[allow(dead_code)]
@@ -88,17 +125,21 @@ struct Inroom {
     pub instance: Arc<Instance>,
     pub handler: Arc<Handler>,
     pub chat: Arc<RwLock<Chat>>,
+    pub lan_server: Option<Arc<LanServer>>,
 }
 
 pub struct Handler {
     k: RwLock<Option<Inroom>>,
-    peers: RwLock<HashMap<usize, Arc<RwLock<GuiPeer>>>>,
+    peers: RwLock<HashMap<ClientId, Arc<RwLock<GuiPeer>>>>,
+    disconnected: AtomicBool,
 }
 
 pub struct GuiPeer {
     peer: Arc<Peer>,
     resources: HashMap<String, GuiResource>,
     username: Option<String>,
+    /// string of a running verification, shown in a dialog
+    verification: Option<Sas>,
 }
 
 struct GuiResource {
@@ -116,62 +157,124 @@ enum GuiResourceState {
 
 impl App {
     pub fn new(args: Args) -> Self {
-        Self::Prejoin(args.default_room_secret, args.default_username)
+        Self {
+            identity: args.identity,
+            known_identities: args.known_identities,
+            key_cache: args.key_cache,
+            rekey: args.rekey,
+            state: State::Prejoin(
+                args.default_room_secret,
+                args.default_username,
+                Network::Server,
+            ),
+        }
     }
 }
 
 impl eframe::App for App {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
-        egui::CentralPanel::default().show(ctx, |ui| match self {
-            App::Prejoin(secret, username) => {
+        egui::CentralPanel::default().show(ctx, |ui| match &mut self.state {
+            State::Prejoin(secret, username, network) => {
                 ui.heading("Join a meeting");
                 ui.label("Room secret:");
                 ui.text_edit_singleline(secret);
                 ui.label("Username:");
                 ui.text_edit_singleline(username);
+                ui.horizontal(|ui| {
+                    ui.radio_value(network, Network::Server, "Server");
+                    ui.radio_value(network, Network::HostLan, "Host on local network");
+                    ui.radio_value(network, Network::FindLan, "Find on local network");
+                });
                 if ui.button("Join").clicked() {
                     let secret = secret.clone();
                     let username = username.clone();
-                    *self = Self::Joining(Some(tokio::spawn(async move {
-                        Inroom::new(
-                            Config {
-                                username,
-                                signaling_uri: "wss://meet.metamuffin.org".to_string(),
-                            },
-                            &secret,
-                        )
-                        .await
-                    })))
+                    let network = *network;
+                    let task = {
+                        let (secret, username) = (secret.clone(), username.clone());
+                        let identity = self.identity.clone();
+                        let known_identities = self.known_identities.clone();
+                        let key_cache = self.key_cache.clone();
+                        let rekey = self.rekey;
+                        tokio::spawn(async move {
+                            let identity = identity
+                                .as_deref()
+                                .map(Identity::load_or_create)
+                                .transpose()?;
+                            let lan_server = match network {
+                                Network::HostLan => Some(Arc::new(LanServer::start(0).await?)),
+                                _ => None,
+                            };
+                            let signaling_uri = match (network, &lan_server) {
+                                (_, Some(server)) => server.signaling_uri(),
+                                (Network::FindLan, _) => {
+                                    lan::discover(Duration::from_secs(5)).await?.ok_or(anyhow!(
+                                        "no signaling server found on the local network"
+                                    ))?
+                                }
+                                _ => "wss://meet.metamuffin.org".to_string(),
+                            };
+                            Inroom::new(
+                                Config {
+                                    username,
+                                    signaling_uri,
+                                    ice_servers: None,
+                                    moderator_secret: None,
+                                    private_relay: false,
+                                    identity,
+                                    known_identities,
+                                    key_cache,
+                                    rekey,
+                                    rekey_grace: REKEY_GRACE,
+                                    rejoin_timeout: REJOIN_TIMEOUT,
+                                },
+                                &secret,
+                                lan_server,
+                            )
+                            .await
+                        })
+                    };
+                    self.state = State::Joining(Some(task), secret, username, network)
                 }
             }
-            App::Joining(fut) => {
+            State::Joining(fut, secret, username, network) => {
                 ui.spinner();
                 if fut.as_ref().map(|f| f.is_finished()).unwrap_or(false) {
-                    *self = Self::Inroom(block_on(fut.take().unwrap()).unwrap());
+                    match block_on(fut.take().unwrap()).unwrap() {
+                        Ok(inroom) => self.state = State::Inroom(inroom),
+                        Err(e) => {
+                            error!("cannot join: {e}");
+                            self.state = State::Prejoin(secret.clone(), username.clone(), *network)
+                        }
+                    }
                 }
             }
-            App::Inroom(x) => x.ui(ui),
+            State::Inroom(x) => x.ui(ui),
         });
     }
 }
 
 impl Inroom {
-    pub async fn new(config: Config, secret: &str) -> Self {
+    pub async fn new(
+        config: Config,
+        secret: &str,
+        lan_server: Option<Arc<LanServer>>,
+    ) -> anyhow::Result<Self> {
         let handler = Arc::new(Handler::default());
-        let instance = Instance::new(config, handler.clone()).await;
+        let instance = Instance::new(config, handler.clone()).await?;
         instance.spawn_ping().await;
         {
             let instance = instance.clone();
             tokio::spawn(instance.receive_loop());
         }
-        instance.join(Some(secret)).await;
+        instance.join(DEFAULT_ROOM, Some(secret)).await?;
         let k = Self {
             chat: Arc::new(RwLock::new(Chat::new(instance.clone()))),
             instance,
             handler,
+            lan_server,
         };
         *k.handler.k.write().unwrap() = Some(k.clone());
-        k
+        Ok(k)
     }
 
     pub fn ui(&mut self, ui: &mut Ui) {
@@ -184,6 +287,20 @@ impl Inroom {
                 ui.allocate_space(ui.available_size());
             });
         egui::CentralPanel::default().show_inside(ui, |ui| {
+            if self.handler.disconnected.load(Ordering::Relaxed) {
+                ui.horizontal(|ui| {
+                    ui.spinner();
+                    ui.label("Reconnecting...")
+                });
+            }
+            if ui.button("New room key").clicked() {
+                let instance = self.instance.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = instance.rekey(DEFAULT_ROOM).await {
+                        error!("{e}")
+                    }
+                });
+            }
             self.ui_user_list(ui);
         });
     }
@@ -195,10 +312,52 @@ impl Inroom {
                     let mut gp = gp.write().unwrap();
                     ui.collapsing(gp.display_name(), |ui| {
                         let peer = gp.peer.clone();
+                        if ui.button("Verify").clicked() {
+                            let peer = peer.clone();
+                            tokio::spawn(async move {
+                                if let Err(e) = peer.start_verification().await {
+                                    error!("{e}")
+                                }
+                            });
+                        }
                         for resource in gp.resources.values_mut() {
                             resource.ui(ui, &peer)
                         }
                     });
+                    if let Some(sas) = gp.verification {
+                        let mut answer = None;
+                        egui::Window::new(format!("Verify {}", gp.display_name()))
+                            .id(egui::Id::new(("verify", gp.peer.id)))
+                            .collapsible(false)
+                            .show(ui.ctx(), |ui| {
+                                ui.label("Compare these with what the other user sees:");
+                                ui.horizontal(|ui| {
+                                    for (emoji, name) in sas.emoji() {
+                                        ui.vertical(|ui| {
+                                            ui.heading(emoji);
+                                            ui.label(name);
+                                        });
+                                    }
+                                });
+                                ui.horizontal(|ui| {
+                                    if ui.button("They match").clicked() {
+                                        answer = Some(true);
+                                    }
+                                    if ui.button("They don't match").clicked() {
+                                        answer = Some(false);
+                                    }
+                                });
+                            });
+                        if let Some(matches) = answer {
+                            gp.verification = None;
+                            let peer = gp.peer.clone();
+                            tokio::spawn(async move {
+                                if let Err(e) = peer.confirm_verification(matches).await {
+                                    error!("{e}")
+                                }
+                            });
+                        }
+                    }
                 }
             });
     }
@@ -216,7 +375,11 @@ impl GuiResource {
                     let id = self.info.id.clone();
                     let peer = peer.clone();
                     *self.state.write().unwrap() = GuiResourceState::Connecting;
-                    tokio::spawn(async move { peer.request_resource(id).await });
+                    tokio::spawn(async move {
+                        if let Err(e) = peer.request_resource(id).await {
+                            error!("{e}")
+                        }
+                    });
                 }
             }
             GuiResourceState::Connecting => {
@@ -236,7 +399,11 @@ impl GuiResource {
                     let id = self.info.id.clone();
                     let peer = peer.clone();
                     *self.state.write().unwrap() = GuiResourceState::Disconnecting;
-                    tokio::spawn(async move { peer.request_stop_resource(id).await });
+                    tokio::spawn(async move {
+                        if let Err(e) = peer.request_stop_resource(id).await {
+                            error!("{e}")
+                        }
+                    });
                 }
             }
         }
@@ -248,15 +415,26 @@ impl Default for Handler {
         Self {
             k: RwLock::new(None),
             peers: Default::default(),
+            disconnected: AtomicBool::new(false),
         }
     }
 }
 
 impl GuiPeer {
     pub fn display_name(&self) -> String {
-        self.username
+        let name = self
+            .username
             .clone()
-            .unwrap_or_else(|| format!("Unknown ({})", self.peer.id))
+            .unwrap_or_else(|| format!("Unknown ({})", self.peer.id));
+        let name = match self.peer.identity.try_read() {
+            Ok(identity) => format!("{name} ({identity})"),
+            Err(_) => name,
+        };
+        if self.peer.sas_verified.load(Ordering::Relaxed) {
+            format!("{name} ✔")
+        } else {
+            name
+        }
     }
 }
 
@@ -268,6 +446,7 @@ impl EventHandler for Handler {
                 resources: HashMap::new(),
                 peer: peer.clone(),
                 username: None,
+                verification: None,
             })),
         );
         Box::pin(async move {})
@@ -278,6 +457,16 @@ impl EventHandler for Handler {
         Box::pin(async move {})
     }
 
+    fn disconnected(&self) -> libkeks::DynFut<()> {
+        self.disconnected.store(true, Ordering::Relaxed);
+        Box::pin(async move {})
+    }
+
+    fn reconnected(&self) -> libkeks::DynFut<()> {
+        self.disconnected.store(false, Ordering::Relaxed);
+        Box::pin(async move {})
+    }
+
     fn resource_added(
         &self,
         peer: std::sync::Arc<libkeks::peer::Peer>,
@@ -334,6 +523,33 @@ impl EventHandler for Handler {
         Box::pin(async move {})
     }
 
+    fn identity_changed(&self, peer: Arc<Peer>) -> libkeks::DynFut<()> {
+        Box::pin(async move {
+            warn!(
+                "{} is not who they were before: {}",
+                peer.id,
+                peer.identity.read().await
+            );
+        })
+    }
+
+    fn verification_sas(&self, peer: Arc<Peer>, sas: Sas) -> libkeks::DynFut<()> {
+        if let Some(gp) = self.peers.read().unwrap().get(&peer.id) {
+            gp.write().unwrap().verification = Some(sas);
+        }
+        Box::pin(async move {})
+    }
+
+    fn verification_done(&self, peer: Arc<Peer>, verified: bool) -> libkeks::DynFut<()> {
+        if let Some(gp) = self.peers.read().unwrap().get(&peer.id) {
+            gp.write().unwrap().verification = None;
+        }
+        if !verified {
+            warn!("verification of {} failed", peer.id);
+        }
+        Box::pin(async move {})
+    }
+
     fn on_relay(
         &self,
         peer: Arc<Peer>,
@@ -342,7 +558,7 @@ impl EventHandler for Handler {
         let guard = self.peers.read().unwrap();
         let mut p = guard.get(&peer.id).unwrap().write().unwrap();
         match message.clone() {
-            RelayMessage::Identify { username } => p.username = Some(username),
+            RelayMessage::Identify { username, .. } => p.username = Some(username),
             RelayMessage::Chat(message) => self
                 .k
                 .read()
@@ -467,7 +683,9 @@ pub fn play(peer: Arc<Peer>, track: Arc<TrackRemote>) {
     });
     exit_rx.recv().unwrap();
     has_exited.store(true, Ordering::Relaxed);
-    block_on(peer.request_stop_resource(rid))
+    if let Err(e) = block_on(peer.request_stop_resource(rid)) {
+        warn!("{e}")
+    }
 }
 
 async fn track_to_raw(
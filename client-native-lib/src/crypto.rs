@@ -3,53 +3,170 @@
     which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
     Copyright (C) 2023 metamuffin <metamuffin.org>
 */
+use crate::Error;
 use aes_gcm::{
     aead::{generic_array::sequence::GenericSequence, Aead},
     Aes256Gcm, KeyInit, Nonce,
 };
+use argon2::{Algorithm, Argon2, Params, Version};
 use base64::Engine;
+use hkdf::Hkdf;
 use log::info;
+use rand::rngs::OsRng;
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey};
 
 pub struct Key(Aes256Gcm);
 
 const CRYPTO_SALT: &str = "keksmeet/cryptosaltAAA==";
 const HASH_SALT: &str = "keksmeet/roomhashsaltA==";
+const PAIRWISE_INFO: &[u8] = b"keksmeet/pairwise";
 
-impl Key {
-    pub fn derive(secret: &str) -> Self {
-        info!("running key generation...");
+/// Key derivation function of a room secret. Secrets starting with `v2:` use
+/// [`Kdf::Argon2id`], all others [`Kdf::Pbkdf2`] like the web client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kdf {
+    /// 250000 iterations of PBKDF2-HMAC-SHA512 over the whole secret
+    Pbkdf2,
+    /// `v2:` Argon2id with 64 MiB, 3 passes and 4 lanes
+    Argon2id,
+}
+
+impl Kdf {
+    /// Splits the version prefix off a secret. There is no prefix for
+    /// PBKDF2, so existing secrets keep their keys.
+    pub fn parse(secret: &str) -> (Self, &str) {
+        match secret.strip_prefix("v2:") {
+            Some(rest) => (Kdf::Argon2id, rest),
+            None => (Kdf::Pbkdf2, secret),
+        }
+    }
+    fn derive(self, secret: &str, salt: &str) -> [u8; 32] {
         let salt = base64::engine::general_purpose::STANDARD
-            .decode(CRYPTO_SALT)
+            .decode(salt)
             .unwrap();
-        let mut key = [0u8; 64];
-        fastpbkdf2::pbkdf2_hmac_sha512(secret.as_bytes(), salt.as_slice(), 250000, &mut key);
+        let mut key = [0u8; 32];
+        match self {
+            Kdf::Pbkdf2 => {
+                let mut full = [0u8; 64];
+                fastpbkdf2::pbkdf2_hmac_sha512(secret.as_bytes(), &salt, 250000, &mut full);
+                key.copy_from_slice(&full[..32]);
+            }
+            Kdf::Argon2id => {
+                let params = Params::new(64 * 1024, 3, 4, Some(32)).unwrap();
+                Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                    .hash_password_into(secret.as_bytes(), &salt, &mut key)
+                    .unwrap();
+            }
+        }
+        key
+    }
+}
 
-        let key = Aes256Gcm::new_from_slice(&key[0..32]).unwrap();
+/// Raw AES key of a room secret. Slow; see [`crate::keycache::KeyCache`]
+/// for running it off the async runtime.
+pub fn derive_key(secret: &str) -> [u8; 32] {
+    let (kdf, secret) = Kdf::parse(secret);
+    info!("running key generation ({kdf:?})...");
+    let key = kdf.derive(secret, CRYPTO_SALT);
+    info!("done");
+    key
+}
 
-        info!("done");
-        Self(key)
+impl Key {
+    pub fn derive(secret: &str) -> Self {
+        Self::from_bytes(&derive_key(secret))
+    }
+    pub fn from_bytes(key: &[u8; 32]) -> Self {
+        Self(Aes256Gcm::new_from_slice(key).unwrap())
     }
     pub fn encrypt(&self, s: &str) -> String {
         let iv = Nonce::generate(|_| rand::random()); // TODO check if this is secure randomness
-        let ciphertext = self.0.encrypt(&iv, s.as_bytes()).unwrap();
+        self.encrypt_with_nonce(&iv.into(), s)
+    }
+    /// Like [`Key::encrypt`] but with a caller-chosen IV, for reproducing test
+    /// vectors. Never reuse an IV with the same key for real messages.
+    pub fn encrypt_with_nonce(&self, iv: &[u8; 12], s: &str) -> String {
+        let ciphertext = self.0.encrypt(Nonce::from_slice(iv), s.as_bytes()).unwrap();
         let mut packet = iv.to_vec(); // TODO this could be doing less allocations
         packet.extend(ciphertext);
         base64::engine::general_purpose::STANDARD.encode(packet)
     }
-    pub fn decrypt(&self, s: &str) -> String {
-        let r = base64::engine::general_purpose::STANDARD.decode(s).unwrap();
-        let iv = &r[0..12];
-        let ciphertext = &r[12..];
-        let plaintext = self.0.decrypt(Nonce::from_slice(iv), ciphertext).unwrap();
-        String::from_utf8(plaintext).unwrap()
+    /// Fails if the message is malformed or was not encrypted with this key.
+    pub fn decrypt(&self, s: &str) -> Result<String, Error> {
+        let r = base64::engine::general_purpose::STANDARD
+            .decode(s)
+            .map_err(|_| Error::Decrypt)?;
+        if r.len() < 12 {
+            return Err(Error::Decrypt);
+        }
+        let (iv, ciphertext) = r.split_at(12);
+        let plaintext = self
+            .0
+            .decrypt(Nonce::from_slice(iv), ciphertext)
+            .map_err(|_| Error::Decrypt)?;
+        String::from_utf8(plaintext).map_err(|_| Error::Decrypt)
     }
 }
 
+/// Our half of the X25519 exchange with one peer. The secret is never
+/// stored, so recorded relays stay confidential even if the room secret leaks.
+pub struct KeyExchange {
+    secret: EphemeralSecret,
+    public: PublicKey,
+}
+
+impl KeyExchange {
+    pub fn new() -> Self {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+        Self { secret, public }
+    }
+    pub fn public_key(&self) -> String {
+        base64::engine::general_purpose::STANDARD.encode(self.public.as_bytes())
+    }
+    /// Derives the key shared with the owner of `theirs`, consuming our secret.
+    pub fn finish(self, theirs: &str) -> Result<Key, Error> {
+        let theirs: [u8; 32] = base64::engine::general_purpose::STANDARD
+            .decode(theirs)
+            .ok()
+            .and_then(|k| k.try_into().ok())
+            .ok_or(Error::KeyExchange)?;
+        let theirs = PublicKey::from(theirs);
+        let shared = self.secret.diffie_hellman(&theirs);
+        if !shared.was_contributory() {
+            return Err(Error::KeyExchange);
+        }
+        // both sides have to agree on the order of the public keys
+        let (a, b) = if self.public.as_bytes() < theirs.as_bytes() {
+            (self.public, theirs)
+        } else {
+            (theirs, self.public)
+        };
+        let mut info = PAIRWISE_INFO.to_vec();
+        info.extend(a.as_bytes());
+        info.extend(b.as_bytes());
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(None, shared.as_bytes())
+            .expand(&info, &mut key)
+            .unwrap();
+        Ok(Key(Aes256Gcm::new_from_slice(&key).unwrap()))
+    }
+}
+impl Default for KeyExchange {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Identifies the room to the server without revealing its secret.
 pub fn hash(secret: &str) -> String {
-    let salt = base64::engine::general_purpose::STANDARD
-        .decode(HASH_SALT)
-        .unwrap();
-    let mut key = [0u8; 64];
-    fastpbkdf2::pbkdf2_hmac_sha512(secret.as_bytes(), salt.as_slice(), 250000, &mut key);
-    hex::encode(&key[0..32])
+    let (kdf, secret) = Kdf::parse(secret);
+    hex::encode(kdf.derive(secret, HASH_SALT))
+}
+
+/// Presented to the server on join. Bound to the room so it can not be linked
+/// across rooms that share a moderator secret.
+pub fn moderator_hash(secret: &str, moderator_secret: &str) -> String {
+    hash(&format!("{secret}#moderator#{moderator_secret}"))
 }
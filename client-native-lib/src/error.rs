@@ -0,0 +1,119 @@
+/*
+    This file is part of keks-meet (https://codeberg.org/metamuffin/keks-meet)
+    which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
+    Copyright (C) 2023 metamuffin <metamuffin.org>
+*/
+use crate::protocol::RoomTag;
+use std::fmt;
+use tokio_tungstenite::tungstenite;
+
+#[derive(Debug)]
+pub enum Error {
+    InvalidUri(url::ParseError),
+    /// the signaling connection failed or was closed
+    Signaling(Box<tungstenite::Error>),
+    Http(reqwest::Error),
+    WebRTC(webrtc::Error),
+    Json(serde_json::Error),
+    Io(std::io::Error),
+    /// the server has not sent our id yet
+    NotConnected,
+    NotInRoom(RoomTag),
+    /// a relay was malformed or not encrypted with the room key
+    Decrypt,
+    /// a relay was sent before, or after a newer one
+    Replayed,
+    /// a relay is too old or from the future
+    Stale,
+    /// a peer sent an invalid public key
+    KeyExchange,
+    /// the stored identity key is corrupt
+    InvalidIdentity,
+    /// a peer's identity signature does not match
+    InvalidSignature,
+    /// no short authentication string is being compared with this peer
+    NotVerifying,
+    /// a peer revealed another verification key than it committed to
+    Commitment,
+    /// the key cache holds something that is not a key
+    InvalidKeyCache,
+    /// a peer sent an invalid room key, or one not under a pairwise key
+    Rekey,
+    /// the peer does not provide a resource with this id
+    UnknownResource(String),
+    /// a directory entry's secret is not the one of the published room
+    DirectoryMismatch,
+    #[cfg(feature = "lan")]
+    LanBind(warp::Error),
+    #[cfg(feature = "lan")]
+    Mdns(mdns_sd::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidUri(e) => write!(f, "invalid signaling uri: {e}"),
+            Error::Signaling(e) => write!(f, "signaling: {e}"),
+            Error::Http(e) => write!(f, "http: {e}"),
+            Error::WebRTC(e) => write!(f, "webrtc: {e}"),
+            Error::Json(e) => write!(f, "json: {e}"),
+            Error::Io(e) => write!(f, "io: {e}"),
+            Error::NotConnected => write!(f, "not connected to the signaling server yet"),
+            Error::NotInRoom(room) => write!(f, "not in room {room}"),
+            Error::Decrypt => write!(f, "cannot decrypt message"),
+            Error::Replayed => write!(f, "message was replayed"),
+            Error::Stale => write!(f, "message timestamp is out of range"),
+            Error::KeyExchange => write!(f, "invalid key exchange"),
+            Error::InvalidIdentity => write!(f, "invalid identity key file"),
+            Error::InvalidSignature => write!(f, "invalid identity signature"),
+            Error::NotVerifying => write!(f, "no verification in progress"),
+            Error::Commitment => write!(f, "verification key does not match the commitment"),
+            Error::InvalidKeyCache => write!(f, "invalid key cache entry"),
+            Error::Rekey => write!(f, "invalid room key"),
+            Error::UnknownResource(id) => write!(f, "unknown resource {id:?}"),
+            Error::DirectoryMismatch => write!(f, "directory entry does not match its room"),
+            #[cfg(feature = "lan")]
+            Error::LanBind(e) => write!(f, "cannot bind signaling server: {e}"),
+            #[cfg(feature = "lan")]
+            Error::Mdns(e) => write!(f, "mdns: {e}"),
+        }
+    }
+}
+impl std::error::Error for Error {}
+
+impl From<url::ParseError> for Error {
+    fn from(e: url::ParseError) -> Self {
+        Self::InvalidUri(e)
+    }
+}
+impl From<tungstenite::Error> for Error {
+    fn from(e: tungstenite::Error) -> Self {
+        Self::Signaling(Box::new(e))
+    }
+}
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Self::Http(e)
+    }
+}
+impl From<webrtc::Error> for Error {
+    fn from(e: webrtc::Error) -> Self {
+        Self::WebRTC(e)
+    }
+}
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Self::Json(e)
+    }
+}
+#[cfg(feature = "lan")]
+impl From<mdns_sd::Error> for Error {
+    fn from(e: mdns_sd::Error) -> Self {
+        Self::Mdns(e)
+    }
+}
@@ -0,0 +1,216 @@
+/*
+    This file is part of keks-meet (https://codeberg.org/metamuffin/keks-meet)
+    which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
+    Copyright (C) 2023 metamuffin <metamuffin.org>
+*/
+use crate::{keycache::write_atomic, protocol::ClientId, Error};
+use base64::Engine;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use log::info;
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+use tokio::sync::{Mutex, RwLock};
+
+const IDENTIFY_CONTEXT: &[u8] = b"keksmeet/identify";
+
+/// Long-term Ed25519 key of a user, kept across sessions.
+pub struct Identity(SigningKey);
+
+impl Identity {
+    pub fn generate() -> Self {
+        Self(SigningKey::generate(&mut OsRng))
+    }
+    /// Loads the key stored at `path`, creating it if it does not exist yet.
+    pub fn load_or_create(path: &Path) -> Result<Self, Error> {
+        match fs::read(path) {
+            Ok(secret) => {
+                let secret: [u8; 32] = secret.try_into().map_err(|_| Error::InvalidIdentity)?;
+                Ok(Self(SigningKey::from_bytes(&secret)))
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                info!("creating a new identity at {path:?}");
+                let identity = Self::generate();
+                write_atomic(path, identity.0.as_bytes())?;
+                Ok(identity)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn public_key(&self) -> String {
+        base64::engine::general_purpose::STANDARD.encode(self.0.verifying_key().as_bytes())
+    }
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.public_key())
+    }
+
+    /// Signs an `Identify` for one peer. The signature covers the ids of both
+    /// sides and our ephemeral key exchange key, so it can not be presented
+    /// to anyone else or in another session.
+    pub fn sign_identify(
+        &self,
+        username: &str,
+        sender: ClientId,
+        recipient: ClientId,
+        ephemeral_key: &str,
+    ) -> String {
+        let message = identify_message(username, sender, recipient, ephemeral_key);
+        base64::engine::general_purpose::STANDARD.encode(self.0.sign(&message).to_bytes())
+    }
+}
+
+/// Checks a signature made with [`Identity::sign_identify`].
+pub fn verify_identify(
+    public_key: &str,
+    signature: &str,
+    username: &str,
+    sender: ClientId,
+    recipient: ClientId,
+    ephemeral_key: &str,
+) -> Result<(), Error> {
+    let public_key: [u8; 32] = decode_fixed(public_key).ok_or(Error::InvalidSignature)?;
+    let signature: [u8; 64] = decode_fixed(signature).ok_or(Error::InvalidSignature)?;
+    let public_key = VerifyingKey::from_bytes(&public_key).map_err(|_| Error::InvalidSignature)?;
+    public_key
+        .verify(
+            &identify_message(username, sender, recipient, ephemeral_key),
+            &Signature::from_bytes(&signature),
+        )
+        .map_err(|_| Error::InvalidSignature)
+}
+
+fn identify_message(
+    username: &str,
+    sender: ClientId,
+    recipient: ClientId,
+    ephemeral_key: &str,
+) -> Vec<u8> {
+    let mut message = IDENTIFY_CONTEXT.to_vec();
+    for part in [
+        username,
+        &sender.to_string(),
+        &recipient.to_string(),
+        ephemeral_key,
+    ] {
+        // length prefixes keep the parts apart
+        message.extend((part.len() as u64).to_be_bytes());
+        message.extend(part.as_bytes());
+    }
+    message
+}
+
+fn decode_fixed<const N: usize>(s: &str) -> Option<[u8; N]> {
+    base64::engine::general_purpose::STANDARD
+        .decode(s)
+        .ok()?
+        .try_into()
+        .ok()
+}
+
+/// Short, human-comparable form of an identity public key.
+pub fn fingerprint(public_key: &str) -> String {
+    let hash = hex::encode(&Sha256::digest(public_key.as_bytes())[..16]);
+    hash.as_bytes()
+        .chunks(4)
+        .map(|c| std::str::from_utf8(c).unwrap())
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum IdentityStatus {
+    /// The peer did not present an identity key.
+    Anonymous,
+    /// First time we see this name; its key is remembered from now on.
+    New { fingerprint: String },
+    /// Same key as the last time we saw this name.
+    Verified { fingerprint: String },
+    /// The name was used with another key before.
+    Changed { fingerprint: String, known: String },
+}
+
+impl fmt::Display for IdentityStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IdentityStatus::Anonymous => write!(f, "unverified"),
+            IdentityStatus::New { fingerprint } => write!(f, "new identity {fingerprint}"),
+            IdentityStatus::Verified { fingerprint } => write!(f, "verified {fingerprint}"),
+            IdentityStatus::Changed { fingerprint, known } => {
+                write!(f, "IDENTITY CHANGED from {known} to {fingerprint}")
+            }
+        }
+    }
+}
+
+/// Remembers the identity key of every username on first use.
+pub struct TrustStore {
+    path: Option<PathBuf>,
+    known: RwLock<HashMap<String, String>>,
+    /// held while the file is written, so older contents never win
+    writing: Mutex<()>,
+}
+
+impl TrustStore {
+    /// Without a path nothing is remembered beyond this session.
+    pub fn load(path: Option<PathBuf>) -> Result<Self, Error> {
+        let known = match &path {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(s) => serde_json::from_str(&s)?,
+                Err(e) if e.kind() == ErrorKind::NotFound => HashMap::new(),
+                Err(e) => return Err(e.into()),
+            },
+            None => HashMap::new(),
+        };
+        Ok(Self {
+            path,
+            known: RwLock::new(known),
+            writing: Mutex::new(()),
+        })
+    }
+
+    /// Compares a presented key to the one known for `username`, remembering it if there is none.
+    pub async fn check(&self, username: &str, public_key: &str) -> Result<IdentityStatus, Error> {
+        let fingerprint = fingerprint(public_key);
+        {
+            let mut known = self.known.write().await;
+            match known.get(username) {
+                Some(k) if k == public_key => return Ok(IdentityStatus::Verified { fingerprint }),
+                Some(k) => {
+                    return Ok(IdentityStatus::Changed {
+                        fingerprint,
+                        known: self::fingerprint(k),
+                    })
+                }
+                None => known.insert(username.to_owned(), public_key.to_owned()),
+            };
+        }
+        self.save().await?;
+        Ok(IdentityStatus::New { fingerprint })
+    }
+    /// Accepts a changed key for `username`.
+    pub async fn trust(&self, username: &str, public_key: &str) -> Result<(), Error> {
+        self.known
+            .write()
+            .await
+            .insert(username.to_owned(), public_key.to_owned());
+        self.save().await
+    }
+
+    async fn save(&self) -> Result<(), Error> {
+        if let Some(path) = &self.path {
+            let _writing = self.writing.lock().await;
+            let file = serde_json::to_string_pretty(&*self.known.read().await)?;
+            let path = path.clone();
+            tokio::task::spawn_blocking(move || write_atomic(&path, file.as_bytes()))
+                .await
+                .map_err(std::io::Error::from)??;
+        }
+        Ok(())
+    }
+}
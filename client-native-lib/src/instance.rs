@@ -5,121 +5,371 @@
 */
 use crate::{
     build_api,
-    crypto::{self, hash, Key},
-    peer::Peer,
-    protocol::{self, ClientboundPacket, RelayMessage, RelayMessageWrapper, ServerboundPacket},
+    crypto::Key,
+    identity::TrustStore,
+    keycache::KeyCache,
+    peer::{unix_millis, Peer},
+    protocol::{
+        self, ClientId, ClientboundPacket, DirectoryEntry, IceServer, RelayMessage,
+        RelayMessageWrapper, RoomTag, ServerboundPacket,
+    },
+    room::{Epoch, Room},
     signaling::{self, SignalingConnection},
-    Config, EventHandler, LocalResource,
+    Config, Error, EventHandler, LocalResource,
 };
 use futures_util::{SinkExt, StreamExt};
 use log::{debug, info, warn};
-use std::{collections::HashMap, sync::Arc, time::Duration};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 use tokio::sync::RwLock;
 use webrtc::api::API;
 
+/// Private relays are padded to a multiple of this many bytes.
+const RELAY_PADDING: usize = 256;
+/// Delay before reconnecting, doubled after every failed attempt.
+pub const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+pub const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+/// Peers that have not joined again this long after a reconnect are gone.
+pub const REJOIN_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub struct Instance {
     pub event_handler: Arc<dyn EventHandler>,
     pub conn: SignalingConnection,
     pub config: Config,
     pub api: API,
-    key: RwLock<Option<Key>>,
+    pub ice_servers: Vec<IceServer>,
     pub local_resources: RwLock<HashMap<String, Box<dyn LocalResource>>>,
-    my_id: RwLock<Option<usize>>,
-    pub peers: RwLock<HashMap<usize, Arc<Peer>>>,
+    pub trust_store: TrustStore,
+    pub key_cache: KeyCache,
+    my_id: RwLock<Option<ClientId>>,
+    /// counter of the last relay we sent
+    relay_counter: AtomicU64,
+    pub rooms: RwLock<HashMap<RoomTag, Arc<Room>>>,
 }
 
 impl Instance {
-    pub async fn new(config: Config, event_handler: Arc<dyn EventHandler>) -> Arc<Self> {
-        let conn = signaling::SignalingConnection::new(&config.signaling_uri).await;
+    pub async fn new(
+        config: Config,
+        event_handler: Arc<dyn EventHandler>,
+    ) -> Result<Arc<Self>, Error> {
+        let conn = signaling::SignalingConnection::new(&config.signaling_uri).await?;
+        let ice_servers = match &config.ice_servers {
+            Some(ice_servers) => ice_servers.clone(),
+            None => match signaling::fetch_client_config(&config.signaling_uri).await {
+                Ok(client_config) => client_config.webrtc.ice_servers,
+                Err(e) => {
+                    warn!("cannot fetch client config, continuing without ICE servers: {e}");
+                    vec![]
+                }
+            },
+        };
 
-        Arc::new(Self {
+        Ok(Arc::new(Self {
+            trust_store: TrustStore::load(config.known_identities.clone())?,
+            key_cache: KeyCache::load(config.key_cache.clone())?,
             event_handler,
-            api: build_api(),
+            api: build_api()?,
+            ice_servers,
             my_id: RwLock::new(None),
-            peers: Default::default(),
+            relay_counter: AtomicU64::new(0),
+            rooms: Default::default(),
             local_resources: Default::default(),
             config,
             conn,
-            key: None.into(),
-        })
+        }))
     }
 
-    pub async fn join(&self, secret: Option<&str>) {
-        info!("join room {secret:?}");
-        *self.key.write().await = secret.map(crypto::Key::derive);
+    /// Joins the room under `room`, leaving the one previously joined under
+    /// that tag. Leaves without joining if `secret` is `None`. Rooms joined
+    /// under other tags are kept.
+    pub async fn join(&self, room: RoomTag, secret: Option<&str>) -> Result<(), Error> {
+        info!("join room {secret:?} as {room}");
+        let context = match secret {
+            Some(secret) => Some(Arc::new(
+                Room::new(
+                    room,
+                    secret,
+                    self.config.moderator_secret.as_deref(),
+                    &self.key_cache,
+                    self.config.rekey_grace,
+                )
+                .await?,
+            )),
+            None => None,
+        };
+        let previous = match &context {
+            Some(context) => self.rooms.write().await.insert(room, context.clone()),
+            None => self.rooms.write().await.remove(&room),
+        };
+        if let Some(previous) = previous {
+            self.close_room(&previous).await;
+        }
+        self.send_join(room, context.as_deref()).await
+    }
+    async fn send_join(&self, room: RoomTag, context: Option<&Room>) -> Result<(), Error> {
         self.send_packet(ServerboundPacket::Join {
-            hash: secret.map(hash),
+            hash: context.map(|c| c.hash.clone()),
+            moderator: context.and_then(|c| c.moderator_hash.clone()),
+            room,
         })
-        .await;
+        .await
+    }
+    /// Removes all peers of a room we are no longer in.
+    async fn close_room(&self, room: &Room) {
+        room.moderators.write().await.clear();
+        let mut peers = std::mem::take(&mut *room.peers.write().await);
+        peers.extend(std::mem::take(&mut *room.stale.write().await));
+        for peer in peers.into_values() {
+            peer.on_leave().await;
+            self.event_handler.peer_leave(peer).await;
+        }
+    }
+
+    pub async fn room(&self, room: RoomTag) -> Option<Arc<Room>> {
+        self.rooms.read().await.get(&room).cloned()
+    }
+    /// Peers of all joined rooms. Someone in several rooms with us appears once per room.
+    pub async fn peers(&self) -> Vec<Arc<Peer>> {
+        let mut peers = vec![];
+        for room in self.rooms.read().await.values() {
+            peers.extend(room.peers.read().await.values().cloned());
+        }
+        peers
+    }
+
+    pub async fn is_moderator(&self, room: RoomTag) -> bool {
+        let (Some(id), Some(room)) = (*self.my_id.read().await, self.room(room).await) else {
+            return false;
+        };
+        let is_moderator = room.moderators.read().await.contains(&id);
+        is_moderator
+    }
+    pub async fn kick(&self, room: RoomTag, id: ClientId) -> Result<(), Error> {
+        self.send_packet(ServerboundPacket::Kick { id, room }).await
+    }
+    pub async fn lock(&self, room: RoomTag, locked: bool) -> Result<(), Error> {
+        self.send_packet(ServerboundPacket::Lock { locked, room })
+            .await
+    }
+    pub async fn mute(&self, room: RoomTag, id: ClientId, muted: bool) -> Result<(), Error> {
+        self.send_packet(ServerboundPacket::Mute { id, muted, room })
+            .await
+    }
+
+    /// Lists a room in the server's public directory. Anyone can read the
+    /// room secret from there.
+    pub async fn publish(
+        &self,
+        room: RoomTag,
+        name: String,
+        description: String,
+    ) -> Result<(), Error> {
+        let context = self.room(room).await.ok_or(Error::NotInRoom(room))?;
+        self.send_packet(ServerboundPacket::Publish {
+            name,
+            description,
+            secret: context.secret.clone(),
+            room,
+        })
+        .await
+    }
+    pub async fn unpublish(&self, room: RoomTag) -> Result<(), Error> {
+        self.send_packet(ServerboundPacket::Unpublish { room })
+            .await
+    }
+    pub async fn list_directory(&self) -> Result<Vec<DirectoryEntry>, Error> {
+        signaling::fetch_directory(&self.config.signaling_uri).await
+    }
+    /// Fails if the publisher gave a secret of another room than the one
+    /// the entry was published in.
+    pub async fn join_directory_entry(
+        &self,
+        room: RoomTag,
+        entry: &DirectoryEntry,
+    ) -> Result<(), Error> {
+        if self.key_cache.hash(&entry.secret).await? != entry.hash {
+            return Err(Error::DirectoryMismatch);
+        }
+        self.join(room, Some(&entry.secret)).await
     }
 
     pub async fn spawn_ping(self: &Arc<Self>) {
         let blub = self.clone();
         tokio::spawn(async move {
             loop {
-                blub.ping().await;
-                debug!("ping");
+                // fails while reconnecting, which is fine
+                if let Err(e) = blub.ping().await {
+                    debug!("ping failed: {e}");
+                } else {
+                    debug!("ping");
+                }
                 tokio::time::sleep(Duration::from_secs(30)).await;
             }
         });
     }
 
-    pub async fn ping(&self) {
-        self.conn
-            .send
-            .write()
-            .await
-            .send(ServerboundPacket::Ping)
-            .await
-            .unwrap();
+    pub async fn ping(&self) -> Result<(), Error> {
+        self.send_packet(ServerboundPacket::Ping).await
     }
 
-    pub async fn my_id(&self) -> usize {
-        self.my_id.read().await.expect("not initialized yet")
+    pub async fn my_id(&self) -> Result<ClientId, Error> {
+        self.my_id.read().await.ok_or(Error::NotConnected)
     }
 
+    /// Handles packets forever, reconnecting whenever the connection closes.
+    /// Errors are logged, they only affect the packet that caused them.
     pub async fn receive_loop(self: Arc<Self>) {
-        while let Some(packet) = self.conn.recv.write().await.next().await {
-            let inst = self.clone();
-            inst.on_message(packet).await
+        loop {
+            while let Some(packet) = self.conn.recv.write().await.next().await {
+                let inst = self.clone();
+                if let Err(e) = inst.on_message(packet).await {
+                    warn!("{e}");
+                }
+            }
+            warn!("lost connection to the signaling server");
+            *self.my_id.write().await = None;
+            self.event_handler.disconnected().await;
+            self.reconnect().await;
+            self.event_handler.reconnected().await;
         }
     }
 
-    pub async fn on_message(self: Arc<Self>, packet: ClientboundPacket) {
+    async fn reconnect(self: &Arc<Self>) {
+        let mut delay = RECONNECT_DELAY;
+        loop {
+            info!("reconnecting in {delay:?}");
+            tokio::time::sleep(delay).await;
+            match self.conn.reconnect().await {
+                Ok(()) => break,
+                Err(e) => warn!("reconnect failed: {e}"),
+            }
+            delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+        }
+        let rooms = self
+            .rooms
+            .read()
+            .await
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        for room in rooms {
+            self.rejoin(room).await;
+        }
+    }
+
+    /// Joins a room again with the same key. Its peers are kept aside until
+    /// the server announces them again, which replaces them with new ones.
+    async fn rejoin(self: &Arc<Self>, room: Arc<Room>) {
+        let rejoin = room.rejoins.fetch_add(1, Ordering::Relaxed) + 1;
+        room.moderators.write().await.clear();
+        let peers = std::mem::take(&mut *room.peers.write().await);
+        room.stale.write().await.extend(peers);
+        if let Err(e) = self.send_join(room.tag, Some(&room)).await {
+            warn!("cannot rejoin room {}: {e}", room.tag);
+        }
+        let inst = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(inst.config.rejoin_timeout).await;
+            // a later reconnect gives the peers another timeout
+            if room.rejoins.load(Ordering::Relaxed) != rejoin {
+                return;
+            }
+            let gone = std::mem::take(&mut *room.stale.write().await);
+            for peer in gone.into_values() {
+                room.depart(&peer).await;
+                peer.on_leave().await;
+                inst.event_handler.peer_leave(peer).await;
+            }
+        });
+    }
+
+    pub async fn on_message(self: Arc<Self>, packet: ClientboundPacket) -> Result<(), Error> {
         match packet {
             protocol::ClientboundPacket::Init { your_id, version } => {
                 info!("server is running {version:?}");
                 *self.my_id.write().await = Some(your_id);
             }
-            protocol::ClientboundPacket::ClientJoin { id } => {
-                if id == self.my_id().await {
+            protocol::ClientboundPacket::ClientJoin { id, room } => {
+                if id == self.my_id().await? {
                     // we joined - YAY!
+                } else if let Some(context) = self.room(room).await {
+                    if let Some(previous) = context.stale.write().await.remove(&id) {
+                        // still there, but it dropped the connection to our previous id
+                        previous.on_leave().await;
+                        context.depart(&previous).await;
+                    }
+                    let peer = Peer::create(self.clone(), room, id).await?;
+                    context.resume(&peer).await;
+                    context.peers.write().await.insert(id, peer.clone());
+                    peer.init_remote().await?;
+                    self.event_handler.peer_join(peer.clone()).await;
+                    for p in context.release(id).await {
+                        self.accept_relay(peer.clone(), p).await?;
+                    }
                 } else {
-                    let peer = Peer::create(self.clone(), id).await;
-                    self.peers.write().await.insert(id, peer.clone());
-                    peer.init_remote().await;
-                    self.event_handler.peer_join(peer).await;
+                    warn!("({id}) joined room {room} that we are not in");
                 }
             }
-            protocol::ClientboundPacket::ClientLeave { id } => {
-                if id == self.my_id().await {
+            protocol::ClientboundPacket::ClientLeave { id, room } => {
+                let Some(context) = self.room(room).await else {
+                    return Ok(());
+                };
+                let my_id = self.my_id().await?;
+                if id == my_id {
                     // we left
-                } else if let Some(peer) = self.peers.write().await.remove(&id) {
+                    return Ok(());
+                }
+                context.release(id).await;
+                // not matched in place, that would keep the peers locked
+                let removed = context.peers.write().await.remove(&id);
+                if let Some(peer) = removed {
+                    context.depart(&peer).await;
+                    context.moderators.write().await.remove(&id);
                     peer.on_leave().await;
                     self.event_handler.peer_leave(peer).await;
+                    // only the member with the lowest id rekeys, so it happens once
+                    if self.config.rekey && context.peers.read().await.keys().all(|p| *p > my_id) {
+                        self.rekey(room).await?;
+                    }
                 }
             }
-            protocol::ClientboundPacket::Message { sender, message } => {
-                let message = self
-                    .key
-                    .read()
-                    .await
-                    .as_ref()
-                    .expect("not in a room")
-                    .decrypt(&message);
-                let p = serde_json::from_str::<RelayMessageWrapper>(&message).unwrap();
-                if p.sender == sender {
-                    self.on_relay(sender, p.inner).await;
+            protocol::ClientboundPacket::Message {
+                sender,
+                message,
+                room,
+            } => {
+                let Some(context) = self.room(room).await else {
+                    warn!("({sender}) dropping relay for room {room} that we are not in");
+                    return Ok(());
+                };
+                let Ok((p, pairwise)) = self.decrypt_relay(&context, sender, &message).await else {
+                    if self.config.private_relay {
+                        // likely meant for someone else and encrypted with their pairwise key
+                        debug!("({sender}) dropping relay we can not decrypt");
+                    } else {
+                        warn!("({sender}) dropping malformed relay");
+                    }
+                    return Ok(());
+                };
+                let my_id = self.my_id().await?;
+                if matches!(p.inner, RelayMessage::Rekey { .. }) && !pairwise {
+                    warn!("({sender}) dropping room key that was not sent under a pairwise key");
+                } else if p.recipient.is_some_and(|r| r != my_id) {
+                    // private relay for someone else, or misdirected by the server
+                } else if p.sender == sender {
+                    let Some(peer) = context.peers.read().await.get(&sender).cloned() else {
+                        if !context.hold(p).await {
+                            warn!("({sender}) dropping relay of a peer that did not join");
+                        }
+                        return Ok(());
+                    };
+                    self.accept_relay(peer, p).await?;
                 } else {
                     warn!("dropping packet with inconsistent sender")
                 }
@@ -127,69 +377,248 @@ impl Instance {
             protocol::ClientboundPacket::RoomInfo { hash, user_count } => {
                 self.event_handler.room_info(hash, user_count).await;
             }
+            protocol::ClientboundPacket::Moderator { id, room } => {
+                if let Some(context) = self.room(room).await {
+                    context.moderators.write().await.insert(id);
+                    self.event_handler.moderator(room, id).await;
+                }
+            }
+            protocol::ClientboundPacket::Locked { locked, room } => {
+                self.event_handler.room_locked(room, locked).await;
+            }
+            protocol::ClientboundPacket::Muted { id, muted, room } => {
+                self.event_handler.muted(room, id, muted).await;
+            }
+            protocol::ClientboundPacket::Kicked { room } => self.on_kicked(room).await,
+            protocol::ClientboundPacket::Error { message, room } => {
+                warn!("server error in room {room}: {message}");
+                self.event_handler.server_error(room, message).await;
+            }
+            protocol::ClientboundPacket::Directory { rooms } => {
+                self.event_handler.directory(rooms).await;
+            }
         }
+        Ok(())
     }
 
-    pub async fn on_relay(&self, sender: usize, p: RelayMessage) {
-        debug!("(relay) <- ({sender}) {p:?}");
-        if let Some(peer) = self.peers.read().await.get(&sender) {
-            peer.on_relay(p.clone()).await;
-            self.event_handler.on_relay(peer.to_owned(), &p).await;
-        } else {
-            warn!("got a packet from a non-existent peer")
+    async fn on_kicked(&self, room: RoomTag) {
+        warn!("we were kicked from room {room}");
+        if let Some(context) = self.rooms.write().await.remove(&room) {
+            self.close_room(&context).await;
         }
+        self.event_handler.kicked(room).await;
     }
 
-    pub async fn send_packet(&self, packet: ServerboundPacket) {
-        self.conn
-            .send
-            .write()
-            .await
-            .send(packet)
-            .await
-            .expect("why?");
+    async fn accept_relay(&self, peer: Arc<Peer>, p: RelayMessageWrapper) -> Result<(), Error> {
+        if let Err(e) = peer.replay_guard.check(&p, unix_millis()) {
+            warn!("({}) dropping relay: {e}", peer.id);
+            return Ok(());
+        }
+        self.on_relay(peer, p.inner).await
+    }
+    pub async fn on_relay(&self, peer: Arc<Peer>, p: RelayMessage) -> Result<(), Error> {
+        debug!("(relay) <- ({}) {p:?}", peer.id);
+        peer.on_relay(p.clone()).await?;
+        self.event_handler.on_relay(peer, &p).await;
+        Ok(())
     }
 
-    pub async fn send_relay(&self, recipient: Option<usize>, inner: RelayMessage) {
+    pub async fn send_packet(&self, packet: ServerboundPacket) -> Result<(), Error> {
+        Ok(self.conn.send.write().await.send(packet).await?)
+    }
+
+    pub async fn send_relay(
+        &self,
+        room: RoomTag,
+        recipient: Option<ClientId>,
+        inner: RelayMessage,
+    ) -> Result<(), Error> {
         debug!("(relay) -> ({recipient:?}) {inner:?}");
-        self.send_packet(ServerboundPacket::Relay {
+        let context = self.room(room).await.ok_or(Error::NotInRoom(room))?;
+        // the exchange itself has to be readable before the pairwise key exists
+        let pairwise = !matches!(inner, RelayMessage::KeyExchange { .. });
+        let private = self.config.private_relay && context.private_relay_supported().await;
+        let sender = self.my_id().await?;
+        // the counter is taken under the send lock, otherwise concurrent
+        // relays could reach the wire out of order and be dropped as replays
+        let mut send = self.conn.send.write().await;
+        let mut message = serde_json::to_string(&RelayMessageWrapper {
+            sender,
             recipient,
-            // TODO handle this error
-            message: self
-                .key
-                .read()
-                .await
-                .as_ref()
-                .expect("not in a room")
-                .encrypt(
-                    &serde_json::to_string(&RelayMessageWrapper {
-                        sender: self.my_id.read().await.expect("not ready to relay yet.."),
-                        inner,
-                    })
-                    .unwrap(),
-                ),
-        })
-        .await
+            counter: Some(self.relay_counter.fetch_add(1, Ordering::Relaxed) + 1),
+            timestamp: Some(unix_millis()),
+            inner,
+        })?;
+        if private {
+            // trailing whitespace is valid json
+            let padded = message.len().next_multiple_of(RELAY_PADDING);
+            message.push_str(&" ".repeat(padded - message.len()));
+        }
+        let message = self
+            .encrypt_relay(&context, recipient, pairwise, &message)
+            .await;
+        Ok(send
+            .send(ServerboundPacket::Relay {
+                recipient: if private { None } else { recipient },
+                message,
+                room,
+            })
+            .await?)
     }
 
-    pub async fn add_local_resource(&self, res: Box<dyn LocalResource>) {
-        for (_pid, peer) in self.peers.read().await.iter() {
-            peer.send_relay(RelayMessage::Provide(res.info())).await;
+    /// Directed relays use the pairwise key of the recipient once it is
+    /// established, otherwise the key of the secret, since the recipient may
+    /// not have a newer one yet. Broadcasts use the newest room key.
+    async fn encrypt_relay(
+        &self,
+        room: &Room,
+        recipient: Option<ClientId>,
+        pairwise: bool,
+        message: &str,
+    ) -> String {
+        if let Some(recipient) = recipient {
+            let peer = room.peers.read().await.get(&recipient).cloned();
+            if let Some(peer) = peer.filter(|_| pairwise) {
+                if let Some(key) = &*peer.pairwise_key.read().await {
+                    return key.encrypt(message);
+                }
+            }
+            return room.key.encrypt(message);
+        }
+        let epochs = room.epochs.read().await;
+        match &epochs.current {
+            Some(epoch) if room.rekey_supported().await => epoch.key.encrypt(message),
+            _ => room.key.encrypt(message),
+        }
+    }
+    /// Relays the sender sent before the key exchange completed use a room
+    /// key. Also tells whether the pairwise key was used.
+    ///
+    /// Everyone who ever knew the secret can use its key, kicked members too.
+    /// Once the room was rekeyed, it is only accepted from members that are
+    /// still joining, until [`Config::rekey_grace`] after their key
+    /// exchange.
+    async fn decrypt_relay(
+        &self,
+        room: &Room,
+        sender: ClientId,
+        message: &str,
+    ) -> Result<(RelayMessageWrapper, bool), Error> {
+        let now = Instant::now();
+        let mut pairwise_since = None;
+        let peer = room.peers.read().await.get(&sender).cloned();
+        if let Some(peer) = peer {
+            if let Some(key) = &*peer.pairwise_key.read().await {
+                if let Ok(p) = decode_relay(key, message) {
+                    return Ok((p, true));
+                }
+            }
+            pairwise_since = *peer.pairwise_since.read().await;
+        }
+        let secret_accepted = {
+            let epochs = room.epochs.read().await;
+            for key in epochs.keys(now) {
+                if let Ok(p) = decode_relay(key, message) {
+                    return Ok((p, false));
+                }
+            }
+            epochs.secret_accepted(now)
+        };
+        let joining = pairwise_since.is_none_or(|since| now < since + self.config.rekey_grace);
+        // without rekeying everywhere, broadcasts keep using the secret
+        if !(secret_accepted || joining || !room.rekey_supported().await) {
+            return Err(Error::Decrypt);
+        }
+        Ok((decode_relay(&room.key, message)?, false))
+    }
+
+    /// Replaces the room key with a fresh one and hands it to every member
+    /// under its pairwise key, so someone who only knows the secret can no
+    /// longer read broadcasts. Relays under the old key are accepted for
+    /// [`Config::rekey_grace`].
+    pub async fn rekey(&self, room: RoomTag) -> Result<(), Error> {
+        let context = self.room(room).await.ok_or(Error::NotInRoom(room))?;
+        if !context.rekey_supported().await {
+            warn!("not every peer supports rekeying; broadcasts keep using the secret");
+        }
+        let number = context.epochs.read().await.next_number()?;
+        self.adopt_epoch(&context, Epoch::generate(number, self.my_id().await?))
+            .await;
+        let peers = context
+            .peers
+            .read()
+            .await
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        for peer in peers {
+            self.send_epoch(&context, &peer).await?;
+        }
+        Ok(())
+    }
+    /// Hands the current room key to a peer, if it can receive it safely.
+    /// Peers only take keys from their author, who is still in the room,
+    /// since whoever rekeys after a leave is.
+    pub(crate) async fn send_epoch(&self, room: &Room, peer: &Peer) -> Result<(), Error> {
+        if !peer.rekey.load(Ordering::Relaxed) || peer.pairwise_key.read().await.is_none() {
+            return Ok(());
+        }
+        let my_id = self.my_id().await?;
+        let message = match &room.epochs.read().await.current {
+            Some(epoch) if epoch.author == my_id => RelayMessage::Rekey {
+                epoch: epoch.number,
+                author: epoch.author,
+                key: epoch.encoded.clone(),
+            },
+            _ => return Ok(()),
+        };
+        self.send_relay(room.tag, Some(peer.id), message).await
+    }
+    pub(crate) async fn adopt_epoch(&self, room: &Room, epoch: Epoch) {
+        let number = epoch.number;
+        if room.epochs.write().await.adopt(epoch, Instant::now()) {
+            info!("room {} uses key epoch {number} now", room.tag);
+            self.event_handler.room_rekeyed(room.tag, number).await;
+        }
+    }
+
+    /// Provides the resource to peers in all rooms.
+    pub async fn add_local_resource(&self, res: Box<dyn LocalResource>) -> Result<(), Error> {
+        for peer in self.peers().await {
+            peer.send_relay(RelayMessage::Provide(res.info())).await?;
         }
         self.local_resources
             .write()
             .await
             .insert(res.info().id, res);
+        Ok(())
     }
-    pub async fn remove_local_resource(&self, id: String) -> bool {
-        if let Some(_) = self.local_resources.write().await.remove(&id) {
-            for (_pid, peer) in self.peers.read().await.iter() {
+    /// Stops providing the resource. Every peer is told, even if some fail;
+    /// the first error is returned afterwards.
+    pub async fn remove_local_resource(&self, id: String) -> Result<bool, Error> {
+        if self.local_resources.write().await.remove(&id).is_none() {
+            return Ok(false);
+        }
+        let mut result = Ok(true);
+        for peer in self.peers().await {
+            let stopped = async {
+                peer.stop_sending(&id).await?;
                 peer.send_relay(RelayMessage::ProvideStop { id: id.clone() })
-                    .await;
+                    .await
+            };
+            if let Err(e) = stopped.await {
+                warn!("({}) cannot stop providing {id:?}: {e}", peer.id);
+                if result.is_ok() {
+                    result = Err(e);
+                }
             }
-            true
-        } else {
-            false
         }
+        result
     }
 }
+
+/// Decrypts and parses the contents of a [`ClientboundPacket::Message`].
+/// Everything in it comes from other peers and can not be trusted.
+pub fn decode_relay(key: &Key, message: &str) -> Result<RelayMessageWrapper, Error> {
+    Ok(serde_json::from_str(&key.decrypt(message)?)?)
+}
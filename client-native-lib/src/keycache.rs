@@ -0,0 +1,166 @@
+/*
+    This file is part of keks-meet (https://codeberg.org/metamuffin/keks-meet)
+    which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
+    Copyright (C) 2023 metamuffin <metamuffin.org>
+*/
+use crate::{
+    crypto::{self, Key},
+    Error,
+};
+use base64::Engine;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+use tokio::sync::{Mutex, RwLock};
+
+/// Runs key derivations on the blocking thread pool and remembers their
+/// results, so joining a room again is instant. Entries are as good as the
+/// room secrets themselves; the file is only readable by us.
+pub struct KeyCache {
+    path: Option<PathBuf>,
+    /// random per cache, so entry ids can not be checked against guessed secrets
+    salt: String,
+    /// sha256 of salt, kind and input, to the derived value
+    entries: RwLock<HashMap<String, String>>,
+    /// held while the file is written, so older contents never win
+    writing: Mutex<()>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct CacheFile {
+    salt: String,
+    entries: HashMap<String, String>,
+}
+
+impl KeyCache {
+    /// Without a path nothing is remembered beyond this session. A file that
+    /// can not be parsed is replaced.
+    pub fn load(path: Option<PathBuf>) -> Result<Self, Error> {
+        let file = match &path {
+            Some(path) => match fs::read_to_string(path) {
+                Ok(s) => serde_json::from_str(&s).unwrap_or_else(|e| {
+                    warn!("ignoring corrupt key cache {path:?}: {e}");
+                    CacheFile::default()
+                }),
+                Err(e) if e.kind() == ErrorKind::NotFound => CacheFile::default(),
+                Err(e) => return Err(e.into()),
+            },
+            None => CacheFile::default(),
+        };
+        let (salt, entries) = if file.salt.is_empty() {
+            (hex::encode(rand::random::<[u8; 32]>()), HashMap::new())
+        } else {
+            (file.salt, file.entries)
+        };
+        Ok(Self {
+            path,
+            salt,
+            entries: RwLock::new(entries),
+            writing: Mutex::new(()),
+        })
+    }
+
+    pub async fn key(&self, secret: &str) -> Result<Key, Error> {
+        let owned = secret.to_owned();
+        let key = self
+            .cached("key", secret, move || {
+                base64::engine::general_purpose::STANDARD.encode(crypto::derive_key(&owned))
+            })
+            .await?;
+        let key = base64::engine::general_purpose::STANDARD
+            .decode(key)
+            .ok()
+            .and_then(|k| k.try_into().ok())
+            .ok_or(Error::InvalidKeyCache)?;
+        Ok(Key::from_bytes(&key))
+    }
+    pub async fn hash(&self, secret: &str) -> Result<String, Error> {
+        let owned = secret.to_owned();
+        self.cached("hash", secret, move || crypto::hash(&owned))
+            .await
+    }
+    pub async fn moderator_hash(
+        &self,
+        secret: &str,
+        moderator_secret: &str,
+    ) -> Result<String, Error> {
+        let (owned, moderator_owned) = (secret.to_owned(), moderator_secret.to_owned());
+        self.cached(
+            "moderator",
+            &format!("{secret}\0{moderator_secret}"),
+            move || crypto::moderator_hash(&owned, &moderator_owned),
+        )
+        .await
+    }
+
+    async fn cached(
+        &self,
+        kind: &str,
+        input: &str,
+        derive: impl FnOnce() -> String + Send + 'static,
+    ) -> Result<String, Error> {
+        let id = hex::encode(Sha256::digest(format!("{}\0{kind}\0{input}", self.salt)));
+        if let Some(value) = self.entries.read().await.get(&id) {
+            debug!("using cached {kind}");
+            return Ok(value.clone());
+        }
+        let value = tokio::task::spawn_blocking(derive)
+            .await
+            .map_err(std::io::Error::from)?;
+        self.entries.write().await.insert(id, value.clone());
+        if let Some(path) = &self.path {
+            let _writing = self.writing.lock().await;
+            let file = serde_json::to_string(&CacheFile {
+                salt: self.salt.clone(),
+                entries: self.entries.read().await.clone(),
+            })?;
+            let path = path.clone();
+            tokio::task::spawn_blocking(move || write_atomic(&path, file.as_bytes()))
+                .await
+                .map_err(std::io::Error::from)??;
+        }
+        Ok(value)
+    }
+}
+
+/// Replaces a file by renaming a temporary one only we can read over it, so
+/// readers never see it half written.
+pub(crate) fn write_atomic(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(format!(".{:016x}.tmp", rand::random::<u64>()));
+    let temp = PathBuf::from(temp);
+    let result = create_private(&temp)
+        .and_then(|mut f| f.write_all(data).and_then(|_| f.sync_all()))
+        .and_then(|_| fs::rename(&temp, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    result
+}
+
+#[cfg(unix)]
+fn create_private(path: &Path) -> std::io::Result<fs::File> {
+    use std::os::unix::fs::OpenOptionsExt;
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)
+}
+#[cfg(not(unix))]
+fn create_private(path: &Path) -> std::io::Result<fs::File> {
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+}
@@ -0,0 +1,107 @@
+/*
+    This file is part of keks-meet (https://codeberg.org/metamuffin/keks-meet)
+    which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
+    Copyright (C) 2023 metamuffin <metamuffin.org>
+*/
+//! Signaling without a reachable server: one client hosts the server's
+//! signaling endpoint in-process and announces it on the local network via
+//! mDNS, where other clients can discover it.
+use crate::Error;
+use keks_meet_server::{config::FeaturesConfig, logic::State, signaling_routes};
+use log::{info, warn};
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::task::JoinHandle;
+
+pub const SERVICE_TYPE: &str = "_keks-meet._tcp.local.";
+
+/// A signaling server hosted by this process. Stops serving and announcing
+/// when dropped.
+pub struct LanServer {
+    addr: SocketAddr,
+    mdns: ServiceDaemon,
+    fullname: String,
+    task: JoinHandle<()>,
+}
+
+impl LanServer {
+    /// Serves signaling on all interfaces, on `port` or any free one if 0.
+    pub async fn start(port: u16) -> Result<Self, Error> {
+        let state = Arc::new(State::default());
+        let features = FeaturesConfig {
+            room_watches: false,
+            room_directory: false,
+        };
+        let (addr, server) = warp::serve(signaling_routes(state, features))
+            .try_bind_ephemeral(([0, 0, 0, 0], port))
+            .map_err(Error::LanBind)?;
+        let task = tokio::spawn(server);
+        info!("hosting signaling server on {addr}");
+
+        let mdns = ServiceDaemon::new()?;
+        let name = format!("keks-meet-{:08x}", rand::random::<u32>());
+        let service = ServiceInfo::new(
+            SERVICE_TYPE,
+            &name,
+            &format!("{name}.local."),
+            "",
+            addr.port(),
+            None,
+        )?
+        .enable_addr_auto();
+        let fullname = service.get_fullname().to_owned();
+        mdns.register(service)?;
+        info!("announcing as {fullname:?}");
+
+        Ok(Self {
+            addr,
+            mdns,
+            fullname,
+            task,
+        })
+    }
+
+    pub fn port(&self) -> u16 {
+        self.addr.port()
+    }
+    /// For the hosting client itself.
+    pub fn signaling_uri(&self) -> String {
+        format!("ws://127.0.0.1:{}", self.port())
+    }
+}
+
+impl Drop for LanServer {
+    fn drop(&mut self) {
+        if let Err(e) = self.mdns.unregister(&self.fullname) {
+            warn!("failed to unannounce: {e}");
+        }
+        let _ = self.mdns.shutdown();
+        self.task.abort();
+    }
+}
+
+/// Waits up to `timeout` for a signaling server announced on the local
+/// network and returns its URI. If there are several, the first one to
+/// answer is used.
+pub async fn discover(timeout: Duration) -> Result<Option<String>, Error> {
+    let mdns = ServiceDaemon::new()?;
+    let events = mdns.browse(SERVICE_TYPE)?;
+    let found = tokio::time::timeout(timeout, async {
+        while let Ok(event) = events.recv_async().await {
+            if let ServiceEvent::ServiceResolved(service) = event {
+                // prefer ipv4, link-local ipv6 addresses need a scope
+                let Some(ip) = service.get_addresses().iter().min_by_key(|ip| ip.is_ipv6()) else {
+                    continue;
+                };
+                info!("found {:?} at {ip}", service.get_fullname());
+                return Some(format!("ws://{}", SocketAddr::new(*ip, service.get_port())));
+            }
+        }
+        None
+    })
+    .await
+    .ok()
+    .flatten();
+    let _ = mdns.shutdown();
+    Ok(found)
+}
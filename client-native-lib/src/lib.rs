@@ -7,9 +7,11 @@
 // #![feature(async_fn_in_trait)]
 
 use futures_util::Future;
+use identity::Identity;
 use peer::{Peer, TransportChannel};
-use protocol::{ProvideInfo, RelayMessage};
-use std::{pin::Pin, sync::Arc};
+use protocol::{ClientId, DirectoryEntry, IceServer, ProvideInfo, RelayMessage, RoomTag};
+use sas::Sas;
+use std::{path::PathBuf, pin::Pin, sync::Arc, time::Duration};
 use webrtc::{
     api::{
         interceptor_registry::register_default_interceptors, media_engine::MediaEngine, APIBuilder,
@@ -18,37 +20,73 @@ use webrtc::{
 };
 
 pub mod crypto;
+pub mod error;
+pub mod identity;
 pub mod instance;
+pub mod keycache;
+#[cfg(feature = "lan")]
+pub mod lan;
 pub mod peer;
 pub mod protocol;
+pub mod room;
+pub mod sas;
 pub mod signaling;
 
+pub use error::Error;
 pub use webrtc;
 
 pub struct Config {
     pub signaling_uri: String,
     pub username: String,
+    /// Overrides the ICE servers advertised by the signaling server.
+    pub ice_servers: Option<Vec<IceServer>>,
+    /// Grants moderation in rooms created with the same moderator secret.
+    pub moderator_secret: Option<String>,
+    /// Hide relay recipients and message sizes from the server when every peer supports it.
+    pub private_relay: bool,
+    /// Proves to peers that we are the same user as last time.
+    pub identity: Option<Identity>,
+    /// Where identity keys of other users are remembered; only in memory if `None`.
+    pub known_identities: Option<PathBuf>,
+    /// Where keys derived from room secrets are remembered; only in memory if `None`.
+    pub key_cache: Option<PathBuf>,
+    /// Hand out a fresh room key whenever someone leaves, see [`instance::Instance::rekey`].
+    pub rekey: bool,
+    /// How long relays under a replaced room key are still accepted, usually [`room::REKEY_GRACE`].
+    pub rekey_grace: Duration,
+    /// How long peers have to join again after we reconnected, usually [`instance::REJOIN_TIMEOUT`].
+    pub rejoin_timeout: Duration,
 }
 
-pub(crate) fn build_api() -> webrtc::api::API {
+pub(crate) fn build_api() -> Result<webrtc::api::API, Error> {
     let mut media_engine = MediaEngine::default();
-    media_engine.register_default_codecs().unwrap();
+    media_engine.register_default_codecs()?;
     let mut registry = Registry::new();
-    registry = register_default_interceptors(registry, &mut media_engine).unwrap();
-    APIBuilder::new()
+    registry = register_default_interceptors(registry, &mut media_engine)?;
+    Ok(APIBuilder::new()
         .with_media_engine(media_engine)
         .with_interceptor_registry(registry)
-        .build()
+        .build())
 }
 
 pub type DynFut<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+#[allow(unused_variables)]
 pub trait LocalResource: Send + Sync + 'static {
     fn info(&self) -> ProvideInfo;
+    /// Should send the resource with [`Peer::add_track`] or
+    /// [`Peer::create_data_channel`], so it can be stopped again.
     fn on_request(&self, peer: Arc<Peer>) -> DynFut<()>;
+    /// The peer does not want the resource anymore. Its tracks are removed
+    /// and data channels closed already.
+    fn on_request_stop(&self, peer: Arc<Peer>) -> DynFut<()> {
+        Box::pin(async move {})
+    }
 }
 
 #[allow(unused_variables)]
 pub trait EventHandler: Send + Sync + 'static {
+    /// Also called for peers that are still there after a reconnect. The new
+    /// [`Peer`] replaces the old one with the same id.
     fn peer_join(&self, peer: Arc<Peer>) -> DynFut<()> {
         Box::pin(async move {})
     }
@@ -73,4 +111,48 @@ pub trait EventHandler: Send + Sync + 'static {
     fn room_info(&self, hash: String, user_count: usize) -> DynFut<()> {
         Box::pin(async move {})
     }
+    fn moderator(&self, room: RoomTag, id: ClientId) -> DynFut<()> {
+        Box::pin(async move {})
+    }
+    fn room_locked(&self, room: RoomTag, locked: bool) -> DynFut<()> {
+        Box::pin(async move {})
+    }
+    fn muted(&self, room: RoomTag, id: ClientId, muted: bool) -> DynFut<()> {
+        Box::pin(async move {})
+    }
+    fn kicked(&self, room: RoomTag) -> DynFut<()> {
+        Box::pin(async move {})
+    }
+    fn server_error(&self, room: RoomTag, message: String) -> DynFut<()> {
+        Box::pin(async move {})
+    }
+    fn directory(&self, rooms: Vec<DirectoryEntry>) -> DynFut<()> {
+        Box::pin(async move {})
+    }
+    /// A peer uses a name that was used with another identity key before;
+    /// see [`Peer::identity`].
+    fn identity_changed(&self, peer: Arc<Peer>) -> DynFut<()> {
+        Box::pin(async move {})
+    }
+    /// Relays in the room now use a new key.
+    fn room_rekeyed(&self, room: RoomTag, epoch: u64) -> DynFut<()> {
+        Box::pin(async move {})
+    }
+    /// Both sides derived the string of a verification; the user should
+    /// compare it and call [`Peer::confirm_verification`].
+    fn verification_sas(&self, peer: Arc<Peer>, sas: Sas) -> DynFut<()> {
+        Box::pin(async move {})
+    }
+    /// A verification ended, successfully only if both users confirmed.
+    fn verification_done(&self, peer: Arc<Peer>, verified: bool) -> DynFut<()> {
+        Box::pin(async move {})
+    }
+    /// The signaling connection was lost; reconnecting with backoff.
+    fn disconnected(&self) -> DynFut<()> {
+        Box::pin(async move {})
+    }
+    /// Connected again and rejoined all rooms.
+    fn reconnected(&self) -> DynFut<()> {
+        Box::pin(async move {})
+    }
 }
@@ -4,14 +4,29 @@
     Copyright (C) 2023 metamuffin <metamuffin.org>
 */
 use crate::{
+    crypto::{Key, KeyExchange},
+    identity::{fingerprint, verify_identify, IdentityStatus},
     instance::Instance,
-    protocol::{self, ProvideInfo, RelayMessage, Sdp},
+    protocol::{
+        self, ClientId, IceCandidateInit, ProvideInfo, RelayMessage, RelayMessageWrapper, RoomTag,
+        Sdp,
+    },
+    room::Epoch,
+    sas::{self, Sas, SasExchange, SasParty},
+    Error,
 };
 use log::{debug, info, warn};
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 use tokio::sync::RwLock;
 use webrtc::{
-    data_channel::RTCDataChannel,
+    data_channel::{data_channel_state::RTCDataChannelState, RTCDataChannel},
     ice_transport::{
         ice_candidate::{RTCIceCandidate, RTCIceCandidateInit},
         ice_server::RTCIceServer,
@@ -20,28 +35,177 @@ use webrtc::{
         configuration::RTCConfiguration, peer_connection_state::RTCPeerConnectionState,
         sdp::session_description::RTCSessionDescription, RTCPeerConnection,
     },
-    track::track_remote::TrackRemote,
+    rtp_transceiver::{rtp_receiver::RTCRtpReceiver, rtp_sender::RTCRtpSender},
+    track::{track_local::TrackLocal, track_remote::TrackRemote},
 };
 
 pub struct Peer {
     pub inst: Arc<Instance>,
     pub peer_connection: RTCPeerConnection,
-    pub remote_provided: RwLock<HashMap<String, ProvideInfo>>,
+    pub remote_provided: RwLock<HashMap<String, RemoteResource>>,
+    /// by id of the local resource the peer requested
+    local_senders: RwLock<HashMap<String, Vec<Sender>>>,
     pub username: RwLock<Option<String>>,
-    pub id: usize,
+    pub identity: RwLock<IdentityStatus>,
+    /// public key of the identity the peer presented
+    pub identity_key: RwLock<Option<String>>,
+    pub private_relay: AtomicBool,
+    /// peer follows room rekeys
+    pub rekey: AtomicBool,
+    pub(crate) replay_guard: ReplayGuard,
+    /// taken once the peer sent its public key
+    key_exchange: RwLock<Option<KeyExchange>>,
+    local_exchange_key: String,
+    /// identities are bound to this, see [`crate::identity::Identity::sign_identify`]
+    remote_exchange_key: RwLock<Option<String>>,
+    /// used for relays directed at this peer instead of the room key
+    pub(crate) pairwise_key: RwLock<Option<Key>>,
+    /// the peer may still use the key of the secret for a while after this
+    pub(crate) pairwise_since: RwLock<Option<Instant>>,
+    verification: RwLock<Option<Verification>>,
+    /// a short authentication string was compared in person and matched
+    pub sas_verified: AtomicBool,
+    /// the room we share with this peer; the same client in another room is another peer
+    pub room: RoomTag,
+    pub id: ClientId,
+}
+
+/// A resource provided by the peer, see [`Peer::request_resource`].
+pub struct RemoteResource {
+    pub info: ProvideInfo,
+    state: RemoteResourceState,
+    /// our end while connected
+    receiver: Option<Receiver>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemoteResourceState {
+    Disconnected,
+    /// requested, the track or data channel did not arrive yet
+    AwaitConnect,
+    Connected,
+    /// asked the peer to stop sending
+    AwaitDisconnect,
+}
+
+enum Receiver {
+    Track(Arc<RTCRtpReceiver>),
+    DataChannel(Arc<RTCDataChannel>),
+}
+
+impl Receiver {
+    async fn close(self) -> Result<(), Error> {
+        match self {
+            Receiver::Track(receiver) => receiver.stop().await?,
+            Receiver::DataChannel(dc) => dc.close().await?,
+        }
+        Ok(())
+    }
+}
+
+impl RemoteResource {
+    fn new(info: ProvideInfo) -> Self {
+        Self {
+            info,
+            state: RemoteResourceState::Disconnected,
+            receiver: None,
+        }
+    }
+    pub fn state(&self) -> RemoteResourceState {
+        match &self.receiver {
+            // closed by the provider, e.g. at the end of a file
+            Some(Receiver::DataChannel(dc)) if dc.ready_state() == RTCDataChannelState::Closed => {
+                RemoteResourceState::Disconnected
+            }
+            _ => self.state,
+        }
+    }
+}
+
+/// What we send to the peer for one of our resources.
+enum Sender {
+    Track(Arc<RTCRtpSender>),
+    DataChannel(Arc<RTCDataChannel>),
+}
+
+/// How far the timestamp of a relay may be off, including clock skew between peers.
+pub(crate) const MAX_RELAY_AGE: Duration = Duration::from_secs(5 * 60);
+
+/// Rejects relays of one sender that the server recorded and sent again.
+#[derive(Default)]
+pub struct ReplayGuard(Mutex<ReplayState>);
+
+#[derive(Default, Clone, Copy)]
+struct ReplayState {
+    /// highest counter seen so far; senders start at 1
+    counter: u64,
+    /// timestamp of the relay with that counter
+    timestamp: u64,
+    /// the sender left and joined again, maybe as a new client with the same id
+    resumed: bool,
+}
+
+impl ReplayGuard {
+    /// `now` is in milliseconds since the unix epoch. Relays without counter
+    /// and timestamp (from older clients) are accepted until the sender has
+    /// sent one with them.
+    pub fn check(&self, p: &RelayMessageWrapper, now: u64) -> Result<(), Error> {
+        let mut state = self.0.lock().unwrap();
+        let (Some(counter), Some(timestamp)) = (p.counter, p.timestamp) else {
+            return match state.counter {
+                0 => Ok(()),
+                _ => Err(Error::Replayed),
+            };
+        };
+        if now.abs_diff(timestamp) > MAX_RELAY_AGE.as_millis() as u64 {
+            return Err(Error::Stale);
+        }
+        // a new client restarts its counter, but its relays are newer
+        let restarted = state.resumed && timestamp > state.timestamp;
+        if counter <= state.counter && !restarted {
+            return Err(Error::Replayed);
+        }
+        *state = ReplayState {
+            counter,
+            timestamp,
+            resumed: false,
+        };
+        Ok(())
+    }
+    /// Continues where the guard of a peer with the same id left off, so
+    /// the server can not replay relays by announcing a leave and a join.
+    pub fn resume(&self, previous: &ReplayGuard) {
+        let previous = *previous.0.lock().unwrap();
+        *self.0.lock().unwrap() = ReplayState {
+            resumed: true,
+            ..previous
+        };
+    }
 }
 
-// pub struct RemoteResource {
-//     info: ProvideInfo,
-//     state: RemoteResourceInner,
-// }
-// // (Box<dyn FnOnce(Arc<TransportChannel>) -> Pin<Box<dyn Future<Output = ()>>>>)
-// pub enum RemoteResourceInner {
-//     Disconnected,
-//     AwaitConnect,
-//     Connected(Arc<TransportChannel>),
-//     AwaitDisconnect,
-// }
+pub(crate) fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Progress of a short authentication string comparison, see [`crate::sas`].
+enum Verification {
+    /// we committed to our key and wait for theirs
+    Started(SasExchange),
+    /// we sent our key and wait for the initiator to reveal its own
+    Accepted {
+        exchange: SasExchange,
+        commitment: String,
+    },
+    /// both users compare the string
+    Comparing {
+        sas: Sas,
+        confirmed: bool,
+        remote_confirmed: bool,
+    },
+}
 
 pub enum TransportChannel {
     Track(Arc<TrackRemote>),
@@ -49,22 +213,47 @@ pub enum TransportChannel {
 }
 
 impl Peer {
-    pub async fn create(inst: Arc<Instance>, id: usize) -> Arc<Self> {
+    pub async fn create(
+        inst: Arc<Instance>,
+        room: RoomTag,
+        id: ClientId,
+    ) -> Result<Arc<Self>, Error> {
         info!("({id}) peer joined");
         let config = RTCConfiguration {
-            ice_servers: vec![RTCIceServer {
-                urls: vec!["stun:metamuffin.org:16900".to_owned()],
-                ..Default::default()
-            }],
+            ice_servers: inst
+                .ice_servers
+                .iter()
+                .map(|s| RTCIceServer {
+                    urls: s.urls.clone(),
+                    username: s.username.clone().unwrap_or_default(),
+                    credential: s.credential.clone().unwrap_or_default(),
+                    ..Default::default()
+                })
+                .collect(),
             ..Default::default()
         };
 
-        let peer_connection = inst.api.new_peer_connection(config).await.unwrap();
+        let peer_connection = inst.api.new_peer_connection(config).await?;
+        let key_exchange = KeyExchange::new();
         let peer = Arc::new(Self {
             remote_provided: Default::default(),
+            local_senders: Default::default(),
             inst: inst.clone(),
             peer_connection,
             username: Default::default(),
+            identity: RwLock::new(IdentityStatus::Anonymous),
+            identity_key: Default::default(),
+            private_relay: AtomicBool::new(false),
+            rekey: AtomicBool::new(false),
+            replay_guard: ReplayGuard::default(),
+            local_exchange_key: key_exchange.public_key(),
+            key_exchange: RwLock::new(Some(key_exchange)),
+            remote_exchange_key: RwLock::new(None),
+            pairwise_key: RwLock::new(None),
+            pairwise_since: RwLock::new(None),
+            verification: RwLock::new(None),
+            sas_verified: AtomicBool::new(false),
+            room,
             id,
         });
         peer.peer_connection
@@ -78,7 +267,9 @@ impl Peer {
                 if let Some(peer) = weak.upgrade() {
                     Box::pin(async move {
                         if let Some(c) = c {
-                            peer.on_ice_candidate(c).await
+                            if let Err(e) = peer.on_ice_candidate(c).await {
+                                warn!("({}) cannot publish ICE candidate: {e}", peer.id)
+                            }
                         }
                     })
                 } else {
@@ -91,8 +282,15 @@ impl Peer {
             let weak = Arc::<Peer>::downgrade(&peer);
             peer.peer_connection
                 .on_negotiation_needed(Box::new(move || {
-                    let peer = weak.upgrade().unwrap();
-                    Box::pin(async { peer.on_negotiation_needed().await })
+                    let Some(peer) = weak.upgrade() else {
+                        return Box::pin(async {});
+                    };
+                    Box::pin(async move {
+                        let id = peer.id;
+                        if let Err(e) = peer.on_negotiation_needed().await {
+                            warn!("({id}) negotiation failed: {e}")
+                        }
+                    })
                 }))
         }
 
@@ -100,22 +298,31 @@ impl Peer {
             let weak = Arc::<Peer>::downgrade(&peer);
             peer.peer_connection
                 .on_track(Box::new(move |track_remote, receiver, _transceiver| {
-                    let peer = weak.upgrade().unwrap();
+                    let Some(peer) = weak.upgrade() else {
+                        return Box::pin(async {});
+                    };
                     Box::pin(async move {
                         let id = &track_remote.stream_id();
-                        if let Some(res) = peer.remote_provided.read().await.get(id) {
+                        let res = peer.remote_provided.write().await.get_mut(id).map(|res| {
+                            res.state = RemoteResourceState::Connected;
+                            res.receiver = Some(Receiver::Track(receiver.clone()));
+                            res.info.clone()
+                        });
+                        if let Some(res) = res {
                             info!("track for ({:?}) '{:?}'", res.id, res.label);
                             peer.inst
                                 .event_handler
                                 .resource_connected(
                                     peer.clone(),
-                                    res,
+                                    &res,
                                     TransportChannel::Track(track_remote),
                                 )
                                 .await;
                         } else {
                             warn!("got unassociated track; stopping receiver");
-                            receiver.stop().await.unwrap();
+                            if let Err(e) = receiver.stop().await {
+                                warn!("cannot stop receiver: {e}")
+                            }
                         }
                     })
                 }))
@@ -124,59 +331,167 @@ impl Peer {
         {
             let weak = Arc::<Peer>::downgrade(&peer);
             peer.peer_connection.on_data_channel(Box::new(move |dc| {
-                let peer = weak.upgrade().unwrap();
+                let Some(peer) = weak.upgrade() else {
+                    return Box::pin(async {});
+                };
                 Box::pin(async move {
-                    if let Some(res) = peer
+                    let res = peer
                         .remote_provided
-                        .read()
+                        .write()
                         .await
-                        .get(&dc.label().to_string())
-                    {
+                        .get_mut(dc.label())
+                        .map(|res| {
+                            res.state = RemoteResourceState::Connected;
+                            res.receiver = Some(Receiver::DataChannel(dc.clone()));
+                            res.info.clone()
+                        });
+                    if let Some(res) = res {
                         info!("data channel for ({:?}) '{:?}'", res.id, res.label);
                         peer.inst
                             .event_handler
                             .resource_connected(
                                 peer.clone(),
-                                res,
+                                &res,
                                 TransportChannel::DataChannel(dc),
                             )
                             .await;
                     } else {
                         warn!("got unassociated data channel; closed connection");
-                        dc.close().await.unwrap();
+                        if let Err(e) = dc.close().await {
+                            warn!("cannot close data channel: {e}")
+                        }
                     }
                 })
             }))
         }
-        peer
+        Ok(peer)
     }
 
-    pub async fn init_remote(&self) {
+    pub async fn init_remote(&self) -> Result<(), Error> {
+        // sent first so the identity signature can be checked against it
+        self.send_relay(RelayMessage::KeyExchange {
+            public_key: self.local_exchange_key.clone(),
+        })
+        .await?;
+        let username = self.inst.config.username.clone();
+        let (identity, signature) = match &self.inst.config.identity {
+            Some(identity) => (
+                Some(identity.public_key()),
+                Some(identity.sign_identify(
+                    &username,
+                    self.inst.my_id().await?,
+                    self.id,
+                    &self.local_exchange_key,
+                )),
+            ),
+            None => (None, None),
+        };
         self.send_relay(RelayMessage::Identify {
-            username: self.inst.config.username.clone(),
+            username,
+            private_relay: self.inst.config.private_relay,
+            rekey: true,
+            identity,
+            signature,
         })
-        .await;
+        .await?;
         for res in self.inst.local_resources.read().await.values() {
-            self.send_relay(RelayMessage::Provide(res.info())).await;
+            self.send_relay(RelayMessage::Provide(res.info())).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn request_resource(&self, id: String) -> Result<(), Error> {
+        {
+            let mut remote = self.remote_provided.write().await;
+            let res = remote
+                .get_mut(&id)
+                .ok_or_else(|| Error::UnknownResource(id.clone()))?;
+            // ports open another channel for every request
+            if res.state() != RemoteResourceState::Connected {
+                res.state = RemoteResourceState::AwaitConnect;
+            }
+        }
+        self.send_relay(RelayMessage::Request { id }).await
+    }
+    /// Also closes our end of the track or data channel.
+    pub async fn request_stop_resource(&self, id: String) -> Result<(), Error> {
+        let receiver = match self.remote_provided.write().await.get_mut(&id) {
+            Some(res) => {
+                res.state = RemoteResourceState::AwaitDisconnect;
+                res.receiver.take()
+            }
+            // removed by the peer in the meantime, nothing to stop
+            None => return Ok(()),
+        };
+        self.send_relay(RelayMessage::RequestStop { id: id.clone() })
+            .await?;
+        if let Some(receiver) = receiver {
+            receiver.close().await?;
+        }
+        if let Some(res) = self.remote_provided.write().await.get_mut(&id) {
+            if res.state == RemoteResourceState::AwaitDisconnect {
+                res.state = RemoteResourceState::Disconnected;
+            }
         }
+        Ok(())
     }
 
-    pub async fn request_resource(&self, id: String) {
-        self.send_relay(RelayMessage::Request { id }).await;
+    /// Creates the data channel for a local resource the peer requested. It
+    /// is closed when the peer stops the request.
+    pub async fn create_data_channel(&self, resource: &str) -> Result<Arc<RTCDataChannel>, Error> {
+        let channel = self
+            .peer_connection
+            .create_data_channel(resource, None)
+            .await?;
+        let mut senders = self.local_senders.write().await;
+        let senders = senders.entry(resource.to_owned()).or_default();
+        // a port forward opens a channel per connection, forget the finished ones
+        senders.retain(|s| match s {
+            Sender::DataChannel(dc) => dc.ready_state() != RTCDataChannelState::Closed,
+            Sender::Track(_) => true,
+        });
+        senders.push(Sender::DataChannel(channel.clone()));
+        Ok(channel)
+    }
+    /// Sends a track of a local resource the peer requested. The stream id of
+    /// the track has to be the id of the resource. It is removed again when
+    /// the peer stops the request.
+    pub async fn add_track(
+        &self,
+        resource: &str,
+        track: Arc<dyn TrackLocal + Send + Sync>,
+    ) -> Result<Arc<RTCRtpSender>, Error> {
+        let sender = self.peer_connection.add_track(track).await?;
+        self.local_senders
+            .write()
+            .await
+            .entry(resource.to_owned())
+            .or_default()
+            .push(Sender::Track(sender.clone()));
+        Ok(sender)
     }
-    pub async fn request_stop_resource(&self, id: String) {
-        self.send_relay(RelayMessage::RequestStop { id }).await;
+    /// Removes the tracks and closes the data channels of a local resource.
+    /// Removing a track renegotiates the connection.
+    pub(crate) async fn stop_sending(&self, resource: &str) -> Result<(), Error> {
+        let senders = self.local_senders.write().await.remove(resource);
+        for sender in senders.unwrap_or_default() {
+            match sender {
+                Sender::Track(sender) => self.peer_connection.remove_track(&sender).await?,
+                Sender::DataChannel(dc) => dc.close().await?,
+            }
+        }
+        Ok(())
     }
 
-    pub async fn send_relay(&self, inner: RelayMessage) {
-        self.inst.send_relay(Some(self.id), inner).await
+    pub async fn send_relay(&self, inner: RelayMessage) -> Result<(), Error> {
+        self.inst.send_relay(self.room, Some(self.id), inner).await
     }
 
-    pub async fn on_relay(self: &Arc<Self>, p: RelayMessage) {
+    pub async fn on_relay(self: &Arc<Self>, p: RelayMessage) -> Result<(), Error> {
         match p {
-            RelayMessage::Offer(o) => self.on_offer(o).await,
-            RelayMessage::Answer(a) => self.on_answer(a).await,
-            RelayMessage::IceCandidate(c) => self.on_remote_ice_candidate(c).await,
+            RelayMessage::Offer(o) => self.on_offer(o).await?,
+            RelayMessage::Answer(a) => self.on_answer(a).await?,
+            RelayMessage::IceCandidate(c) => self.on_remote_ice_candidate(c).await?,
             RelayMessage::Provide(info) => {
                 info!(
                     "remote resource provided: ({:?}) {:?} {:?}",
@@ -185,7 +500,9 @@ impl Peer {
                 self.remote_provided
                     .write()
                     .await
-                    .insert(info.id.clone(), info.clone());
+                    .entry(info.id.clone())
+                    .and_modify(|res| res.info = info.clone())
+                    .or_insert_with(|| RemoteResource::new(info.clone()));
                 self.inst
                     .event_handler
                     .resource_added(self.clone(), info)
@@ -193,16 +510,76 @@ impl Peer {
             }
             RelayMessage::ProvideStop { id } => {
                 info!("remote resource removed: ({:?}) ", id);
-                self.remote_provided.write().await.remove(&id);
+                let res = self.remote_provided.write().await.remove(&id);
+                // the peer stopped sending, close our end as well
+                if let Some(receiver) = res.and_then(|res| res.receiver) {
+                    receiver.close().await?;
+                }
                 self.inst
                     .event_handler
                     .resource_removed(self.clone(), id)
                     .await;
             }
             RelayMessage::Chat(_) => (),
-            RelayMessage::Identify { username } => {
-                info!("peer {} is known as {username:?}", self.id);
+            RelayMessage::Identify {
+                username,
+                private_relay,
+                rekey,
+                identity,
+                signature,
+            } => {
+                let status = match (identity, signature) {
+                    (Some(identity), Some(signature)) => {
+                        self.check_identity(&username, identity, &signature).await?
+                    }
+                    _ => IdentityStatus::Anonymous,
+                };
+                info!("peer {} is known as {username:?} ({status})", self.id);
+                let changed = matches!(status, IdentityStatus::Changed { .. });
+                *self.identity.write().await = status;
                 *self.username.write().await = Some(username);
+                self.private_relay.store(private_relay, Ordering::Relaxed);
+                self.rekey.store(rekey, Ordering::Relaxed);
+                if let Some(room) = self.inst.room(self.room).await {
+                    // the pairwise key exists by now, the exchange is sent first
+                    self.inst.send_epoch(&room, self).await?;
+                }
+                if changed {
+                    warn!("({}) presents a different identity than before", self.id);
+                    self.inst.event_handler.identity_changed(self.clone()).await;
+                }
+            }
+            RelayMessage::KeyExchange { public_key } => {
+                let Some(exchange) = self.key_exchange.write().await.take() else {
+                    warn!("({}) ignoring repeated key exchange", self.id);
+                    return Ok(());
+                };
+                *self.remote_exchange_key.write().await = Some(public_key.clone());
+                *self.pairwise_key.write().await = Some(exchange.finish(&public_key)?);
+                *self.pairwise_since.write().await = Some(Instant::now());
+                info!("({}) established pairwise key", self.id);
+            }
+            RelayMessage::VerifyStart { commitment } => self.on_verify_start(commitment).await?,
+            RelayMessage::VerifyKey { public_key } => {
+                if let Err(e) = self.on_verify_key(public_key).await {
+                    self.send_relay(RelayMessage::VerifyDone { confirmed: false })
+                        .await?;
+                    self.finish_verification(false).await?;
+                    return Err(e);
+                }
+            }
+            RelayMessage::VerifyDone { confirmed } => self.on_verify_done(confirmed).await?,
+            RelayMessage::Rekey { epoch, author, key } => {
+                // otherwise anyone could win ties in the name of someone else
+                if author != self.id {
+                    warn!("({}) ignoring room key of {author}", self.id);
+                    return Ok(());
+                }
+                if let Some(room) = self.inst.room(self.room).await {
+                    self.inst
+                        .adopt_epoch(&room, Epoch::decode(epoch, author, key)?)
+                        .await;
+                }
             }
             RelayMessage::Request { id } => {
                 if let Some(res) = self.inst.local_resources.read().await.get(&id) {
@@ -211,67 +588,310 @@ impl Peer {
                     warn!("({}) requested unknown local resource", self.id)
                 }
             }
-            RelayMessage::RequestStop { id: _ } => {} // TODO
+            RelayMessage::RequestStop { id } => {
+                info!("({}) stopped requesting ({id:?})", self.id);
+                self.stop_sending(&id).await?;
+                if let Some(res) = self.inst.local_resources.read().await.get(&id) {
+                    res.on_request_stop(self.clone()).await;
+                } else {
+                    warn!("({}) stopped unknown local resource", self.id)
+                }
+            }
+            RelayMessage::Preview { .. } => (),
+        }
+        Ok(())
+    }
+
+    /// Fails if the signature is invalid, so the name is not taken over.
+    async fn check_identity(
+        &self,
+        username: &str,
+        identity: String,
+        signature: &str,
+    ) -> Result<IdentityStatus, Error> {
+        let exchange_key = self.remote_exchange_key.read().await.clone();
+        verify_identify(
+            &identity,
+            signature,
+            username,
+            self.id,
+            self.inst.my_id().await?,
+            exchange_key.as_deref().ok_or(Error::InvalidSignature)?,
+        )?;
+        let status = self.inst.trust_store.check(username, &identity).await?;
+        *self.identity_key.write().await = Some(identity);
+        Ok(status)
+    }
+
+    /// Accepts the identity the peer presented for its name, e.g. after a
+    /// changed identity was confirmed in person. `false` if it has none.
+    pub async fn trust_identity(&self) -> Result<bool, Error> {
+        let (Some(username), Some(key)) = (
+            self.username.read().await.clone(),
+            self.identity_key.read().await.clone(),
+        ) else {
+            return Ok(false);
+        };
+        self.inst.trust_store.trust(&username, &key).await?;
+        *self.identity.write().await = IdentityStatus::Verified {
+            fingerprint: fingerprint(&key),
+        };
+        Ok(true)
+    }
+
+    /// Starts comparing a short authentication string with this peer. Both
+    /// sides are told the string with [`crate::EventHandler::verification_sas`].
+    pub async fn start_verification(&self) -> Result<(), Error> {
+        let exchange = SasExchange::new();
+        let commitment = sas::commitment(&exchange.public_key());
+        *self.verification.write().await = Some(Verification::Started(exchange));
+        self.send_relay(RelayMessage::VerifyStart { commitment })
+            .await
+    }
+
+    /// The string the user should currently compare, if any.
+    pub async fn pending_sas(&self) -> Option<Sas> {
+        match &*self.verification.read().await {
+            Some(Verification::Comparing { sas, .. }) => Some(*sas),
+            _ => None,
+        }
+    }
+
+    /// Tells the peer whether the user saw the same string on both sides.
+    pub async fn confirm_verification(self: &Arc<Self>, matches: bool) -> Result<(), Error> {
+        let mut verification = self.verification.write().await;
+        let Some(Verification::Comparing {
+            confirmed,
+            remote_confirmed,
+            ..
+        }) = &mut *verification
+        else {
+            return Err(Error::NotVerifying);
+        };
+        *confirmed = matches;
+        let done = !matches || *remote_confirmed;
+        if done {
+            *verification = None;
+        }
+        drop(verification);
+        self.send_relay(RelayMessage::VerifyDone { confirmed: matches })
+            .await?;
+        if done {
+            self.finish_verification(matches).await?;
+        }
+        Ok(())
+    }
+
+    async fn on_verify_start(&self, commitment: String) -> Result<(), Error> {
+        let mut verification = self.verification.write().await;
+        // when both sides start at once, the one with the lower id stays initiator
+        if matches!(*verification, Some(Verification::Started(_)))
+            && self.inst.my_id().await? < self.id
+        {
+            return Ok(());
+        }
+        info!("({}) wants to verify", self.id);
+        let exchange = SasExchange::new();
+        let public_key = exchange.public_key();
+        *verification = Some(Verification::Accepted {
+            exchange,
+            commitment,
+        });
+        drop(verification);
+        self.send_relay(RelayMessage::VerifyKey { public_key })
+            .await
+    }
+
+    async fn on_verify_key(self: &Arc<Self>, public_key: String) -> Result<(), Error> {
+        let mut verification = self.verification.write().await;
+        let (exchange, initiator) = match verification.take() {
+            Some(Verification::Started(exchange)) => (exchange, true),
+            Some(Verification::Accepted {
+                exchange,
+                commitment,
+            }) => {
+                if sas::commitment(&public_key) != commitment {
+                    return Err(Error::Commitment);
+                }
+                (exchange, false)
+            }
+            other => {
+                *verification = other;
+                warn!("({}) ignoring unexpected verification key", self.id);
+                return Ok(());
+            }
+        };
+        let own_key = exchange.public_key();
+        let sas = self
+            .derive_sas(exchange, own_key.clone(), public_key, initiator)
+            .await?;
+        *verification = Some(Verification::Comparing {
+            sas,
+            confirmed: false,
+            remote_confirmed: false,
+        });
+        drop(verification);
+        if initiator {
+            self.send_relay(RelayMessage::VerifyKey {
+                public_key: own_key,
+            })
+            .await?;
+        }
+        self.inst
+            .event_handler
+            .verification_sas(self.clone(), sas)
+            .await;
+        Ok(())
+    }
+
+    async fn on_verify_done(self: &Arc<Self>, confirmed: bool) -> Result<(), Error> {
+        let mut verification = self.verification.write().await;
+        let verified = match &mut *verification {
+            None => return Ok(()),
+            Some(Verification::Comparing {
+                confirmed: true, ..
+            }) if confirmed => true,
+            Some(Verification::Comparing {
+                remote_confirmed, ..
+            }) if confirmed => {
+                *remote_confirmed = true;
+                return Ok(());
+            }
+            // cancelled, or the strings did not match
+            Some(_) => false,
+        };
+        *verification = None;
+        drop(verification);
+        self.finish_verification(verified).await
+    }
+
+    /// Both sides derive the string from the same values, so a man in the
+    /// middle of either the signaling or the DTLS connection changes it.
+    async fn derive_sas(
+        &self,
+        exchange: SasExchange,
+        own_key: String,
+        their_key: String,
+        initiator: bool,
+    ) -> Result<Sas, Error> {
+        let ours = SasParty {
+            id: self.inst.my_id().await?,
+            key: own_key,
+            dtls_fingerprints: dtls_fingerprints(self.peer_connection.local_description().await),
+            identity: self.inst.config.identity.as_ref().map(|i| i.public_key()),
+        };
+        let theirs = SasParty {
+            id: self.id,
+            key: their_key,
+            dtls_fingerprints: dtls_fingerprints(self.peer_connection.remote_description().await),
+            identity: self.identity_key.read().await.clone(),
+        };
+        if ours.dtls_fingerprints.is_empty() || theirs.dtls_fingerprints.is_empty() {
+            info!(
+                "({}) no connection negotiated yet; only the signaling is verified",
+                self.id
+            );
+        }
+        if initiator {
+            exchange.finish(&ours, &theirs)
+        } else {
+            exchange.finish(&theirs, &ours)
         }
     }
 
+    async fn finish_verification(self: &Arc<Self>, verified: bool) -> Result<(), Error> {
+        self.sas_verified.store(verified, Ordering::Relaxed);
+        if verified {
+            info!("({}) verified", self.id);
+            // the identity key was part of the string, so it is confirmed as well
+            self.trust_identity().await?;
+        } else {
+            warn!("({}) verification failed", self.id);
+        }
+        self.inst
+            .event_handler
+            .verification_done(self.clone(), verified)
+            .await;
+        Ok(())
+    }
+
     pub async fn on_leave(&self) {
         info!("({}) peer left", self.id);
     }
 
-    pub async fn on_ice_candidate(&self, candidate: RTCIceCandidate) {
+    pub async fn on_ice_candidate(&self, candidate: RTCIceCandidate) -> Result<(), Error> {
         debug!("publishing local ICE candidate");
-        self.send_relay(RelayMessage::IceCandidate(candidate.to_json().unwrap()))
-            .await;
+        let c = candidate.to_json()?;
+        self.send_relay(RelayMessage::IceCandidate(IceCandidateInit {
+            candidate: c.candidate,
+            sdp_mid: c.sdp_mid,
+            sdp_mline_index: c.sdp_mline_index,
+            username_fragment: c.username_fragment,
+        }))
+        .await
     }
-    pub async fn on_remote_ice_candidate(&self, candidate: RTCIceCandidateInit) {
+    pub async fn on_remote_ice_candidate(&self, c: IceCandidateInit) -> Result<(), Error> {
         debug!("adding remote ICE candidate");
         self.peer_connection
-            .add_ice_candidate(candidate)
-            .await
-            .unwrap();
+            .add_ice_candidate(RTCIceCandidateInit {
+                candidate: c.candidate,
+                sdp_mid: c.sdp_mid,
+                sdp_mline_index: c.sdp_mline_index,
+                username_fragment: c.username_fragment,
+            })
+            .await?;
+        Ok(())
     }
 
-    pub async fn on_negotiation_needed(self: Arc<Self>) {
+    pub async fn on_negotiation_needed(self: Arc<Self>) -> Result<(), Error> {
         info!("({}) negotiation needed", self.id);
         self.offer().await
     }
 
-    pub async fn offer(&self) {
+    pub async fn offer(&self) -> Result<(), Error> {
         info!("({}) sending offer", self.id);
-        let offer = self.peer_connection.create_offer(None).await.unwrap();
+        let offer = self.peer_connection.create_offer(None).await?;
         self.peer_connection
             .set_local_description(offer.clone())
-            .await
-            .unwrap();
+            .await?;
         self.send_relay(protocol::RelayMessage::Offer(offer.sdp))
             .await
     }
-    pub async fn on_offer(&self, offer: Sdp) {
+    pub async fn on_offer(&self, offer: Sdp) -> Result<(), Error> {
         info!("({}) received offer", self.id);
-        let offer = RTCSessionDescription::offer(offer).unwrap();
-        self.peer_connection
-            .set_remote_description(offer)
-            .await
-            .unwrap();
+        let offer = RTCSessionDescription::offer(offer)?;
+        self.peer_connection.set_remote_description(offer).await?;
         self.answer().await
     }
-    pub async fn answer(&self) {
+    pub async fn answer(&self) -> Result<(), Error> {
         info!("({}) sending answer", self.id);
-        let offer = self.peer_connection.create_answer(None).await.unwrap();
+        let offer = self.peer_connection.create_answer(None).await?;
         self.peer_connection
             .set_local_description(offer.clone())
-            .await
-            .unwrap();
+            .await?;
         self.send_relay(protocol::RelayMessage::Answer(offer.sdp))
             .await
     }
-    pub async fn on_answer(&self, answer: Sdp) {
+    pub async fn on_answer(&self, answer: Sdp) -> Result<(), Error> {
         info!("({}) received answer", self.id);
-        let offer = RTCSessionDescription::answer(answer).unwrap();
-        self.peer_connection
-            .set_remote_description(offer)
-            .await
-            .unwrap();
+        let offer = RTCSessionDescription::answer(answer)?;
+        self.peer_connection.set_remote_description(offer).await?;
+        Ok(())
     }
 }
+
+/// The `a=fingerprint` lines of a description, which identify the DTLS certificate.
+fn dtls_fingerprints(description: Option<RTCSessionDescription>) -> String {
+    let mut lines = description
+        .map(|d| {
+            d.sdp
+                .lines()
+                .filter(|l| l.starts_with("a=fingerprint:"))
+                .map(|l| l.trim().to_owned())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    lines.sort();
+    lines.dedup();
+    lines.join("\n")
+}
@@ -3,76 +3,25 @@
     which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
     Copyright (C) 2023 metamuffin <metamuffin.org>
 */
+pub use keks_protocol::*;
 use serde::{Deserialize, Serialize};
-use webrtc::ice_transport::ice_candidate::RTCIceCandidateInit;
 
-pub type Sdp = String;
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(rename_all = "snake_case")]
-pub enum ClientboundPacket {
-    Init { your_id: usize, version: String },
-    ClientJoin { id: usize },
-    ClientLeave { id: usize },
-    Message { sender: usize, message: String },
-    RoomInfo { hash: String, user_count: usize },
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(rename_all = "snake_case")]
-pub enum ServerboundPacket {
-    Join {
-        hash: Option<String>,
-    },
-    Ping,
-    Relay {
-        recipient: Option<usize>,
-        message: String,
-    },
-    WatchRooms(Vec<String>),
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct RelayMessageWrapper {
-    pub sender: usize, // redundant, but ensures the server didnt cheat
-    pub inner: RelayMessage,
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(rename_all = "snake_case")]
-pub enum RelayMessage {
-    Chat(ChatMesssage),
-    Identify { username: String },
-
-    Provide(ProvideInfo),
-    Request { id: String },
-    ProvideStop { id: String },
-    RequestStop { id: String },
-
-    Offer(Sdp),
-    Answer(Sdp),
-    IceCandidate(RTCIceCandidateInit),
-}
-
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
-#[serde(rename_all = "snake_case")]
-pub enum ChatMesssage {
-    Text(String),
-    Image(String),
+/// The parts of the server's `/config.json` that native clients use.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClientConfig {
+    #[serde(default)]
+    pub webrtc: WebrtcConfig,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
-#[serde(rename_all = "snake_case")]
-pub enum TrackKind {
-    Audio,
-    Video,
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct WebrtcConfig {
+    #[serde(default)]
+    pub ice_servers: Vec<IceServer>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
-pub struct ProvideInfo {
-    pub id: String,
-    pub kind: String, // not an enum so we dont fail if we dont support it
-    pub track_kind: Option<TrackKind>,
-    pub label: Option<String>,
-    pub size: Option<usize>,
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct IceServer {
+    pub urls: Vec<String>,
+    pub username: Option<String>,
+    pub credential: Option<String>,
 }
@@ -0,0 +1,247 @@
+/*
+    This file is part of keks-meet (https://codeberg.org/metamuffin/keks-meet)
+    which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
+    Copyright (C) 2023 metamuffin <metamuffin.org>
+*/
+use crate::{
+    crypto::Key,
+    keycache::KeyCache,
+    peer::{Peer, ReplayGuard, MAX_RELAY_AGE},
+    protocol::{ClientId, RelayMessageWrapper, RoomTag},
+    Error,
+};
+use base64::Engine;
+use rand::{rngs::OsRng, RngCore};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::RwLock;
+
+/// How long relays under the replaced room key are still accepted.
+pub const REKEY_GRACE: Duration = Duration::from_secs(30);
+/// How many epochs a peer may skip ahead, e.g. after we missed a rekey while
+/// joining. Keeps anyone from making the numbers overflow quickly.
+pub const MAX_EPOCH_JUMP: u64 = 8;
+/// Relays held back per member whose join was not announced yet, and how
+/// many members that may be at once.
+const MAX_EARLY_RELAYS: usize = 16;
+const MAX_EARLY_SENDERS: usize = 16;
+
+/// A room joined over the shared signaling connection. Every room has its
+/// own key, so peers in one room can not read relays of another.
+pub struct Room {
+    pub tag: RoomTag,
+    pub secret: String,
+    /// derived from the secret; peers that just joined only know this one
+    pub(crate) key: Key,
+    pub(crate) epochs: RwLock<Epochs>,
+    /// sent to the server on every (re)join
+    pub(crate) hash: String,
+    pub(crate) moderator_hash: Option<String>,
+    pub peers: RwLock<HashMap<ClientId, Arc<Peer>>>,
+    /// peers from before a reconnect that have not joined again yet
+    pub(crate) stale: RwLock<HashMap<ClientId, Arc<Peer>>>,
+    /// counts reconnects, so only the timeout of the latest removes stale peers
+    pub(crate) rejoins: AtomicU64,
+    /// replay guards of peers that left, kept until their relays are stale
+    pub(crate) departed: RwLock<HashMap<ClientId, (Instant, ReplayGuard)>>,
+    /// relays of members that reached us before the server announced them
+    early: RwLock<HashMap<ClientId, Vec<RelayMessageWrapper>>>,
+    pub moderators: RwLock<HashSet<ClientId>>,
+}
+
+impl Room {
+    pub async fn new(
+        tag: RoomTag,
+        secret: &str,
+        moderator_secret: Option<&str>,
+        cache: &KeyCache,
+        rekey_grace: Duration,
+    ) -> Result<Self, Error> {
+        let (key, hash, moderator_hash) =
+            tokio::try_join!(cache.key(secret), cache.hash(secret), async {
+                match moderator_secret {
+                    Some(m) => cache.moderator_hash(secret, m).await.map(Some),
+                    None => Ok(None),
+                }
+            })?;
+        Ok(Self {
+            tag,
+            secret: secret.to_owned(),
+            key,
+            epochs: RwLock::new(Epochs::new(rekey_grace)),
+            hash,
+            moderator_hash,
+            peers: Default::default(),
+            stale: Default::default(),
+            rejoins: AtomicU64::new(0),
+            departed: Default::default(),
+            early: Default::default(),
+            moderators: Default::default(),
+        })
+    }
+
+    /// Remembers the replay guard of a peer that left.
+    pub(crate) async fn depart(&self, peer: &Peer) {
+        let guard = ReplayGuard::default();
+        guard.resume(&peer.replay_guard);
+        let mut departed = self.departed.write().await;
+        departed.retain(|_, (left, _)| left.elapsed() < MAX_RELAY_AGE);
+        departed.insert(peer.id, (Instant::now(), guard));
+    }
+    /// Continues the replay guard of an earlier peer with the same id.
+    pub(crate) async fn resume(&self, peer: &Peer) {
+        if let Some((left, guard)) = self.departed.write().await.remove(&peer.id) {
+            if left.elapsed() < MAX_RELAY_AGE {
+                peer.replay_guard.resume(&guard);
+            }
+        }
+    }
+
+    /// Keeps a relay of a member until the server announces its join, which
+    /// can arrive after the first relays of members that joined earlier.
+    /// `false` if too much is held back already.
+    pub(crate) async fn hold(&self, relay: RelayMessageWrapper) -> bool {
+        let mut early = self.early.write().await;
+        if !early.contains_key(&relay.sender) && early.len() >= MAX_EARLY_SENDERS {
+            return false;
+        }
+        let held = early.entry(relay.sender).or_default();
+        if held.len() >= MAX_EARLY_RELAYS {
+            return false;
+        }
+        held.push(relay);
+        true
+    }
+    /// Relays held back for a member, in the order they arrived.
+    pub(crate) async fn release(&self, id: ClientId) -> Vec<RelayMessageWrapper> {
+        self.early.write().await.remove(&id).unwrap_or_default()
+    }
+
+    /// Everyone has to follow rekeys before broadcasts use the new key.
+    pub(crate) async fn rekey_supported(&self) -> bool {
+        self.peers
+            .read()
+            .await
+            .values()
+            .all(|p| p.rekey.load(Ordering::Relaxed))
+    }
+
+    /// Broadcasting directed relays is only safe if every peer filters them.
+    pub(crate) async fn private_relay_supported(&self) -> bool {
+        self.peers
+            .read()
+            .await
+            .values()
+            .all(|p| p.private_relay.load(Ordering::Relaxed))
+    }
+}
+
+/// A room key that replaced the one derived from the secret.
+pub struct Epoch {
+    pub number: u64,
+    /// whoever generated it; breaks ties between concurrent rekeys
+    pub author: ClientId,
+    /// base64, handed to peers that join later
+    pub encoded: String,
+    pub key: Key,
+}
+
+impl Epoch {
+    pub fn generate(number: u64, author: ClientId) -> Self {
+        let mut key = [0u8; 32];
+        OsRng.fill_bytes(&mut key);
+        Self {
+            number,
+            author,
+            encoded: base64::engine::general_purpose::STANDARD.encode(key),
+            key: Key::from_bytes(&key),
+        }
+    }
+    pub fn decode(number: u64, author: ClientId, encoded: String) -> Result<Self, Error> {
+        let key: [u8; 32] = base64::engine::general_purpose::STANDARD
+            .decode(&encoded)
+            .ok()
+            .and_then(|k| k.try_into().ok())
+            .ok_or(Error::Rekey)?;
+        Ok(Self {
+            number,
+            author,
+            encoded,
+            key: Key::from_bytes(&key),
+        })
+    }
+}
+
+pub struct Epochs {
+    pub current: Option<Epoch>,
+    /// accepted until the deadline, for relays that were in flight
+    previous: Option<(Epoch, Instant)>,
+    /// the key of the secret is accepted until the first epoch is everywhere
+    secret_deadline: Option<Instant>,
+    grace: Duration,
+}
+
+impl Default for Epochs {
+    fn default() -> Self {
+        Self::new(REKEY_GRACE)
+    }
+}
+
+impl Epochs {
+    /// Replaced keys are accepted for `grace`.
+    pub fn new(grace: Duration) -> Self {
+        Self {
+            current: None,
+            previous: None,
+            secret_deadline: None,
+            grace,
+        }
+    }
+    /// Switches to `epoch` if it is newer than the current one, but not by
+    /// more than [`MAX_EPOCH_JUMP`], counting the first epoch from zero. Of
+    /// two keys for the same epoch, the one of the lower author wins
+    /// everywhere.
+    pub fn adopt(&mut self, epoch: Epoch, now: Instant) -> bool {
+        let newer = match &self.current {
+            None => epoch.number <= MAX_EPOCH_JUMP,
+            Some(current) if epoch.number.saturating_sub(current.number) > MAX_EPOCH_JUMP => false,
+            Some(current) => (epoch.number, current.author) > (current.number, epoch.author),
+        };
+        if newer {
+            self.previous = self.current.replace(epoch).map(|e| (e, now + self.grace));
+            self.secret_deadline.get_or_insert(now + self.grace);
+        }
+        newer
+    }
+    /// Fails once the numbers are used up.
+    pub fn next_number(&self) -> Result<u64, Error> {
+        match &self.current {
+            Some(e) => e.number.checked_add(1).ok_or(Error::Rekey),
+            None => Ok(1),
+        }
+    }
+    /// Whether members that already have a pairwise key may still use the
+    /// key of the secret.
+    pub fn secret_accepted(&self, now: Instant) -> bool {
+        self.secret_deadline.is_none_or(|deadline| now < deadline)
+    }
+    /// Keys relays may be encrypted with, besides pairwise ones and the one of the secret.
+    pub fn keys(&self, now: Instant) -> impl Iterator<Item = &Key> {
+        let previous = self
+            .previous
+            .as_ref()
+            .filter(|(_, deadline)| now < *deadline)
+            .map(|(e, _)| &e.key);
+        self.current
+            .as_ref()
+            .map(|e| &e.key)
+            .into_iter()
+            .chain(previous)
+    }
+}
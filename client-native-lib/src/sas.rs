@@ -0,0 +1,187 @@
+/*
+    This file is part of keks-meet (https://codeberg.org/metamuffin/keks-meet)
+    which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
+    Copyright (C) 2023 metamuffin <metamuffin.org>
+*/
+//! Short authentication strings, compared in person to rule out a man in the middle.
+//!
+//! The initiator commits to its ephemeral key before the responder reveals
+//! its own, so neither side can search for a colliding string.
+use crate::{protocol::ClientId, Error};
+use base64::Engine;
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+const SAS_INFO: &[u8] = b"keksmeet/sas";
+
+/// Same table as the emoji verification of matrix, so the pictures are
+/// recognizable across clients.
+pub const EMOJI: [(&str, &str); 64] = [
+    ("🐶", "dog"),
+    ("🐱", "cat"),
+    ("🦁", "lion"),
+    ("🐎", "horse"),
+    ("🦄", "unicorn"),
+    ("🐷", "pig"),
+    ("🐘", "elephant"),
+    ("🐰", "rabbit"),
+    ("🐼", "panda"),
+    ("🐓", "rooster"),
+    ("🐧", "penguin"),
+    ("🐢", "turtle"),
+    ("🐟", "fish"),
+    ("🐙", "octopus"),
+    ("🦋", "butterfly"),
+    ("🌷", "flower"),
+    ("🌳", "tree"),
+    ("🌵", "cactus"),
+    ("🍄", "mushroom"),
+    ("🌏", "globe"),
+    ("🌙", "moon"),
+    ("☁️", "cloud"),
+    ("🔥", "fire"),
+    ("🍌", "banana"),
+    ("🍎", "apple"),
+    ("🍓", "strawberry"),
+    ("🌽", "corn"),
+    ("🍕", "pizza"),
+    ("🎂", "cake"),
+    ("❤️", "heart"),
+    ("😀", "smiley"),
+    ("🤖", "robot"),
+    ("🎩", "hat"),
+    ("👓", "glasses"),
+    ("🔧", "spanner"),
+    ("🎅", "santa"),
+    ("👍", "thumbs up"),
+    ("☂️", "umbrella"),
+    ("⌛", "hourglass"),
+    ("⏰", "clock"),
+    ("🎁", "gift"),
+    ("💡", "light bulb"),
+    ("📕", "book"),
+    ("✏️", "pencil"),
+    ("📎", "paperclip"),
+    ("✂️", "scissors"),
+    ("🔒", "lock"),
+    ("🔑", "key"),
+    ("🔨", "hammer"),
+    ("☎️", "telephone"),
+    ("🏁", "flag"),
+    ("🚂", "train"),
+    ("🚲", "bicycle"),
+    ("✈️", "aeroplane"),
+    ("🚀", "rocket"),
+    ("🏆", "trophy"),
+    ("⚽", "ball"),
+    ("🎸", "guitar"),
+    ("🎺", "trumpet"),
+    ("🔔", "bell"),
+    ("⚓", "anchor"),
+    ("🎧", "headphones"),
+    ("📁", "folder"),
+    ("📌", "pin"),
+];
+
+/// Seven indices into [`EMOJI`], 42 bits in total.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sas([u8; 7]);
+
+impl Sas {
+    fn from_bytes(b: [u8; 6]) -> Self {
+        let bits = b.iter().fold(0u64, |a, b| (a << 8) | *b as u64);
+        Self(std::array::from_fn(|i| ((bits >> (42 - 6 * i)) & 63) as u8))
+    }
+    pub fn emoji(&self) -> impl Iterator<Item = (&'static str, &'static str)> + '_ {
+        self.0.iter().map(|i| EMOJI[*i as usize])
+    }
+}
+
+impl fmt::Display for Sas {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (emoji, name)) in self.emoji().enumerate() {
+            if i != 0 {
+                write!(f, "  ")?;
+            }
+            write!(f, "{emoji} {name}")?;
+        }
+        Ok(())
+    }
+}
+
+/// What one side of the verification contributes to the string.
+pub struct SasParty {
+    pub id: ClientId,
+    /// ephemeral key of this verification
+    pub key: String,
+    /// fingerprints of the DTLS certificate this side uses, as in its SDP
+    pub dtls_fingerprints: String,
+    pub identity: Option<String>,
+}
+
+/// Our ephemeral key for one verification.
+pub struct SasExchange {
+    secret: EphemeralSecret,
+    public: PublicKey,
+}
+
+impl SasExchange {
+    pub fn new() -> Self {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let public = PublicKey::from(&secret);
+        Self { secret, public }
+    }
+    pub fn public_key(&self) -> String {
+        base64::engine::general_purpose::STANDARD.encode(self.public.as_bytes())
+    }
+    /// Derives the string both users compare. The key of the other party is
+    /// taken from `initiator` or `responder`, whichever is not ours.
+    pub fn finish(self, initiator: &SasParty, responder: &SasParty) -> Result<Sas, Error> {
+        let ours = self.public_key();
+        let theirs = if initiator.key == ours {
+            &responder.key
+        } else {
+            &initiator.key
+        };
+        let theirs: [u8; 32] = base64::engine::general_purpose::STANDARD
+            .decode(theirs)
+            .ok()
+            .and_then(|k| k.try_into().ok())
+            .ok_or(Error::KeyExchange)?;
+        let shared = self.secret.diffie_hellman(&PublicKey::from(theirs));
+        if !shared.was_contributory() {
+            return Err(Error::KeyExchange);
+        }
+        let mut info = SAS_INFO.to_vec();
+        for party in [initiator, responder] {
+            for part in [
+                &party.id.to_string(),
+                &party.key,
+                &party.dtls_fingerprints,
+                party.identity.as_deref().unwrap_or_default(),
+            ] {
+                // length prefixes keep the parts apart
+                info.extend((part.len() as u64).to_be_bytes());
+                info.extend(part.as_bytes());
+            }
+        }
+        let mut sas = [0u8; 6];
+        Hkdf::<Sha256>::new(None, shared.as_bytes())
+            .expand(&info, &mut sas)
+            .unwrap();
+        Ok(Sas::from_bytes(sas))
+    }
+}
+impl Default for SasExchange {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Sent by the initiator instead of its key, which is revealed only after the responder's.
+pub fn commitment(public_key: &str) -> String {
+    base64::engine::general_purpose::STANDARD.encode(Sha256::digest(public_key.as_bytes()))
+}
@@ -3,73 +3,120 @@
     which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
     Copyright (C) 2023 metamuffin <metamuffin.org>
 */
-use crate::protocol::{ClientboundPacket, ServerboundPacket};
+use crate::{
+    protocol::{ClientConfig, ClientboundPacket, DirectoryEntry, ServerboundPacket},
+    Error,
+};
 use futures_util::{Sink, SinkExt, Stream, StreamExt};
-use log::{debug, error, info, trace};
-use std::pin::Pin;
+use log::{debug, error, info, trace, warn};
+use std::{future::ready, pin::Pin};
 use tokio::sync::RwLock;
 use tokio_tungstenite::tungstenite::{self, Message};
 
-pub struct SignalingConnection {
-    pub send: RwLock<
-        Pin<
-            Box<
-                dyn Sink<ServerboundPacket, Error = tokio_tungstenite::tungstenite::Error>
-                    + Send
-                    + Sync
-                    + 'static,
-            >,
-        >,
+type PacketSink = Pin<
+    Box<
+        dyn Sink<ServerboundPacket, Error = tokio_tungstenite::tungstenite::Error>
+            + Send
+            + Sync
+            + 'static,
     >,
-    pub recv: RwLock<Pin<Box<dyn Stream<Item = ClientboundPacket> + Send + Sync + 'static>>>,
+>;
+type PacketStream = Pin<Box<dyn Stream<Item = ClientboundPacket> + Send + Sync + 'static>>;
+
+pub struct SignalingConnection {
+    pub uri: String,
+    pub send: RwLock<PacketSink>,
+    /// Ends when the websocket is closed; see [`SignalingConnection::reconnect`].
+    pub recv: RwLock<PacketStream>,
 }
 
 impl SignalingConnection {
-    pub async fn new(signaling_server: &str) -> Self {
+    pub async fn new(signaling_server: &str) -> Result<Self, Error> {
         let uri = format!("{signaling_server}/signaling");
-        info!("connecting to signaling server at {uri:?}");
-        let (conn, _) = tokio_tungstenite::connect_async(url::Url::parse(&uri).unwrap())
-            .await
-            .unwrap();
-        info!("connection established");
+        let (send, recv) = connect(&uri).await?;
+        Ok(Self {
+            uri,
+            recv: RwLock::new(recv),
+            send: RwLock::new(send),
+        })
+    }
 
-        let (tx, rx): (_, _) = conn.split();
+    /// Replaces a closed connection with a new one to the same server.
+    pub async fn reconnect(&self) -> Result<(), Error> {
+        let (send, recv) = connect(&self.uri).await?;
+        *self.send.write().await = send;
+        *self.recv.write().await = recv;
+        Ok(())
+    }
+}
 
-        let tx = tx.with(async move |packet: ServerboundPacket| {
-            match packet {
-                ServerboundPacket::Relay { .. } => trace!(" ->  {packet:?}"),
-                _ => debug!(" ->  {packet:?}"),
-            }
-            Ok::<_, _>(Message::Text(
-                serde_json::to_string::<ServerboundPacket>(&packet).unwrap(),
-            ))
-        });
+async fn connect(uri: &str) -> Result<(PacketSink, PacketStream), Error> {
+    info!("connecting to signaling server at {uri:?}");
+    let (conn, _) = tokio_tungstenite::connect_async(url::Url::parse(uri)?).await?;
+    info!("connection established");
 
-        let rx = rx.filter_map(async move |mesg| match mesg {
-            Ok(mesg) => match mesg {
-                tungstenite::Message::Text(t) => {
-                    let packet: ClientboundPacket = serde_json::from_str(t.as_str()).unwrap();
-                    match packet {
-                        ClientboundPacket::Message { .. } => trace!(" <- {packet:?}"),
-                        _ => debug!(" <- {packet:?}"),
-                    }
-                    Some(packet)
+    let (tx, rx): (_, _) = conn.split();
+
+    let tx = tx.with(async move |packet: ServerboundPacket| {
+        match packet {
+            ServerboundPacket::Relay { .. } => trace!(" ->  {packet:?}"),
+            _ => debug!(" ->  {packet:?}"),
+        }
+        Ok::<_, _>(Message::Text(
+            serde_json::to_string::<ServerboundPacket>(&packet).unwrap(),
+        ))
+    });
+
+    let rx = rx
+        .take_while(|mesg| {
+            ready(match mesg {
+                Ok(tungstenite::Message::Close(e)) => {
+                    warn!("ws closed {e:?}");
+                    false
+                }
+                Ok(_) => true,
+                Err(e) => {
+                    error!("websocket error: {e}");
+                    false
                 }
-                tungstenite::Message::Close(e) => {
-                    error!("ws closed {e:?}");
-                    None
+            })
+        })
+        .filter_map(async move |mesg| match mesg {
+            Ok(tungstenite::Message::Text(t)) => {
+                let packet: ClientboundPacket = match serde_json::from_str(t.as_str()) {
+                    Ok(p) => p,
+                    Err(e) => {
+                        warn!("ignoring invalid packet from the server: {e}");
+                        return None;
+                    }
+                };
+                match packet {
+                    ClientboundPacket::Message { .. } => trace!(" <- {packet:?}"),
+                    _ => debug!(" <- {packet:?}"),
                 }
-                _ => None,
-            },
-            Err(e) => {
-                error!("websocket error: {e}");
-                None
+                Some(packet)
             }
+            _ => None,
         });
 
-        Self {
-            recv: RwLock::new(Box::pin(rx)),
-            send: RwLock::new(Box::pin(tx)),
-        }
+    Ok((Box::pin(tx), Box::pin(rx)))
+}
+
+/// Fetches `/config.json` from the host of the signaling server.
+pub async fn fetch_client_config(signaling_server: &str) -> Result<ClientConfig, Error> {
+    let uri = format!("{}/config.json", http_base(signaling_server));
+    info!("fetching client config from {uri:?}");
+    Ok(reqwest::get(uri).await?.error_for_status()?.json().await?)
+}
+
+pub async fn fetch_directory(signaling_server: &str) -> Result<Vec<DirectoryEntry>, Error> {
+    let uri = format!("{}/directory.json", http_base(signaling_server));
+    Ok(reqwest::get(uri).await?.error_for_status()?.json().await?)
+}
+
+fn http_base(signaling_server: &str) -> String {
+    match signaling_server.strip_prefix("ws") {
+        Some(rest) => format!("http{rest}"),
+        None => signaling_server.to_owned(),
     }
 }
@@ -0,0 +1,297 @@
+/*
+    This file is part of keks-meet (https://codeberg.org/metamuffin/keks-meet)
+    which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
+    Copyright (C) 2023 metamuffin <metamuffin.org>
+*/
+//! Shared by the integration tests that talk to a signaling server; not
+//! every test uses everything.
+#![allow(dead_code)]
+
+use futures_util::{SinkExt, StreamExt};
+use keks_meet_server::{config::FeaturesConfig, logic::State, signaling_routes};
+use libkeks::protocol::{ClientboundPacket, ServerboundPacket};
+use libkeks::{
+    instance::{Instance, REJOIN_TIMEOUT},
+    peer::{Peer, TransportChannel},
+    protocol::{ChatMessage, ClientId, ProvideInfo, RelayMessage},
+    room::REKEY_GRACE,
+    Config, DynFut, EventHandler,
+};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    task::JoinHandle,
+};
+use tokio_tungstenite::tungstenite::Message;
+
+/// Serves signaling in-process for as long as the test runtime lives.
+pub async fn serve() -> SocketAddr {
+    let features = FeaturesConfig {
+        room_watches: false,
+        room_directory: true,
+    };
+    let (addr, server) = warp::serve(signaling_routes(Arc::new(State::default()), features))
+        .bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+    addr
+}
+
+pub fn config(addr: SocketAddr) -> Config {
+    Config {
+        signaling_uri: format!("ws://{addr}"),
+        username: "test".to_string(),
+        ice_servers: Some(vec![]),
+        moderator_secret: None,
+        private_relay: false,
+        identity: None,
+        known_identities: None,
+        key_cache: None,
+        rekey: false,
+        rekey_grace: REKEY_GRACE,
+        rejoin_timeout: REJOIN_TIMEOUT,
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Event {
+    Join(ClientId),
+    Leave(ClientId),
+    Chat(ClientId, ChatMessage),
+    Disconnected,
+    Reconnected,
+    ResourceAdded(ClientId, String),
+    ResourceRemoved(ClientId, String),
+    ResourceConnected(ClientId, String),
+}
+
+pub struct Recorder(mpsc::UnboundedSender<Event>);
+impl EventHandler for Recorder {
+    fn peer_join(&self, peer: Arc<Peer>) -> DynFut<()> {
+        let _ = self.0.send(Event::Join(peer.id));
+        Box::pin(async move {})
+    }
+    fn peer_leave(&self, peer: Arc<Peer>) -> DynFut<()> {
+        let _ = self.0.send(Event::Leave(peer.id));
+        Box::pin(async move {})
+    }
+    fn on_relay(&self, peer: Arc<Peer>, message: &RelayMessage) -> DynFut<()> {
+        if let RelayMessage::Chat(chat) = message {
+            let _ = self.0.send(Event::Chat(peer.id, chat.clone()));
+        }
+        Box::pin(async move {})
+    }
+    fn disconnected(&self) -> DynFut<()> {
+        let _ = self.0.send(Event::Disconnected);
+        Box::pin(async move {})
+    }
+    fn reconnected(&self) -> DynFut<()> {
+        let _ = self.0.send(Event::Reconnected);
+        Box::pin(async move {})
+    }
+    fn resource_added(&self, peer: Arc<Peer>, info: ProvideInfo) -> DynFut<()> {
+        let _ = self.0.send(Event::ResourceAdded(peer.id, info.id));
+        Box::pin(async move {})
+    }
+    fn resource_removed(&self, peer: Arc<Peer>, id: String) -> DynFut<()> {
+        let _ = self.0.send(Event::ResourceRemoved(peer.id, id));
+        Box::pin(async move {})
+    }
+    fn resource_connected(
+        &self,
+        peer: Arc<Peer>,
+        resource: &ProvideInfo,
+        _channel: TransportChannel,
+    ) -> DynFut<()> {
+        let _ = self
+            .0
+            .send(Event::ResourceConnected(peer.id, resource.id.clone()));
+        Box::pin(async move {})
+    }
+}
+
+pub type Events = mpsc::UnboundedReceiver<Event>;
+
+/// An instance handling packets in the background, reporting its events.
+pub async fn instance(addr: SocketAddr) -> (Arc<Instance>, Events) {
+    instance_with(config(addr)).await
+}
+/// Like [`instance`], with another configuration.
+pub async fn instance_with(config: Config) -> (Arc<Instance>, Events) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let inst = Instance::new(config, Arc::new(Recorder(tx))).await.unwrap();
+    tokio::spawn(inst.clone().receive_loop());
+    (inst, rx)
+}
+
+pub async fn next(events: &mut Events, timeout: Duration) -> Event {
+    tokio::time::timeout(timeout, events.recv())
+        .await
+        .expect("timed out waiting for an event")
+        .unwrap()
+}
+pub async fn expect_no_event(events: &mut Events) {
+    if let Ok(event) = tokio::time::timeout(Duration::from_millis(500), events.recv()).await {
+        panic!("unexpected event {event:?}");
+    }
+}
+
+/// A signaling server that only sends what the test tells it to, like a
+/// malicious one would.
+pub struct FakeServer {
+    pub addr: SocketAddr,
+    listener: TcpListener,
+}
+
+pub struct FakeConnection {
+    ws: tokio_tungstenite::WebSocketStream<TcpStream>,
+}
+
+impl FakeServer {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        Self {
+            addr: listener.local_addr().unwrap(),
+            listener,
+        }
+    }
+    pub async fn accept(&self) -> FakeConnection {
+        let (stream, _) = self.listener.accept().await.unwrap();
+        let ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+        FakeConnection { ws }
+    }
+}
+
+impl FakeConnection {
+    pub async fn send(&mut self, packet: ClientboundPacket) {
+        let text = serde_json::to_string(&packet).unwrap();
+        self.ws.send(Message::text(text)).await.unwrap();
+    }
+    /// The next packet the client sends.
+    pub async fn recv(&mut self) -> ServerboundPacket {
+        while let Some(Ok(message)) = self.ws.next().await {
+            if let Message::Text(text) = message {
+                return serde_json::from_str(&text).unwrap();
+            }
+        }
+        panic!("connection closed");
+    }
+    /// Skips everything else the client sends.
+    pub async fn expect_join(&mut self) {
+        while let Some(Ok(message)) = self.ws.next().await {
+            if let Message::Text(text) = message {
+                if let Ok(ServerboundPacket::Join { .. }) = serde_json::from_str(&text) {
+                    return;
+                }
+            }
+        }
+        panic!("connection closed before join");
+    }
+    /// Keeps reading so the client never blocks on us.
+    pub fn drain(self) -> mpsc::Sender<ClientboundPacket> {
+        let (tx, mut rx) = mpsc::channel::<ClientboundPacket>(16);
+        let (mut sink, mut stream) = self.ws.split();
+        tokio::spawn(async move { while let Some(Ok(_)) = stream.next().await {} });
+        tokio::spawn(async move {
+            while let Some(packet) = rx.recv().await {
+                let text = serde_json::to_string(&packet).unwrap();
+                if sink.send(Message::text(text)).await.is_err() {
+                    break;
+                }
+            }
+        });
+        tx
+    }
+}
+
+/// Forwards connections to a server and can break them on demand.
+pub struct Proxy {
+    pub addr: SocketAddr,
+    state: Arc<Mutex<ProxyState>>,
+}
+
+#[derive(Default)]
+struct ProxyState {
+    /// number of upcoming connections closed right away
+    refuse: usize,
+    /// holds back what the server sends after the handshake of the next connection
+    delay: Option<Duration>,
+    links: Vec<JoinHandle<()>>,
+    attempts: Vec<Instant>,
+}
+
+impl Proxy {
+    pub async fn start(upstream: SocketAddr) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = Arc::<Mutex<ProxyState>>::default();
+        let s = state.clone();
+        tokio::spawn(async move {
+            while let Ok((client, _)) = listener.accept().await {
+                let mut state = s.lock().unwrap();
+                state.attempts.push(Instant::now());
+                if state.refuse > 0 {
+                    state.refuse -= 1;
+                    continue;
+                }
+                let delay = state.delay.take();
+                state
+                    .links
+                    .push(tokio::spawn(link(client, upstream, delay)));
+            }
+        });
+        Self { addr, state }
+    }
+    /// Drops the open connections and refuses the next ones.
+    pub fn cut(&self, refuse: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.refuse = refuse;
+        for link in state.links.drain(..) {
+            link.abort();
+        }
+    }
+    pub fn delay_next(&self, delay: Duration) {
+        self.state.lock().unwrap().delay = Some(delay);
+    }
+    /// When connections were attempted.
+    pub fn attempts(&self) -> Vec<Instant> {
+        self.state.lock().unwrap().attempts.clone()
+    }
+}
+
+async fn link(client: TcpStream, upstream: SocketAddr, delay: Option<Duration>) {
+    let server = TcpStream::connect(upstream).await.unwrap();
+    let (mut client_rx, mut client_tx) = client.into_split();
+    let (mut server_rx, mut server_tx) = server.into_split();
+    let up = async {
+        let _ = tokio::io::copy(&mut client_rx, &mut server_tx).await;
+    };
+    let down = async {
+        let mut buf = vec![0; 4096];
+        let mut handshake = delay.is_some();
+        while let Ok(n) = server_rx.read(&mut buf).await {
+            if n == 0 {
+                break;
+            }
+            let mut chunk = &buf[..n];
+            if handshake {
+                if let Some(end) = chunk.windows(4).position(|w| w == b"\r\n\r\n") {
+                    handshake = false;
+                    let (head, rest) = chunk.split_at(end + 4);
+                    let _ = client_tx.write_all(head).await;
+                    tokio::time::sleep(delay.unwrap()).await;
+                    chunk = rest;
+                }
+            }
+            if client_tx.write_all(chunk).await.is_err() {
+                break;
+            }
+        }
+    };
+    tokio::join!(up, down);
+}
@@ -0,0 +1,122 @@
+/*
+    This file is part of keks-meet (https://codeberg.org/metamuffin/keks-meet)
+    which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
+    Copyright (C) 2023 metamuffin <metamuffin.org>
+*/
+mod common;
+
+use common::{instance, next, Event, FakeServer};
+use libkeks::{
+    crypto::{Key, KeyExchange},
+    protocol::{
+        ChatMessage, ClientId, ClientboundPacket, RelayMessage, RelayMessageWrapper,
+        ServerboundPacket, DEFAULT_ROOM,
+    },
+};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[test]
+fn pairwise_keys_match() {
+    let (alice, bob) = (KeyExchange::new(), KeyExchange::new());
+    let (alice_public, bob_public) = (alice.public_key(), bob.public_key());
+    let alice = alice.finish(&bob_public).unwrap();
+    let bob = bob.finish(&alice_public).unwrap();
+    assert_eq!(bob.decrypt(&alice.encrypt("hello")).unwrap(), "hello");
+    assert_eq!(alice.decrypt(&bob.encrypt("hello")).unwrap(), "hello");
+}
+
+#[test]
+fn pairwise_keys_differ_per_pair() {
+    let (alice, bob, eve) = (KeyExchange::new(), KeyExchange::new(), KeyExchange::new());
+    let eve_public = eve.public_key();
+    let alice_bob = alice.finish(&bob.public_key()).unwrap();
+    let bob_eve = bob.finish(&eve_public).unwrap();
+    assert!(bob_eve.decrypt(&alice_bob.encrypt("hello")).is_err());
+}
+
+#[test]
+fn rejects_invalid_public_keys() {
+    // the last one is the all-zero point, which would make the shared secret predictable
+    for public_key in ["", "AAAA", "not base64", &format!("{}=", "A".repeat(43))] {
+        assert!(
+            KeyExchange::new().finish(public_key).is_err(),
+            "{public_key:?}"
+        );
+    }
+}
+
+fn relay(key: &Key, counter: u64, inner: RelayMessage) -> ClientboundPacket {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    let wrapper = RelayMessageWrapper {
+        sender: ClientId(1),
+        recipient: None,
+        counter: Some(counter),
+        timestamp: Some(timestamp),
+        inner,
+    };
+    ClientboundPacket::Message {
+        sender: ClientId(1),
+        message: key.encrypt(&serde_json::to_string(&wrapper).unwrap()),
+        room: DEFAULT_ROOM,
+    }
+}
+
+/// A member that was in the room before us starts the exchange as soon as
+/// we join, which the server may deliver before announcing the member.
+#[tokio::test]
+async fn key_exchange_before_join() {
+    let server = FakeServer::start().await;
+    let ((inst, mut events), mut conn) = tokio::join!(instance(server.addr), server.accept());
+    conn.send(ClientboundPacket::Init {
+        your_id: ClientId(2),
+        version: "test".to_string(),
+    })
+    .await;
+    inst.join(DEFAULT_ROOM, Some("hunter2")).await.unwrap();
+    conn.expect_join().await;
+    conn.send(ClientboundPacket::ClientJoin {
+        id: ClientId(2),
+        room: DEFAULT_ROOM,
+    })
+    .await;
+
+    let secret = Key::derive("hunter2");
+    let exchange = KeyExchange::new();
+    let ours = exchange.public_key();
+    conn.send(relay(
+        &secret,
+        1,
+        RelayMessage::KeyExchange { public_key: ours },
+    ))
+    .await;
+    conn.send(ClientboundPacket::ClientJoin {
+        id: ClientId(1),
+        room: DEFAULT_ROOM,
+    })
+    .await;
+    let second = Duration::from_secs(1);
+    assert_eq!(next(&mut events, second).await, Event::Join(ClientId(1)));
+
+    let theirs = loop {
+        if let ServerboundPacket::Relay { message, .. } = conn.recv().await {
+            let wrapper: RelayMessageWrapper =
+                serde_json::from_str(&secret.decrypt(&message).unwrap()).unwrap();
+            if let RelayMessage::KeyExchange { public_key } = wrapper.inner {
+                break public_key;
+            }
+        }
+    };
+    let pairwise = exchange.finish(&theirs).unwrap();
+    let conn = conn.drain();
+    let hello = ChatMessage::Text("hello".to_string());
+    conn.send(relay(&pairwise, 2, RelayMessage::Chat(hello.clone())))
+        .await
+        .unwrap();
+    assert_eq!(
+        next(&mut events, second).await,
+        Event::Chat(ClientId(1), hello)
+    );
+}
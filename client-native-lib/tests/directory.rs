@@ -0,0 +1,38 @@
+/*
+    This file is part of keks-meet (https://codeberg.org/metamuffin/keks-meet)
+    which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
+    Copyright (C) 2023 metamuffin <metamuffin.org>
+*/
+mod common;
+
+use common::{instance, serve};
+use libkeks::{crypto, protocol::DirectoryEntry, Error};
+
+fn entry(secret: &str, hash: String) -> DirectoryEntry {
+    DirectoryEntry {
+        name: "lobby".to_string(),
+        description: String::new(),
+        secret: secret.to_string(),
+        hash,
+        user_count: 1,
+    }
+}
+
+#[tokio::test]
+async fn joins_matching_entry() {
+    let (inst, _) = instance(serve().await).await;
+    inst.join_directory_entry(0, &entry("hunter2", crypto::hash("hunter2")))
+        .await
+        .unwrap();
+    assert_eq!(inst.room(0).await.unwrap().secret, "hunter2");
+}
+
+#[tokio::test]
+async fn rejects_secret_of_another_room() {
+    let (inst, _) = instance(serve().await).await;
+    let result = inst
+        .join_directory_entry(0, &entry("hunter2", crypto::hash("hunter3")))
+        .await;
+    assert!(matches!(result, Err(Error::DirectoryMismatch)));
+    assert!(inst.room(0).await.is_none());
+}
@@ -0,0 +1,74 @@
+/*
+    This file is part of keks-meet (https://codeberg.org/metamuffin/keks-meet)
+    which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
+    Copyright (C) 2023 metamuffin <metamuffin.org>
+*/
+use libkeks::{
+    identity::{verify_identify, Identity, IdentityStatus, TrustStore},
+    protocol::ClientId,
+};
+
+#[test]
+fn identify_signature_is_bound_to_session() {
+    let (a, b, c) = (ClientId(1), ClientId(2), ClientId(3));
+    let alice = Identity::generate();
+    let signature = alice.sign_identify("alice", a, b, "ephemeral");
+    let key = alice.public_key();
+    assert!(verify_identify(&key, &signature, "alice", a, b, "ephemeral").is_ok());
+    assert!(verify_identify(&key, &signature, "mallory", a, b, "ephemeral").is_err());
+    assert!(verify_identify(&key, &signature, "alice", a, c, "ephemeral").is_err());
+    assert!(verify_identify(&key, &signature, "alice", c, b, "ephemeral").is_err());
+    assert!(verify_identify(&key, &signature, "alice", a, b, "other").is_err());
+    let mallory = Identity::generate().public_key();
+    assert!(verify_identify(&mallory, &signature, "alice", a, b, "ephemeral").is_err());
+}
+
+#[tokio::test]
+async fn trust_on_first_use() {
+    let store = TrustStore::load(None).unwrap();
+    let (alice, mallory) = (Identity::generate(), Identity::generate());
+    assert!(matches!(
+        store.check("alice", &alice.public_key()).await.unwrap(),
+        IdentityStatus::New { .. }
+    ));
+    assert_eq!(
+        store.check("alice", &alice.public_key()).await.unwrap(),
+        IdentityStatus::Verified {
+            fingerprint: alice.fingerprint()
+        }
+    );
+    assert_eq!(
+        store.check("alice", &mallory.public_key()).await.unwrap(),
+        IdentityStatus::Changed {
+            fingerprint: mallory.fingerprint(),
+            known: alice.fingerprint()
+        }
+    );
+    store.trust("alice", &mallory.public_key()).await.unwrap();
+    assert!(matches!(
+        store.check("alice", &mallory.public_key()).await.unwrap(),
+        IdentityStatus::Verified { .. }
+    ));
+}
+
+#[tokio::test]
+async fn trust_store_persists() {
+    let path = std::env::temp_dir().join(format!("keks-known-{}.json", std::process::id()));
+    let alice = Identity::generate();
+    TrustStore::load(Some(path.clone()))
+        .unwrap()
+        .check("alice", &alice.public_key())
+        .await
+        .unwrap();
+    let store = TrustStore::load(Some(path.clone())).unwrap();
+    let status = store.check("alice", &alice.public_key()).await.unwrap();
+    #[cfg(unix)]
+    let mode = {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::metadata(&path).unwrap().permissions().mode() & 0o777
+    };
+    std::fs::remove_file(path).unwrap();
+    assert!(matches!(status, IdentityStatus::Verified { .. }));
+    #[cfg(unix)]
+    assert_eq!(mode, 0o600);
+}
@@ -0,0 +1,93 @@
+/*
+    This file is part of keks-meet (https://codeberg.org/metamuffin/keks-meet)
+    which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
+    Copyright (C) 2023 metamuffin <metamuffin.org>
+*/
+use libkeks::{
+    crypto::{derive_key, hash, Kdf},
+    keycache::KeyCache,
+};
+
+#[test]
+fn version_prefix() {
+    assert_eq!(Kdf::parse("hunter2"), (Kdf::Pbkdf2, "hunter2"));
+    assert_eq!(Kdf::parse("v1:hunter2"), (Kdf::Pbkdf2, "v1:hunter2"));
+    assert_eq!(Kdf::parse("v2:hunter2"), (Kdf::Argon2id, "hunter2"));
+    assert_eq!(Kdf::parse("v3:hunter2"), (Kdf::Pbkdf2, "v3:hunter2"));
+}
+
+#[test]
+fn other_prefixes_keep_their_key() {
+    assert_ne!(hash("v1:hunter2"), hash("hunter2"));
+}
+
+#[test]
+fn argon2id_differs() {
+    let key = derive_key("v2:hunter2");
+    assert_eq!(key, derive_key("v2:hunter2"));
+    assert_ne!(key, derive_key("v2:hunter3"));
+    assert_ne!(hash("v2:hunter2"), hex::encode(key));
+}
+
+#[tokio::test]
+async fn cache_persists() {
+    let path = std::env::temp_dir().join(format!("keks-keys-{}.json", std::process::id()));
+    let cache = KeyCache::load(Some(path.clone())).unwrap();
+    let key = cache.key("v2:hunter2").await.unwrap();
+    let hash = cache.hash("v2:hunter2").await.unwrap();
+    let cache = KeyCache::load(Some(path.clone())).unwrap();
+    let cached_key = cache.key("v2:hunter2").await.unwrap();
+    let cached_hash = cache.hash("v2:hunter2").await.unwrap();
+    std::fs::remove_file(path).unwrap();
+    assert_eq!(cached_key.decrypt(&key.encrypt("hello")).unwrap(), "hello");
+    assert_eq!(hash, cached_hash);
+}
+
+#[tokio::test]
+async fn corrupt_cache_is_replaced() {
+    let path = std::env::temp_dir().join(format!("keks-corrupt-{}.json", std::process::id()));
+    std::fs::write(&path, "{\"trunc").unwrap();
+    let cache = KeyCache::load(Some(path.clone())).unwrap();
+    let hash = cache.hash("hunter2").await.unwrap();
+    let cache = KeyCache::load(Some(path.clone())).unwrap();
+    let cached_hash = cache.hash("hunter2").await.unwrap();
+    std::fs::remove_file(path).unwrap();
+    assert_eq!(hash, cached_hash);
+}
+
+#[tokio::test]
+async fn cache_file_is_salted_and_private() {
+    let dir = std::env::temp_dir().join(format!("keks-salted-{}", std::process::id()));
+    let (a, b) = (dir.join("a.json"), dir.join("b.json"));
+    KeyCache::load(Some(a.clone()))
+        .unwrap()
+        .hash("hunter2")
+        .await
+        .unwrap();
+    KeyCache::load(Some(b.clone()))
+        .unwrap()
+        .hash("hunter2")
+        .await
+        .unwrap();
+    let ids = |path: &std::path::Path| {
+        let file: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap();
+        file["entries"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+    let (ids_a, ids_b) = (ids(&a), ids(&b));
+    #[cfg(unix)]
+    let mode = {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::metadata(&a).unwrap().permissions().mode() & 0o777
+    };
+    std::fs::remove_dir_all(dir).unwrap();
+    assert_eq!(ids_a.len(), 1);
+    assert_ne!(ids_a, ids_b);
+    #[cfg(unix)]
+    assert_eq!(mode, 0o600);
+}
@@ -0,0 +1,36 @@
+/*
+    This file is part of keks-meet (https://codeberg.org/metamuffin/keks-meet)
+    which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
+    Copyright (C) 2023 metamuffin <metamuffin.org>
+*/
+//! Inputs from peers that used to panic; found with the targets in /fuzz.
+use libkeks::{crypto::Key, instance::decode_relay};
+use std::sync::LazyLock;
+
+static KEY: LazyLock<Key> = LazyLock::new(|| Key::derive("test"));
+
+#[test]
+fn decrypt_rejects_malformed() {
+    for message in ["", "not base64!", "AAAA", "AAAAAAAAAAAAAAAA"] {
+        assert!(KEY.decrypt(message).is_err(), "{message:?}");
+    }
+}
+
+#[test]
+fn decrypt_rejects_other_key() {
+    let other = Key::derive("other");
+    assert!(KEY.decrypt(&other.encrypt("hello")).is_err());
+    assert_eq!(KEY.decrypt(&KEY.encrypt("hello")).unwrap(), "hello");
+}
+
+#[test]
+fn decode_relay_rejects_malformed() {
+    for plaintext in ["", "null", "{}", r#"{"sender":1,"inner":{"offer":5}}"#] {
+        assert!(decode_relay(&KEY, &KEY.encrypt(plaintext)).is_err());
+    }
+    assert!(decode_relay(
+        &KEY,
+        &KEY.encrypt(r#"{"sender":1,"inner":{"offer":"v=0"}}"#)
+    )
+    .is_ok());
+}
@@ -0,0 +1,138 @@
+/*
+    This file is part of keks-meet (https://codeberg.org/metamuffin/keks-meet)
+    which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
+    Copyright (C) 2023 metamuffin <metamuffin.org>
+*/
+mod common;
+
+use common::{config, instance_with, next, serve, Event, Events, Proxy};
+use libkeks::{
+    instance::{Instance, RECONNECT_DELAY},
+    protocol::DEFAULT_ROOM,
+    Config,
+};
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+const SHORT: Duration = Duration::from_secs(5);
+/// Shorter than the default, but a reconnect still fits into half of it.
+const REJOIN_TIMEOUT: Duration = Duration::from_secs(4);
+
+async fn instance(addr: SocketAddr) -> (Arc<Instance>, Events) {
+    instance_with(Config {
+        rejoin_timeout: REJOIN_TIMEOUT,
+        ..config(addr)
+    })
+    .await
+}
+
+async fn join(inst: &Arc<Instance>) {
+    inst.join(DEFAULT_ROOM, Some("hunter2")).await.unwrap();
+}
+
+/// Waits for the connection to drop and come back.
+async fn reconnected(events: &mut Events, timeout: Duration) {
+    assert_eq!(next(events, SHORT).await, Event::Disconnected);
+    assert_eq!(next(events, timeout).await, Event::Reconnected);
+}
+
+#[tokio::test]
+async fn reconnects_with_backoff() {
+    let proxy = Proxy::start(serve().await).await;
+    let (inst, mut events) = instance(proxy.addr).await;
+    join(&inst).await;
+
+    let cut = Instant::now();
+    proxy.cut(2);
+    reconnected(&mut events, 8 * RECONNECT_DELAY).await;
+
+    let attempts = proxy.attempts();
+    assert_eq!(attempts.len(), 4);
+    let mut previous = cut;
+    for (i, attempt) in attempts[1..].iter().enumerate() {
+        let delay = RECONNECT_DELAY * 2u32.pow(i as u32);
+        let waited = *attempt - previous;
+        assert!(waited >= delay, "attempt {i} after {waited:?}");
+        assert!(
+            waited < delay + Duration::from_millis(500),
+            "attempt {i} after {waited:?}"
+        );
+        previous = *attempt;
+    }
+}
+
+#[tokio::test]
+async fn peers_that_stayed_are_replaced() {
+    let addr = serve().await;
+    let proxy = Proxy::start(addr).await;
+    let (a, mut a_events) = instance(proxy.addr).await;
+    let (b, mut b_events) = instance(addr).await;
+    join(&a).await;
+    join(&b).await;
+    let b_id = b.my_id().await.unwrap();
+    assert_eq!(next(&mut a_events, SHORT).await, Event::Join(b_id));
+    let a_id = a.my_id().await.unwrap();
+    assert_eq!(next(&mut b_events, SHORT).await, Event::Join(a_id));
+
+    proxy.cut(0);
+    reconnected(&mut a_events, SHORT).await;
+    assert_eq!(next(&mut a_events, SHORT).await, Event::Join(b_id));
+    assert_eq!(
+        a.room(DEFAULT_ROOM).await.unwrap().peers.read().await.len(),
+        1
+    );
+
+    // the server announces a's new id to b
+    assert_eq!(next(&mut b_events, SHORT).await, Event::Leave(a_id));
+    let a_id = a.my_id().await.unwrap();
+    assert_eq!(next(&mut b_events, SHORT).await, Event::Join(a_id));
+}
+
+#[tokio::test]
+async fn gone_peers_leave_after_timeout() {
+    let addr = serve().await;
+    let (a_proxy, b_proxy) = (Proxy::start(addr).await, Proxy::start(addr).await);
+    let (a, mut a_events) = instance(a_proxy.addr).await;
+    let (b, _b_events) = instance(b_proxy.addr).await;
+    join(&a).await;
+    join(&b).await;
+    let b_id = b.my_id().await.unwrap();
+    assert_eq!(next(&mut a_events, SHORT).await, Event::Join(b_id));
+
+    a_proxy.cut(0);
+    assert_eq!(next(&mut a_events, SHORT).await, Event::Disconnected);
+    // b never comes back
+    b_proxy.cut(usize::MAX);
+    assert_eq!(next(&mut a_events, SHORT).await, Event::Reconnected);
+    let rejoined = Instant::now();
+
+    let timeout = REJOIN_TIMEOUT + Duration::from_secs(1);
+    assert_eq!(next(&mut a_events, timeout).await, Event::Leave(b_id));
+    assert!(rejoined.elapsed() >= REJOIN_TIMEOUT - Duration::from_millis(500));
+}
+
+#[tokio::test]
+async fn only_latest_reconnect_times_out() {
+    let addr = serve().await;
+    let proxy = Proxy::start(addr).await;
+    let (a, mut a_events) = instance(proxy.addr).await;
+    let (b, _b_events) = instance(addr).await;
+    join(&a).await;
+    join(&b).await;
+    let b_id = b.my_id().await.unwrap();
+    assert_eq!(next(&mut a_events, SHORT).await, Event::Join(b_id));
+
+    proxy.cut(0);
+    reconnected(&mut a_events, SHORT).await;
+    assert_eq!(next(&mut a_events, SHORT).await, Event::Join(b_id));
+    tokio::time::sleep(REJOIN_TIMEOUT / 2).await;
+
+    // b is announced again only after the first timeout ran out
+    proxy.delay_next(REJOIN_TIMEOUT * 3 / 4);
+    proxy.cut(0);
+    reconnected(&mut a_events, SHORT).await;
+    assert_eq!(next(&mut a_events, REJOIN_TIMEOUT).await, Event::Join(b_id));
+}
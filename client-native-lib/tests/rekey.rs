@@ -0,0 +1,216 @@
+/*
+    This file is part of keks-meet (https://codeberg.org/metamuffin/keks-meet)
+    which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
+    Copyright (C) 2023 metamuffin <metamuffin.org>
+*/
+mod common;
+
+use common::{config, expect_no_event, instance_with, next, Event, FakeServer};
+use libkeks::{
+    crypto::{Key, KeyExchange},
+    protocol::{
+        ChatMessage, ClientId, ClientboundPacket, RelayMessage, RelayMessageWrapper,
+        ServerboundPacket, DEFAULT_ROOM,
+    },
+    room::{Epoch, Epochs, MAX_EPOCH_JUMP, REKEY_GRACE},
+    Config,
+};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const SECOND: Duration = Duration::from_secs(1);
+
+fn readable(epochs: &Epochs, now: Instant, epoch: &Epoch) -> bool {
+    let message = epoch.key.encrypt("hello");
+    epochs.keys(now).any(|k| k.decrypt(&message).is_ok())
+}
+
+#[test]
+fn newer_epoch_replaces_older() {
+    let now = Instant::now();
+    let mut epochs = Epochs::default();
+    assert_eq!(epochs.next_number().unwrap(), 1);
+    assert!(epochs.adopt(Epoch::generate(1, ClientId(3)), now));
+    assert!(epochs.adopt(Epoch::generate(2, ClientId(3)), now));
+    assert!(!epochs.adopt(Epoch::generate(1, ClientId(1)), now));
+    assert_eq!(epochs.next_number().unwrap(), 3);
+}
+
+#[test]
+fn epochs_can_not_jump_far_ahead() {
+    let now = Instant::now();
+    let mut epochs = Epochs::default();
+    assert!(epochs.adopt(Epoch::generate(1, ClientId(3)), now));
+    assert!(!epochs.adopt(Epoch::generate(u64::MAX, ClientId(3)), now));
+    assert!(!epochs.adopt(Epoch::generate(2 + MAX_EPOCH_JUMP, ClientId(3)), now));
+    assert!(epochs.adopt(Epoch::generate(1 + MAX_EPOCH_JUMP, ClientId(3)), now));
+}
+
+#[test]
+fn first_epoch_can_not_jump_far_ahead() {
+    let now = Instant::now();
+    let mut epochs = Epochs::default();
+    assert!(!epochs.adopt(Epoch::generate(u64::MAX, ClientId(3)), now));
+    assert!(!epochs.adopt(Epoch::generate(1 + MAX_EPOCH_JUMP, ClientId(3)), now));
+    assert!(epochs.current.is_none());
+    assert!(epochs.adopt(Epoch::generate(MAX_EPOCH_JUMP, ClientId(3)), now));
+}
+
+#[test]
+fn epoch_numbers_do_not_overflow() {
+    let now = Instant::now();
+    let mut epochs = Epochs::default();
+    let start = u64::MAX - 3 * MAX_EPOCH_JUMP + 1;
+    epochs.current = Some(Epoch::generate(start, ClientId(3)));
+    loop {
+        let number = epochs.next_number().unwrap();
+        let jump = number.saturating_add(MAX_EPOCH_JUMP - 1);
+        assert!(epochs.adopt(Epoch::generate(jump, ClientId(3)), now));
+        if jump == u64::MAX {
+            break;
+        }
+    }
+    assert!(epochs.next_number().is_err());
+    assert!(!epochs.adopt(Epoch::generate(u64::MAX, ClientId(4)), now));
+}
+
+#[test]
+fn lower_author_wins_ties() {
+    let now = Instant::now();
+    let mut epochs = Epochs::default();
+    assert!(epochs.adopt(Epoch::generate(1, ClientId(3)), now));
+    assert!(epochs.adopt(Epoch::generate(1, ClientId(2)), now));
+    assert!(!epochs.adopt(Epoch::generate(1, ClientId(4)), now));
+    assert_eq!(epochs.current.as_ref().unwrap().author, ClientId(2));
+}
+
+#[test]
+fn forwarded_epoch_is_not_adopted_twice() {
+    let now = Instant::now();
+    let mut epochs = Epochs::default();
+    let epoch = Epoch::generate(1, ClientId(3));
+    let copy = Epoch::decode(1, ClientId(3), epoch.encoded.clone()).unwrap();
+    assert!(epochs.adopt(epoch, now));
+    assert!(!epochs.adopt(copy, now));
+}
+
+#[test]
+fn previous_key_accepted_during_grace() {
+    let now = Instant::now();
+    let mut epochs = Epochs::default();
+    let first = Epoch::generate(1, ClientId(1));
+    let old = Epoch::decode(1, ClientId(1), first.encoded.clone()).unwrap();
+    epochs.adopt(first, now);
+    epochs.adopt(Epoch::generate(2, ClientId(1)), now);
+    assert!(readable(&epochs, now + Duration::from_secs(1), &old));
+    assert!(!readable(&epochs, now + REKEY_GRACE, &old));
+}
+
+#[test]
+fn secret_accepted_until_grace_after_first_epoch() {
+    let now = Instant::now();
+    let mut epochs = Epochs::default();
+    assert!(epochs.secret_accepted(now + REKEY_GRACE));
+    epochs.adopt(Epoch::generate(1, ClientId(1)), now);
+    epochs.adopt(Epoch::generate(2, ClientId(1)), now + REKEY_GRACE);
+    assert!(epochs.secret_accepted(now + Duration::from_secs(1)));
+    assert!(!epochs.secret_accepted(now + REKEY_GRACE));
+}
+
+#[test]
+fn invalid_key_rejected() {
+    assert!(Epoch::decode(1, ClientId(1), "AAAA".to_string()).is_err());
+}
+
+fn relay(key: &Key, counter: u64, inner: RelayMessage) -> ClientboundPacket {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    let wrapper = RelayMessageWrapper {
+        sender: ClientId(1),
+        recipient: None,
+        counter: Some(counter),
+        timestamp: Some(timestamp),
+        inner,
+    };
+    ClientboundPacket::Message {
+        sender: ClientId(1),
+        message: key.encrypt(&serde_json::to_string(&wrapper).unwrap()),
+        room: DEFAULT_ROOM,
+    }
+}
+
+/// Someone who knows the secret, like a kicked member with help of the
+/// server, poses as a member that has the room key.
+#[tokio::test]
+async fn secret_rejected_after_grace() {
+    let server = FakeServer::start().await;
+    let config = Config {
+        rekey_grace: SECOND,
+        ..config(server.addr)
+    };
+    let ((inst, mut events), mut conn) = tokio::join!(instance_with(config), server.accept());
+    conn.send(ClientboundPacket::Init {
+        your_id: ClientId(2),
+        version: "test".to_string(),
+    })
+    .await;
+    inst.join(DEFAULT_ROOM, Some("hunter2")).await.unwrap();
+    conn.expect_join().await;
+    for id in [2, 1] {
+        conn.send(ClientboundPacket::ClientJoin {
+            id: ClientId(id),
+            room: DEFAULT_ROOM,
+        })
+        .await;
+    }
+    assert_eq!(next(&mut events, SECOND).await, Event::Join(ClientId(1)));
+
+    let secret = Key::derive("hunter2");
+    let theirs = loop {
+        if let ServerboundPacket::Relay { message, .. } = conn.recv().await {
+            let wrapper: RelayMessageWrapper =
+                serde_json::from_str(&secret.decrypt(&message).unwrap()).unwrap();
+            if let RelayMessage::KeyExchange { public_key } = wrapper.inner {
+                break public_key;
+            }
+        }
+    };
+    let exchange = KeyExchange::new();
+    let ours = exchange.public_key();
+    let pairwise = exchange.finish(&theirs).unwrap();
+    let conn = conn.drain();
+    let send = |packet| async { conn.send(packet).await.unwrap() };
+    send(relay(
+        &secret,
+        1,
+        RelayMessage::KeyExchange { public_key: ours },
+    ))
+    .await;
+    let identify = RelayMessage::Identify {
+        username: "alice".to_string(),
+        private_relay: false,
+        rekey: true,
+        identity: None,
+        signature: None,
+    };
+    send(relay(&secret, 2, identify)).await;
+    inst.rekey(DEFAULT_ROOM).await.unwrap();
+
+    let hello = ChatMessage::Text("hello".to_string());
+    let chat = || RelayMessage::Chat(hello.clone());
+    // still in flight from before the rekey
+    send(relay(&secret, 3, chat())).await;
+    assert_eq!(
+        next(&mut events, SECOND).await,
+        Event::Chat(ClientId(1), hello.clone())
+    );
+    tokio::time::sleep(SECOND).await;
+    send(relay(&secret, 4, chat())).await;
+    expect_no_event(&mut events).await;
+    send(relay(&pairwise, 5, chat())).await;
+    assert_eq!(
+        next(&mut events, SECOND).await,
+        Event::Chat(ClientId(1), hello)
+    );
+}
@@ -0,0 +1,186 @@
+/*
+    This file is part of keks-meet (https://codeberg.org/metamuffin/keks-meet)
+    which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
+    Copyright (C) 2023 metamuffin <metamuffin.org>
+*/
+mod common;
+
+use common::{expect_no_event, instance, next, serve, Event, FakeServer};
+use libkeks::{
+    crypto::Key,
+    peer::ReplayGuard,
+    protocol::{
+        ChatMessage, ClientId, ClientboundPacket, RelayMessage, RelayMessageWrapper, DEFAULT_ROOM,
+    },
+};
+use std::{
+    collections::HashSet,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+const NOW: u64 = 1_700_000_000_000;
+const HOUR: u64 = 3_600_000;
+const SECOND: Duration = Duration::from_secs(1);
+
+fn relay(counter: Option<u64>, timestamp: Option<u64>) -> RelayMessageWrapper {
+    RelayMessageWrapper {
+        sender: ClientId(1),
+        recipient: None,
+        counter,
+        timestamp,
+        inner: RelayMessage::Chat(ChatMessage::Text("hello".to_string())),
+    }
+}
+
+#[test]
+fn rejects_duplicates_and_reordering() {
+    let guard = ReplayGuard::default();
+    assert!(guard.check(&relay(Some(1), Some(NOW)), NOW).is_ok());
+    assert!(guard.check(&relay(Some(3), Some(NOW)), NOW).is_ok());
+    assert!(guard.check(&relay(Some(3), Some(NOW)), NOW).is_err());
+    assert!(guard.check(&relay(Some(2), Some(NOW)), NOW).is_err());
+    assert!(guard.check(&relay(Some(4), Some(NOW)), NOW).is_ok());
+}
+
+#[test]
+fn rejects_stale() {
+    let guard = ReplayGuard::default();
+    assert!(guard.check(&relay(Some(1), Some(NOW - HOUR)), NOW).is_err());
+    assert!(guard.check(&relay(Some(2), Some(NOW + HOUR)), NOW).is_err());
+    // a stale relay does not advance the counter
+    assert!(guard.check(&relay(Some(1), Some(NOW - 1000)), NOW).is_ok());
+}
+
+#[test]
+fn legacy_only_until_first_counter() {
+    let guard = ReplayGuard::default();
+    assert!(guard.check(&relay(None, None), NOW).is_ok());
+    assert!(guard.check(&relay(None, None), NOW).is_ok());
+    assert!(guard.check(&relay(Some(1), Some(NOW)), NOW).is_ok());
+    assert!(guard.check(&relay(None, None), NOW).is_err());
+}
+
+#[test]
+fn resumed_guard_rejects_replays() {
+    let left = ReplayGuard::default();
+    assert!(left.check(&relay(Some(1), Some(NOW)), NOW).is_ok());
+    assert!(left.check(&relay(Some(2), Some(NOW + 1)), NOW).is_ok());
+    let guard = ReplayGuard::default();
+    guard.resume(&left);
+    assert!(guard.check(&relay(Some(1), Some(NOW)), NOW).is_err());
+    assert!(guard.check(&relay(Some(2), Some(NOW + 1)), NOW).is_err());
+    assert!(guard.check(&relay(None, None), NOW).is_err());
+    assert!(guard.check(&relay(Some(3), Some(NOW + 2)), NOW).is_ok());
+}
+
+#[test]
+fn resumed_guard_accepts_new_client_with_same_id() {
+    let left = ReplayGuard::default();
+    assert!(left.check(&relay(Some(500), Some(NOW)), NOW).is_ok());
+    let guard = ReplayGuard::default();
+    guard.resume(&left);
+    assert!(guard.check(&relay(Some(1), Some(NOW + 1)), NOW).is_ok());
+    assert!(guard.check(&relay(Some(2), Some(NOW + 1)), NOW).is_ok());
+    assert!(guard.check(&relay(Some(2), Some(NOW + 1)), NOW).is_err());
+}
+
+fn encrypted(key: &Key, counter: u64) -> ClientboundPacket {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64;
+    let wrapper = RelayMessageWrapper {
+        counter: Some(counter),
+        timestamp: Some(timestamp),
+        ..relay(None, None)
+    };
+    ClientboundPacket::Message {
+        sender: ClientId(1),
+        message: key.encrypt(&serde_json::to_string(&wrapper).unwrap()),
+        room: DEFAULT_ROOM,
+    }
+}
+
+/// The server announces that the sender left and joined again, hoping we
+/// forget which relays we have seen.
+#[tokio::test]
+async fn replay_after_leave_and_join() {
+    let server = FakeServer::start().await;
+    let ((inst, mut events), mut conn) = tokio::join!(instance(server.addr), server.accept());
+    conn.send(ClientboundPacket::Init {
+        your_id: ClientId(2),
+        version: "test".to_string(),
+    })
+    .await;
+    inst.join(DEFAULT_ROOM, Some("hunter2")).await.unwrap();
+    conn.expect_join().await;
+    let conn = conn.drain();
+    let send = |packet| async { conn.send(packet).await.unwrap() };
+    let join = |id| ClientboundPacket::ClientJoin {
+        id: ClientId(id),
+        room: DEFAULT_ROOM,
+    };
+    send(join(2)).await;
+    send(join(1)).await;
+    assert_eq!(next(&mut events, SECOND).await, Event::Join(ClientId(1)));
+
+    let key = Key::derive("hunter2");
+    let recorded = encrypted(&key, 1);
+    let hello = Event::Chat(ClientId(1), ChatMessage::Text("hello".to_string()));
+    send(recorded.clone()).await;
+    assert_eq!(next(&mut events, SECOND).await, hello);
+
+    send(ClientboundPacket::ClientLeave {
+        id: ClientId(1),
+        room: DEFAULT_ROOM,
+    })
+    .await;
+    send(join(1)).await;
+    assert_eq!(next(&mut events, SECOND).await, Event::Leave(ClientId(1)));
+    assert_eq!(next(&mut events, SECOND).await, Event::Join(ClientId(1)));
+    send(recorded).await;
+    expect_no_event(&mut events).await;
+
+    send(encrypted(&key, 2)).await;
+    assert_eq!(next(&mut events, SECOND).await, hello);
+}
+
+/// Relays sent at the same time must still reach the wire in counter order.
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_relays_are_not_dropped() {
+    const COUNT: usize = 200;
+    let addr = serve().await;
+    let (sender, _) = instance(addr).await;
+    let (receiver, mut events) = instance(addr).await;
+    sender.join(DEFAULT_ROOM, Some("hunter2")).await.unwrap();
+    receiver.join(DEFAULT_ROOM, Some("hunter2")).await.unwrap();
+    let sender_id = sender.my_id().await.unwrap();
+    assert_eq!(next(&mut events, SECOND).await, Event::Join(sender_id));
+
+    let tasks = (0..COUNT)
+        .map(|i| {
+            let sender = sender.clone();
+            tokio::spawn(async move {
+                let chat = ChatMessage::Text(i.to_string());
+                sender
+                    .send_relay(DEFAULT_ROOM, None, RelayMessage::Chat(chat))
+                    .await
+                    .unwrap();
+            })
+        })
+        .collect::<Vec<_>>();
+    for task in tasks {
+        task.await.unwrap();
+    }
+    let mut received = HashSet::new();
+    for _ in 0..COUNT {
+        match next(&mut events, SECOND).await {
+            Event::Chat(id, ChatMessage::Text(text)) if id == sender_id => {
+                received.insert(text);
+            }
+            event => panic!("unexpected {event:?}"),
+        }
+    }
+    assert_eq!(received.len(), COUNT);
+    expect_no_event(&mut events).await;
+}
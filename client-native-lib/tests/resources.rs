@@ -0,0 +1,253 @@
+/*
+    This file is part of keks-meet (https://codeberg.org/metamuffin/keks-meet)
+    which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
+    Copyright (C) 2023 metamuffin <metamuffin.org>
+*/
+//! Requesting and stopping resources between two instances, connected with
+//! webrtc over loopback.
+mod common;
+
+use common::{instance, next, serve, Event, Events};
+use libkeks::{
+    instance::Instance,
+    peer::{Peer, RemoteResourceState},
+    protocol::{ProvideInfo, TrackKind, DEFAULT_ROOM},
+    DynFut, Error, LocalResource,
+};
+use std::{sync::Arc, time::Duration};
+use tokio::sync::mpsc;
+use webrtc::{
+    api::media_engine::MIME_TYPE_VP8,
+    data_channel::{data_channel_state::RTCDataChannelState, RTCDataChannel},
+    rtp_transceiver::{rtp_codec::RTCRtpCodecCapability, rtp_sender::RTCRtpSender},
+    track::track_local::track_local_static_sample::TrackLocalStaticSample,
+};
+
+const SHORT: Duration = Duration::from_secs(5);
+/// webrtc needs a moment to connect
+const CONNECT: Duration = Duration::from_secs(20);
+
+enum Call {
+    Request(Arc<RTCDataChannel>),
+    Track(Arc<RTCRtpSender>),
+    RequestStop,
+}
+
+/// Opens a data channel, or sends a video track, for every request and
+/// reports what it was asked.
+struct Resource {
+    calls: mpsc::UnboundedSender<Call>,
+    track: bool,
+}
+impl LocalResource for Resource {
+    fn info(&self) -> ProvideInfo {
+        ProvideInfo {
+            id: "res".to_string(),
+            kind: if self.track { "track" } else { "file" }.to_string(),
+            track_kind: self.track.then_some(TrackKind::Video),
+            label: Some("test".to_string()),
+            size: None,
+        }
+    }
+    fn on_request(&self, peer: Arc<Peer>) -> DynFut<()> {
+        let calls = self.calls.clone();
+        let track = self.track;
+        Box::pin(async move {
+            if track {
+                let codec = RTCRtpCodecCapability {
+                    mime_type: MIME_TYPE_VP8.to_string(),
+                    ..Default::default()
+                };
+                let local =
+                    TrackLocalStaticSample::new(codec, "video".to_string(), "res".to_string());
+                let sender = peer.add_track("res", Arc::new(local)).await.unwrap();
+                let _ = calls.send(Call::Track(sender));
+            } else {
+                let channel = peer.create_data_channel("res").await.unwrap();
+                let _ = calls.send(Call::Request(channel));
+            }
+        })
+    }
+    fn on_request_stop(&self, _peer: Arc<Peer>) -> DynFut<()> {
+        let _ = self.calls.send(Call::RequestStop);
+        Box::pin(async move {})
+    }
+}
+
+struct Pair {
+    provider: Arc<Instance>,
+    calls: mpsc::UnboundedReceiver<Call>,
+    /// the provider as seen by the consumer
+    peer: Arc<Peer>,
+    events: Events,
+}
+
+/// Two instances in one room, the first providing a resource.
+async fn pair(track: bool) -> Pair {
+    let addr = serve().await;
+    let (provider, mut provider_events) = instance(addr).await;
+    let (consumer, mut events) = instance(addr).await;
+    provider.join(DEFAULT_ROOM, Some("hunter2")).await.unwrap();
+    consumer.join(DEFAULT_ROOM, Some("hunter2")).await.unwrap();
+    let provider_id = provider.my_id().await.unwrap();
+    assert_eq!(next(&mut events, SHORT).await, Event::Join(provider_id));
+    assert!(matches!(
+        next(&mut provider_events, SHORT).await,
+        Event::Join(_)
+    ));
+
+    let (tx, calls) = mpsc::unbounded_channel();
+    let resource = Resource { calls: tx, track };
+    provider
+        .add_local_resource(Box::new(resource))
+        .await
+        .unwrap();
+    assert_eq!(
+        next(&mut events, SHORT).await,
+        Event::ResourceAdded(provider_id, "res".to_string())
+    );
+    let peer = consumer.peers().await.pop().unwrap();
+    Pair {
+        provider,
+        calls,
+        peer,
+        events,
+    }
+}
+
+async fn state(peer: &Peer) -> RemoteResourceState {
+    peer.remote_provided.read().await["res"].state()
+}
+
+async fn call(calls: &mut mpsc::UnboundedReceiver<Call>) -> Call {
+    tokio::time::timeout(SHORT, calls.recv())
+        .await
+        .expect("timed out waiting for the resource")
+        .unwrap()
+}
+
+/// Requests the resource and waits for its data channel to arrive.
+async fn connect(pair: &mut Pair) -> Arc<RTCDataChannel> {
+    assert_eq!(state(&pair.peer).await, RemoteResourceState::Disconnected);
+    pair.peer.request_resource("res".to_string()).await.unwrap();
+    assert_eq!(state(&pair.peer).await, RemoteResourceState::AwaitConnect);
+    let Call::Request(channel) = call(&mut pair.calls).await else {
+        panic!("expected a request")
+    };
+    assert_eq!(
+        next(&mut pair.events, CONNECT).await,
+        Event::ResourceConnected(pair.peer.id, "res".to_string())
+    );
+    assert_eq!(state(&pair.peer).await, RemoteResourceState::Connected);
+    channel
+}
+
+/// Waits until the consumer applied an offer of the provider that matches.
+async fn wait_for_offer(peer: &Peer, matches: impl Fn(&str) -> bool) {
+    tokio::time::timeout(CONNECT, async {
+        loop {
+            let offer = peer.peer_connection.remote_description().await;
+            if offer.is_some_and(|offer| matches(&offer.sdp)) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("no matching offer");
+}
+
+/// Closed by us; it only becomes [`RTCDataChannelState::Closed`] once the
+/// other side closed it as well.
+fn assert_closed(channel: &RTCDataChannel) {
+    assert!(matches!(
+        channel.ready_state(),
+        RTCDataChannelState::Closing | RTCDataChannelState::Closed
+    ));
+}
+
+#[tokio::test]
+async fn request_and_stop() {
+    let mut pair = pair(false).await;
+    let channel = connect(&mut pair).await;
+
+    pair.peer
+        .request_stop_resource("res".to_string())
+        .await
+        .unwrap();
+    assert_eq!(state(&pair.peer).await, RemoteResourceState::Disconnected);
+    // the channel is closed before the resource is told
+    assert!(matches!(call(&mut pair.calls).await, Call::RequestStop));
+    assert_closed(&channel);
+
+    // can be requested again afterwards
+    connect(&mut pair).await;
+}
+
+#[tokio::test]
+async fn closed_by_provider() {
+    let mut pair = pair(false).await;
+    let channel = connect(&mut pair).await;
+
+    channel.close().await.unwrap();
+    tokio::time::timeout(SHORT, async {
+        while state(&pair.peer).await != RemoteResourceState::Disconnected {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await
+    .expect("resource stayed connected");
+}
+
+#[tokio::test]
+async fn removing_stops_sending() {
+    let mut pair = pair(false).await;
+    let channel = connect(&mut pair).await;
+
+    assert!(pair
+        .provider
+        .remove_local_resource("res".to_string())
+        .await
+        .unwrap());
+    assert_closed(&channel);
+    assert_eq!(
+        next(&mut pair.events, SHORT).await,
+        Event::ResourceRemoved(pair.peer.id, "res".to_string())
+    );
+    assert!(matches!(
+        pair.peer.request_resource("res".to_string()).await,
+        Err(Error::UnknownResource(_))
+    ));
+    // stopping what is gone already is fine
+    pair.peer
+        .request_stop_resource("res".to_string())
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn stopping_a_track_renegotiates() {
+    let mut pair = pair(true).await;
+    pair.peer.request_resource("res".to_string()).await.unwrap();
+    let Call::Track(sender) = call(&mut pair.calls).await else {
+        panic!("expected a track")
+    };
+    wait_for_offer(&pair.peer, |sdp| {
+        sdp.contains("m=video") && sdp.contains("a=sendrecv")
+    })
+    .await;
+
+    pair.peer
+        .request_stop_resource("res".to_string())
+        .await
+        .unwrap();
+    // the sender is removed before the resource is told
+    assert!(matches!(call(&mut pair.calls).await, Call::RequestStop));
+    assert!(sender.track().await.is_none());
+    // the provider offers to only receive now
+    wait_for_offer(&pair.peer, |sdp| {
+        sdp.contains("m=video") && sdp.contains("a=recvonly")
+    })
+    .await;
+    assert_eq!(state(&pair.peer).await, RemoteResourceState::Disconnected);
+}
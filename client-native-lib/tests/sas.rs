@@ -0,0 +1,61 @@
+/*
+    This file is part of keks-meet (https://codeberg.org/metamuffin/keks-meet)
+    which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
+    Copyright (C) 2023 metamuffin <metamuffin.org>
+*/
+use libkeks::{
+    protocol::ClientId,
+    sas::{commitment, SasExchange, SasParty},
+};
+
+fn party(id: u64, exchange: &SasExchange, dtls: &str) -> SasParty {
+    SasParty {
+        id: ClientId(id),
+        key: exchange.public_key(),
+        dtls_fingerprints: dtls.to_string(),
+        identity: None,
+    }
+}
+
+#[test]
+fn both_sides_see_the_same_string() {
+    let (alice, bob) = (SasExchange::new(), SasExchange::new());
+    let (a, b) = (
+        party(1, &alice, "a=fingerprint:A"),
+        party(2, &bob, "a=fingerprint:B"),
+    );
+    assert_eq!(alice.finish(&a, &b).unwrap(), bob.finish(&a, &b).unwrap());
+}
+
+#[test]
+fn man_in_the_middle_changes_the_string() {
+    let (alice, bob) = (SasExchange::new(), SasExchange::new());
+    let (mallory_a, mallory_b) = (SasExchange::new(), SasExchange::new());
+    let (a, b) = (party(1, &alice, "A"), party(2, &bob, "B"));
+    let (ma, mb) = (party(2, &mallory_a, "B"), party(1, &mallory_b, "A"));
+    assert_ne!(alice.finish(&a, &ma).unwrap(), bob.finish(&mb, &b).unwrap());
+}
+
+#[test]
+fn dtls_fingerprints_change_the_string() {
+    let (alice, bob) = (SasExchange::new(), SasExchange::new());
+    let a = party(1, &alice, "A");
+    let (b, fake_b) = (party(2, &bob, "B"), party(2, &bob, "M"));
+    assert_ne!(
+        alice.finish(&a, &fake_b).unwrap(),
+        bob.finish(&a, &b).unwrap()
+    );
+}
+
+#[test]
+fn commitment_binds_key() {
+    let (alice, bob) = (SasExchange::new(), SasExchange::new());
+    assert_eq!(
+        commitment(&alice.public_key()),
+        commitment(&alice.public_key())
+    );
+    assert_ne!(
+        commitment(&alice.public_key()),
+        commitment(&bob.public_key())
+    );
+}
@@ -0,0 +1,69 @@
+/*
+    This file is part of keks-meet (https://codeberg.org/metamuffin/keks-meet)
+    which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
+    Copyright (C) 2023 metamuffin <metamuffin.org>
+*/
+//! Checks against `common/crypto_vectors.json`, generated by the web client's
+//! WebCrypto with `client-web/scripts/gen_crypto_vectors.ts`.
+use base64::{engine::general_purpose::STANDARD, Engine};
+use libkeks::crypto::{derive_key, hash, Key};
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+struct Vectors {
+    rooms: Vec<RoomVector>,
+    messages: Vec<MessageVector>,
+}
+#[derive(Deserialize)]
+struct RoomVector {
+    secret: String,
+    room_hash: String,
+    key: String,
+}
+#[derive(Deserialize)]
+struct MessageVector {
+    key: String,
+    iv: String,
+    plaintext: String,
+    message: String,
+}
+
+fn vectors() -> Vectors {
+    serde_json::from_str(include_str!("../../common/crypto_vectors.json")).unwrap()
+}
+
+fn key(hex: &str) -> Key {
+    Key::from_bytes(&hex::decode(hex).unwrap().try_into().unwrap())
+}
+
+#[test]
+fn room_hashes_and_keys() {
+    for room in vectors().rooms {
+        assert_eq!(hash(&room.secret), room.room_hash, "{:?}", room.secret);
+        assert_eq!(
+            hex::encode(derive_key(&room.secret)),
+            room.key,
+            "{:?}",
+            room.secret
+        );
+    }
+}
+
+#[test]
+fn messages() {
+    for m in vectors().messages {
+        let key = key(&m.key);
+        let iv = hex::decode(&m.iv).unwrap().try_into().unwrap();
+        assert_eq!(key.encrypt_with_nonce(&iv, &m.plaintext), m.message);
+        assert_eq!(key.decrypt(&m.message).unwrap(), m.plaintext);
+    }
+}
+
+#[test]
+fn tampered_message_is_rejected() {
+    let m = &vectors().messages[1];
+    let mut packet = STANDARD.decode(&m.message).unwrap();
+    *packet.last_mut().unwrap() ^= 1;
+    let tampered = STANDARD.encode(packet);
+    assert!(key(&m.key).decrypt(&tampered).is_err());
+}
@@ -0,0 +1,225 @@
+/*
+    This file is part of keks-meet (https://codeberg.org/metamuffin/keks-meet)
+    which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
+    Copyright (C) 2023 metamuffin <metamuffin.org>
+*/
+use crate::stats::Stats;
+use anyhow::{anyhow, bail, Result};
+use futures_util::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+use libkeks::{
+    crypto::Key,
+    instance::decode_relay,
+    protocol::{
+        ChatMessage, ClientId, ClientboundPacket, RelayMessage, RelayMessageWrapper,
+        ServerboundPacket, DEFAULT_ROOM,
+    },
+};
+use log::debug;
+use rand::seq::IteratorRandom;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+use tokio::{
+    net::TcpStream,
+    time::{interval_at, timeout, timeout_at, Instant, MissedTickBehavior},
+};
+use tokio_tungstenite::{tungstenite::Message, MaybeTlsStream, WebSocketStream};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+type Tx = SplitSink<Socket, Message>;
+type Rx = SplitStream<Socket>;
+
+/// A signaling client that joins a room and pings random members of it with
+/// encrypted relays, answering their pings in turn.
+pub struct SimulatedClient {
+    pub uri: String,
+    pub room: String,
+    pub key: Arc<Key>,
+    pub stats: Arc<Stats>,
+    pub payload: usize,
+    pub relay_interval: Duration,
+    pub end: Instant,
+}
+
+impl SimulatedClient {
+    pub async fn run(self) {
+        let ws = match timeout(CONNECT_TIMEOUT, tokio_tungstenite::connect_async(&self.uri))
+            .await
+            .map_err(|e| anyhow!(e))
+            .and_then(|r| r.map_err(|e| anyhow!(e)))
+        {
+            Ok((ws, _)) => ws,
+            Err(e) => {
+                debug!("connect failed: {e}");
+                return Stats::count(&self.stats.connect_errors);
+            }
+        };
+        let (mut tx, mut rx) = ws.split();
+        if let Err(e) = self.session(&mut tx, &mut rx).await {
+            debug!("session failed: {e}");
+        }
+        let _ = tx.close().await;
+    }
+
+    async fn session(&self, tx: &mut Tx, rx: &mut Rx) -> Result<()> {
+        let my_id = match self.recv(rx).await? {
+            Some(ClientboundPacket::Init { your_id, .. }) => your_id,
+            _ => bail!("expected init"),
+        };
+
+        let join_start = Instant::now();
+        self.send(
+            tx,
+            ServerboundPacket::Join {
+                hash: Some(self.room.clone()),
+                moderator: None,
+                room: DEFAULT_ROOM,
+            },
+        )
+        .await?;
+
+        let mut members = HashSet::new();
+        let mut pending = HashMap::new();
+        let mut seq = 0u64;
+
+        // spread relays of different clients over the interval
+        let offset = self.relay_interval.mul_f64(rand::random());
+        let mut ticks = interval_at(Instant::now() + offset, self.relay_interval);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+        loop {
+            tokio::select! {
+                _ = ticks.tick() => {
+                    let Some(&peer) = members.iter().choose(&mut rand::thread_rng()) else {
+                        continue;
+                    };
+                    seq += 1;
+                    pending.insert(seq, Instant::now());
+                    self.relay(tx, my_id, peer, format!("ping {seq} ")).await?;
+                }
+                packet = timeout_at(self.end, self.recv(rx)) => {
+                    let Ok(packet) = packet else { break };
+                    match packet? {
+                        Some(ClientboundPacket::ClientJoin { id, .. }) if id == my_id => {
+                            self.stats.join_latency.lock().unwrap().push(join_start.elapsed());
+                            Stats::count(&self.stats.joined);
+                        }
+                        Some(ClientboundPacket::ClientJoin { id, .. }) => {
+                            members.insert(id);
+                        }
+                        Some(ClientboundPacket::ClientLeave { id, .. }) => {
+                            members.remove(&id);
+                        }
+                        Some(ClientboundPacket::Message { sender, message, .. }) => {
+                            Stats::count(&self.stats.relays_received);
+                            self.on_message(tx, my_id, sender, &message, &mut pending).await?;
+                        }
+                        Some(ClientboundPacket::Error { message, .. }) => {
+                            debug!("server error: {message}");
+                            Stats::count(&self.stats.server_errors);
+                        }
+                        Some(_) => (),
+                        None => Stats::count(&self.stats.protocol_errors),
+                    }
+                }
+            }
+        }
+        self.stats.lost.fetch_add(pending.len(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    async fn on_message(
+        &self,
+        tx: &mut Tx,
+        my_id: ClientId,
+        sender: ClientId,
+        message: &str,
+        pending: &mut HashMap<u64, Instant>,
+    ) -> Result<()> {
+        let Ok(RelayMessageWrapper {
+            inner: RelayMessage::Chat(ChatMessage::Text(text)),
+            ..
+        }) = decode_relay(&self.key, message)
+        else {
+            Stats::count(&self.stats.protocol_errors);
+            return Ok(());
+        };
+        let mut words = text.split(' ');
+        match (
+            words.next(),
+            words.next().and_then(|s| s.parse::<u64>().ok()),
+        ) {
+            (Some("ping"), Some(seq)) => {
+                self.relay(tx, my_id, sender, format!("pong {seq} "))
+                    .await?
+            }
+            (Some("pong"), Some(seq)) => match pending.remove(&seq) {
+                Some(sent) => self.stats.round_trip.lock().unwrap().push(sent.elapsed()),
+                None => Stats::count(&self.stats.protocol_errors),
+            },
+            _ => Stats::count(&self.stats.protocol_errors),
+        }
+        Ok(())
+    }
+
+    async fn relay(
+        &self,
+        tx: &mut Tx,
+        my_id: ClientId,
+        recipient: ClientId,
+        mut text: String,
+    ) -> Result<()> {
+        if text.len() < self.payload {
+            text.push_str(&"A".repeat(self.payload - text.len()));
+        }
+        let inner = serde_json::to_string(&RelayMessageWrapper {
+            sender: my_id,
+            recipient: None,
+            counter: None,
+            timestamp: None,
+            inner: RelayMessage::Chat(ChatMessage::Text(text)),
+        })?;
+        Stats::count(&self.stats.relays_sent);
+        self.send(
+            tx,
+            ServerboundPacket::Relay {
+                recipient: Some(recipient),
+                message: self.key.encrypt(&inner),
+                room: DEFAULT_ROOM,
+            },
+        )
+        .await
+    }
+
+    async fn send(&self, tx: &mut Tx, packet: ServerboundPacket) -> Result<()> {
+        if let Err(e) = tx
+            .send(Message::Text(serde_json::to_string(&packet)?))
+            .await
+        {
+            Stats::count(&self.stats.disconnects);
+            bail!(e)
+        }
+        Ok(())
+    }
+
+    /// `None` if the packet did not parse.
+    async fn recv(&self, rx: &mut Rx) -> Result<Option<ClientboundPacket>> {
+        loop {
+            match rx.next().await {
+                Some(Ok(Message::Text(text))) => return Ok(serde_json::from_str(&text).ok()),
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {
+                    Stats::count(&self.stats.disconnects);
+                    bail!("connection closed")
+                }
+                Some(Ok(_)) => (),
+            }
+        }
+    }
+}
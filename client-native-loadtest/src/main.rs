@@ -0,0 +1,107 @@
+/*
+    This file is part of keks-meet (https://codeberg.org/metamuffin/keks-meet)
+    which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
+    Copyright (C) 2023 metamuffin <metamuffin.org>
+*/
+pub mod client;
+pub mod stats;
+
+use clap::Parser;
+use libkeks::crypto::{hash, Key};
+use log::info;
+use stats::Stats;
+use std::{sync::Arc, time::Duration};
+use tokio::time::{sleep, Instant};
+
+fn main() {
+    env_logger::builder()
+        .filter_module("keks_meet_loadtest", log::LevelFilter::Info)
+        .parse_env("LOG")
+        .init();
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(run())
+}
+
+#[derive(Parser, Clone)]
+/// simulates many signaling clients against a keks-meet server.
+pub struct Args {
+    /// keks-meet server to test; do not point this at servers you do not run
+    #[clap(long, default_value = "ws://127.0.0.1:24319")]
+    signaling_uri: String,
+    /// number of simulated clients
+    #[clap(short, long, default_value_t = 1000)]
+    clients: usize,
+    /// clients per room
+    #[clap(short, long, default_value_t = 4)]
+    room_size: usize,
+    /// pings per second, summed over all clients; each is answered with a pong
+    #[clap(long, default_value_t = 500.)]
+    rate: f64,
+    /// size of the relayed plaintext in bytes
+    #[clap(long, default_value_t = 256)]
+    payload: usize,
+    /// clients connecting per second
+    #[clap(long, default_value_t = 200.)]
+    connect_rate: f64,
+    /// seconds to keep relaying after the last client connected
+    #[clap(short, long, default_value_t = 30.)]
+    duration: f64,
+    /// rooms are named after this
+    #[clap(long, default_value = "keks-meet-loadtest")]
+    secret: String,
+}
+
+async fn run() {
+    let args = Arc::new(Args::parse());
+    assert!(args.clients > 0 && args.room_size > 0 && args.rate > 0.);
+
+    // Deriving keys is expensive and the server can not tell rooms apart by
+    // their key anyway, so all rooms share one.
+    let key = Arc::new(Key::derive(&args.secret));
+    let room_hash = hash(&args.secret);
+    let stats = Arc::new(Stats::default());
+
+    let ramp = Duration::from_secs_f64(args.clients as f64 / args.connect_rate);
+    let start = Instant::now();
+    let end = start + ramp + Duration::from_secs_f64(args.duration);
+    let relay_interval = Duration::from_secs_f64(args.clients as f64 / args.rate);
+    info!(
+        "{} clients in {} rooms, ramping up over {ramp:?}",
+        args.clients,
+        args.clients.div_ceil(args.room_size)
+    );
+
+    let reporter = tokio::spawn({
+        let stats = stats.clone();
+        async move {
+            loop {
+                sleep(Duration::from_secs(1)).await;
+                info!("{}", stats.progress());
+            }
+        }
+    });
+
+    let mut tasks = Vec::new();
+    for i in 0..args.clients {
+        let client = client::SimulatedClient {
+            uri: format!("{}/signaling", args.signaling_uri),
+            room: format!("{room_hash}-{}", i / args.room_size),
+            key: key.clone(),
+            stats: stats.clone(),
+            payload: args.payload,
+            relay_interval,
+            end,
+        };
+        tasks.push(tokio::spawn(client.run()));
+        sleep(Duration::from_secs_f64(1. / args.connect_rate)).await;
+    }
+    for t in tasks {
+        t.await.unwrap();
+    }
+    reporter.abort();
+
+    println!("{}", stats.report(start.elapsed()));
+}
@@ -0,0 +1,108 @@
+/*
+    This file is part of keks-meet (https://codeberg.org/metamuffin/keks-meet)
+    which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
+    Copyright (C) 2023 metamuffin <metamuffin.org>
+*/
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::Duration,
+};
+
+#[derive(Default)]
+pub struct Stats {
+    pub joined: AtomicUsize,
+    pub relays_sent: AtomicUsize,
+    pub relays_received: AtomicUsize,
+    pub join_latency: Mutex<Vec<Duration>>,
+    pub round_trip: Mutex<Vec<Duration>>,
+
+    /// connection could not be established
+    pub connect_errors: AtomicUsize,
+    /// connection closed before the test ended
+    pub disconnects: AtomicUsize,
+    /// the server sent an error packet
+    pub server_errors: AtomicUsize,
+    /// a packet or relay did not parse or decrypt
+    pub protocol_errors: AtomicUsize,
+    /// pings still unanswered at the end
+    pub lost: AtomicUsize,
+}
+
+impl Stats {
+    pub fn count(counter: &AtomicUsize) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn errors(&self) -> usize {
+        [
+            &self.connect_errors,
+            &self.disconnects,
+            &self.server_errors,
+            &self.protocol_errors,
+        ]
+        .iter()
+        .map(|c| c.load(Ordering::Relaxed))
+        .sum()
+    }
+
+    pub fn progress(&self) -> String {
+        format!(
+            "{} joined, {} relays sent, {} received, {} errors",
+            self.joined.load(Ordering::Relaxed),
+            self.relays_sent.load(Ordering::Relaxed),
+            self.relays_received.load(Ordering::Relaxed),
+            self.errors(),
+        )
+    }
+
+    pub fn report(&self, elapsed: Duration) -> String {
+        let load = |c: &AtomicUsize| c.load(Ordering::Relaxed).to_string();
+        let sent = self.relays_sent.load(Ordering::Relaxed);
+        let lines = [
+            ("joined", load(&self.joined)),
+            (
+                "relays sent",
+                format!("{sent} ({:.1}/s)", sent as f64 / elapsed.as_secs_f64()),
+            ),
+            ("relays received", load(&self.relays_received)),
+            (
+                "join latency",
+                percentiles(&mut self.join_latency.lock().unwrap()),
+            ),
+            (
+                "round trip",
+                percentiles(&mut self.round_trip.lock().unwrap()),
+            ),
+            ("connect errors", load(&self.connect_errors)),
+            ("disconnects", load(&self.disconnects)),
+            ("server errors", load(&self.server_errors)),
+            ("protocol errors", load(&self.protocol_errors)),
+            ("lost pings", load(&self.lost)),
+        ];
+        let mut s = format!("finished after {:.1}s\n", elapsed.as_secs_f64());
+        for (name, value) in lines {
+            writeln!(s, "{:<17}{value}", format!("{name}:")).unwrap();
+        }
+        s
+    }
+}
+
+fn percentiles(samples: &mut [Duration]) -> String {
+    if samples.is_empty() {
+        return "no samples".to_string();
+    }
+    samples.sort();
+    let at = |p: f64| samples[((samples.len() - 1) as f64 * p).round() as usize];
+    format!(
+        "p50 {:?}  p90 {:?}  p99 {:?}  max {:?}  (n={})",
+        at(0.5),
+        at(0.9),
+        at(0.99),
+        at(1.),
+        samples.len()
+    )
+}
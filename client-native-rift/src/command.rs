@@ -5,10 +5,13 @@ use anyhow::bail;
 use libkeks::{
     instance::Instance,
     peer::Peer,
-    protocol::{ChatMesssage, ProvideInfo, RelayMessage},
+    protocol::{ChatMessage, ProvideInfo, RelayMessage, DEFAULT_ROOM},
 };
 use log::{debug, error, info};
-use std::{os::unix::prelude::MetadataExt, sync::Arc};
+use std::{
+    os::unix::prelude::MetadataExt,
+    sync::{atomic::Ordering, Arc},
+};
 use tokio::{fs, net::TcpListener, sync::RwLock};
 
 pub(crate) async fn dispatch_command(
@@ -18,20 +21,25 @@ pub(crate) async fn dispatch_command(
 ) -> anyhow::Result<()> {
     match command {
         Command::List => {
-            let peers = inst.peers.read().await;
-            for p in peers.values() {
+            for p in inst.peers().await {
                 let username = p
                     .username
                     .read()
                     .await
                     .clone()
                     .unwrap_or("<unknown>".to_string());
-                info!("{username}:");
+                let verified = if p.sas_verified.load(Ordering::Relaxed) {
+                    ", compared in person"
+                } else {
+                    ""
+                };
+                info!("{username} ({}{verified}):", p.identity.read().await);
                 for (rid, r) in p.remote_provided.read().await.iter() {
                     info!(
-                        "\t{rid:?}: {} {:?}",
-                        r.kind,
-                        r.label.clone().unwrap_or_default()
+                        "\t{rid:?}: {} {:?} ({:?})",
+                        r.info.kind,
+                        r.info.label.clone().unwrap_or_default(),
+                        r.state()
                     )
                 }
             }
@@ -47,7 +55,7 @@ pub(crate) async fn dispatch_command(
                     .collect::<Vec<_>>();
             }
             for id in ids {
-                if !inst.remove_local_resource(id.clone()).await {
+                if !inst.remove_local_resource(id.clone()).await? {
                     bail!("service {id:?} not found.")
                 }
             }
@@ -58,12 +66,12 @@ pub(crate) async fn dispatch_command(
                     id: id.unwrap_or("file".to_owned()),
                     kind: "file".to_string(),
                     track_kind: None,
-                    label: Some(path.file_name().unwrap().to_str().unwrap().to_string()),
+                    label: path.file_name().map(|n| n.to_string_lossy().to_string()),
                     size: Some(fs::metadata(&path).await?.size() as usize),
                 },
                 path: path.into(),
             }))
-            .await;
+            .await?;
         }
         Command::Download { id, path } => {
             let (peer, _resource) = find_id(inst, id.clone(), "file").await?;
@@ -72,7 +80,7 @@ pub(crate) async fn dispatch_command(
                 .await
                 .requested
                 .insert(id.clone(), Box::new(DownloadHandler { path }));
-            peer.request_resource(id).await;
+            peer.request_resource(id).await?;
         }
         Command::Expose { port, id } => {
             inst.add_local_resource(Box::new(PortExposer {
@@ -85,7 +93,7 @@ pub(crate) async fn dispatch_command(
                     size: None,
                 },
             }))
-            .await;
+            .await?;
         }
         Command::Forward { id, port } => {
             let (peer, _resource) = find_id(inst, id.clone(), "port").await?;
@@ -95,7 +103,9 @@ pub(crate) async fn dispatch_command(
                     error!("cannot bind tcp listener");
                     return;
                 };
-                info!("tcp listener bound to {}", listener.local_addr().unwrap());
+                if let Ok(addr) = listener.local_addr() {
+                    info!("tcp listener bound to {addr}");
+                }
                 while let Ok((stream, addr)) = listener.accept().await {
                     debug!("new connection from {addr:?}");
                     state.write().await.requested.insert(
@@ -104,31 +114,101 @@ pub(crate) async fn dispatch_command(
                             stream: Arc::new(RwLock::new(Some(stream))),
                         }),
                     );
-                    peer.request_resource(id.clone()).await;
+                    if let Err(e) = peer.request_resource(id.clone()).await {
+                        error!("{e}");
+                    }
                 }
             });
         }
+        Command::Cancel { id } => {
+            for peer in inst.peers().await {
+                if peer.remote_provided.read().await.contains_key(&id) {
+                    state.write().await.requested.remove(&id);
+                    peer.request_stop_resource(id).await?;
+                    return Ok(());
+                }
+            }
+            bail!("id not found")
+        }
         Command::Chat { message } => {
-            inst.send_relay(None, RelayMessage::Chat(ChatMesssage::Text(message)))
-                .await;
+            inst.send_relay(
+                DEFAULT_ROOM,
+                None,
+                RelayMessage::Chat(ChatMessage::Text(message)),
+            )
+            .await?;
+        }
+        Command::Kick { user } => {
+            let peer = find_peer(inst, &user).await?;
+            inst.kick(peer.room, peer.id).await?;
+        }
+        Command::Lock => inst.lock(DEFAULT_ROOM, true).await?,
+        Command::Unlock => inst.lock(DEFAULT_ROOM, false).await?,
+        Command::Rekey => inst.rekey(DEFAULT_ROOM).await?,
+        Command::Mute { user } => {
+            let peer = find_peer(inst, &user).await?;
+            inst.mute(peer.room, peer.id, true).await?;
+        }
+        Command::Unmute { user } => {
+            let peer = find_peer(inst, &user).await?;
+            inst.mute(peer.room, peer.id, false).await?;
+        }
+        Command::Publish { name, description } => {
+            inst.publish(DEFAULT_ROOM, name, description).await?
+        }
+        Command::Unpublish => inst.unpublish(DEFAULT_ROOM).await?,
+        Command::Trust { user } => {
+            let peer = find_peer(inst, &user).await?;
+            if !peer.trust_identity().await? {
+                bail!("user has no identity")
+            }
+        }
+        Command::Verify { user } => find_peer(inst, &user).await?.start_verification().await?,
+        Command::Confirm { user } => {
+            find_peer(inst, &user)
+                .await?
+                .confirm_verification(true)
+                .await?
+        }
+        Command::Reject { user } => {
+            find_peer(inst, &user)
+                .await?
+                .confirm_verification(false)
+                .await?
+        }
+        Command::Directory => {
+            for entry in inst.list_directory().await? {
+                info!(
+                    "{:?} ({} users) {:?}: {}",
+                    entry.name, entry.user_count, entry.secret, entry.description
+                );
+            }
         }
     }
     Ok(())
 }
 
+async fn find_peer(inst: &Arc<Instance>, user: &str) -> anyhow::Result<Arc<Peer>> {
+    for peer in inst.peers().await {
+        if peer.id.to_string() == user || peer.username.read().await.as_deref() == Some(user) {
+            return Ok(peer);
+        }
+    }
+    bail!("user not found")
+}
+
 async fn find_id(
     inst: &Arc<Instance>,
     id: String,
     kind: &str,
 ) -> anyhow::Result<(Arc<Peer>, ProvideInfo)> {
-    let peers = inst.peers.read().await;
-    for peer in peers.values() {
+    for peer in inst.peers().await {
         for (rid, r) in peer.remote_provided.read().await.iter() {
             if rid == &id {
-                if r.kind == kind {
-                    return Ok((peer.to_owned(), r.to_owned()));
+                if r.info.kind == kind {
+                    return Ok((peer.clone(), r.info.to_owned()));
                 } else {
-                    bail!("wrong type: expected {kind:?}, found {:?}", r.kind)
+                    bail!("wrong type: expected {kind:?}, found {:?}", r.info.kind)
                 }
             }
         }
@@ -35,22 +35,35 @@ impl LocalResource for FileSender {
         let total_size = self.info().size.unwrap_or(0);
         let path = self.path.clone();
         Box::pin(async move {
-            let channel = peer
-                .peer_connection
-                .create_data_channel(&id, None)
-                .await
-                .unwrap();
+            let channel = match peer.create_data_channel(&id).await {
+                Ok(channel) => channel,
+                Err(e) => {
+                    error!("cannot create data channel: {e}");
+                    return;
+                }
+            };
             let pos = Arc::new(AtomicUsize::new(0));
             let reader: Arc<RwLock<Option<Pin<Box<dyn AsyncRead + Send + Sync>>>>> =
                 Arc::new(RwLock::new(None));
             {
                 let reader = reader.clone();
                 let path = path.clone();
+                let channel2 = channel.clone();
                 channel.on_open(Box::new(move || {
                     let reader = reader.clone();
+                    let path = path.clone();
+                    let channel = channel2.clone();
                     Box::pin(async move {
                         info!("channel open");
-                        *reader.write().await = Some(Box::pin(File::open(&*path).await.unwrap()));
+                        match File::open(&*path).await {
+                            Ok(file) => *reader.write().await = Some(Box::pin(file)),
+                            Err(e) => {
+                                error!("cannot read {path:?}: {e}");
+                                if let Err(e) = channel.close().await {
+                                    error!("cannot close channel: {e}");
+                                }
+                            }
+                        }
                     })
                 }))
             }
@@ -76,29 +89,36 @@ impl LocalResource for FileSender {
                         Box::pin(async move {
                             debug!("buffered amount low");
                             let mut buf = [0u8; 1 << 15];
-                            let size = reader
-                                .write()
-                                .await
-                                .as_mut()
-                                .unwrap()
-                                .read(&mut buf)
-                                .await
-                                .unwrap();
-                            if size == 0 {
-                                info!("reached EOF, closing channel");
-                                let _ = channel.send_text("end").await;
-                                channel.close().await.unwrap();
-                            } else {
-                                let progress_size = pos.fetch_add(size, Ordering::Relaxed);
-                                info!(
-                                    "sending {size} bytes ({} of {})",
-                                    humansize::format_size(progress_size, DECIMAL),
-                                    humansize::format_size(total_size, DECIMAL),
-                                );
-                                channel
-                                    .send(&Bytes::copy_from_slice(&buf[..size]))
-                                    .await
-                                    .unwrap();
+                            // the file is closed once the channel is
+                            let read = match reader.write().await.as_mut() {
+                                Some(reader) => reader.read(&mut buf).await,
+                                None => return,
+                            };
+                            match read {
+                                Ok(0) => {
+                                    info!("reached EOF, closing channel");
+                                    let _ = channel.send_text("end").await;
+                                    if let Err(e) = channel.close().await {
+                                        error!("cannot close channel: {e}");
+                                    }
+                                }
+                                Ok(size) => {
+                                    let progress_size = pos.fetch_add(size, Ordering::Relaxed);
+                                    info!(
+                                        "sending {size} bytes ({} of {})",
+                                        humansize::format_size(progress_size, DECIMAL),
+                                        humansize::format_size(total_size, DECIMAL),
+                                    );
+                                    if let Err(e) =
+                                        channel.send(&Bytes::copy_from_slice(&buf[..size])).await
+                                    {
+                                        error!("cannot send: {e}");
+                                    }
+                                }
+                                Err(e) => {
+                                    error!("cannot read file: {e}");
+                                    let _ = channel.close().await;
+                                }
                             }
                         })
                     }))
@@ -155,7 +175,9 @@ impl RequestHandler for DownloadHandler {
                             }
                             Err(e) => {
                                 error!("cannot write download: {e}");
-                                channel2.close().await.unwrap();
+                                if let Err(e) = channel2.close().await {
+                                    error!("cannot close channel: {e}");
+                                }
                             }
                         }
                     })
@@ -173,13 +195,14 @@ impl RequestHandler for DownloadHandler {
             }
             {
                 let writer = writer.clone();
+                let channel2 = channel.clone();
                 channel.on_message(Box::new(move |mesg| {
                     let writer = writer.clone();
                     let pos = pos.clone();
+                    let channel = channel2.clone();
                     Box::pin(async move {
                         if mesg.is_string {
-                            let s = String::from_utf8(mesg.data.to_vec()).unwrap();
-                            if &s == "end" {
+                            if &mesg.data[..] == b"end" {
                                 info!("transfer complete")
                             }
                         } else {
@@ -190,14 +213,15 @@ impl RequestHandler for DownloadHandler {
                                 humansize::format_size(pos, DECIMAL),
                                 humansize::format_size(resource.size.unwrap_or(0), DECIMAL),
                             );
-                            writer
-                                .write()
-                                .await
-                                .as_mut()
-                                .unwrap()
-                                .write_all(&mesg.data)
-                                .await
-                                .unwrap();
+                            // nothing is written if the file could not be created
+                            let written = match writer.write().await.as_mut() {
+                                Some(writer) => writer.write_all(&mesg.data).await,
+                                None => return,
+                            };
+                            if let Err(e) = written {
+                                error!("cannot write download: {e}");
+                                let _ = channel.close().await;
+                            }
                         }
                     })
                 }))
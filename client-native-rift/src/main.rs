@@ -10,11 +10,16 @@ pub mod port;
 pub mod repl;
 
 use crate::command::dispatch_command;
+use anyhow::anyhow;
 use clap::{ColorChoice, Parser};
 use libkeks::{
-    instance::Instance,
+    identity::Identity,
+    instance::{Instance, REJOIN_TIMEOUT},
+    lan::{self, LanServer},
     peer::{Peer, TransportChannel},
-    protocol::{ChatMesssage, ProvideInfo, RelayMessage},
+    protocol::{ChatMessage, ClientId, ProvideInfo, RelayMessage, RoomTag, DEFAULT_ROOM},
+    room::REKEY_GRACE,
+    sas::Sas,
     webrtc::data_channel::RTCDataChannel,
     Config, DynFut, EventHandler,
 };
@@ -22,11 +27,12 @@ use log::{error, info, trace, warn};
 use repl::repl;
 use std::{
     collections::HashMap, future::Future, path::PathBuf, pin::Pin, process::exit, sync::Arc,
+    time::Duration,
 };
 use tokio::sync::RwLock;
 use users::get_current_username;
 
-fn main() {
+fn main() -> anyhow::Result<()> {
     pretty_env_logger::formatted_builder()
         .filter_module("rift", log::LevelFilter::Info)
         .filter_module("libkeks", log::LevelFilter::Info)
@@ -35,10 +41,8 @@ fn main() {
         .init();
     tokio::runtime::Builder::new_multi_thread()
         .enable_all()
-        .build()
-        .unwrap()
+        .build()?
         .block_on(run())
-        .unwrap();
 }
 
 /// If no command is provided, rift will enter REPL-mode.
@@ -53,6 +57,30 @@ pub struct Args {
     username: String,
     /// pre-shared secret (aka. room name)
     secret: String,
+    /// moderator secret; whoever creates a room with it becomes its moderator
+    #[clap(long)]
+    moderator_secret: Option<String>,
+    /// hide who we talk to from the server if all peers support it
+    #[clap(long)]
+    private_relay: bool,
+    /// hand out a fresh room key whenever someone leaves
+    #[clap(long)]
+    rekey: bool,
+    /// host a signaling server for the local network and announce it via mDNS
+    #[clap(long, conflicts_with = "lan")]
+    host_lan: bool,
+    /// use a signaling server found on the local network instead of --signaling-uri
+    #[clap(long)]
+    lan: bool,
+    /// prove our identity to peers with the key in this file; created if missing
+    #[clap(long)]
+    identity: Option<PathBuf>,
+    /// remember identities of other users in this file
+    #[clap(long)]
+    known_identities: Option<PathBuf>,
+    /// remember keys derived from room secrets in this file, so joining again is faster
+    #[clap(long)]
+    key_cache: Option<PathBuf>,
     // /// Dispatch a single command after startup
     #[clap(subcommand)]
     command: Option<Command>,
@@ -76,8 +104,40 @@ pub enum Command {
     Expose { port: u16, id: Option<String> },
     /// Forward TCP connections to local port to another peer.
     Forward { id: String, port: Option<u16> },
+    /// Stop receiving another peer's service, e.g. an unfinished download.
+    Cancel { id: String },
     /// Send a message in the room chat.
     Chat { message: String },
+    /// Remove a user (by name or ID) from the room. Requires moderation.
+    Kick { user: String },
+    /// Prevent new users from joining. Requires moderation.
+    Lock,
+    /// Allow new users to join again. Requires moderation.
+    Unlock,
+    /// Stop relaying messages of a user. Requires moderation.
+    Mute { user: String },
+    /// Relay messages of a user again. Requires moderation.
+    Unmute { user: String },
+    /// List this room in the server's public directory. This reveals the secret.
+    Publish {
+        name: String,
+        #[clap(default_value = "")]
+        description: String,
+    },
+    /// Remove this room from the public directory.
+    Unpublish,
+    /// Show rooms in the public directory.
+    Directory,
+    /// Accept the new identity of a user (by name or ID).
+    Trust { user: String },
+    /// Compare a short authentication string with a user (by name or ID) to rule out a man in the middle.
+    Verify { user: String },
+    /// Confirm that the user sees the same string.
+    Confirm { user: String },
+    /// Abort a verification, e.g. because the strings differ.
+    Reject { user: String },
+    /// Hand out a fresh room key, so those who left can no longer read along.
+    Rekey,
 }
 
 struct State {
@@ -98,7 +158,7 @@ struct Handler {
 
 fn get_username() -> String {
     get_current_username()
-        .map(|u| u.to_str().unwrap().to_string())
+        .and_then(|u| u.into_string().ok())
         .unwrap_or("guest".to_string())
         .to_owned()
 }
@@ -108,18 +168,52 @@ async fn run() -> anyhow::Result<()> {
     let state = Arc::new(RwLock::new(State {
         requested: Default::default(),
     }));
+    // kept alive until rift exits
+    let lan_server = match args.host_lan {
+        true => Some(LanServer::start(0).await?),
+        false => None,
+    };
+    let signaling_uri = if let Some(server) = &lan_server {
+        server.signaling_uri()
+    } else if args.lan {
+        info!("looking for a signaling server on the local network...");
+        lan::discover(Duration::from_secs(5))
+            .await?
+            .ok_or(anyhow!("no signaling server found on the local network"))?
+    } else {
+        args.signaling_uri.clone()
+    };
+
+    let identity = match &args.identity {
+        Some(path) => {
+            let identity = Identity::load_or_create(path)?;
+            info!("our identity is {}", identity.fingerprint());
+            Some(identity)
+        }
+        None => None,
+    };
+
     let inst = Instance::new(
         Config {
-            signaling_uri: args.signaling_uri.clone(),
+            signaling_uri,
             username: args.username.clone(),
+            ice_servers: None,
+            moderator_secret: args.moderator_secret.clone(),
+            private_relay: args.private_relay,
+            identity,
+            known_identities: args.known_identities.clone(),
+            key_cache: args.key_cache.clone(),
+            rekey: args.rekey,
+            rekey_grace: REKEY_GRACE,
+            rejoin_timeout: REJOIN_TIMEOUT,
         },
         Arc::new(Handler {
             state: state.clone(),
         }),
     )
-    .await;
+    .await?;
 
-    inst.join(Some(&args.secret)).await;
+    inst.join(DEFAULT_ROOM, Some(&args.secret)).await?;
 
     inst.spawn_ping().await;
     tokio::task::spawn(inst.clone().receive_loop());
@@ -160,7 +254,7 @@ impl EventHandler for Handler {
         let message = message.to_owned();
         Box::pin(async move {
             match message {
-                RelayMessage::Chat(ChatMesssage::Text(message)) => {
+                RelayMessage::Chat(ChatMessage::Text(message)) => {
                     let username = peer
                         .username
                         .read()
@@ -174,6 +268,60 @@ impl EventHandler for Handler {
             }
         })
     }
+    fn room_locked(&self, _room: RoomTag, locked: bool) -> DynFut<()> {
+        Box::pin(async move { info!("room {}", if locked { "locked" } else { "unlocked" }) })
+    }
+    fn room_rekeyed(&self, _room: RoomTag, epoch: u64) -> DynFut<()> {
+        Box::pin(async move { info!("room key replaced (epoch {epoch})") })
+    }
+    fn muted(&self, _room: RoomTag, id: ClientId, muted: bool) -> DynFut<()> {
+        Box::pin(async move { info!("user {id} {}", if muted { "muted" } else { "unmuted" }) })
+    }
+    fn kicked(&self, _room: RoomTag) -> DynFut<()> {
+        Box::pin(async move {
+            error!("we were kicked from the room");
+            exit(1);
+        })
+    }
+    fn server_error(&self, _room: RoomTag, message: String) -> DynFut<()> {
+        Box::pin(async move { error!("server: {message}") })
+    }
+    fn identity_changed(&self, peer: Arc<Peer>) -> DynFut<()> {
+        Box::pin(async move {
+            let username = peer.username.read().await.clone().unwrap_or_default();
+            error!(
+                "{username:?} ({}) is using a different identity than before: {}",
+                peer.id,
+                peer.identity.read().await
+            );
+            error!("if this is expected, accept it with `trust {}`", peer.id);
+        })
+    }
+    fn verification_sas(&self, peer: Arc<Peer>, sas: Sas) -> DynFut<()> {
+        Box::pin(async move {
+            let username = peer.username.read().await.clone().unwrap_or_default();
+            info!("verifying {username:?} ({}): {sas}", peer.id);
+            info!(
+                "if they see the same, run `confirm {}`, otherwise `reject {}`",
+                peer.id, peer.id
+            );
+        })
+    }
+    fn verification_done(&self, peer: Arc<Peer>, verified: bool) -> DynFut<()> {
+        Box::pin(async move {
+            if verified {
+                info!("({}) is verified", peer.id);
+            } else {
+                error!("verification of ({}) failed", peer.id);
+            }
+        })
+    }
+    fn disconnected(&self) -> DynFut<()> {
+        Box::pin(async move { warn!("disconnected from the signaling server, reconnecting...") })
+    }
+    fn reconnected(&self) -> DynFut<()> {
+        Box::pin(async move { info!("reconnected") })
+    }
     fn resource_connected(
         &self,
         _peer: Arc<Peer>,
@@ -1,4 +1,5 @@
 use crate::RequestHandler;
+use anyhow::anyhow;
 use bytes::Bytes;
 use libkeks::{
     peer::Peer, protocol::ProvideInfo, webrtc::data_channel::RTCDataChannel, DynFut, LocalResource,
@@ -25,11 +26,13 @@ impl LocalResource for PortExposer {
         let id = self.info().id.clone();
         let port = self.port;
         Box::pin(async move {
-            let channel = peer
-                .peer_connection
-                .create_data_channel(&id, None)
-                .await
-                .unwrap();
+            let channel = match peer.create_data_channel(&id).await {
+                Ok(channel) => channel,
+                Err(e) => {
+                    error!("cannot create data channel: {e}");
+                    return;
+                }
+            };
             let writer: Arc<RwLock<Option<Pin<Box<dyn AsyncWrite + Send + Sync>>>>> =
                 Arc::new(RwLock::new(None));
             {
@@ -54,16 +57,21 @@ impl LocalResource for PortExposer {
                                             break;
                                         }
                                         debug!("send {size}");
-                                        channel
+                                        if let Err(e) = channel
                                             .send(&Bytes::copy_from_slice(&buf[..size]))
                                             .await
-                                            .unwrap();
+                                        {
+                                            warn!("cannot send: {e}");
+                                            break;
+                                        }
                                     }
                                 });
                             }
                             Err(e) => {
                                 warn!("upstream connect failed: {e}");
-                                channel2.close().await.unwrap();
+                                if let Err(e) = channel2.close().await {
+                                    error!("cannot close channel: {e}");
+                                }
                             }
                         }
                     })
@@ -81,18 +89,23 @@ impl LocalResource for PortExposer {
             }
             {
                 let writer = writer.clone();
+                let channel2 = channel.clone();
                 channel.on_message(Box::new(move |message| {
                     let writer = writer.clone();
+                    let channel = channel2.clone();
                     Box::pin(async move {
                         debug!("recv {}", message.data.len());
-                        writer
-                            .write()
-                            .await
-                            .as_mut()
-                            .unwrap()
-                            .write_all(&message.data)
-                            .await
-                            .unwrap();
+                        let written = match writer.write().await.as_mut() {
+                            Some(writer) => writer.write_all(&message.data).await,
+                            None => {
+                                warn!("no upstream connection");
+                                return;
+                            }
+                        };
+                        if let Err(e) = written {
+                            warn!("upstream write failed: {e}");
+                            let _ = channel.close().await;
+                        }
                     })
                 }));
             }
@@ -114,7 +127,11 @@ impl RequestHandler for ForwardHandler {
     ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + Sync>> {
         let stream = self.stream.clone();
         Box::pin(async move {
-            let stream = stream.write().await.take().unwrap();
+            let stream = stream
+                .write()
+                .await
+                .take()
+                .ok_or_else(|| anyhow!("connection is forwarded already"))?;
             let (mut read, write) = stream.into_split();
             let write = Arc::new(RwLock::new(write));
 
@@ -133,10 +150,12 @@ impl RequestHandler for ForwardHandler {
                                 break;
                             }
                             debug!("send {size}");
-                            channel
-                                .send(&Bytes::copy_from_slice(&buf[..size]))
-                                .await
-                                .unwrap();
+                            if let Err(e) =
+                                channel.send(&Bytes::copy_from_slice(&buf[..size])).await
+                            {
+                                warn!("cannot send: {e}");
+                                break;
+                            }
                         }
                     });
                 })
@@ -151,11 +170,17 @@ impl RequestHandler for ForwardHandler {
             }));
             {
                 let write = write.clone();
+                let channel2 = channel.clone();
                 channel.on_message(Box::new(move |message| {
                     let write = write.clone();
+                    let channel = channel2.clone();
                     Box::pin(async move {
                         debug!("recv {}", message.data.len());
-                        write.write().await.write_all(&message.data).await.unwrap();
+                        let written = write.write().await.write_all(&message.data).await;
+                        if let Err(e) = written {
+                            warn!("downstream write failed: {e}");
+                            let _ = channel.close().await;
+                        }
                     })
                 }));
             }
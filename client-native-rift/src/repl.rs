@@ -26,7 +26,9 @@ pub(crate) async fn repl(inst: Arc<Instance>, state: Arc<RwLock<State>>) -> anyh
                         Ok(()) => (),
                         Err(err) => error!(target: "rift", "{err}"),
                     },
-                    Err(err) => err.print().unwrap(),
+                    Err(err) => {
+                        let _ = err.print();
+                    }
                 },
                 None => warn!("fix your quoting"),
             },
@@ -0,0 +1,85 @@
+/*
+    This file is part of keks-meet (https://codeberg.org/metamuffin/keks-meet)
+    which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
+    Copyright (C) 2023 metamuffin <metamuffin.org>
+*/
+// TODO any better idea for random room names?
+function random_name() {
+    const frags = [
+        [
+            "Sweet",
+            "Delicious",
+            "Fluffy",
+            "Small",
+            "Glowing",
+            "Flavourful",
+            "Tasty",
+            "Yummy",
+            "Soft",
+        ],
+        [
+            "Strawberry",
+            "Raspberry",
+            "Blueberry",
+            "Chocolate",
+            "Vanilla",
+            "Potato",
+            "Tomato",
+            "Lemon",
+            "Apple",
+            "Pineapple",
+            "Pumpkin",
+        ],
+        [
+            "Muffin",
+            "Pie",
+            "Cupcake",
+            "Sandwish",
+            "Cake",
+            "Cookie",
+            "Crêpe",
+            "Pancake",
+            "Biscuit",
+            "IceCream",
+            "Yogurt",
+            "Milkshake",
+        ],
+    ];
+    return frags
+        .map(f => f[Math.floor(Math.random() * f.length)])
+        .join("")
+        .trim();
+}
+
+function random_secret() {
+    const random = window.crypto.getRandomValues(
+        new Uint8Array(32)
+    );
+    return Array.from(random)
+        .map((b) => b.toString(16).padStart(2, "0"))
+        .join("");
+}
+
+const rname = random_name();
+const room_input = document.createElement("input");
+room_input.type = "text";
+room_input.id = "room-id-input";
+room_input.placeholder = `Edit room name: ${rname}`;
+room_input.ariaLabel = "Room Secret";
+
+const submit = document.createElement("input");
+submit.type = "button";
+function go() {
+    if (room_input.value.length == "") room_input.value = rname;
+    const url = `/room#${room_input.value}#${random_secret()}`;
+    window.location.href = url;
+}
+submit.addEventListener("click", () => go());
+room_input.addEventListener("keydown", (ev) => {
+    if (ev.code == "Enter") go();
+});
+submit.value = "Create/Join room!";
+
+document
+    .querySelector("div.start-box")
+    ?.append(room_input, document.createElement("br"), submit);
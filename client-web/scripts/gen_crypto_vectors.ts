@@ -0,0 +1,52 @@
+/*
+    This file is part of keks-meet (https://codeberg.org/metamuffin/keks-meet)
+    which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
+    Copyright (C) 2023 metamuffin <metamuffin.org>
+*/
+// Generates common/crypto_vectors.json with source/protocol/crypto.ts. The
+// native client checks itself against them. With --check, it fails if the
+// file does not match what crypto.ts produces.
+
+import { buf_to_hex, derive_seeded_key, encrypt, room_hash } from "../source/protocol/crypto.ts"
+
+const SECRETS = ["hunter2", "", "v1:hunter2", "grüße 🍪#room", "a".repeat(200)]
+const MESSAGES = [
+    { secret: "hunter2", iv: "000000000000000000000000", plaintext: "" },
+    { secret: "hunter2", iv: "000102030405060708090a0b", plaintext: "{\"sender\":1,\"inner\":{\"chat\":{\"text\":\"hello\"}}}" },
+    { secret: "grüße 🍪#room", iv: "ffffffffffffffffffffffff", plaintext: "grüße 🍪" },
+]
+
+const hex_to_buf = (s: string) => Uint8Array.from(s.match(/../g) ?? [], x => parseInt(x, 16))
+
+const rooms = []
+const keys = new Map<string, CryptoKey>()
+for (const secret of SECRETS) {
+    const key = await derive_seeded_key(secret, true)
+    keys.set(secret, key)
+    rooms.push({
+        secret,
+        room_hash: await room_hash(secret),
+        key: buf_to_hex(new Uint8Array(await crypto.subtle.exportKey("raw", key))),
+    })
+}
+
+const messages = []
+for (const { secret, iv, plaintext } of MESSAGES) {
+    messages.push({
+        key: rooms.find(r => r.secret == secret)!.key,
+        iv,
+        plaintext,
+        message: await encrypt(keys.get(secret)!, plaintext, hex_to_buf(iv)),
+    })
+}
+
+const output = JSON.stringify({ rooms, messages }, null, 4)
+if (Deno.args.includes("--check")) {
+    const path = new URL("../../common/crypto_vectors.json", import.meta.url)
+    if (JSON.stringify(JSON.parse(await Deno.readTextFile(path))) != JSON.stringify(JSON.parse(output))) {
+        console.error("common/crypto_vectors.json does not match source/protocol/crypto.ts")
+        Deno.exit(1)
+    }
+} else {
+    console.log(output)
+}
@@ -28,11 +28,11 @@ export interface ClientConfig {
         background: string
         background_dark: string
     }
+    branding?: {
+        instance_name?: string
+    }
     webrtc: {
-        stun: string,
-        turn?: string,
-        turn_user?: string,
-        turn_cred?: string
+        ice_servers: RTCIceServer[]
     }
 }
 
@@ -42,6 +42,8 @@ export interface User {
 }
 
 
+let instance_name = "keks-meet"
+
 export interface AppState {
     conn: SignalingConnection,
     room?: Room
@@ -55,7 +57,7 @@ function set_room(state: AppState, secret: string, rtc_config: RTCConfiguration)
         state.room.destroy()
     }
     if (secret.length < 8) log({ scope: "crypto", warn: true }, PO.warn_short_secret)
-    if (secret.split("#").length > 1) document.title = `${secret.split("#")[0]} | keks-meet`
+    if (secret.split("#").length > 1) document.title = `${secret.split("#")[0]} | ${instance_name}`
     state.room = new Room(state.conn, state.chat, rtc_config)
     state.chat.room = state.room
     state.conn.join(secret)
@@ -71,6 +73,7 @@ export async function main() {
     if (!config_res.ok) return log({ scope: "*", error: true }, "cannot load config")
     const config: ClientConfig = await config_res.json()
     log("*", "config loaded. starting")
+    if (config.branding?.instance_name) document.title = instance_name = config.branding.instance_name
 
     init_locale(PREFS.language ?? "en-US")
 
@@ -92,11 +95,7 @@ export async function main() {
 
     const rtc_config: RTCConfiguration = {
         iceCandidatePoolSize: 10,
-        iceServers: [{
-            urls: [config.webrtc.stun, ...(config.webrtc.turn ? [config.webrtc.turn] : [])],
-            credential: config.webrtc.turn_cred,
-            username: config.webrtc.turn_user,
-        }]
+        iceServers: config.webrtc.ice_servers
     }
 
     setup_keybinds(state)
@@ -3,45 +3,50 @@
     which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
     Copyright (C) 2023 metamuffin <metamuffin.org>
 */
-import { log } from "../logger.ts";
-
 //! I am not a crypto expert at all! Please read carefully and report any issues to me. 
 
+// parameters here are pinned by common/crypto_vectors.json (scripts/gen_crypto_vectors.ts),
+// which imports this module. it must not depend on the DOM for that.
 const IV_LENGTH = 12
 
 const CRYPTO_SALT = base64_to_buf("keksmeet/cryptosaltAAA==")
 const HASH_SALT = base64_to_buf("keksmeet/roomhashsaltA==")
 
-export async function derive_seeded_key(seed: string): Promise<CryptoKey> {
-    log("crypto", "deriving crytographic key...")
-    const seed_key = await window.crypto.subtle.importKey(
+// secrets starting with "v2:" use Argon2id, which only the native clients implement.
+// everything else is PBKDF2 over the whole secret.
+function kdf_input(secret: string): string {
+    if (secret.startsWith("v2:")) throw new Error("this room uses Argon2id key derivation, which requires a native client")
+    return secret
+}
+
+// the key is only extractable for the test vectors
+export async function derive_seeded_key(seed: string, extractable = false): Promise<CryptoKey> {
+    const seed_key = await globalThis.crypto.subtle.importKey(
         "raw",
-        new TextEncoder().encode(seed),
+        new TextEncoder().encode(kdf_input(seed)),
         "PBKDF2",
         false,
         ["deriveKey"]
     )
-    const key = await window.crypto.subtle.deriveKey(
+    const key = await globalThis.crypto.subtle.deriveKey(
         { name: "PBKDF2", salt: CRYPTO_SALT, iterations: 250000, hash: "SHA-512" },
         seed_key,
         { name: "AES-GCM", length: 256 },
-        false,
+        extractable,
         ["encrypt", "decrypt"]
     )
-    log("crypto", "ready")
     return key
 }
 
 export async function room_hash(input: string): Promise<string> {
-    log("crypto", "deriving room hash...")
-    const seed_key = await window.crypto.subtle.importKey(
+    const seed_key = await globalThis.crypto.subtle.importKey(
         "raw",
-        new TextEncoder().encode(input),
+        new TextEncoder().encode(kdf_input(input)),
         "PBKDF2",
         false,
         ["deriveBits"]
     )
-    const key = await window.crypto.subtle.deriveBits(
+    const key = await globalThis.crypto.subtle.deriveBits(
         { name: "PBKDF2", salt: HASH_SALT, iterations: 250000, hash: "SHA-512" },
         seed_key,
         512
@@ -50,9 +55,10 @@ export async function room_hash(input: string): Promise<string> {
     return hex
 }
 
-export async function encrypt(key: CryptoKey, data: string): Promise<string> {
-    const iv = window.crypto.getRandomValues(new Uint8Array(IV_LENGTH));
-    const ciphertext = new Uint8Array(await window.crypto.subtle.encrypt(
+// the iv is only given for the test vectors
+export async function encrypt(key: CryptoKey, data: string, iv?: Uint8Array): Promise<string> {
+    iv ??= globalThis.crypto.getRandomValues(new Uint8Array(IV_LENGTH));
+    const ciphertext = new Uint8Array(await globalThis.crypto.subtle.encrypt(
         { name: "AES-GCM", iv },
         key,
         new TextEncoder().encode(data)
@@ -64,12 +70,12 @@ export async function encrypt(key: CryptoKey, data: string): Promise<string> {
     return b64;
 }
 
-export async function decrypt(key: CryptoKey, data: string): Promise<string> {
+export async function decrypt(key: CryptoKey, data: string): Promise<string | undefined> {
     try {
         const buf = base64_to_buf(data);
         const iv = buf.slice(0, IV_LENGTH);
         const ciphertext = buf.slice(IV_LENGTH);
-        const decryptedContent = await window.crypto.subtle.decrypt(
+        const decryptedContent = await globalThis.crypto.subtle.decrypt(
             { name: "AES-GCM", iv },
             key,
             ciphertext
@@ -77,8 +83,7 @@ export async function decrypt(key: CryptoKey, data: string): Promise<string> {
         const plain = new TextDecoder().decode(decryptedContent);
         return plain
     } catch (_e) {
-        log({ scope: "crypto", warn: true }, "unable to decrypt")
-        return "{}" // :)
+        return undefined
     }
 }
 
@@ -8,12 +8,23 @@ import { EventEmitter } from "../helper.ts";
 import { log } from "../logger.ts"
 import { encrypt, derive_seeded_key, decrypt, room_hash } from "./crypto.ts"
 
+const MAX_RELAY_AGE = 5 * 60 * 1000 // includes clock skew between peers
+
+interface LastRelay {
+    counter: number
+    timestamp: number
+    left?: number // when the sender left; it may come back as a new client with the same id
+}
+
 export class SignalingConnection {
     websocket!: WebSocket
     room?: string
     room_hash?: string
     key?: CryptoKey
     my_id?: number // needed for outgoing relay messages
+    relay_counter = 0
+    relay_queue = Promise.resolve() // encryption is async, this keeps relays in counter order
+    last_relays = new Map<number, LastRelay>() // highest relay counter seen per sender, kept for a while after it left
 
     control_handler = new EventEmitter<ClientboundPacket>()
     relay_handler = new EventEmitter<[number, RelayMessage]>()
@@ -48,7 +59,10 @@ export class SignalingConnection {
 
     async join(room: string) {
         this.room = room;
+        log("crypto", "deriving crytographic key...")
         this.key = await derive_seeded_key(room)
+        log("crypto", "ready")
+        log("crypto", "deriving room hash...")
         this.room_hash = await room_hash(room)
         this.send_control({ join: { hash: this.room_hash } })
     }
@@ -66,8 +80,11 @@ export class SignalingConnection {
         }
         this.control_handler.dispatch(packet)
         if (packet.init) this.my_id = packet.init.your_id;
+        if (packet.client_leave) this.on_client_leave(packet.client_leave.id)
         if (packet.message) {
             const plain_json = await decrypt(this.key!, packet.message.message)
+            if (plain_json === undefined)
+                return log({ scope: "crypto", warn: true }, "unable to decrypt")
 
             let plain: RelayMessageWrapper
             try {
@@ -75,21 +92,57 @@ export class SignalingConnection {
             } catch (_e) {
                 return log({ scope: "ws", warn: true }, "somebody sent invalid json");
             }
-            if (plain.sender == packet.message.sender)
-                this.relay_handler.dispatch([packet.message.sender, plain.inner])
-            else {
-                log({ scope: "crypto", warn: true }, `message dropped: sender inconsistent (${plain.sender} != ${packet.message.sender})`)
-            }
+            if (plain.sender != packet.message.sender)
+                return log({ scope: "crypto", warn: true }, `message dropped: sender inconsistent (${plain.sender} != ${packet.message.sender})`)
+            if (plain.recipient !== undefined && plain.recipient != this.my_id)
+                return log({ scope: "crypto", warn: true }, `message dropped: meant for ${plain.recipient}`)
+            if (!this.check_fresh(plain))
+                return log({ scope: "crypto", warn: true }, `message dropped: replayed or stale`)
+            this.relay_handler.dispatch([packet.message.sender, plain.inner])
         }
     }
 
+    on_client_leave(id: number) {
+        // forgetting the counter right away would let the server replay relays after a fake leave and join
+        const now = Date.now()
+        for (const [sender, last] of this.last_relays)
+            if (last.left !== undefined && now - last.left > MAX_RELAY_AGE) this.last_relays.delete(sender)
+        const last = this.last_relays.get(id)
+        if (last) last.left = now
+    }
+
+    check_fresh(message: RelayMessageWrapper): boolean {
+        let last = this.last_relays.get(message.sender)
+        if (last?.left !== undefined && Date.now() - last.left > MAX_RELAY_AGE) last = undefined
+        // older clients send neither, accept that until the sender proves otherwise
+        if (message.counter === undefined || message.timestamp === undefined) return last === undefined
+        if (Math.abs(Date.now() - message.timestamp) > MAX_RELAY_AGE) return false
+        if (last) {
+            // a new client restarts its counter, but its relays are newer
+            const restarted = last.left !== undefined && message.timestamp > last.timestamp
+            if (message.counter <= last.counter && !restarted) return false
+        }
+        this.last_relays.set(message.sender, { counter: message.counter, timestamp: message.timestamp })
+        return true
+    }
+
     send_control(data: ServerboundPacket) {
         this.websocket.send(JSON.stringify(data))
     }
-    async send_relay(data: RelayMessage, recipient?: number | null) {
+    send_relay(data: RelayMessage, recipient?: number | null): Promise<void> {
         recipient ??= undefined // null -> undefined
-        const packet: RelayMessageWrapper = { inner: data, sender: this.my_id! }
-        const message = await encrypt(this.key!, JSON.stringify(packet))
-        this.send_control({ relay: { recipient, message } })
+        const send = this.relay_queue.then(async () => {
+            const packet: RelayMessageWrapper = {
+                inner: data,
+                sender: this.my_id!,
+                recipient,
+                counter: ++this.relay_counter,
+                timestamp: Date.now(),
+            }
+            const message = await encrypt(this.key!, JSON.stringify(packet))
+            this.send_control({ relay: { recipient, message } })
+        })
+        this.relay_queue = send.catch(() => { })
+        return send
     }
 }
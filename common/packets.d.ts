@@ -3,56 +3,88 @@
     which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
     Copyright (C) 2023 metamuffin <metamuffin.org>
 */
-
-// copy pasted from dom.lib.d.ts because it can not be referenced in the server.
-type Sdp = string
-interface F_RTCIceCandidateInit {
-    candidate?: string
-    sdpMLineIndex?: number | null
-    sdpMid?: string | null
-    usernameFragment?: string | null
-}
+// Generated from the keks-protocol crate; do not edit.
+// Unit variants may also be sent as plain strings, e.g. "ping".
 
 export interface ClientboundPacket {
     init?: { your_id: number, version: string }
-    client_join?: { id: number }  // join: more like "appear" - also sent when you join for others that were there before you.
-    client_leave?: { id: number }
-    message?: { sender: number, message: string /* encrypted RelayMessageWrapper */ }
+    client_join?: { id: number, room?: number } // join: more like "appear" - also sent when you join for others that were there before you.
+    client_leave?: { id: number, room?: number }
+    message?: { sender: number, message: string, room?: number } // message is an encrypted RelayMessageWrapper
     room_info?: { hash: string, user_count: number }
+    moderator?: { id: number, room?: number }
+    locked?: { locked: boolean, room?: number }
+    muted?: { id: number, muted: boolean, room?: number }
+    kicked?: { room?: number } // kicked from the room
+    error?: { message: string, room?: number }
+    directory?: { rooms: DirectoryEntry[] }
 }
 
 export interface ServerboundPacket {
-    join?: { hash?: string }
+    join?: { hash?: string, moderator?: string /* grants moderation if it matches the one given by whoever created the room */, room?: number } // joins the room with this hash under the tag, leaving whatever room had the tag before
     ping?: null
-    relay?: { recipient?: number, message: string /* encrypted RelayMessageWrapper */ }
+    relay?: { recipient?: number, message: string, room?: number } // message is an encrypted RelayMessageWrapper
     watch_rooms?: string[]
+    kick?: { id: number, room?: number }
+    lock?: { locked: boolean, room?: number }
+    mute?: { id: number, muted: boolean, room?: number }
+    publish?: { name: string, description: string, secret: string, room?: number } // list the room in the public directory. this reveals its secret.
+    unpublish?: { room?: number } // remove the room from the public directory
+    list_directory?: null
+}
+
+export interface DirectoryEntry {
+    name: string
+    description: string
+    secret: string // published rooms are not private
+    hash: string // room the entry was published in; clients check it against the secret
+    user_count: number
 }
 
 export interface RelayMessageWrapper {
-    sender: number, // redundancy to ensure the server didn't cheat
+    sender: number // redundant, but ensures the server didnt cheat
+    recipient?: number // intended recipient of a directed message, so the server can not redirect it
+    counter?: number // increases with every message of the sender, so the server can not replay it
+    timestamp?: number // milliseconds since the unix epoch, so the server can not hold it back
     inner: RelayMessage
 }
 
 export interface RelayMessage {
     chat?: ChatMessage
-    identify?: { username: string }
+    identify?: { username: string, private_relay?: boolean /* peer drops broadcasts addressed to someone else */, rekey?: boolean /* peer follows room rekeys */, identity?: string /* long-term Ed25519 public key (base64) of the user */, signature?: string /* signature (base64) over username, both ids and the sender's key_exchange key */ }
+    key_exchange?: { public_key: string } // ephemeral X25519 public key (base64); directed relays then use the derived pairwise key
+    verify_start?: { commitment: string } // starts a short authentication string comparison; hash (base64) of the initiator's verify_key
+    verify_key?: { public_key: string } // ephemeral X25519 public key (base64) of a verification; the responder sends it first
+    verify_done?: { confirmed: boolean } // ends a verification; confirmed if the user saw the same string on both sides
+    rekey?: { epoch: number, author: number, key: string } // replaces the room key; only accepted under a pairwise key. key is 32 bytes (base64)
     provide?: ProvideInfo
     request?: { id: string }
     provide_stop?: { id: string }
     request_stop?: { id: string }
-    offer?: Sdp
-    answer?: Sdp
-    ice_candidate?: F_RTCIceCandidateInit
-    preview?: { id: string, data: string }
+    offer?: string
+    answer?: string
+    ice_candidate?: IceCandidateInit
+    preview?: { id: string, data: string } // small preview image of a track; data is a data url
+}
+
+export interface ChatMessage {
+    text?: string
+    image?: string
 }
 
-export interface ChatMessage { text?: string, image?: string }
-export type ResourceKind = "track" | "file"
 export type TrackKind = "audio" | "video"
+
 export interface ProvideInfo {
     id: string // for datachannels this is `label`, for tracks this will be the `id` of the only associated stream.
-    kind: ResourceKind
+    kind: string // "track" or "file"; not an enum so we dont fail if we dont support it
     track_kind?: TrackKind // used to indicate a/v for tracks only
     label?: string
     size?: number // size for file transfers
 }
+
+export interface IceCandidateInit {
+    candidate?: string
+    sdpMid?: string | null
+    sdpMLineIndex?: number | null
+    usernameFragment?: string | null
+}
@@ -0,0 +1,352 @@
+/*
+    This file is part of keks-meet (https://codeberg.org/metamuffin/keks-meet)
+    which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
+    Copyright (C) 2023 metamuffin <metamuffin.org>
+*/
+//! Packets exchanged between keks-meet clients and the server. This crate is
+//! the single source of truth for the wire format; `packets.d.ts` is generated
+//! from it with `cargo run -p keks-protocol > common/packets.d.ts`.
+#[macro_use]
+pub mod typescript;
+
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use typescript::{TsDecl, TsType};
+
+/// Assigned by the server, unique among its connected clients.
+#[repr(transparent)]
+#[derive(Debug, Hash, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct ClientId(pub u64);
+
+impl fmt::Display for ClientId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+impl TsType for ClientId {
+    fn ts_type() -> String {
+        "number".to_string()
+    }
+}
+
+pub type Sdp = String;
+
+/// Chosen by the client to tell apart the rooms it joined over one
+/// connection. Clients that only ever join one room never see the tag.
+pub type RoomTag = u32;
+pub const DEFAULT_ROOM: RoomTag = 0;
+
+pub fn is_default_room(tag: &RoomTag) -> bool {
+    *tag == DEFAULT_ROOM
+}
+
+protocol_enum! {
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum ClientboundPacket {
+        Init { your_id: ClientId, version: String },
+        /// join: more like "appear" - also sent when you join for others that were there before you.
+        ClientJoin {
+            id: ClientId,
+            #[serde(default, skip_serializing_if = "is_default_room")]
+            room: RoomTag,
+        },
+        ClientLeave {
+            id: ClientId,
+            #[serde(default, skip_serializing_if = "is_default_room")]
+            room: RoomTag,
+        },
+        /// message is an encrypted RelayMessageWrapper
+        Message {
+            sender: ClientId,
+            message: String,
+            #[serde(default, skip_serializing_if = "is_default_room")]
+            room: RoomTag,
+        },
+        RoomInfo { hash: String, user_count: usize },
+        Moderator {
+            id: ClientId,
+            #[serde(default, skip_serializing_if = "is_default_room")]
+            room: RoomTag,
+        },
+        Locked {
+            locked: bool,
+            #[serde(default, skip_serializing_if = "is_default_room")]
+            room: RoomTag,
+        },
+        Muted {
+            id: ClientId,
+            muted: bool,
+            #[serde(default, skip_serializing_if = "is_default_room")]
+            room: RoomTag,
+        },
+        /// kicked from the room
+        Kicked {
+            #[serde(default, skip_serializing_if = "is_default_room")]
+            room: RoomTag,
+        },
+        Error {
+            message: String,
+            #[serde(default, skip_serializing_if = "is_default_room")]
+            room: RoomTag,
+        },
+        Directory { rooms: Vec<DirectoryEntry> },
+    }
+}
+
+protocol_enum! {
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum ServerboundPacket {
+        /// joins the room with this hash under the tag, leaving whatever room had the tag before
+        Join {
+            hash: Option<String>,
+            /// grants moderation if it matches the one given by whoever created the room
+            moderator: Option<String>,
+            #[serde(default, skip_serializing_if = "is_default_room")]
+            room: RoomTag,
+        },
+        Ping,
+        /// message is an encrypted RelayMessageWrapper
+        Relay {
+            recipient: Option<ClientId>,
+            message: String,
+            #[serde(default, skip_serializing_if = "is_default_room")]
+            room: RoomTag,
+        },
+        WatchRooms(Vec<String>),
+        Kick {
+            id: ClientId,
+            #[serde(default, skip_serializing_if = "is_default_room")]
+            room: RoomTag,
+        },
+        Lock {
+            locked: bool,
+            #[serde(default, skip_serializing_if = "is_default_room")]
+            room: RoomTag,
+        },
+        Mute {
+            id: ClientId,
+            muted: bool,
+            #[serde(default, skip_serializing_if = "is_default_room")]
+            room: RoomTag,
+        },
+        /// list the room in the public directory. this reveals its secret.
+        Publish {
+            name: String,
+            description: String,
+            secret: String,
+            #[serde(default, skip_serializing_if = "is_default_room")]
+            room: RoomTag,
+        },
+        /// remove the room from the public directory
+        Unpublish {
+            #[serde(default, skip_serializing_if = "is_default_room")]
+            room: RoomTag,
+        },
+        ListDirectory,
+    }
+}
+
+impl ServerboundPacket {
+    /// Tag of the room this packet concerns, if any.
+    pub fn room(&self) -> Option<RoomTag> {
+        match self {
+            Self::Join { room, .. }
+            | Self::Relay { room, .. }
+            | Self::Kick { room, .. }
+            | Self::Lock { room, .. }
+            | Self::Mute { room, .. }
+            | Self::Publish { room, .. }
+            | Self::Unpublish { room } => Some(*room),
+            Self::Ping | Self::WatchRooms(_) | Self::ListDirectory => None,
+        }
+    }
+}
+
+protocol_struct! {
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct DirectoryEntry {
+        pub name: String,
+        pub description: String,
+        /// published rooms are not private
+        pub secret: String,
+        /// room the entry was published in; clients check it against the secret
+        pub hash: String,
+        pub user_count: usize,
+    }
+}
+
+protocol_struct! {
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    pub struct RelayMessageWrapper {
+        /// redundant, but ensures the server didnt cheat
+        pub sender: ClientId,
+        /// intended recipient of a directed message, so the server can not redirect it
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub recipient: Option<ClientId>,
+        /// increases with every message of the sender, so the server can not replay it
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub counter: Option<u64>,
+        /// milliseconds since the unix epoch, so the server can not hold it back
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub timestamp: Option<u64>,
+        pub inner: RelayMessage,
+    }
+}
+
+protocol_enum! {
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "snake_case")]
+    pub enum RelayMessage {
+        Chat(ChatMessage),
+        Identify {
+            username: String,
+            /// peer drops broadcasts addressed to someone else
+            #[serde(default)]
+            private_relay: bool,
+            /// peer follows room rekeys
+            #[serde(default)]
+            rekey: bool,
+            /// long-term Ed25519 public key (base64) of the user
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            identity: Option<String>,
+            /// signature (base64) over username, both ids and the sender's key_exchange key
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            signature: Option<String>,
+        },
+        /// ephemeral X25519 public key (base64); directed relays then use the derived pairwise key
+        KeyExchange {
+            public_key: String,
+        },
+        /// starts a short authentication string comparison; hash (base64) of the initiator's verify_key
+        VerifyStart {
+            commitment: String,
+        },
+        /// ephemeral X25519 public key (base64) of a verification; the responder sends it first
+        VerifyKey {
+            public_key: String,
+        },
+        /// ends a verification; confirmed if the user saw the same string on both sides
+        VerifyDone {
+            confirmed: bool,
+        },
+        /// replaces the room key; only accepted under a pairwise key. key is 32 bytes (base64)
+        Rekey {
+            epoch: u64,
+            author: ClientId,
+            key: String,
+        },
+
+        Provide(ProvideInfo),
+        Request {
+            id: String,
+        },
+        ProvideStop {
+            id: String,
+        },
+        RequestStop {
+            id: String,
+        },
+
+        Offer(Sdp),
+        Answer(Sdp),
+        IceCandidate(IceCandidateInit),
+        /// small preview image of a track; data is a data url
+        Preview {
+            id: String,
+            data: String,
+        },
+    }
+}
+
+protocol_enum! {
+    #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+    #[serde(rename_all = "snake_case")]
+    pub enum ChatMessage {
+        Text(String),
+        Image(String),
+    }
+}
+
+protocol_enum! {
+    #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+    #[serde(rename_all = "snake_case")]
+    pub enum TrackKind {
+        Audio,
+        Video,
+    }
+}
+
+protocol_struct! {
+    #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+    pub struct ProvideInfo {
+        /// for datachannels this is `label`, for tracks this will be the `id` of the only associated stream.
+        pub id: String,
+        /// "track" or "file"; not an enum so we dont fail if we dont support it
+        pub kind: String,
+        /// used to indicate a/v for tracks only
+        pub track_kind: Option<TrackKind>,
+        pub label: Option<String>,
+        /// size for file transfers
+        pub size: Option<usize>,
+    }
+}
+
+/// Same as `RTCIceCandidateInit` of the DOM and webrtc-rs.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IceCandidateInit {
+    #[serde(default)]
+    pub candidate: String,
+    pub sdp_mid: Option<String>,
+    #[serde(rename = "sdpMLineIndex")]
+    pub sdp_mline_index: Option<u16>,
+    pub username_fragment: Option<String>,
+}
+impl TsType for IceCandidateInit {
+    fn ts_type() -> String {
+        "IceCandidateInit".to_string()
+    }
+}
+impl TsDecl for IceCandidateInit {
+    fn ts_declaration() -> String {
+        "export interface IceCandidateInit {
+    candidate?: string
+    sdpMid?: string | null
+    sdpMLineIndex?: number | null
+    usernameFragment?: string | null
+}
+"
+        .to_string()
+    }
+}
+
+/// Contents of `packets.d.ts`
+pub fn typescript_declarations() -> String {
+    let mut s = String::from(
+        "/*
+    This file is part of keks-meet (https://codeberg.org/metamuffin/keks-meet)
+    which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
+    Copyright (C) 2023 metamuffin <metamuffin.org>
+*/
+// Generated from the keks-protocol crate; do not edit.
+// Unit variants may also be sent as plain strings, e.g. \"ping\".
+",
+    );
+    for decl in [
+        ClientboundPacket::ts_declaration(),
+        ServerboundPacket::ts_declaration(),
+        DirectoryEntry::ts_declaration(),
+        RelayMessageWrapper::ts_declaration(),
+        RelayMessage::ts_declaration(),
+        ChatMessage::ts_declaration(),
+        TrackKind::ts_declaration(),
+        ProvideInfo::ts_declaration(),
+        IceCandidateInit::ts_declaration(),
+    ] {
+        s += "\n";
+        s += &decl;
+    }
+    s
+}
@@ -0,0 +1,236 @@
+/*
+    This file is part of keks-meet (https://codeberg.org/metamuffin/keks-meet)
+    which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
+    Copyright (C) 2023 metamuffin <metamuffin.org>
+*/
+//! TypeScript declarations for the web client, derived from the Rust types.
+//! Types are declared through [`protocol_enum!`] and [`protocol_struct!`] so
+//! that the declaration can not get out of sync with the definition.
+
+/// A type that can be referenced from a TypeScript declaration.
+pub trait TsType {
+    fn ts_type() -> String;
+    /// Field may be omitted
+    fn optional() -> bool {
+        false
+    }
+}
+
+/// A type that gets its own TypeScript declaration.
+pub trait TsDecl {
+    fn ts_declaration() -> String;
+}
+
+macro_rules! ts_primitive {
+    ($ts:literal, $($t:ty),*) => {
+        $(impl TsType for $t {
+            fn ts_type() -> String {
+                $ts.to_string()
+            }
+        })*
+    };
+}
+ts_primitive!("string", String);
+ts_primitive!("boolean", bool);
+ts_primitive!("number", u16, u32, u64, usize);
+
+impl<T: TsType> TsType for Vec<T> {
+    fn ts_type() -> String {
+        format!("{}[]", T::ts_type())
+    }
+}
+impl<T: TsType> TsType for Option<T> {
+    fn ts_type() -> String {
+        T::ts_type()
+    }
+    fn optional() -> bool {
+        true
+    }
+}
+
+macro_rules! protocol_enum {
+    (
+        $(#[$attr:meta])*
+        pub enum $name:ident {
+            $(
+                $(#[doc = $vdoc:literal])*
+                $variant:ident
+                $({ $( $(#[doc = $fdoc:literal])* $(#[serde($($fserde:tt)*)])* $field:ident: $fty:ty ),* $(,)? })?
+                $(( $nty:ty ))?
+            ),* $(,)?
+        }
+    ) => {
+        $(#[$attr])*
+        pub enum $name {
+            $(
+                $(#[doc = $vdoc])*
+                $variant
+                $({ $( $(#[doc = $fdoc])* $(#[serde($($fserde)*)])* $field: $fty ),* })?
+                $(( $nty ))?
+            ),*
+        }
+        impl $crate::typescript::TsType for $name {
+            fn ts_type() -> String {
+                stringify!($name).to_string()
+            }
+        }
+        impl $crate::typescript::TsDecl for $name {
+            fn ts_declaration() -> String {
+                $crate::typescript::enum_declaration(
+                    stringify!($name),
+                    &[$((
+                        stringify!($variant),
+                        protocol_enum!(@payload
+                            $({ $( [$($fdoc)*] [$($($fserde)*)*] $field: $fty ),* })?
+                            $(( $nty ))?
+                        ),
+                        &[$($vdoc),*],
+                    )),*],
+                )
+            }
+        }
+    };
+    (@payload) => {
+        None
+    };
+    (@payload ( $nty:ty )) => {
+        Some(<$nty as $crate::typescript::TsType>::ts_type())
+    };
+    (@payload { $( [$($fdoc:literal)*] [$($fserde:tt)*] $field:ident: $fty:ty ),* }) => {
+        Some($crate::typescript::inline_object(&[$(
+            $crate::typescript::field::<$fty>(
+                stringify!($field),
+                stringify!($($fserde)*),
+                &[$($fdoc),*],
+            )
+        ),*]))
+    };
+}
+
+macro_rules! protocol_struct {
+    (
+        $(#[$attr:meta])*
+        pub struct $name:ident {
+            $( $(#[doc = $fdoc:literal])* $(#[serde($($fserde:tt)*)])* pub $field:ident: $fty:ty ),* $(,)?
+        }
+    ) => {
+        $(#[$attr])*
+        pub struct $name {
+            $( $(#[doc = $fdoc])* $(#[serde($($fserde)*)])* pub $field: $fty ),*
+        }
+        impl $crate::typescript::TsType for $name {
+            fn ts_type() -> String {
+                stringify!($name).to_string()
+            }
+        }
+        impl $crate::typescript::TsDecl for $name {
+            fn ts_declaration() -> String {
+                $crate::typescript::interface(
+                    stringify!($name),
+                    &[$(
+                        $crate::typescript::field::<$fty>(
+                            stringify!($field),
+                            stringify!($($($fserde)*)*),
+                            &[$($fdoc),*],
+                        )
+                    ),*],
+                )
+            }
+        }
+    };
+}
+
+pub struct Field {
+    name: String,
+    ty: String,
+    optional: bool,
+    doc: Option<String>,
+}
+
+pub fn field<T: TsType>(name: &str, serde: &str, doc: &[&str]) -> Field {
+    Field {
+        name: name.to_string(),
+        ty: T::ts_type(),
+        optional: T::optional() || serde.contains("default"),
+        doc: join_doc(doc),
+    }
+}
+
+pub fn inline_object(fields: &[Field]) -> String {
+    let fields = fields
+        .iter()
+        .map(|f| {
+            let mut s = format!("{}{}: {}", f.name, if f.optional { "?" } else { "" }, f.ty);
+            if let Some(doc) = &f.doc {
+                s += &format!(" /* {doc} */");
+            }
+            s
+        })
+        .collect::<Vec<_>>();
+    format!("{{ {} }}", fields.join(", "))
+}
+
+pub fn interface(name: &str, fields: &[Field]) -> String {
+    let mut s = format!("export interface {name} {{\n");
+    for f in fields {
+        s += &format!(
+            "    {}{}: {}",
+            f.name,
+            if f.optional { "?" } else { "" },
+            f.ty
+        );
+        if let Some(doc) = &f.doc {
+            s += &format!(" // {doc}");
+        }
+        s += "\n";
+    }
+    s + "}\n"
+}
+
+/// Externally tagged enums become interfaces with one optional key per
+/// variant, or a union of strings if no variant has data.
+pub fn enum_declaration(name: &str, variants: &[(&str, Option<String>, &[&str])]) -> String {
+    if variants.iter().all(|(_, payload, _)| payload.is_none()) {
+        let names = variants
+            .iter()
+            .map(|(v, _, _)| format!("\"{}\"", snake_case(v)))
+            .collect::<Vec<_>>();
+        return format!("export type {name} = {}\n", names.join(" | "));
+    }
+    let mut s = format!("export interface {name} {{\n");
+    for (variant, payload, doc) in variants {
+        s += &format!(
+            "    {}?: {}",
+            snake_case(variant),
+            payload.as_deref().unwrap_or("null")
+        );
+        if let Some(doc) = join_doc(doc) {
+            s += &format!(" // {doc}");
+        }
+        s += "\n";
+    }
+    s + "}\n"
+}
+
+fn join_doc(doc: &[&str]) -> Option<String> {
+    if doc.is_empty() {
+        None
+    } else {
+        Some(doc.iter().map(|l| l.trim()).collect::<Vec<_>>().join(" "))
+    }
+}
+
+fn snake_case(s: &str) -> String {
+    let mut out = String::new();
+    for (i, c) in s.chars().enumerate() {
+        if c.is_uppercase() {
+            if i != 0 {
+                out.push('_');
+            }
+            out.extend(c.to_lowercase());
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
@@ -0,0 +1,368 @@
+/*
+    This file is part of keks-meet (https://codeberg.org/metamuffin/keks-meet)
+    which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
+    Copyright (C) 2023 metamuffin <metamuffin.org>
+*/
+//! The web client and older native clients depend on this exact format.
+use keks_protocol::{
+    ChatMessage, ClientId, ClientboundPacket, DirectoryEntry, IceCandidateInit, ProvideInfo,
+    RelayMessage, RelayMessageWrapper, ServerboundPacket, TrackKind, DEFAULT_ROOM,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::fmt::Debug;
+
+fn golden<T: Serialize + DeserializeOwned + PartialEq + Debug>(value: T, json: &str) {
+    assert_eq!(serde_json::to_string(&value).unwrap(), json);
+    assert_eq!(serde_json::from_str::<T>(json).unwrap(), value);
+}
+
+#[test]
+fn clientbound() {
+    use ClientboundPacket::*;
+    golden(
+        Init {
+            your_id: ClientId(1),
+            version: "keks-meet 1.0.4".to_string(),
+        },
+        r#"{"init":{"your_id":1,"version":"keks-meet 1.0.4"}}"#,
+    );
+    golden(
+        ClientJoin {
+            id: ClientId(2),
+            room: DEFAULT_ROOM,
+        },
+        r#"{"client_join":{"id":2}}"#,
+    );
+    golden(
+        ClientLeave {
+            id: ClientId(2),
+            room: DEFAULT_ROOM,
+        },
+        r#"{"client_leave":{"id":2}}"#,
+    );
+    golden(
+        Message {
+            sender: ClientId(3),
+            message: "AAAA".to_string(),
+            room: DEFAULT_ROOM,
+        },
+        r#"{"message":{"sender":3,"message":"AAAA"}}"#,
+    );
+    golden(
+        RoomInfo {
+            hash: "abc".to_string(),
+            user_count: 4,
+        },
+        r#"{"room_info":{"hash":"abc","user_count":4}}"#,
+    );
+    golden(
+        Moderator {
+            id: ClientId(5),
+            room: DEFAULT_ROOM,
+        },
+        r#"{"moderator":{"id":5}}"#,
+    );
+    golden(
+        Locked {
+            locked: true,
+            room: DEFAULT_ROOM,
+        },
+        r#"{"locked":{"locked":true}}"#,
+    );
+    golden(
+        Muted {
+            id: ClientId(6),
+            muted: false,
+            room: DEFAULT_ROOM,
+        },
+        r#"{"muted":{"id":6,"muted":false}}"#,
+    );
+    golden(Kicked { room: DEFAULT_ROOM }, r#"{"kicked":{}}"#);
+    golden(
+        Error {
+            message: "room is locked".to_string(),
+            room: DEFAULT_ROOM,
+        },
+        r#"{"error":{"message":"room is locked"}}"#,
+    );
+    golden(
+        Directory {
+            rooms: vec![DirectoryEntry {
+                name: "lobby".to_string(),
+                description: "".to_string(),
+                secret: "hunter2".to_string(),
+                hash: "abc".to_string(),
+                user_count: 1,
+            }],
+        },
+        r#"{"directory":{"rooms":[{"name":"lobby","description":"","secret":"hunter2","hash":"abc","user_count":1}]}}"#,
+    );
+}
+
+#[test]
+fn serverbound() {
+    use ServerboundPacket::*;
+    golden(
+        Join {
+            hash: Some("abc".to_string()),
+            moderator: None,
+            room: DEFAULT_ROOM,
+        },
+        r#"{"join":{"hash":"abc","moderator":null}}"#,
+    );
+    golden(Ping, r#""ping""#);
+    golden(
+        Relay {
+            recipient: Some(ClientId(7)),
+            message: "AAAA".to_string(),
+            room: DEFAULT_ROOM,
+        },
+        r#"{"relay":{"recipient":7,"message":"AAAA"}}"#,
+    );
+    golden(
+        WatchRooms(vec!["abc".to_string()]),
+        r#"{"watch_rooms":["abc"]}"#,
+    );
+    golden(
+        Kick {
+            id: ClientId(8),
+            room: DEFAULT_ROOM,
+        },
+        r#"{"kick":{"id":8}}"#,
+    );
+    golden(
+        Lock {
+            locked: false,
+            room: DEFAULT_ROOM,
+        },
+        r#"{"lock":{"locked":false}}"#,
+    );
+    golden(
+        Mute {
+            id: ClientId(9),
+            muted: true,
+            room: DEFAULT_ROOM,
+        },
+        r#"{"mute":{"id":9,"muted":true}}"#,
+    );
+    golden(
+        Publish {
+            name: "lobby".to_string(),
+            description: "hi".to_string(),
+            secret: "hunter2".to_string(),
+            room: DEFAULT_ROOM,
+        },
+        r#"{"publish":{"name":"lobby","description":"hi","secret":"hunter2"}}"#,
+    );
+    golden(Unpublish { room: DEFAULT_ROOM }, r#"{"unpublish":{}}"#);
+    golden(ListDirectory, r#""list_directory""#);
+}
+
+/// Packets concerning rooms other than the default one carry their tag.
+#[test]
+fn tagged() {
+    golden(
+        ClientboundPacket::ClientJoin {
+            id: ClientId(2),
+            room: 3,
+        },
+        r#"{"client_join":{"id":2,"room":3}}"#,
+    );
+    golden(
+        ClientboundPacket::Message {
+            sender: ClientId(3),
+            message: "AAAA".to_string(),
+            room: 1,
+        },
+        r#"{"message":{"sender":3,"message":"AAAA","room":1}}"#,
+    );
+    golden(
+        ClientboundPacket::Kicked { room: 2 },
+        r#"{"kicked":{"room":2}}"#,
+    );
+    golden(
+        ServerboundPacket::Join {
+            hash: Some("abc".to_string()),
+            moderator: None,
+            room: 4,
+        },
+        r#"{"join":{"hash":"abc","moderator":null,"room":4}}"#,
+    );
+    golden(
+        ServerboundPacket::Unpublish { room: 4 },
+        r#"{"unpublish":{"room":4}}"#,
+    );
+}
+
+#[test]
+fn serverbound_as_sent_by_web_client() {
+    let parse = |s| serde_json::from_str::<ServerboundPacket>(s).unwrap();
+    assert_eq!(parse(r#"{"ping":null}"#), ServerboundPacket::Ping);
+    assert_eq!(
+        parse(r#"{"join":{"hash":"abc"}}"#),
+        ServerboundPacket::Join {
+            hash: Some("abc".to_string()),
+            moderator: None,
+            room: DEFAULT_ROOM,
+        }
+    );
+    assert_eq!(
+        parse(r#"{"relay":{"message":"AAAA"}}"#),
+        ServerboundPacket::Relay {
+            recipient: None,
+            message: "AAAA".to_string(),
+            room: DEFAULT_ROOM,
+        }
+    );
+}
+
+#[test]
+fn relay() {
+    use RelayMessage::*;
+    golden(
+        RelayMessageWrapper {
+            sender: ClientId(1),
+            recipient: None,
+            counter: None,
+            timestamp: None,
+            inner: Chat(ChatMessage::Text("hello".to_string())),
+        },
+        r#"{"sender":1,"inner":{"chat":{"text":"hello"}}}"#,
+    );
+    golden(
+        RelayMessageWrapper {
+            sender: ClientId(1),
+            recipient: Some(ClientId(2)),
+            counter: None,
+            timestamp: None,
+            inner: Request {
+                id: "file".to_string(),
+            },
+        },
+        r#"{"sender":1,"recipient":2,"inner":{"request":{"id":"file"}}}"#,
+    );
+    golden(
+        RelayMessageWrapper {
+            sender: ClientId(1),
+            recipient: Some(ClientId(2)),
+            counter: Some(7),
+            timestamp: Some(1700000000000),
+            inner: Request {
+                id: "file".to_string(),
+            },
+        },
+        r#"{"sender":1,"recipient":2,"counter":7,"timestamp":1700000000000,"inner":{"request":{"id":"file"}}}"#,
+    );
+    golden(
+        Chat(ChatMessage::Image("data:".to_string())),
+        r#"{"chat":{"image":"data:"}}"#,
+    );
+    golden(
+        Identify {
+            username: "alice".to_string(),
+            private_relay: true,
+            rekey: true,
+            identity: None,
+            signature: None,
+        },
+        r#"{"identify":{"username":"alice","private_relay":true,"rekey":true}}"#,
+    );
+    assert_eq!(
+        serde_json::from_str::<RelayMessage>(r#"{"identify":{"username":"alice"}}"#).unwrap(),
+        Identify {
+            username: "alice".to_string(),
+            private_relay: false,
+            rekey: false,
+            identity: None,
+            signature: None,
+        }
+    );
+    golden(
+        Identify {
+            username: "alice".to_string(),
+            private_relay: false,
+            rekey: false,
+            identity: Some("AAAA".to_string()),
+            signature: Some("BBBB".to_string()),
+        },
+        r#"{"identify":{"username":"alice","private_relay":false,"rekey":false,"identity":"AAAA","signature":"BBBB"}}"#,
+    );
+    golden(
+        KeyExchange {
+            public_key: "AAAA".to_string(),
+        },
+        r#"{"key_exchange":{"public_key":"AAAA"}}"#,
+    );
+    golden(
+        VerifyStart {
+            commitment: "AAAA".to_string(),
+        },
+        r#"{"verify_start":{"commitment":"AAAA"}}"#,
+    );
+    golden(
+        VerifyKey {
+            public_key: "AAAA".to_string(),
+        },
+        r#"{"verify_key":{"public_key":"AAAA"}}"#,
+    );
+    golden(
+        VerifyDone { confirmed: true },
+        r#"{"verify_done":{"confirmed":true}}"#,
+    );
+    golden(
+        Rekey {
+            epoch: 1,
+            author: ClientId(2),
+            key: "AAAA".to_string(),
+        },
+        r#"{"rekey":{"epoch":1,"author":2,"key":"AAAA"}}"#,
+    );
+    golden(
+        Provide(ProvideInfo {
+            id: "cam".to_string(),
+            kind: "track".to_string(),
+            track_kind: Some(TrackKind::Video),
+            label: Some("Camera".to_string()),
+            size: None,
+        }),
+        r#"{"provide":{"id":"cam","kind":"track","track_kind":"video","label":"Camera","size":null}}"#,
+    );
+    golden(
+        ProvideStop {
+            id: "cam".to_string(),
+        },
+        r#"{"provide_stop":{"id":"cam"}}"#,
+    );
+    golden(
+        RequestStop {
+            id: "cam".to_string(),
+        },
+        r#"{"request_stop":{"id":"cam"}}"#,
+    );
+    golden(Offer("v=0".to_string()), r#"{"offer":"v=0"}"#);
+    golden(Answer("v=0".to_string()), r#"{"answer":"v=0"}"#);
+    golden(
+        IceCandidate(IceCandidateInit {
+            candidate: "candidate:1".to_string(),
+            sdp_mid: Some("0".to_string()),
+            sdp_mline_index: Some(0),
+            username_fragment: None,
+        }),
+        r#"{"ice_candidate":{"candidate":"candidate:1","sdpMid":"0","sdpMLineIndex":0,"usernameFragment":null}}"#,
+    );
+    golden(
+        Preview {
+            id: "cam".to_string(),
+            data: "data:".to_string(),
+        },
+        r#"{"preview":{"id":"cam","data":"data:"}}"#,
+    );
+}
+
+#[test]
+fn typescript_declarations_are_up_to_date() {
+    assert!(
+        keks_protocol::typescript_declarations() == include_str!("../packets.d.ts"),
+        "packets.d.ts is outdated; run `cargo run -p keks-protocol > common/packets.d.ts`"
+    );
+}
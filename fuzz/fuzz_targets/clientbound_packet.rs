@@ -0,0 +1,15 @@
+/*
+    This file is part of keks-meet (https://codeberg.org/metamuffin/keks-meet)
+    which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
+    Copyright (C) 2023 metamuffin <metamuffin.org>
+*/
+//! What native clients parse from every websocket message.
+#![no_main]
+use keks_protocol::ClientboundPacket;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &str| {
+    if let Ok(packet) = serde_json::from_str::<ClientboundPacket>(data) {
+        serde_json::to_string(&packet).unwrap();
+    }
+});
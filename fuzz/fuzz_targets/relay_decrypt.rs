@@ -0,0 +1,17 @@
+/*
+    This file is part of keks-meet (https://codeberg.org/metamuffin/keks-meet)
+    which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
+    Copyright (C) 2023 metamuffin <metamuffin.org>
+*/
+//! Relay messages as sent by arbitrary peers in the room.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use libkeks::{crypto::Key, instance::decode_relay};
+use std::sync::LazyLock;
+
+static KEY: LazyLock<Key> = LazyLock::new(|| Key::derive("fuzz"));
+
+fuzz_target!(|data: &str| {
+    let _ = KEY.decrypt(data);
+    let _ = decode_relay(&KEY, data);
+});
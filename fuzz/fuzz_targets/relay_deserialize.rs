@@ -0,0 +1,19 @@
+/*
+    This file is part of keks-meet (https://codeberg.org/metamuffin/keks-meet)
+    which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
+    Copyright (C) 2023 metamuffin <metamuffin.org>
+*/
+//! Relay messages from peers that know the room secret, so that the input
+//! reaches deserialization instead of failing authentication.
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use libkeks::{crypto::Key, instance::decode_relay};
+use std::sync::LazyLock;
+
+static KEY: LazyLock<Key> = LazyLock::new(|| Key::derive("fuzz"));
+
+fuzz_target!(|data: &str| {
+    if let Ok(wrapper) = decode_relay(&KEY, &KEY.encrypt(data)) {
+        serde_json::to_string(&wrapper).unwrap();
+    }
+});
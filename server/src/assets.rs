@@ -102,6 +102,13 @@ impl grass::Fs for GrassFs {
     }
 }
 
+pub fn start_html() -> String {
+    #[cfg(debug_assertions)]
+    return std::fs::read_to_string("../client-web/public/start.html").unwrap();
+    #[cfg(not(debug_assertions))]
+    return include_str!("../../client-web/public/start.html").to_string();
+}
+
 static CSS_BUNDLE: LazyLock<String> = LazyLock::new(css_bundle);
 
 pub fn css() -> String {
@@ -0,0 +1,106 @@
+/*
+    This file is part of keks-meet (https://codeberg.org/metamuffin/keks-meet)
+    which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
+    Copyright (C) 2023 metamuffin <metamuffin.org>
+*/
+use crate::{assets::start_html, config::BrandingConfig};
+use log::error;
+use std::path::PathBuf;
+use warp::{
+    filters::BoxedFilter,
+    reply::{self, Response},
+    Filter, Reply,
+};
+
+/// Routes for operator-provided files. Everything here takes precedence over
+/// the embedded client files and rejects if nothing is configured.
+pub fn routes(config: BrandingConfig) -> BoxedFilter<(Response,)> {
+    let start: _ = {
+        let config = config.clone();
+        warp::path!().and_then(move || {
+            let page = start_page(&config);
+            async move {
+                page.map(|p| reply::html(p).into_response())
+                    .ok_or_else(warp::reject::not_found)
+            }
+        })
+    };
+    let favicon: _ = warp::path!("favicon.ico").and(file_override(config.favicon));
+    let logo: _ = warp::path!("branding" / "logo").and(file_override(config.logo));
+    let imprint: _ = warp::path!("imprint").and(file_override(config.imprint));
+    let privacy: _ = warp::path!("privacy").and(file_override(config.privacy));
+
+    start
+        .or(favicon)
+        .unify()
+        .or(logo)
+        .unify()
+        .or(imprint)
+        .unify()
+        .or(privacy)
+        .unify()
+        .boxed()
+}
+
+fn file_override(path: Option<PathBuf>) -> BoxedFilter<(Response,)> {
+    match path {
+        Some(path) => warp::fs::file(path).map(Reply::into_response).boxed(),
+        None => warp::any()
+            .and_then(|| async { Err::<Response, _>(warp::reject::not_found()) })
+            .boxed(),
+    }
+}
+
+fn start_page(config: &BrandingConfig) -> Option<String> {
+    if config.instance_name.is_none()
+        && config.logo.is_none()
+        && config.start_text.is_none()
+        && config.imprint.is_none()
+        && config.privacy.is_none()
+    {
+        return None;
+    }
+    let mut page = start_html();
+    if let Some(name) = &config.instance_name {
+        let name = escape_html(name);
+        page = page
+            .replace(
+                "<title>keks-meet</title>",
+                &format!("<title>{name}</title>"),
+            )
+            .replace("<h1>keks-meet</h1>", &format!("<h1>{name}</h1>"));
+    }
+    if config.logo.is_some() {
+        page = page.replace(
+            "<!-- branding:logo -->",
+            r#"<img class="logo" src="/branding/logo" alt="" />"#,
+        );
+    }
+    if let Some(path) = &config.start_text {
+        match std::fs::read_to_string(path) {
+            Ok(text) => page = page.replace("<!-- branding:start_text -->", &text),
+            Err(e) => error!("cannot read start text from {path:?}: {e}"),
+        }
+    }
+    let mut legal = String::new();
+    if config.imprint.is_some() {
+        legal += r#"<a href="/imprint">Imprint</a>"#;
+    }
+    if config.privacy.is_some() {
+        legal += r#"<a href="/privacy">Privacy</a>"#;
+    }
+    if !legal.is_empty() {
+        page = page.replace(
+            "<!-- branding:legal -->",
+            &format!(r#"<footer class="legal">{legal}</footer>"#),
+        );
+    }
+    Some(page)
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,534 @@
+/*
+    This file is part of keks-meet (https://codeberg.org/metamuffin/keks-meet)
+    which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
+    Copyright (C) 2023 metamuffin <metamuffin.org>
+*/
+//! Several servers can form a cluster. Every room is owned by exactly one node
+//! and all packets concerning it are processed there. Other nodes forward the
+//! packets of their clients to the owner and deliver its replies.
+
+use crate::{
+    config::{ClusterConfig, FeaturesConfig},
+    logic::{Client, ClientState, Clients, RoomKey, State},
+    protocol::{ClientboundPacket, RoomTag, ServerboundPacket},
+};
+use aes_gcm::{
+    aead::{Aead, Nonce},
+    Aes256Gcm, KeyInit,
+};
+use hmac::{Hmac, Mac};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream,
+    },
+    sync::{broadcast, mpsc, RwLock},
+};
+
+/// Client ids are prefixed with the node index so they are unique in the cluster.
+const CLIENT_ID_BITS: u32 = 40;
+/// for connecting to a node and for the handshake each
+const LINK_TIMEOUT: Duration = Duration::from_secs(5);
+/// Handshake messages are short; this is all an unauthenticated peer can
+/// make us buffer.
+const MAX_PLAIN_LINE: usize = 4096;
+/// The largest websocket message (64 MiB) in hex, with room for the envelope.
+const MAX_LINE: usize = 256 << 20;
+
+pub struct Cluster {
+    pub node: usize,
+    nodes: Vec<SocketAddr>,
+    listen: SocketAddr,
+    secret: String,
+    /// features of every virtual host; forwarded packets are handled with these
+    features: HashMap<String, FeaturesConfig>,
+    links: RwLock<HashMap<usize, mpsc::Sender<ClusterMessage>>>,
+    lost: broadcast::Sender<Arc<LostNode>>,
+    clients: Arc<Clients>,
+}
+
+/// A link to a room owner broke, so it forgot about our clients.
+#[derive(Debug)]
+pub struct LostNode {
+    pub node: usize,
+    /// members of the rooms it delivered for, by client and room tag
+    pub members: HashMap<(Client, RoomTag), HashSet<Client>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ClusterMessage {
+    Hello {
+        node: usize,
+        nonce: String,
+    },
+    Auth {
+        mac: String,
+    },
+    /// forwarded to the room owner
+    Packet {
+        client: Client,
+        vhost: String,
+        packet: ServerboundPacket,
+    },
+    Disconnect {
+        client: Client,
+    },
+    /// sent back by the room owner
+    Deliver {
+        client: Client,
+        packet: ClientboundPacket,
+    },
+}
+
+impl Cluster {
+    pub fn new(
+        config: ClusterConfig,
+        features: HashMap<String, FeaturesConfig>,
+        clients: Arc<Clients>,
+    ) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            config.node < config.nodes.len(),
+            "cluster node index out of range"
+        );
+        // every node only knows the rooms it owns
+        anyhow::ensure!(
+            !features.values().any(|f| f.room_directory),
+            "the room directory can not be used in a cluster"
+        );
+        Ok(Self {
+            listen: config.listen.unwrap_or(config.nodes[config.node]),
+            node: config.node,
+            nodes: config.nodes,
+            secret: config.secret,
+            features,
+            links: Default::default(),
+            lost: broadcast::channel(16).0,
+            clients,
+        })
+    }
+
+    /// Notifies of links to room owners that broke.
+    pub fn subscribe_lost(&self) -> broadcast::Receiver<Arc<LostNode>> {
+        self.lost.subscribe()
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn client_id(&self, n: u64) -> u64 {
+        ((self.node as u64) << CLIENT_ID_BITS) | n
+    }
+
+    /// Node that owns a room. Must agree on all nodes, so this can not use
+    /// the randomly seeded std hasher.
+    pub fn owner(&self, key: &RoomKey) -> usize {
+        // FNV-1a
+        let mut h: u64 = 0xcbf29ce484222325;
+        for b in key.vhost.bytes().chain([0]).chain(key.hash.bytes()) {
+            h ^= b as u64;
+            h = h.wrapping_mul(0x100000001b3);
+        }
+        (h % self.nodes.len() as u64) as usize
+    }
+
+    pub async fn forward(
+        &self,
+        node: usize,
+        client: Client,
+        cstate: &ClientState,
+        packet: ServerboundPacket,
+    ) {
+        self.send(
+            node,
+            ClusterMessage::Packet {
+                client,
+                vhost: cstate.vhost.clone(),
+                packet,
+            },
+        )
+        .await
+    }
+    pub async fn disconnect(&self, node: usize, client: Client) {
+        self.send(node, ClusterMessage::Disconnect { client }).await
+    }
+
+    async fn send(&self, node: usize, message: ClusterMessage) {
+        let link = match self.link(node).await {
+            Ok(link) => link,
+            Err(e) => {
+                error!("cannot reach cluster node {node}: {e}");
+                return;
+            }
+        };
+        if link.send(message).await.is_err() {
+            warn!("link to cluster node {node} closed");
+            self.links.write().await.remove(&node);
+        }
+    }
+
+    async fn link(&self, node: usize) -> anyhow::Result<mpsc::Sender<ClusterMessage>> {
+        if let Some(link) = self.open_link(node).await {
+            return Ok(link);
+        }
+        // connected without holding the lock, so a slow node only delays
+        // packets for its own rooms
+        let addr = self.nodes[node];
+        info!("connecting to cluster node {node} at {addr}");
+        let stream = tokio::time::timeout(LINK_TIMEOUT, TcpStream::connect(addr)).await??;
+        stream.set_nodelay(true)?;
+        let (rx, tx) = stream.into_split();
+        let (mut rx, mut tx) = (BufReader::new(rx), tx);
+        let (remote, mut seal, mut open) =
+            tokio::time::timeout(LINK_TIMEOUT, self.handshake(&mut rx, &mut tx, true))
+                .await
+                .map_err(|_| anyhow::anyhow!("handshake timed out"))??;
+        if remote != node {
+            anyhow::bail!("node at {addr} claims to be node {remote}");
+        }
+
+        let mut links = self.links.write().await;
+        if let Some(link) = links.get(&node).filter(|link| !link.is_closed()) {
+            // connected concurrently, this connection is dropped unused
+            return Ok(link.clone());
+        }
+        let (link_tx, link_rx) = mpsc::channel(256);
+        let writer = tokio::spawn(async move { write_loop(tx, &mut seal, link_rx).await });
+        let clients = self.clients.clone();
+        let lost = self.lost.clone();
+        tokio::spawn(async move {
+            // the owner only ever sends deliveries on this link
+            let mut members = HashMap::new();
+            let mut line = String::new();
+            while let Ok(Some(message)) = read_message(&mut rx, &mut open, &mut line).await {
+                match message {
+                    ClusterMessage::Deliver { client, packet } => {
+                        track_members(&mut members, client, &packet);
+                        clients.send(client, packet).await
+                    }
+                    m => warn!("unexpected message from cluster node {node}: {m:?}"),
+                }
+            }
+            warn!("link to cluster node {node} lost");
+            // closes the link, so the next packet for this node opens a new one
+            writer.abort();
+            let _ = lost.send(Arc::new(LostNode { node, members }));
+        });
+        links.insert(node, link_tx.clone());
+        Ok(link_tx)
+    }
+    async fn open_link(&self, node: usize) -> Option<mpsc::Sender<ClusterMessage>> {
+        let links = self.links.read().await;
+        links.get(&node).filter(|link| !link.is_closed()).cloned()
+    }
+
+    /// Both sides prove knowledge of the cluster secret by authenticating the
+    /// transcript of nonces and node indices under their role. The responder
+    /// only answers once the initiator is authenticated, so it can not be used
+    /// as an oracle. Returns the index of the remote node and the keys for
+    /// sending and receiving.
+    async fn handshake(
+        &self,
+        rx: &mut BufReader<OwnedReadHalf>,
+        tx: &mut OwnedWriteHalf,
+        initiator: bool,
+    ) -> anyhow::Result<(usize, Seal, Seal)> {
+        let nonce = hex::encode(rand::random::<[u8; 32]>());
+        write_plain(
+            tx,
+            &ClusterMessage::Hello {
+                node: self.node,
+                nonce: nonce.clone(),
+            },
+        )
+        .await?;
+        let mut line = String::new();
+        let Some(ClusterMessage::Hello {
+            node: remote,
+            nonce: remote_nonce,
+        }) = read_plain(rx, &mut line).await?
+        else {
+            anyhow::bail!("expected hello");
+        };
+        if remote >= self.nodes.len() || remote == self.node {
+            anyhow::bail!("invalid node index {remote}");
+        }
+        if hex::decode(&remote_nonce).map_or(true, |n| n.len() != 32) {
+            anyhow::bail!("invalid nonce");
+        }
+        let transcript = if initiator {
+            format!("{} {remote} {nonce} {remote_nonce}", self.node)
+        } else {
+            format!("{remote} {} {remote_nonce} {nonce}", self.node)
+        };
+        let (ours, theirs) = match initiator {
+            true => ("initiator", "responder"),
+            false => ("responder", "initiator"),
+        };
+
+        let auth = ClusterMessage::Auth {
+            mac: hex::encode(self.mac(ours, &transcript).finalize().into_bytes()),
+        };
+        if initiator {
+            write_plain(tx, &auth).await?;
+        }
+        let Some(ClusterMessage::Auth { mac }) = read_plain(rx, &mut line).await? else {
+            anyhow::bail!("expected auth");
+        };
+        self.mac(theirs, &transcript)
+            .verify_slice(&hex::decode(mac)?)
+            .map_err(|_| anyhow::anyhow!("node {remote} failed authentication"))?;
+        if !initiator {
+            write_plain(tx, &auth).await?;
+        }
+
+        let key = |role| self.mac(&format!("{role} key"), &transcript).finalize();
+        let (seal, open) = (
+            Seal::new(&key(ours).into_bytes()),
+            Seal::new(&key(theirs).into_bytes()),
+        );
+        Ok((remote, seal, open))
+    }
+
+    fn mac(&self, label: &str, transcript: &str) -> Hmac<Sha256> {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(self.secret.as_bytes()).unwrap();
+        mac.update(format!("keks-meet cluster {label} {transcript}").as_bytes());
+        mac
+    }
+}
+
+/// One direction of an authenticated link. Every message is encrypted under a
+/// counting nonce, so it can not be read, altered, dropped or replayed
+/// unnoticed.
+struct Seal {
+    cipher: Aes256Gcm,
+    counter: u64,
+}
+
+impl Seal {
+    fn new(key: &[u8]) -> Self {
+        Self {
+            cipher: Aes256Gcm::new_from_slice(key).unwrap(),
+            counter: 0,
+        }
+    }
+    fn nonce(&mut self) -> Nonce<Aes256Gcm> {
+        let mut nonce = [0; 12];
+        nonce[4..].copy_from_slice(&self.counter.to_be_bytes());
+        self.counter += 1;
+        nonce.into()
+    }
+}
+
+/// Follows the membership of the rooms a link delivers for.
+fn track_members(
+    members: &mut HashMap<(Client, RoomTag), HashSet<Client>>,
+    client: Client,
+    packet: &ClientboundPacket,
+) {
+    match *packet {
+        ClientboundPacket::ClientJoin { id, room } => {
+            members.entry((client, room)).or_default().insert(id);
+        }
+        ClientboundPacket::ClientLeave { id, room } if id == client => {
+            members.remove(&(client, room));
+        }
+        ClientboundPacket::ClientLeave { id, room } => {
+            if let Some(m) = members.get_mut(&(client, room)) {
+                m.remove(&id);
+            }
+        }
+        ClientboundPacket::Kicked { room } => {
+            members.remove(&(client, room));
+        }
+        _ => (),
+    }
+}
+
+/// Accepts links from other nodes and processes the packets they forward.
+pub async fn listen(state: Arc<State>) {
+    let cluster = state.cluster.as_ref().expect("clustering not configured");
+    let listener = TcpListener::bind(cluster.listen)
+        .await
+        .expect("cannot bind cluster listener");
+    info!(
+        "cluster node {} listening on {}",
+        cluster.node, cluster.listen
+    );
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(s) => s,
+            Err(e) => {
+                error!("cluster accept error: {e}");
+                continue;
+            }
+        };
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_link(state, stream).await {
+                warn!("cluster link from {addr} failed: {e}");
+            }
+        });
+    }
+}
+
+async fn serve_link(state: Arc<State>, stream: TcpStream) -> anyhow::Result<()> {
+    let cluster = state.cluster.as_ref().unwrap();
+    stream.set_nodelay(true)?;
+    let (rx, tx) = stream.into_split();
+    let (mut rx, mut tx) = (BufReader::new(rx), tx);
+    let (node, mut seal, mut open) =
+        tokio::time::timeout(LINK_TIMEOUT, cluster.handshake(&mut rx, &mut tx, false))
+            .await
+            .map_err(|_| anyhow::anyhow!("handshake timed out"))??;
+    info!("cluster node {node} connected");
+
+    let (link_tx, link_rx) = mpsc::channel(256);
+    tokio::spawn(async move { write_loop(tx, &mut seal, link_rx).await });
+
+    let mut clients = HashMap::<Client, ClientState>::new();
+    let mut line = String::new();
+    let result = async {
+        while let Some(message) = read_message(&mut rx, &mut open, &mut line).await? {
+            match message {
+                ClusterMessage::Packet {
+                    client,
+                    vhost,
+                    packet,
+                } => {
+                    debug!("<-  ({node}) {packet:?}");
+                    if client.0 >> CLIENT_ID_BITS != node as u64 {
+                        warn!("cluster node {node} forwarded foreign client {client:?}");
+                        continue;
+                    }
+                    let Some(features) = cluster.features.get(&vhost) else {
+                        warn!("cluster node {node} forwarded unknown vhost {vhost:?}");
+                        continue;
+                    };
+                    let cstate = match clients.entry(client) {
+                        std::collections::hash_map::Entry::Occupied(e) => e.into_mut(),
+                        std::collections::hash_map::Entry::Vacant(e) => {
+                            let (tx, mut rx) = mpsc::channel::<ClientboundPacket>(64);
+                            let link_tx = link_tx.clone();
+                            tokio::spawn(async move {
+                                while let Some(packet) = rx.recv().await {
+                                    let _ = link_tx
+                                        .send(ClusterMessage::Deliver { client, packet })
+                                        .await;
+                                }
+                            });
+                            state.clients.register(client, tx).await;
+                            e.insert(ClientState::new(vhost, features.clone()))
+                        }
+                    };
+                    state.on_recv(client, cstate, packet).await;
+                }
+                ClusterMessage::Disconnect { client } => {
+                    if let Some(mut cstate) = clients.remove(&client) {
+                        state.disconnect(client, &mut cstate).await;
+                        state.clients.unregister(client).await;
+                    }
+                }
+                m => warn!("unexpected message from cluster node {node}: {m:?}"),
+            }
+        }
+        anyhow::Ok(())
+    }
+    .await;
+
+    info!("cluster node {node} disconnected");
+    for (client, mut cstate) in clients {
+        state.disconnect(client, &mut cstate).await;
+        state.clients.unregister(client).await;
+    }
+    result
+}
+
+async fn write_loop(
+    mut tx: OwnedWriteHalf,
+    seal: &mut Seal,
+    mut rx: mpsc::Receiver<ClusterMessage>,
+) {
+    while let Some(message) = rx.recv().await {
+        if let Err(e) = write_message(&mut tx, seal, &message).await {
+            warn!("cluster write error: {e}");
+            break;
+        }
+    }
+}
+
+async fn write_message(
+    tx: &mut OwnedWriteHalf,
+    seal: &mut Seal,
+    message: &ClusterMessage,
+) -> anyhow::Result<()> {
+    let nonce = seal.nonce();
+    let ciphertext = seal
+        .cipher
+        .encrypt(&nonce, serde_json::to_vec(message)?.as_slice())
+        .map_err(|_| anyhow::anyhow!("encryption failed"))?;
+    let mut buf = hex::encode(ciphertext).into_bytes();
+    buf.push(b'\n');
+    tx.write_all(&buf).await?;
+    Ok(())
+}
+
+async fn read_message(
+    rx: &mut BufReader<OwnedReadHalf>,
+    open: &mut Seal,
+    line: &mut String,
+) -> anyhow::Result<Option<ClusterMessage>> {
+    if !read_line(rx, line, MAX_LINE).await? {
+        return Ok(None);
+    }
+    let nonce = open.nonce();
+    let plaintext = open
+        .cipher
+        .decrypt(&nonce, hex::decode(line.trim_end())?.as_slice())
+        .map_err(|_| anyhow::anyhow!("message failed authentication"))?;
+    Ok(Some(serde_json::from_slice(&plaintext)?))
+}
+
+/// Only used for the handshake, which establishes the keys.
+async fn write_plain(tx: &mut OwnedWriteHalf, message: &ClusterMessage) -> anyhow::Result<()> {
+    let mut buf = serde_json::to_vec(message)?;
+    buf.push(b'\n');
+    tx.write_all(&buf).await?;
+    Ok(())
+}
+
+async fn read_plain(
+    rx: &mut BufReader<OwnedReadHalf>,
+    line: &mut String,
+) -> anyhow::Result<Option<ClusterMessage>> {
+    if !read_line(rx, line, MAX_PLAIN_LINE).await? {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_str(line)?))
+}
+
+/// Reads a line of at most `max` bytes including the newline, returning false
+/// at the end of the stream. Longer lines are an error.
+async fn read_line(
+    rx: &mut BufReader<OwnedReadHalf>,
+    line: &mut String,
+    max: usize,
+) -> anyhow::Result<bool> {
+    line.clear();
+    let n = (&mut *rx).take(max as u64 + 1).read_line(line).await?;
+    if n > max {
+        anyhow::bail!("line longer than {max} bytes");
+    }
+    Ok(n > 0)
+}
@@ -4,33 +4,184 @@
     Copyright (C) 2023 metamuffin <metamuffin.org>
 */
 use serde::{Deserialize, Serialize};
-use std::net::SocketAddr;
+use std::{collections::HashMap, fmt, net::SocketAddr, path::PathBuf, str::FromStr};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
+    #[serde(skip_serializing)]
     pub server: ServerConfig,
     pub features: FeaturesConfig,
+    #[serde(default)]
     pub webrtc: WebrtcConfig,
     pub appearance: AppearanceConfig,
+    #[serde(default)]
+    pub branding: BrandingConfig,
+    #[serde(default, skip_serializing)]
+    pub headers: HeadersConfig,
+    #[serde(default, skip_serializing)]
+    pub vhosts: HashMap<String, VhostConfig>,
+    #[serde(default, skip_serializing)]
+    pub cluster: Option<ClusterConfig>,
+}
+
+/// Every node of a cluster must list the same nodes in the same order.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClusterConfig {
+    /// index of this node in `nodes`
+    pub node: usize,
+    pub nodes: Vec<SocketAddr>,
+    /// defaults to the address of this node in `nodes`
+    pub listen: Option<SocketAddr>,
+    /// shared by all nodes to authenticate links
+    pub secret: String,
+}
+
+/// Per-domain overrides selected by the `Host` header. Missing sections fall
+/// back to the top-level ones.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VhostConfig {
+    pub features: Option<FeaturesConfig>,
+    pub webrtc: Option<WebrtcConfig>,
+    pub appearance: Option<AppearanceConfig>,
+}
+
+impl Config {
+    /// Effective configuration for a virtual host. Unknown names get the defaults.
+    pub fn vhost(&self, name: &str) -> Config {
+        let mut config = self.clone();
+        if let Some(vhost) = self.vhosts.get(name) {
+            if let Some(features) = &vhost.features {
+                config.features = features.clone();
+            }
+            if let Some(webrtc) = &vhost.webrtc {
+                config.webrtc = webrtc.clone();
+            }
+            if let Some(appearance) = &vhost.appearance {
+                config.appearance = appearance.clone();
+            }
+        }
+        config
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServerConfig {
-    pub bind: SocketAddr,
+    #[serde(with = "one_or_many")]
+    pub bind: Vec<BindAddr>,
+    /// File mode for unix sockets, e.g. `0o660`
+    pub unix_socket_mode: Option<u32>,
+}
+
+/// Either a TCP socket address or `unix:<path>`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum BindAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for BindAddr {
+    type Err = std::net::AddrParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some(path) => Ok(Self::Unix(path.into())),
+            None => Ok(Self::Tcp(s.parse()?)),
+        }
+    }
+}
+impl TryFrom<String> for BindAddr {
+    type Error = std::net::AddrParseError;
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+impl From<BindAddr> for String {
+    fn from(addr: BindAddr) -> Self {
+        addr.to_string()
+    }
+}
+impl fmt::Display for BindAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BindAddr::Tcp(addr) => write!(f, "{addr}"),
+            BindAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+mod one_or_many {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+
+    pub fn serialize<T: Serialize, S: Serializer>(v: &[T], s: S) -> Result<S::Ok, S::Error> {
+        v.serialize(s)
+    }
+    pub fn deserialize<'de, T: Deserialize<'de>, D: Deserializer<'de>>(
+        d: D,
+    ) -> Result<Vec<T>, D::Error> {
+        Ok(match OneOrMany::deserialize(d)? {
+            OneOrMany::One(x) => vec![x],
+            OneOrMany::Many(x) => x,
+        })
+    }
 }
 
 #[rustfmt::skip]
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FeaturesConfig {
     #[serde(default)] pub room_watches: bool,
+    #[serde(default)] pub room_directory: bool,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(from = "WebrtcConfigFile")]
 pub struct WebrtcConfig {
-    pub stun: String,
-    pub turn: Option<String>,
-    pub turn_user: Option<String>,
-    pub turn_cred: Option<String>,
+    pub ice_servers: Vec<IceServerConfig>,
+}
+
+/// Also accepts the `stun`, `turn`, `turn_user` and `turn_cred` keys of older
+/// configurations, so they keep handing out their ICE servers.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct WebrtcConfigFile {
+    #[serde(default)]
+    ice_servers: Vec<IceServerConfig>,
+    stun: Option<String>,
+    turn: Option<String>,
+    turn_user: Option<String>,
+    turn_cred: Option<String>,
+}
+
+impl From<WebrtcConfigFile> for WebrtcConfig {
+    fn from(c: WebrtcConfigFile) -> Self {
+        let mut ice_servers = c.ice_servers;
+        // the web client used to build a single server from these
+        let urls = c.stun.into_iter().chain(c.turn).collect::<Vec<_>>();
+        if !urls.is_empty() {
+            ice_servers.push(IceServerConfig {
+                urls,
+                username: c.turn_user,
+                credential: c.turn_cred,
+            });
+        }
+        Self { ice_servers }
+    }
+}
+
+/// Same shape as `RTCIceServer` so clients can use it as-is.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IceServerConfig {
+    pub urls: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub credential: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -42,3 +193,54 @@ pub struct AppearanceConfig {
     pub background_dark: String,
     pub background_light: String,
 }
+
+/// Paths are only read by the server and never sent to clients.
+#[rustfmt::skip]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BrandingConfig {
+    pub instance_name: Option<String>,
+    #[serde(skip_serializing)] pub logo: Option<PathBuf>,
+    #[serde(skip_serializing)] pub favicon: Option<PathBuf>,
+    #[serde(skip_serializing)] pub start_text: Option<PathBuf>,
+    #[serde(skip_serializing)] pub imprint: Option<PathBuf>,
+    #[serde(skip_serializing)] pub privacy: Option<PathBuf>,
+}
+
+/// Response headers added to every route. An empty string disables a header.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HeadersConfig {
+    pub content_security_policy: String,
+    pub strict_transport_security: String,
+    pub referrer_policy: String,
+    pub permissions_policy: String,
+    pub cross_origin_opener_policy: String,
+    pub cross_origin_embedder_policy: String,
+}
+
+impl Default for HeadersConfig {
+    fn default() -> Self {
+        Self {
+            // wasm-unsafe-eval is required for the RNNoise worklet
+            content_security_policy: "default-src 'self'; \
+                script-src 'self' 'wasm-unsafe-eval'; \
+                style-src 'self' 'unsafe-inline'; \
+                img-src 'self' data: blob:; \
+                media-src 'self' blob:; \
+                connect-src 'self' data: blob:; \
+                worker-src 'self' blob:; \
+                object-src 'none'; \
+                base-uri 'none'; \
+                form-action 'none'; \
+                frame-ancestors 'none'"
+                .to_string(),
+            strict_transport_security: "max-age=31536000".to_string(),
+            referrer_policy: "no-referrer".to_string(),
+            permissions_policy: "camera=(self), microphone=(self), display-capture=(self), \
+                geolocation=(), payment=(), usb=()"
+                .to_string(),
+            cross_origin_opener_policy: "same-origin".to_string(),
+            cross_origin_embedder_policy: "require-corp".to_string(),
+        }
+    }
+}
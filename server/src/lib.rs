@@ -0,0 +1,293 @@
+/*
+    This file is part of keks-meet (https://codeberg.org/metamuffin/keks-meet)
+    which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
+    Copyright (C) 2023 metamuffin <metamuffin.org>
+*/
+#![feature(lazy_cell)]
+#![allow(clippy::let_with_type_underscore)]
+#[cfg(feature = "web")]
+pub mod assets;
+#[cfg(feature = "web")]
+pub mod branding;
+pub mod cluster;
+pub mod config;
+pub mod idgen;
+pub mod logic;
+pub mod protocol;
+
+use crate::protocol::ClientboundPacket;
+use config::FeaturesConfig;
+use futures_util::{SinkExt, StreamExt, TryFutureExt};
+use log::{debug, error, warn};
+use logic::State;
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use warp::http::StatusCode;
+use warp::{
+    filters::BoxedFilter,
+    reply::Response,
+    ws::{Message, WebSocket},
+    Filter, Rejection, Reply,
+};
+#[cfg(feature = "web")]
+use {
+    assets::css,
+    config::{AppearanceConfig, Config, HeadersConfig},
+    std::collections::HashMap,
+    warp::http::{uri::Authority, HeaderMap, HeaderName, HeaderValue},
+    warp::reply,
+};
+
+struct Vhost {
+    name: String,
+    features: FeaturesConfig,
+    client_config_json: String,
+    #[cfg(feature = "web")]
+    client_config_css: String,
+}
+
+#[cfg(feature = "web")]
+pub fn routes(config: &Config, state: Arc<State>) -> BoxedFilter<(Response,)> {
+    let security_headers = security_headers(&config.headers);
+    let vhosts: HashMap<String, Arc<Vhost>> = std::iter::once(String::new())
+        .chain(config.vhosts.keys().cloned())
+        .map(|name| {
+            let config = config.vhost(&name);
+            let vhost = Vhost {
+                client_config_json: serde_json::to_string(&config).unwrap(),
+                client_config_css: css_overrides(&config.appearance),
+                features: config.features,
+                name: name.clone(),
+            };
+            (name.to_lowercase(), Arc::new(vhost))
+        })
+        .collect();
+
+    let state: _ = warp::any().map(move || state.clone());
+    let vhost: _ = warp::host::optional().map(move |host: Option<Authority>| {
+        host.map(|h| h.host().to_lowercase())
+            .and_then(|h| vhosts.get(&h))
+            .unwrap_or(&vhosts[""])
+            .clone()
+    });
+
+    let signaling: _ = warp::path!("signaling")
+        .and(state.clone())
+        .and(vhost.clone())
+        .and(warp::ws())
+        .map(signaling_connect);
+    let directory: _ = warp::path!("directory.json")
+        .and(state)
+        .and(vhost.clone())
+        .and_then(|state: Arc<State>, vhost: Arc<Vhost>| async move {
+            if !vhost.features.room_directory {
+                return Err(warp::reject::not_found());
+            }
+            Ok(warp::reply::json(&state.directory(&vhost.name).await))
+        });
+
+    let branding: _ = branding::routes(config.branding.clone());
+    let index: _ = warp::path!().and(s_file!("client-web/public/start.html", "text/html"));
+    let favicon: _ =
+        warp::path!("favicon.ico").and(s_file!("client-web/public/favicon.ico", "image/avif"));
+    let room: _ = warp::path!("room").and(s_file!("client-web/public/app.html", "text/html"));
+    let assets: _ = warp::path("assets").and(s_asset_dir!());
+    let sw_script: _ = warp::path("sw.js").and(s_file!(
+        "client-web/public/assets/sw.js",
+        "application/javascript"
+    ));
+    let client_config: _ =
+        warp::path!("config.json")
+            .and(vhost.clone())
+            .map(|vhost: Arc<Vhost>| {
+                warp::reply::with_header(
+                    vhost.client_config_json.clone(),
+                    "content-type",
+                    "application/json",
+                )
+            });
+    let client_config_css: _ = warp::path!("overrides.css")
+        .and(vhost)
+        .map(|vhost: Arc<Vhost>| {
+            warp::reply::with_header(vhost.client_config_css.clone(), "content-type", "text/css")
+        });
+    let css: _ = warp::path!("style.css")
+        .map(move || warp::reply::with_header(css(), "content-type", "text/css"));
+    let old_format_redirect: _ = warp::path!("room" / String).map(|rsecret| {
+        reply::with_header(
+            StatusCode::MOVED_PERMANENTLY,
+            "location",
+            format!("/room#{rsecret}?warn_redirect=true"),
+        )
+        .into_response()
+    });
+    let version: _ = warp::path!("version").map(|| env!("CARGO_PKG_VERSION"));
+
+    signaling
+        .or(directory)
+        // operators can change these files at any time, browsers have to revalidate
+        .or(branding.map(|r| warp::reply::with_header(r, "cache-control", "no-cache")))
+        .or(assets
+            .or(room)
+            .or(index)
+            .or(client_config)
+            .or(version)
+            .or(css)
+            .or(favicon)
+            .or(sw_script)
+            .or(old_format_redirect)
+            .or(client_config_css)
+            .map(|r| {
+                warp::reply::with_header(
+                    r,
+                    "cache-control",
+                    if cfg!(debug_assertions) {
+                        "no-cache"
+                    } else {
+                        "max-age=1000000"
+                    },
+                )
+            }))
+        .recover(handle_rejection)
+        .with(warp::log("keks-meet"))
+        .map(|r| warp::reply::with_header(r, "server", "keks-meet"))
+        .with(warp::reply::with::headers(security_headers))
+        .map(Reply::into_response)
+        .boxed()
+}
+
+/// Only the signaling endpoint of a single vhost, for embedding the server into
+/// native clients. `/config.json` is served empty so that clients use their
+/// own ICE servers.
+pub fn signaling_routes(state: Arc<State>, features: FeaturesConfig) -> BoxedFilter<(Response,)> {
+    let vhost = Arc::new(Vhost {
+        name: String::new(),
+        features,
+        client_config_json: "{}".to_string(),
+        #[cfg(feature = "web")]
+        client_config_css: String::new(),
+    });
+    let vhost: _ = warp::any().map(move || vhost.clone());
+    let signaling: _ = warp::path!("signaling")
+        .and(warp::any().map(move || state.clone()))
+        .and(vhost.clone())
+        .and(warp::ws())
+        .map(signaling_connect);
+    let client_config: _ = warp::path!("config.json")
+        .and(vhost)
+        .map(|vhost: Arc<Vhost>| {
+            warp::reply::with_header(
+                vhost.client_config_json.clone(),
+                "content-type",
+                "application/json",
+            )
+        });
+
+    signaling
+        .or(client_config)
+        .recover(handle_rejection)
+        .with(warp::log("keks-meet"))
+        .map(Reply::into_response)
+        .boxed()
+}
+
+async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
+    let code = if err.is_not_found() {
+        StatusCode::NOT_FOUND
+    } else if err
+        .find::<warp::filters::body::BodyDeserializeError>()
+        .is_some()
+    {
+        StatusCode::BAD_REQUEST
+    } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
+        StatusCode::METHOD_NOT_ALLOWED
+    } else {
+        error!("unhandled rejection: {:?}", err);
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+    let json = warp::reply::html(format!(
+        "<!DOCTYPE html><html><head></head><body><pre>{}</pre></body></html>",
+        code.canonical_reason().unwrap_or("!?")
+    ));
+    Ok(warp::reply::with_status(json, code))
+}
+
+fn signaling_connect(state: Arc<State>, vhost: Arc<Vhost>, ws: warp::ws::Ws) -> impl Reply {
+    async fn inner(sock: WebSocket, state: Arc<State>, vhost: Arc<Vhost>) {
+        debug!("ws upgrade");
+        let (mut user_ws_tx, user_ws_rx) = sock.split();
+        let (tx, mut rx) = mpsc::channel::<ClientboundPacket>(64);
+        tokio::task::spawn(async move {
+            while let Some(packet) = rx.recv().await {
+                debug!(" -> {packet:?}");
+                user_ws_tx
+                    .send(Message::text(serde_json::to_string(&packet).unwrap()))
+                    .unwrap_or_else(|e| {
+                        warn!("websocket send error: {}", e);
+                    })
+                    .await;
+            }
+        });
+        state
+            .connect(vhost.name.clone(), vhost.features.clone(), user_ws_rx, tx)
+            .await;
+    }
+    ws.on_upgrade(move |sock| inner(sock, state, vhost))
+}
+
+#[cfg(feature = "web")]
+fn security_headers(
+    HeadersConfig {
+        content_security_policy,
+        strict_transport_security,
+        referrer_policy,
+        permissions_policy,
+        cross_origin_opener_policy,
+        cross_origin_embedder_policy,
+    }: &HeadersConfig,
+) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for (name, value) in [
+        ("content-security-policy", content_security_policy),
+        ("strict-transport-security", strict_transport_security),
+        ("referrer-policy", referrer_policy),
+        ("permissions-policy", permissions_policy),
+        ("cross-origin-opener-policy", cross_origin_opener_policy),
+        ("cross-origin-embedder-policy", cross_origin_embedder_policy),
+    ] {
+        // an empty value disables the header
+        if !value.is_empty() {
+            headers.insert(
+                HeaderName::from_static(name),
+                HeaderValue::from_str(value).expect("invalid header value in configuration"),
+            );
+        }
+    }
+    headers
+}
+
+#[cfg(feature = "web")]
+fn css_overrides(
+    AppearanceConfig {
+        accent,
+        accent_light,
+        accent_dark,
+        background,
+        background_dark,
+        background_light,
+    }: &AppearanceConfig,
+) -> String {
+    format!(
+        r#":root {{
+--bg: {background};
+--bg-dark: {background_dark};
+--bg-light: {background_light};
+--ac: {accent};
+--ac-dark: {accent_dark};
+--ac-dark-transparent: {accent_dark}c9;
+--ac-light: {accent_light};
+}}
+"#
+    )
+}
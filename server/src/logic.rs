@@ -4,64 +4,150 @@
     Copyright (C) 2023 metamuffin <metamuffin.org>
 */
 use crate::{
+    cluster::{Cluster, LostNode},
+    config::{Config, FeaturesConfig},
     idgen::IdGenerator,
-    protocol::{ClientboundPacket, ServerboundPacket},
+    protocol::{ClientboundPacket, DirectoryEntry, RoomTag, ServerboundPacket},
 };
 use futures_util::{stream::SplitStream, StreamExt};
-use log::{debug, error, warn};
-use serde::{Deserialize, Serialize};
+use log::{debug, error, info, warn};
 use std::{
     collections::{HashMap, HashSet},
-    sync::{Arc, LazyLock},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
 };
-use tokio::sync::{mpsc::Sender, RwLock};
+use tokio::sync::{broadcast, mpsc::Sender, Mutex, RwLock};
 use warp::ws::WebSocket;
 
-#[allow(clippy::redundant_closure)]
-static CLIENTS: LazyLock<RwLock<HashMap<Client, Sender<ClientboundPacket>>>> =
-    LazyLock::new(|| Default::default());
+pub use keks_protocol::ClientId as Client;
 
-#[repr(transparent)]
-#[derive(Debug, Hash, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
-pub struct Client(u64);
+/// Senders of clients connected to this node or forwarded to it by other cluster nodes.
+#[derive(Debug, Default)]
+pub struct Clients(RwLock<HashMap<Client, Sender<ClientboundPacket>>>);
 
 #[derive(Default)]
 pub struct State {
+    pub cluster: Option<Cluster>,
+    pub clients: Arc<Clients>,
     idgen: IdGenerator,
-    rooms: RwLock<HashMap<String, Arc<Room>>>,
-    watches: RwLock<HashMap<String, HashSet<Client>>>,
+    rooms: RwLock<HashMap<RoomKey, Arc<Room>>>,
+    watches: RwLock<HashMap<RoomKey, HashSet<Client>>>,
+    /// published rooms with their publisher; removed once the room is empty
+    directory: RwLock<HashMap<RoomKey, (Client, DirectoryEntry)>>,
+}
+
+const MAX_DIRECTORY_NAME: usize = 64;
+const MAX_DIRECTORY_DESCRIPTION: usize = 512;
+const MAX_DIRECTORY_SECRET: usize = 256;
+const MAX_ROOMS_PER_CLIENT: usize = 16;
+
+/// Rooms are namespaced by virtual host, so equal hashes on different domains never meet.
+#[derive(Debug, Hash, Clone, PartialEq, Eq)]
+pub struct RoomKey {
+    pub vhost: String,
+    pub hash: String,
 }
 
 #[derive(Debug)]
 pub struct Room {
-    pub hash: String,
-    pub users: RwLock<HashSet<Client>>,
+    pub key: RoomKey,
+    /// members with the tag they joined under
+    pub users: RwLock<HashMap<Client, RoomTag>>,
+    /// moderator hash given by whoever created the room
+    pub moderator_hash: RwLock<Option<String>>,
+    pub moderators: RwLock<HashSet<Client>>,
+    pub muted: RwLock<HashSet<Client>>,
+    pub locked: AtomicBool,
+    /// joins that have not added their member yet; the room is kept until then
+    pending: AtomicUsize,
+    /// joins are announced one after another
+    joining: Mutex<()>,
+    clients: Arc<Clients>,
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct ClientState {
-    current_room: Option<Arc<Room>>,
+    pub(crate) vhost: String,
+    pub(crate) features: FeaturesConfig,
+    /// rooms joined on this node
+    rooms: HashMap<RoomTag, Arc<Room>>,
     watches: Vec<String>,
+    /// cluster nodes that own the joined rooms
+    room_nodes: HashMap<RoomTag, usize>,
+    /// cluster nodes that hold state for this client
+    remote_nodes: HashSet<usize>,
 }
 
 impl State {
-    pub async fn connect(&self, rx: SplitStream<WebSocket>, tx: Sender<ClientboundPacket>) {
+    /// Fails if the cluster configuration is invalid.
+    pub fn new(config: &Config) -> anyhow::Result<Self> {
+        let clients = Arc::<Clients>::default();
+        let features = std::iter::once(String::new())
+            .chain(config.vhosts.keys().cloned())
+            .map(|name| {
+                let features = config.vhost(&name).features;
+                (name, features)
+            })
+            .collect();
+        Ok(Self {
+            cluster: config
+                .cluster
+                .clone()
+                .map(|c| Cluster::new(c, features, clients.clone()))
+                .transpose()?,
+            clients,
+            ..Default::default()
+        })
+    }
+
+    pub async fn connect(
+        &self,
+        vhost: String,
+        features: FeaturesConfig,
+        rx: SplitStream<WebSocket>,
+        tx: Sender<ClientboundPacket>,
+    ) {
         debug!("new client connected");
-        let client = Client(self.idgen.generate().await);
-        CLIENTS.write().await.insert(client, tx);
-        self.connect_inner(client, rx).await;
-        CLIENTS.write().await.remove(&client);
+        let id = self.idgen.generate().await;
+        let client = Client(match &self.cluster {
+            Some(cluster) => cluster.client_id(id),
+            None => id,
+        });
+        self.clients.register(client, tx).await;
+        self.connect_inner(client, ClientState::new(vhost, features), rx)
+            .await;
+        self.clients.unregister(client).await;
     }
-    async fn connect_inner(&self, client: Client, mut rx: SplitStream<WebSocket>) {
-        let mut cstate = ClientState::default();
-        client
-            .send(ClientboundPacket::Init {
-                your_id: client,
-                version: format!("keks-meet {}", env!("CARGO_PKG_VERSION")),
-            })
+    async fn connect_inner(
+        &self,
+        client: Client,
+        mut cstate: ClientState,
+        mut rx: SplitStream<WebSocket>,
+    ) {
+        self.clients
+            .send(
+                client,
+                ClientboundPacket::Init {
+                    your_id: client,
+                    version: format!("keks-meet {}", env!("CARGO_PKG_VERSION")),
+                },
+            )
             .await;
 
-        while let Some(result) = rx.next().await {
+        let mut lost = self.cluster.as_ref().map(|c| c.subscribe_lost());
+        loop {
+            let result = tokio::select! {
+                result = rx.next() => match result {
+                    Some(result) => result,
+                    None => break,
+                },
+                lost = lost_node(&mut lost) => {
+                    self.node_lost(client, &mut cstate, &lost).await;
+                    continue;
+                }
+            };
             let msg = match result {
                 Ok(msg) => msg,
                 Err(e) => {
@@ -78,17 +164,140 @@ impl State {
                     }
                 };
                 debug!("<-  {packet:?}");
-                self.on_recv(client, &mut cstate, packet).await;
+                self.dispatch(client, &mut cstate, packet).await;
+            }
+        }
+
+        self.disconnect(client, &mut cstate).await;
+        if let Some(cluster) = &self.cluster {
+            for node in std::mem::take(&mut cstate.remote_nodes) {
+                cluster.disconnect(node, client).await;
+            }
+        }
+    }
+
+    /// The owner of some rooms forgot about this client, so it is told that
+    /// it left them.
+    async fn node_lost(&self, client: Client, cstate: &mut ClientState, lost: &LostNode) {
+        if !cstate.remote_nodes.remove(&lost.node) {
+            return;
+        }
+        let tags = cstate
+            .room_nodes
+            .iter()
+            .filter(|(_, node)| **node == lost.node)
+            .map(|(tag, _)| *tag)
+            .collect::<Vec<_>>();
+        for tag in tags {
+            cstate.room_nodes.remove(&tag);
+            for &id in lost.members.get(&(client, tag)).into_iter().flatten() {
+                if id != client {
+                    self.clients
+                        .send(client, ClientboundPacket::ClientLeave { id, room: tag })
+                        .await;
+                }
+            }
+            self.error(client, tag, "lost connection to the room").await;
+        }
+    }
+
+    /// Sends packets concerning rooms to the cluster node owning them.
+    async fn dispatch(&self, client: Client, cstate: &mut ClientState, packet: ServerboundPacket) {
+        let Some(cluster) = &self.cluster else {
+            return self.on_recv(client, cstate, packet).await;
+        };
+        match &packet {
+            ServerboundPacket::Ping | ServerboundPacket::ListDirectory => {
+                self.on_recv(client, cstate, packet).await
+            }
+            ServerboundPacket::Join { hash, room, .. } => {
+                let room = *room;
+                let node = hash
+                    .as_ref()
+                    .map(|h| cluster.owner(&cstate.room_key(h.to_owned())));
+                if node.is_some()
+                    && !cstate.room_nodes.contains_key(&room)
+                    && cstate.room_nodes.len() >= MAX_ROOMS_PER_CLIENT
+                {
+                    self.error(client, room, "too many rooms").await;
+                    return;
+                }
+                let prev = cstate.room_nodes.get(&room).copied();
+                if let Some(prev) = prev.filter(|prev| Some(*prev) != node) {
+                    let leave = ServerboundPacket::Join {
+                        hash: None,
+                        moderator: None,
+                        room,
+                    };
+                    self.route(cluster, prev, client, cstate, leave).await;
+                }
+                match node {
+                    Some(node) => {
+                        cstate.room_nodes.insert(room, node);
+                        self.route(cluster, node, client, cstate, packet).await;
+                    }
+                    None => {
+                        cstate.room_nodes.remove(&room);
+                    }
+                }
             }
+            ServerboundPacket::WatchRooms(list) => {
+                let mut lists = vec![vec![]; cluster.node_count()];
+                for hash in list {
+                    lists[cluster.owner(&cstate.room_key(hash.to_owned()))].push(hash.to_owned());
+                }
+                for (node, list) in lists.into_iter().enumerate() {
+                    // nodes that never heard of us have no watches to reset
+                    if list.is_empty()
+                        && node != cluster.node
+                        && !cstate.remote_nodes.contains(&node)
+                    {
+                        continue;
+                    }
+                    self.route(
+                        cluster,
+                        node,
+                        client,
+                        cstate,
+                        ServerboundPacket::WatchRooms(list),
+                    )
+                    .await;
+                }
+            }
+            _ => {
+                let node = packet.room().and_then(|room| cstate.room_nodes.get(&room));
+                if let Some(&node) = node {
+                    self.route(cluster, node, client, cstate, packet).await;
+                }
+            }
+        }
+    }
+    async fn route(
+        &self,
+        cluster: &Cluster,
+        node: usize,
+        client: Client,
+        cstate: &mut ClientState,
+        packet: ServerboundPacket,
+    ) {
+        if node == cluster.node {
+            self.on_recv(client, cstate, packet).await
+        } else {
+            cstate.remote_nodes.insert(node);
+            cluster.forward(node, client, cstate, packet).await
         }
+    }
 
-        if let Some(room) = cstate.current_room {
+    /// Leaves all rooms and removes all watches of a client.
+    pub(crate) async fn disconnect(&self, client: Client, cstate: &mut ClientState) {
+        for (_, room) in cstate.rooms.drain() {
             room.leave(self, client).await;
-            // TODO dont leak room
+            self.remove_if_empty(&room).await;
         }
         {
             let mut w = self.watches.write().await;
-            for e in cstate.watches {
+            for e in std::mem::take(&mut cstate.watches) {
+                let e = cstate.room_key(e);
                 let mut remove = false;
                 if let Some(e) = w.get_mut(&e) {
                     e.remove(&client);
@@ -101,35 +310,78 @@ impl State {
         }
     }
 
-    async fn on_recv(&self, client: Client, cstate: &mut ClientState, packet: ServerboundPacket) {
+    pub(crate) async fn on_recv(
+        &self,
+        client: Client,
+        cstate: &mut ClientState,
+        packet: ServerboundPacket,
+    ) {
+        // a moderator might have kicked us
+        let mut kicked = vec![];
+        for (tag, room) in &cstate.rooms {
+            if !room.users.read().await.contains_key(&client) {
+                kicked.push(*tag);
+            }
+        }
+        for tag in kicked {
+            cstate.rooms.remove(&tag);
+        }
         match packet {
             ServerboundPacket::Ping => (),
-            ServerboundPacket::Join { hash } => {
-                if let Some(room) = &cstate.current_room {
+            ServerboundPacket::Join {
+                hash,
+                moderator,
+                room: tag,
+            } => {
+                if let Some(room) = cstate.rooms.remove(&tag) {
                     room.leave(self, client).await;
-                    if room.should_remove().await {
-                        self.rooms.write().await.remove(&room.hash);
-                    }
+                    self.remove_if_empty(&room).await;
                 }
                 if let Some(hash) = hash {
-                    let room = self
-                        .rooms
-                        .write()
-                        .await
-                        .entry(hash.clone())
-                        .or_insert_with(|| Room::new(&hash).into())
+                    let key = cstate.room_key(hash);
+                    if cstate.rooms.values().any(|room| room.key == key) {
+                        self.error(client, tag, "already in this room").await;
+                        return;
+                    }
+                    if cstate.rooms.len() >= MAX_ROOMS_PER_CLIENT {
+                        self.error(client, tag, "too many rooms").await;
+                        return;
+                    }
+                    let mut rooms = self.rooms.write().await;
+                    let room = rooms
+                        .entry(key.clone())
+                        .or_insert_with(|| Room::new(key, self.clients.clone()).into())
                         .clone();
-                    room.join(self, client).await;
-                    cstate.current_room = Some(room.clone())
-                } else {
-                    cstate.current_room = None
+                    let is_moderator = room.check_moderator(moderator).await;
+                    if room.locked.load(Ordering::Relaxed) && !is_moderator {
+                        drop(rooms);
+                        self.error(client, tag, "room is locked").await;
+                    } else {
+                        // counted before releasing the lock, so that the room
+                        // is not removed as empty in the meantime. Members are
+                        // notified without it, so a slow client only holds up
+                        // joins to its own room.
+                        room.pending.fetch_add(1, Ordering::SeqCst);
+                        drop(rooms);
+                        room.join(self, client, tag, is_moderator).await;
+                        cstate.rooms.insert(tag, room);
+                    }
                 }
             }
-            ServerboundPacket::Relay { recipient, message } => {
-                if let Some(room) = &cstate.current_room {
-                    let packet = ClientboundPacket::Message {
+            ServerboundPacket::Relay {
+                recipient,
+                message,
+                room: tag,
+            } => {
+                if let Some(room) = cstate.rooms.get(&tag) {
+                    if room.muted.read().await.contains(&client) {
+                        debug!("dropping relay from muted client {client:?}");
+                        return;
+                    }
+                    let packet = |room| ClientboundPacket::Message {
                         sender: client,
-                        message,
+                        message: message.clone(),
+                        room,
                     };
                     if let Some(recipient) = recipient {
                         room.send_to_client(recipient, packet).await;
@@ -138,23 +390,30 @@ impl State {
                     }
                 }
             }
+            // disabled on this vhost, like the directory
+            ServerboundPacket::WatchRooms(_) if !cstate.features.room_watches => (),
             ServerboundPacket::WatchRooms(mut list) => {
                 let mut w = self.watches.write().await;
                 let r = self.rooms.read().await;
 
                 for e in list.iter().cloned() {
-                    w.entry(e.to_string()).or_default().insert(client);
-                    if let Some(r) = r.get(&e) {
-                        client
-                            .send(ClientboundPacket::RoomInfo {
-                                hash: e,
-                                user_count: r.users.read().await.len(),
-                            })
+                    let key = cstate.room_key(e.clone());
+                    w.entry(key.clone()).or_default().insert(client);
+                    if let Some(r) = r.get(&key) {
+                        self.clients
+                            .send(
+                                client,
+                                ClientboundPacket::RoomInfo {
+                                    hash: e,
+                                    user_count: r.users.read().await.len(),
+                                },
+                            )
                             .await;
                     }
                 }
                 std::mem::swap(&mut cstate.watches, &mut list);
                 for e in list {
+                    let e = cstate.room_key(e);
                     let mut remove = false;
                     if let Some(e) = w.get_mut(&e) {
                         e.remove(&client);
@@ -165,65 +424,334 @@ impl State {
                     }
                 }
             }
+            ServerboundPacket::Kick { id, room: tag } => {
+                if let Some(room) = cstate.moderated_room(client, tag).await {
+                    if room.users.read().await.contains_key(&id) {
+                        info!("{client:?} kicked {id:?}");
+                        room.send_to_client(id, |room| ClientboundPacket::Kicked { room })
+                            .await;
+                        room.leave(self, id).await;
+                        self.remove_if_empty(room).await;
+                    }
+                }
+            }
+            ServerboundPacket::Lock { locked, room: tag } => {
+                if let Some(room) = cstate.moderated_room(client, tag).await {
+                    room.locked.store(locked, Ordering::Relaxed);
+                    room.broadcast(None, |room| ClientboundPacket::Locked { locked, room })
+                        .await;
+                }
+            }
+            ServerboundPacket::Mute {
+                id,
+                muted,
+                room: tag,
+            } => {
+                if let Some(room) = cstate.moderated_room(client, tag).await {
+                    if muted {
+                        room.muted.write().await.insert(id);
+                    } else {
+                        room.muted.write().await.remove(&id);
+                    }
+                    room.broadcast(None, |room| ClientboundPacket::Muted { id, muted, room })
+                        .await;
+                }
+            }
+            ServerboundPacket::Publish {
+                name,
+                description,
+                secret,
+                room: tag,
+            } => {
+                if let Some(room) = self.publishable_room(client, cstate, tag).await {
+                    if name.len() > MAX_DIRECTORY_NAME
+                        || description.len() > MAX_DIRECTORY_DESCRIPTION
+                        || secret.len() > MAX_DIRECTORY_SECRET
+                    {
+                        self.error(client, tag, "directory entry too long").await;
+                        return;
+                    }
+                    info!("{client:?} published {:?}", room.key);
+                    self.directory.write().await.insert(
+                        room.key.clone(),
+                        (
+                            client,
+                            DirectoryEntry {
+                                name,
+                                description,
+                                secret,
+                                hash: room.key.hash.clone(),
+                                user_count: 0,
+                            },
+                        ),
+                    );
+                }
+            }
+            ServerboundPacket::Unpublish { room } => self.unpublish(client, cstate, room).await,
+            ServerboundPacket::ListDirectory => {
+                if cstate.features.room_directory {
+                    let rooms = self.directory(&cstate.vhost).await;
+                    self.clients
+                        .send(client, ClientboundPacket::Directory { rooms })
+                        .await;
+                }
+            }
+        }
+    }
+
+    /// Forgets a room after its last member left, including its moderator
+    /// hash and whether it was locked.
+    async fn remove_if_empty(&self, room: &Arc<Room>) {
+        let mut rooms = self.rooms.write().await;
+        if room.should_remove().await && rooms.get(&room.key).is_some_and(|r| Arc::ptr_eq(r, room))
+        {
+            rooms.remove(&room.key);
+        }
+    }
+
+    async fn unpublish(&self, client: Client, cstate: &ClientState, tag: RoomTag) {
+        if let Some(room) = self.publishable_room(client, cstate, tag).await {
+            self.directory.write().await.remove(&room.key);
+        }
+    }
+    /// Moderated rooms can only be published by their moderators. An entry
+    /// can only be replaced or removed by its publisher or a moderator.
+    async fn publishable_room<'a>(
+        &self,
+        client: Client,
+        cstate: &'a ClientState,
+        tag: RoomTag,
+    ) -> Option<&'a Arc<Room>> {
+        if !cstate.features.room_directory {
+            return None;
+        }
+        let room = cstate.rooms.get(&tag)?;
+        if room.moderators.read().await.contains(&client) {
+            return Some(room);
+        }
+        let publisher = self.directory.read().await.get(&room.key).map(|e| e.0);
+        let foreign = match publisher {
+            // entries of members that left are up for grabs
+            Some(p) => p != client && room.users.read().await.contains_key(&p),
+            None => false,
+        };
+        if room.moderator_hash.read().await.is_some() || foreign {
+            warn!("{client:?} tried to publish without permission");
+            return None;
+        }
+        Some(room)
+    }
+
+    async fn error(&self, client: Client, room: RoomTag, message: &str) {
+        let message = message.to_string();
+        self.clients
+            .send(client, ClientboundPacket::Error { message, room })
+            .await;
+    }
+
+    /// Published rooms of a virtual host with their current user count.
+    pub async fn directory(&self, vhost: &str) -> Vec<DirectoryEntry> {
+        let rooms = self.rooms.read().await;
+        let mut entries = vec![];
+        for (key, (_, entry)) in self.directory.read().await.iter() {
+            if key.vhost != vhost {
+                continue;
+            }
+            if let Some(room) = rooms.get(key) {
+                entries.push(DirectoryEntry {
+                    user_count: room.users.read().await.len(),
+                    ..entry.clone()
+                })
+            }
+        }
+        entries
+    }
+}
+
+async fn lost_node(lost: &mut Option<broadcast::Receiver<Arc<LostNode>>>) -> Arc<LostNode> {
+    if let Some(lost) = lost {
+        loop {
+            match lost.recv().await {
+                Ok(lost) => return lost,
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    warn!("missed {n} lost cluster links")
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    }
+    std::future::pending().await
+}
+
+impl ClientState {
+    pub fn new(vhost: String, features: FeaturesConfig) -> Self {
+        Self {
+            vhost,
+            features,
+            rooms: HashMap::new(),
+            watches: vec![],
+            room_nodes: HashMap::new(),
+            remote_nodes: HashSet::new(),
+        }
+    }
+    fn room_key(&self, hash: String) -> RoomKey {
+        RoomKey {
+            vhost: self.vhost.clone(),
+            hash,
+        }
+    }
+    async fn moderated_room(&self, client: Client, tag: RoomTag) -> Option<&Arc<Room>> {
+        let room = self.rooms.get(&tag)?;
+        if room.moderators.read().await.contains(&client) {
+            Some(room)
+        } else {
+            warn!("{client:?} tried to moderate without permission");
+            None
         }
     }
 }
 
-impl Client {
-    pub async fn send(&self, packet: ClientboundPacket) {
-        if let Some(s) = CLIENTS.read().await.get(self) {
+impl Clients {
+    pub async fn register(&self, client: Client, tx: Sender<ClientboundPacket>) {
+        self.0.write().await.insert(client, tx);
+    }
+    pub async fn unregister(&self, client: Client) {
+        self.0.write().await.remove(&client);
+    }
+    pub async fn send(&self, client: Client, packet: ClientboundPacket) {
+        if let Some(s) = self.0.read().await.get(&client) {
             s.send(packet).await.unwrap();
         } else {
-            warn!("invalid recipient {self:?}")
+            warn!("invalid recipient {client:?}")
         }
     }
 }
 
 impl Room {
-    pub fn new(hash: &String) -> Self {
+    pub fn new(key: RoomKey, clients: Arc<Clients>) -> Self {
         Self {
-            hash: hash.to_owned(),
+            key,
+            moderator_hash: Default::default(),
+            clients,
             users: Default::default(),
+            moderators: Default::default(),
+            muted: Default::default(),
+            locked: AtomicBool::new(false),
+            pending: AtomicUsize::new(0),
+            joining: Mutex::new(()),
+        }
+    }
+    /// Whether the hash grants moderation. Only whoever creates the room sets
+    /// its hash, so nobody can claim a room that already has members.
+    pub async fn check_moderator(&self, hash: Option<String>) -> bool {
+        let Some(hash) = hash else {
+            return false;
+        };
+        let mut moderator_hash = self.moderator_hash.write().await;
+        if moderator_hash.is_none() && self.should_remove().await {
+            *moderator_hash = Some(hash.clone());
         }
+        moderator_hash.as_ref() == Some(&hash)
     }
-    pub async fn join(&self, state: &State, client: Client) {
+    /// The caller counts the join as pending while it holds the lock of the room map.
+    pub async fn join(&self, state: &State, client: Client, tag: RoomTag, moderator: bool) {
         debug!("client join {client:?}");
 
+        let _joining = self.joining.lock().await;
         let user_count = {
             let mut g = self.users.write().await;
-            g.insert(client);
+            g.insert(client, tag);
+            self.pending.fetch_sub(1, Ordering::SeqCst);
             g.len()
         };
         for w in state
             .watches
             .read()
             .await
-            .get(&self.hash)
+            .get(&self.key)
             .into_iter()
             .flatten()
         {
-            w.send(ClientboundPacket::RoomInfo {
-                hash: self.hash.to_owned(),
-                user_count,
-            })
-            .await;
+            self.clients
+                .send(
+                    *w,
+                    ClientboundPacket::RoomInfo {
+                        hash: self.key.hash.to_owned(),
+                        user_count,
+                    },
+                )
+                .await;
         }
         // send join of this client to all clients
-        self.broadcast(None, ClientboundPacket::ClientJoin { id: client })
-            .await;
+        self.broadcast(None, |room| ClientboundPacket::ClientJoin {
+            id: client,
+            room,
+        })
+        .await;
         // send join of all other clients to this one
-        for rc in self.users.read().await.iter() {
+        for rc in self.users.read().await.keys() {
             if *rc != client {
-                self.send_to_client(client, ClientboundPacket::ClientJoin { id: *rc })
+                self.clients
+                    .send(client, ClientboundPacket::ClientJoin { id: *rc, room: tag })
                     .await;
             }
         }
+        if moderator {
+            self.moderators.write().await.insert(client);
+            self.broadcast(None, |room| ClientboundPacket::Moderator {
+                id: client,
+                room,
+            })
+            .await;
+            if self.locked.load(Ordering::Relaxed) {
+                self.clients
+                    .send(
+                        client,
+                        ClientboundPacket::Locked {
+                            locked: true,
+                            room: tag,
+                        },
+                    )
+                    .await;
+            }
+        }
+        for id in self.moderators.read().await.iter() {
+            if *id != client {
+                self.clients
+                    .send(client, ClientboundPacket::Moderator { id: *id, room: tag })
+                    .await;
+            }
+        }
+        for id in self.muted.read().await.iter() {
+            self.clients
+                .send(
+                    client,
+                    ClientboundPacket::Muted {
+                        id: *id,
+                        muted: true,
+                        room: tag,
+                    },
+                )
+                .await;
+        }
     }
 
     pub async fn leave(&self, state: &State, client: Client) {
+        if !self.users.read().await.contains_key(&client) {
+            return;
+        }
         debug!("client leave {client:?}");
-        for c in self.users.read().await.iter() {
-            self.send_to_client(*c, ClientboundPacket::ClientLeave { id: client })
+        self.moderators.write().await.remove(&client);
+        self.muted.write().await.remove(&client);
+        for (c, room) in self.users.read().await.iter() {
+            self.clients
+                .send(
+                    *c,
+                    ClientboundPacket::ClientLeave {
+                        id: client,
+                        room: *room,
+                    },
+                )
                 .await;
         }
         let user_count = {
@@ -231,38 +759,54 @@ impl Room {
             g.remove(&client);
             g.len()
         };
+        if user_count == 0 {
+            state.directory.write().await.remove(&self.key);
+        }
         for w in state
             .watches
             .read()
             .await
-            .get(&self.hash)
+            .get(&self.key)
             .into_iter()
             .flatten()
         {
-            w.send(ClientboundPacket::RoomInfo {
-                hash: self.hash.to_owned(),
-                user_count,
-            })
-            .await;
+            self.clients
+                .send(
+                    *w,
+                    ClientboundPacket::RoomInfo {
+                        hash: self.key.hash.to_owned(),
+                        user_count,
+                    },
+                )
+                .await;
         }
-        self.broadcast(Some(client), ClientboundPacket::ClientLeave { id: client })
-            .await;
     }
 
-    pub async fn broadcast(&self, sender: Option<Client>, packet: ClientboundPacket) {
-        for c in self.users.read().await.iter() {
+    /// The packet is built for every recipient with the tag they know the room by.
+    pub async fn broadcast(
+        &self,
+        sender: Option<Client>,
+        packet: impl Fn(RoomTag) -> ClientboundPacket,
+    ) {
+        for (c, room) in self.users.read().await.iter() {
             if sender != Some(*c) {
-                c.send(packet.clone()).await;
+                self.clients.send(*c, packet(*room)).await;
             }
         }
     }
-    pub async fn send_to_client(&self, recipient: Client, packet: ClientboundPacket) {
-        if let Some(c) = self.users.read().await.get(&recipient) {
-            c.send(packet).await;
+    pub async fn send_to_client(
+        &self,
+        recipient: Client,
+        packet: impl FnOnce(RoomTag) -> ClientboundPacket,
+    ) {
+        if let Some(room) = self.users.read().await.get(&recipient) {
+            self.clients.send(recipient, packet(*room)).await;
         }
     }
 
+    /// Pending joins are only counted under the lock of the room map, which
+    /// the caller holds, so checking them first can not miss a new member.
     pub async fn should_remove(&self) -> bool {
-        self.users.read().await.len() == 0
+        self.pending.load(Ordering::SeqCst) == 0 && self.users.read().await.is_empty()
     }
 }
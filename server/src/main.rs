@@ -3,31 +3,22 @@
     which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
     Copyright (C) 2023 metamuffin <metamuffin.org>
 */
-#![feature(lazy_cell)]
-#![allow(clippy::let_with_type_underscore)]
-pub mod assets;
-pub mod config;
-pub mod idgen;
-pub mod logic;
-pub mod protocol;
-
-use crate::protocol::ClientboundPacket;
-use assets::css;
-use config::{AppearanceConfig, Config};
-use futures_util::{SinkExt, StreamExt, TryFutureExt};
+use futures_util::future::join_all;
+use keks_meet_server::{
+    cluster,
+    config::{BindAddr, Config},
+    logic::State,
+    routes,
+};
 use listenfd::ListenFd;
-use log::{debug, error, warn};
-use logic::State;
-use std::convert::Infallible;
-use std::sync::Arc;
-use tokio::net::TcpListener;
-use tokio::sync::mpsc;
-use warp::http::StatusCode;
-use warp::{
-    reply,
-    ws::{Message, WebSocket},
-    Filter, Rejection, Reply,
+use log::{error, info};
+use std::{
+    fs::Permissions,
+    os::unix::fs::{FileTypeExt, PermissionsExt},
+    sync::Arc,
 };
+use tokio::net::{TcpListener, UnixListener};
+use warp::{filters::BoxedFilter, reply::Response};
 
 fn main() {
     tokio::runtime::Builder::new_multi_thread()
@@ -50,73 +41,18 @@ async fn run() {
     )
     .expect("cannot read configuration");
     let config: Config = toml::from_str(&config).expect("configuration invalid");
-    let client_config_json = serde_json::to_string(&config).unwrap();
-    let client_config_css = css_overrides(&config.appearance);
-
-    let state: _ = Arc::new(State::default());
-    let state: _ = warp::any().map(move || state.clone());
 
-    let signaling: _ = warp::path!("signaling")
-        .and(state)
-        .and(warp::ws())
-        .map(signaling_connect);
-
-    let index: _ = warp::path!().and(s_file!("client-web/public/start.html", "text/html"));
-    let favicon: _ =
-        warp::path!("favicon.ico").and(s_file!("client-web/public/favicon.ico", "image/avif"));
-    let room: _ = warp::path!("room").and(s_file!("client-web/public/app.html", "text/html"));
-    let assets: _ = warp::path("assets").and(s_asset_dir!());
-    let sw_script: _ = warp::path("sw.js").and(s_file!(
-        "client-web/public/assets/sw.js",
-        "application/javascript"
-    ));
-    let client_config: _ = warp::path!("config.json").map(move || {
-        warp::reply::with_header(
-            client_config_json.clone(),
-            "content-type",
-            "application/json",
-        )
-    });
-    let client_config_css: _ = warp::path!("overrides.css").map(move || {
-        warp::reply::with_header(client_config_css.clone(), "content-type", "text/css")
-    });
-    let css: _ = warp::path!("style.css")
-        .map(move || warp::reply::with_header(css(), "content-type", "text/css"));
-    let old_format_redirect: _ = warp::path!("room" / String).map(|rsecret| {
-        reply::with_header(
-            StatusCode::MOVED_PERMANENTLY,
-            "location",
-            format!("/room#{rsecret}?warn_redirect=true"),
-        )
-        .into_response()
-    });
-    let version: _ = warp::path!("version").map(|| env!("CARGO_PKG_VERSION"));
-
-    let routes: _ = signaling
-        .or(assets
-            .or(room)
-            .or(index)
-            .or(client_config)
-            .or(version)
-            .or(css)
-            .or(favicon)
-            .or(sw_script)
-            .or(old_format_redirect)
-            .or(client_config_css)
-            .map(|r| {
-                warp::reply::with_header(
-                    r,
-                    "cache-control",
-                    if cfg!(debug_assertions) {
-                        "no-cache"
-                    } else {
-                        "max-age=1000000"
-                    },
-                )
-            }))
-        .recover(handle_rejection)
-        .with(warp::log("keks-meet"))
-        .map(|r| warp::reply::with_header(r, "server", "keks-meet"));
+    let state = match State::new(&config) {
+        Ok(state) => Arc::new(state),
+        Err(e) => {
+            error!("configuration invalid: {e}");
+            std::process::exit(1);
+        }
+    };
+    if state.cluster.is_some() {
+        tokio::spawn(cluster::listen(state.clone()));
+    }
+    let routes = routes(&config, state);
 
     // if listender fd is passed from the outside world, use it.
     let mut listenfd = ListenFd::from_env();
@@ -131,72 +67,38 @@ async fn run() {
             })
             .await;
     } else {
-        warp::serve(routes).run(config.server.bind).await;
-    };
-}
-
-async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
-    let code = if err.is_not_found() {
-        StatusCode::NOT_FOUND
-    } else if err
-        .find::<warp::filters::body::BodyDeserializeError>()
-        .is_some()
-    {
-        StatusCode::BAD_REQUEST
-    } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
-        StatusCode::METHOD_NOT_ALLOWED
-    } else {
-        error!("unhandled rejection: {:?}", err);
-        StatusCode::INTERNAL_SERVER_ERROR
+        join_all(
+            config
+                .server
+                .bind
+                .into_iter()
+                .map(|addr| serve(routes.clone(), addr, config.server.unix_socket_mode)),
+        )
+        .await;
     };
-    let json = warp::reply::html(format!(
-        "<!DOCTYPE html><html><head></head><body><pre>{}</pre></body></html>",
-        code.canonical_reason().unwrap_or("!?")
-    ));
-    Ok(warp::reply::with_status(json, code))
 }
 
-fn signaling_connect(state: Arc<State>, ws: warp::ws::Ws) -> impl Reply {
-    async fn inner(sock: WebSocket, state: Arc<State>) {
-        debug!("ws upgrade");
-        let (mut user_ws_tx, user_ws_rx) = sock.split();
-        let (tx, mut rx) = mpsc::channel::<ClientboundPacket>(64);
-        tokio::task::spawn(async move {
-            while let Some(packet) = rx.recv().await {
-                debug!(" -> {packet:?}");
-                user_ws_tx
-                    .send(Message::text(serde_json::to_string(&packet).unwrap()))
-                    .unwrap_or_else(|e| {
-                        warn!("websocket send error: {}", e);
-                    })
-                    .await;
+async fn serve(routes: BoxedFilter<(Response,)>, addr: BindAddr, unix_socket_mode: Option<u32>) {
+    match addr {
+        BindAddr::Tcp(addr) => warp::serve(routes).run(addr).await,
+        BindAddr::Unix(path) => {
+            // remove a stale socket from a previous run
+            if std::fs::metadata(&path).is_ok_and(|m| m.file_type().is_socket()) {
+                std::fs::remove_file(&path).unwrap();
             }
-        });
-        state.connect(user_ws_rx, tx).await;
+            let l = UnixListener::bind(&path).expect("cannot bind unix socket");
+            if let Some(mode) = unix_socket_mode {
+                std::fs::set_permissions(&path, Permissions::from_mode(mode))
+                    .expect("cannot set unix socket permissions");
+            }
+            info!("listening on {path:?}");
+            warp::serve(routes)
+                .run_incoming(async_stream::stream! {
+                    loop {
+                        yield l.accept().await.map(|(conn,_addr)| conn);
+                    }
+                })
+                .await;
+        }
     }
-    ws.on_upgrade(move |sock| inner(sock, state))
-}
-
-fn css_overrides(
-    AppearanceConfig {
-        accent,
-        accent_light,
-        accent_dark,
-        background,
-        background_dark,
-        background_light,
-    }: &AppearanceConfig,
-) -> String {
-    format!(
-        r#":root {{
---bg: {background};
---bg-dark: {background_dark};
---bg-light: {background_light};
---ac: {accent};
---ac-dark: {accent_dark};
---ac-dark-transparent: {accent_dark}c9;
---ac-light: {accent_light};
-}}
-"#
-    )
 }
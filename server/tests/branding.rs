@@ -0,0 +1,90 @@
+/*
+    This file is part of keks-meet (https://codeberg.org/metamuffin/keks-meet)
+    which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
+    Copyright (C) 2023 metamuffin <metamuffin.org>
+*/
+mod common;
+
+use common::config;
+use keks_meet_server::routes;
+use std::path::PathBuf;
+
+const CLIENT: &str = "../client-web/public";
+
+/// A directory with operator files, removed when dropped.
+struct Files(PathBuf);
+impl Files {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("keks-branding-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+    fn write(&self, name: &str, content: &str) -> PathBuf {
+        let path = self.0.join(name);
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+}
+impl Drop for Files {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[tokio::test]
+async fn embedded_without_branding() {
+    let routes = routes(&config(), Default::default());
+    let get = |path: &'static str| warp::test::request().path(path).reply(&routes);
+
+    let start = get("/").await;
+    assert_eq!(start.status(), 200);
+    assert_eq!(
+        start.body(),
+        &std::fs::read(format!("{CLIENT}/start.html")).unwrap()
+    );
+    let favicon = get("/favicon.ico").await;
+    assert_eq!(favicon.status(), 200);
+    assert_eq!(
+        favicon.body(),
+        &std::fs::read(format!("{CLIENT}/favicon.ico")).unwrap()
+    );
+    for path in ["/branding/logo", "/imprint", "/privacy"] {
+        assert_eq!(get(path).await.status(), 404, "{path}");
+    }
+}
+
+#[tokio::test]
+async fn files_on_disk_take_precedence() {
+    let files = Files::new("precedence");
+    let mut config = config();
+    config.branding.instance_name = Some("<Kekse>".to_string());
+    config.branding.logo = Some(files.write("logo.svg", "<svg>logo</svg>"));
+    config.branding.favicon = Some(files.write("favicon.ico", "custom favicon"));
+    config.branding.start_text = Some(files.write("start.html", "<p>welcome</p>"));
+    config.branding.imprint = Some(files.write("imprint.html", "imprint"));
+    let routes = routes(&config, Default::default());
+    let get = |path: &'static str| warp::test::request().path(path).reply(&routes);
+
+    let start = get("/").await;
+    let page = String::from_utf8(start.body().to_vec()).unwrap();
+    assert!(page.contains("<title>&lt;Kekse&gt;</title>"));
+    assert!(page.contains(r#"<img class="logo" src="/branding/logo" alt="" />"#));
+    assert!(page.contains("<p>welcome</p>"));
+    assert!(page.contains(r#"<a href="/imprint">Imprint</a>"#));
+    assert!(!page.contains(r#"<a href="/privacy">"#));
+
+    assert_eq!(get("/favicon.ico").await.body(), "custom favicon");
+    assert_eq!(get("/branding/logo").await.body(), "<svg>logo</svg>");
+    assert_eq!(get("/imprint").await.body(), "imprint");
+    assert_eq!(get("/privacy").await.status(), 404);
+    // embedded files that are not branded are still there
+    assert_eq!(get("/room").await.status(), 200);
+
+    for path in ["/", "/favicon.ico", "/branding/logo", "/imprint"] {
+        assert_eq!(
+            get(path).await.headers()["cache-control"],
+            "no-cache",
+            "{path}"
+        );
+    }
+}
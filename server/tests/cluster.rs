@@ -0,0 +1,506 @@
+/*
+    This file is part of keks-meet (https://codeberg.org/metamuffin/keks-meet)
+    which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
+    Copyright (C) 2023 metamuffin <metamuffin.org>
+*/
+mod common;
+
+use aes_gcm::{
+    aead::{Aead, Nonce},
+    Aes256Gcm, KeyInit,
+};
+use common::TestClient;
+use hmac::{Hmac, Mac};
+use keks_meet_server::{
+    config::Config,
+    logic::State,
+    protocol::{ClientboundPacket, ServerboundPacket, DEFAULT_ROOM},
+};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::{
+    net::{SocketAddr, TcpListener},
+    process::{Child, Command},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
+};
+
+struct Node(Child);
+impl Drop for Node {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+    }
+}
+
+fn free_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+/// Starts the nodes and returns their HTTP and cluster addresses.
+async fn start_cluster(size: usize) -> (Vec<Node>, Vec<SocketAddr>, Vec<SocketAddr>) {
+    start_nodes((0..size).map(|_| free_addr()).collect(), size).await
+}
+
+/// Starts only the first `count` nodes of the cluster, the others are up to
+/// the test.
+async fn start_nodes(
+    cluster: Vec<SocketAddr>,
+    count: usize,
+) -> (Vec<Node>, Vec<SocketAddr>, Vec<SocketAddr>) {
+    let http = (0..count).map(|_| free_addr()).collect::<Vec<_>>();
+    let nodes = (0..count)
+        .map(|i| {
+            let config = include_str!("../../config/default.toml").replace(
+                "bind = \"127.0.0.1:24319\"",
+                &format!("bind = \"{}\"", http[i]),
+            ) + &format!(
+                "\n[cluster]\nnode = {i}\nnodes = {:?}\nsecret = \"test\"\n",
+                cluster.iter().map(|a| a.to_string()).collect::<Vec<_>>()
+            );
+            let path = std::env::temp_dir().join(format!(
+                "keks-meet-cluster-{}-{}.toml",
+                std::process::id(),
+                http[i].port()
+            ));
+            std::fs::write(&path, config).unwrap();
+            Node(
+                Command::new(env!("CARGO_BIN_EXE_keks-meet-server"))
+                    .arg(path)
+                    .current_dir(env!("CARGO_MANIFEST_DIR"))
+                    .spawn()
+                    .unwrap(),
+            )
+        })
+        .collect();
+    for addr in &http {
+        while TcpStream::connect(addr).await.is_err() {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+    (nodes, http, cluster)
+}
+
+#[tokio::test]
+async fn rooms_span_nodes() {
+    let (_nodes, http, _) = start_cluster(2).await;
+    // some of these rooms are owned by either node
+    for room in ["a", "b", "c", "d"] {
+        let mut alice = TestClient::connect(http[0]).await;
+        let mut bob = TestClient::connect(http[1]).await;
+        assert_ne!(alice.id, bob.id);
+
+        alice.join(room).await;
+        assert_eq!(
+            alice.recv().await,
+            ClientboundPacket::ClientJoin {
+                id: alice.id,
+                room: DEFAULT_ROOM,
+            }
+        );
+        bob.join(room).await;
+        assert_eq!(
+            alice.recv().await,
+            ClientboundPacket::ClientJoin {
+                id: bob.id,
+                room: DEFAULT_ROOM,
+            }
+        );
+
+        bob.relay(Some(alice.id), "hello").await;
+        assert_eq!(
+            alice.recv().await,
+            ClientboundPacket::Message {
+                sender: bob.id,
+                message: "hello".to_string(),
+                room: DEFAULT_ROOM,
+            }
+        );
+
+        let bob_id = bob.id;
+        bob.close().await;
+        assert_eq!(
+            alice.recv().await,
+            ClientboundPacket::ClientLeave {
+                id: bob_id,
+                room: DEFAULT_ROOM,
+            }
+        );
+        alice.close().await;
+    }
+}
+
+#[tokio::test]
+async fn one_connection_joins_rooms_on_several_nodes() {
+    let (_nodes, http, _) = start_cluster(2).await;
+    let mut alice = TestClient::connect(http[0]).await;
+    let rooms = ["a", "b", "c", "d"];
+    let mut others = vec![];
+    for (tag, room) in rooms.into_iter().enumerate() {
+        let tag = tag as u32 + 1;
+        alice.join_room(tag, room).await;
+        assert_eq!(
+            alice.recv().await,
+            ClientboundPacket::ClientJoin {
+                id: alice.id,
+                room: tag
+            }
+        );
+        let mut bob = TestClient::connect(http[1]).await;
+        bob.join(room).await;
+        bob.recv().await;
+        bob.recv().await;
+        assert_eq!(
+            alice.recv().await,
+            ClientboundPacket::ClientJoin {
+                id: bob.id,
+                room: tag
+            }
+        );
+        others.push(bob);
+    }
+
+    for (tag, bob) in others.iter_mut().enumerate() {
+        bob.relay(None, "hello").await;
+        assert_eq!(
+            alice.recv().await,
+            ClientboundPacket::Message {
+                sender: bob.id,
+                message: "hello".to_string(),
+                room: tag as u32 + 1,
+            }
+        );
+    }
+
+    alice.relay_in(3, None, "hi").await;
+    assert_eq!(
+        others[2].recv().await,
+        ClientboundPacket::Message {
+            sender: alice.id,
+            message: "hi".to_string(),
+            room: DEFAULT_ROOM,
+        }
+    );
+    for (i, bob) in others.iter_mut().enumerate() {
+        if i != 2 {
+            bob.expect_silence().await;
+        }
+    }
+}
+
+/// Speaks the cluster protocol as node 1 to a real node 0.
+struct FakeNode {
+    rx: BufReader<OwnedReadHalf>,
+    tx: OwnedWriteHalf,
+    nonce: String,
+    remote_nonce: String,
+    keys: Option<(Aes256Gcm, Aes256Gcm)>,
+    counters: (u64, u64),
+}
+
+impl FakeNode {
+    async fn hello(addr: SocketAddr) -> Self {
+        let stream = loop {
+            match TcpStream::connect(addr).await {
+                Ok(s) => break s,
+                Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+            }
+        };
+        let (rx, tx) = stream.into_split();
+        let mut node = Self {
+            rx: BufReader::new(rx),
+            tx,
+            nonce: hex::encode([7u8; 32]),
+            remote_nonce: String::new(),
+            keys: None,
+            counters: (0, 0),
+        };
+        node.send_plain(json!({"hello": {"node": 1, "nonce": node.nonce}}))
+            .await;
+        let hello = node.recv_plain().await.expect("no hello");
+        assert_eq!(hello["hello"]["node"], 0);
+        node.remote_nonce = hello["hello"]["nonce"].as_str().unwrap().to_string();
+        node
+    }
+    fn mac(&self, label: &str) -> Hmac<Sha256> {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(b"test").unwrap();
+        mac.update(
+            format!(
+                "keks-meet cluster {label} 1 0 {} {}",
+                self.nonce, self.remote_nonce
+            )
+            .as_bytes(),
+        );
+        mac
+    }
+    async fn authenticate(&mut self) {
+        let mac = hex::encode(self.mac("initiator").finalize().into_bytes());
+        self.send_plain(json!({"auth": {"mac": mac}})).await;
+        let auth = self.recv_plain().await.expect("no auth");
+        self.mac("responder")
+            .verify_slice(&hex::decode(auth["auth"]["mac"].as_str().unwrap()).unwrap())
+            .unwrap();
+        let key =
+            |label| Aes256Gcm::new_from_slice(&self.mac(label).finalize().into_bytes()).unwrap();
+        self.keys = Some((key("initiator key"), key("responder key")));
+    }
+
+    async fn send_plain(&mut self, message: Value) {
+        self.tx
+            .write_all(format!("{message}\n").as_bytes())
+            .await
+            .unwrap();
+    }
+    async fn recv_plain(&mut self) -> Option<Value> {
+        let mut line = String::new();
+        let n = tokio::time::timeout(Duration::from_millis(500), self.rx.read_line(&mut line))
+            .await
+            .ok()?
+            .unwrap_or(0);
+        (n > 0).then(|| serde_json::from_str(&line).unwrap())
+    }
+
+    async fn send(&mut self, message: Value) {
+        let nonce = nonce(&mut self.counters.0);
+        let (seal, _) = self.keys.as_ref().unwrap();
+        let ciphertext = seal
+            .encrypt(&nonce, message.to_string().as_bytes())
+            .unwrap();
+        self.tx
+            .write_all(format!("{}\n", hex::encode(ciphertext)).as_bytes())
+            .await
+            .unwrap();
+    }
+    async fn recv(&mut self) -> Option<Value> {
+        let mut line = String::new();
+        tokio::time::timeout(Duration::from_millis(500), self.rx.read_line(&mut line))
+            .await
+            .ok()?
+            .unwrap();
+        let nonce = nonce(&mut self.counters.1);
+        let (_, open) = self.keys.as_ref().unwrap();
+        let plaintext = open
+            .decrypt(&nonce, hex::decode(line.trim_end()).unwrap().as_slice())
+            .unwrap();
+        Some(serde_json::from_slice(&plaintext).unwrap())
+    }
+    async fn forward(&mut self, client: u64, packet: ServerboundPacket) {
+        self.send(json!({"packet": {"client": client, "vhost": "", "packet": packet}}))
+            .await
+    }
+}
+
+fn nonce(counter: &mut u64) -> Nonce<Aes256Gcm> {
+    let mut nonce = [0; 12];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    *counter += 1;
+    nonce.into()
+}
+
+#[tokio::test]
+async fn responder_authenticates_last() {
+    let (_nodes, _, cluster) = start_cluster(2).await;
+    let mut node = FakeNode::hello(cluster[0]).await;
+    assert_eq!(
+        node.recv_plain().await,
+        None,
+        "responder authenticated first"
+    );
+
+    // a mac for the other role is rejected
+    let mac = hex::encode(node.mac("responder").finalize().into_bytes());
+    node.send_plain(json!({"auth": {"mac": mac}})).await;
+    assert_eq!(node.recv_plain().await, None);
+}
+
+#[tokio::test]
+async fn forwarded_clients_belong_to_their_node() {
+    let (_nodes, _, cluster) = start_cluster(2).await;
+    let mut node = FakeNode::hello(cluster[0]).await;
+    node.authenticate().await;
+
+    let join = |hash: &str| ServerboundPacket::Join {
+        hash: Some(hash.to_string()),
+        moderator: None,
+        room: DEFAULT_ROOM,
+    };
+    // an id of node 0
+    node.forward(1, join("a")).await;
+    let ours = (1 << 40) | 1;
+    node.forward(ours, join("b")).await;
+    assert_eq!(
+        node.recv().await,
+        Some(json!({"deliver": {"client": ours, "packet": {"client_join": {"id": ours}}}}))
+    );
+    assert_eq!(node.recv().await, None);
+}
+
+#[test]
+fn directory_is_refused() {
+    let config = include_str!("../../config/default.toml")
+        .replace("room_directory = false", "room_directory = true")
+        + &format!(
+            "\n[cluster]\nnode = 0\nnodes = [\"{}\"]\nsecret = \"test\"\n",
+            free_addr()
+        );
+    let path = std::env::temp_dir().join(format!(
+        "keks-meet-cluster-{}-directory.toml",
+        std::process::id()
+    ));
+    std::fs::write(&path, config).unwrap();
+    let status = Command::new(env!("CARGO_BIN_EXE_keks-meet-server"))
+        .arg(path)
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .stderr(std::process::Stdio::null())
+        .status()
+        .unwrap();
+    assert!(!status.success());
+}
+
+#[test]
+fn invalid_config_is_an_error() {
+    let default = include_str!("../../config/default.toml");
+    let cluster = format!(
+        "\n[cluster]\nnode = 0\nnodes = [\"{}\"]\nsecret = \"test\"\n",
+        free_addr()
+    );
+    let state = |toml: String| State::new(&toml::from_str::<Config>(&toml).unwrap()).err();
+
+    let directory = default.replace("room_directory = false", "room_directory = true") + &cluster;
+    assert_eq!(
+        state(directory).unwrap().to_string(),
+        "the room directory can not be used in a cluster"
+    );
+    let out_of_range = default.to_string() + &cluster.replace("node = 0", "node = 1");
+    assert_eq!(
+        state(out_of_range).unwrap().to_string(),
+        "cluster node index out of range"
+    );
+    assert!(state(default.to_string() + &cluster).is_none());
+}
+
+/// Node index that owns a room in a cluster of `size`, like `Cluster::owner`.
+fn owner(hash: &str, size: u64) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for b in [0].into_iter().chain(hash.bytes()) {
+        h ^= b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h % size
+}
+
+#[tokio::test]
+async fn silent_node_does_not_block_others() {
+    // node 2 accepts links but never completes a handshake
+    let silent = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let cluster = vec![free_addr(), free_addr(), silent.local_addr().unwrap()];
+    tokio::spawn(async move {
+        let mut held = vec![];
+        while let Ok((stream, _)) = silent.accept().await {
+            held.push(stream);
+        }
+    });
+    let (_nodes, http, _) = start_nodes(cluster, 2).await;
+    let room = |node| {
+        (0..)
+            .map(|i| format!("room{i}"))
+            .find(|hash| owner(hash, 3) == node)
+            .unwrap()
+    };
+
+    let mut alice = TestClient::connect(http[0]).await;
+    alice.join(&room(2)).await;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+
+    let mut bob = TestClient::connect(http[0]).await;
+    bob.join(&room(1)).await;
+    let join = tokio::time::timeout(Duration::from_secs(2), bob.recv())
+        .await
+        .expect("link to node 1 waited for node 2");
+    assert_eq!(
+        join,
+        ClientboundPacket::ClientJoin {
+            id: bob.id,
+            room: DEFAULT_ROOM,
+        }
+    );
+}
+
+#[tokio::test]
+async fn long_handshake_lines_are_refused() {
+    let (_nodes, _, cluster) = start_cluster(2).await;
+    let mut stream = TcpStream::connect(cluster[0]).await.unwrap();
+    let line = vec![b'a'; 1 << 20];
+    // the node may hang up before everything is written
+    let _ = stream.write_all(&line).await;
+    let mut buf = [0; 1024];
+    let read = tokio::time::timeout(Duration::from_secs(2), async {
+        loop {
+            match stream.read(&mut buf).await {
+                Ok(0) | Err(_) => return,
+                Ok(_) => (),
+            }
+        }
+    })
+    .await;
+    assert!(read.is_ok(), "node kept reading the line");
+}
+
+#[tokio::test]
+async fn lost_node_leaves_its_rooms() {
+    let (mut nodes, http, _) = start_cluster(2).await;
+    let room = (0..)
+        .map(|i| format!("room{i}"))
+        .find(|hash| owner(hash, 2) == 1)
+        .unwrap();
+    let mut alice = TestClient::connect(http[0]).await;
+    alice.join(&room).await;
+    alice.recv().await;
+    let mut bob = TestClient::connect(http[1]).await;
+    bob.join(&room).await;
+    assert_eq!(
+        alice.recv().await,
+        ClientboundPacket::ClientJoin {
+            id: bob.id,
+            room: DEFAULT_ROOM,
+        }
+    );
+
+    drop(nodes.remove(1));
+    assert_eq!(
+        alice.recv().await,
+        ClientboundPacket::ClientLeave {
+            id: bob.id,
+            room: DEFAULT_ROOM,
+        }
+    );
+    assert_eq!(
+        alice.recv().await,
+        ClientboundPacket::Error {
+            message: "lost connection to the room".to_string(),
+            room: DEFAULT_ROOM,
+        }
+    );
+
+    // the connection is still usable for rooms of the remaining node
+    let room = (0..)
+        .map(|i| format!("room{i}"))
+        .find(|hash| owner(hash, 2) == 0)
+        .unwrap();
+    alice.join(&room).await;
+    assert_eq!(
+        alice.recv().await,
+        ClientboundPacket::ClientJoin {
+            id: alice.id,
+            room: DEFAULT_ROOM,
+        }
+    );
+}
@@ -0,0 +1,148 @@
+/*
+    This file is part of keks-meet (https://codeberg.org/metamuffin/keks-meet)
+    which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
+    Copyright (C) 2023 metamuffin <metamuffin.org>
+*/
+//! Shared by the integration tests; not every test uses everything.
+#![allow(dead_code)]
+
+use futures_util::{SinkExt, StreamExt};
+use keks_meet_server::{
+    config::Config,
+    logic::{Client, State},
+    protocol::{ClientboundPacket, RoomTag, ServerboundPacket, DEFAULT_ROOM},
+    routes,
+};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::net::TcpStream;
+use tokio_tungstenite::{
+    connect_async,
+    tungstenite::{client::IntoClientRequest, handshake::client::Request, Message},
+    MaybeTlsStream, WebSocketStream,
+};
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+pub fn config() -> Config {
+    toml::from_str(include_str!("../../../config/default.toml")).unwrap()
+}
+
+/// Serves the routes on an ephemeral port for as long as the test runtime lives.
+pub async fn serve(config: Config) -> SocketAddr {
+    let state = Arc::new(State::default());
+    let (addr, server) = warp::serve(routes(&config, state)).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+    addr
+}
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+pub struct TestClient {
+    ws: Socket,
+    pub id: Client,
+}
+
+impl TestClient {
+    pub async fn connect(addr: SocketAddr) -> Self {
+        Self::connect_request(
+            format!("ws://{addr}/signaling")
+                .into_client_request()
+                .unwrap(),
+        )
+        .await
+    }
+    /// Connects to the virtual host `host`.
+    pub async fn connect_host(addr: SocketAddr, host: &str) -> Self {
+        let mut request = format!("ws://{addr}/signaling")
+            .into_client_request()
+            .unwrap();
+        request.headers_mut().insert("host", host.parse().unwrap());
+        Self::connect_request(request).await
+    }
+    async fn connect_request(request: Request) -> Self {
+        let (mut ws, _) = connect_async(request).await.unwrap();
+        match recv(&mut ws).await {
+            ClientboundPacket::Init { your_id, .. } => Self { ws, id: your_id },
+            p => panic!("expected init, got {p:?}"),
+        }
+    }
+
+    pub async fn send(&mut self, packet: ServerboundPacket) {
+        self.send_raw(&serde_json::to_string(&packet).unwrap())
+            .await
+    }
+    pub async fn send_raw(&mut self, text: &str) {
+        self.ws.send(Message::text(text)).await.unwrap();
+    }
+
+    pub async fn join(&mut self, hash: &str) {
+        self.join_room(DEFAULT_ROOM, hash).await
+    }
+    pub async fn join_room(&mut self, room: RoomTag, hash: &str) {
+        self.send(ServerboundPacket::Join {
+            hash: Some(hash.to_string()),
+            moderator: None,
+            room,
+        })
+        .await
+    }
+    pub async fn join_moderated(&mut self, hash: &str, moderator: &str) {
+        self.send(ServerboundPacket::Join {
+            hash: Some(hash.to_string()),
+            moderator: Some(moderator.to_string()),
+            room: DEFAULT_ROOM,
+        })
+        .await
+    }
+    pub async fn relay(&mut self, recipient: Option<Client>, message: &str) {
+        self.relay_in(DEFAULT_ROOM, recipient, message).await
+    }
+    pub async fn relay_in(&mut self, room: RoomTag, recipient: Option<Client>, message: &str) {
+        self.send(ServerboundPacket::Relay {
+            recipient,
+            message: message.to_string(),
+            room,
+        })
+        .await
+    }
+
+    pub async fn recv(&mut self) -> ClientboundPacket {
+        recv(&mut self.ws).await
+    }
+    /// Asserts that nothing arrives for a short while.
+    pub async fn expect_silence(&mut self) {
+        if let Ok(Some(Ok(message))) =
+            tokio::time::timeout(Duration::from_millis(200), self.ws.next()).await
+        {
+            panic!("unexpected message {message:?}");
+        }
+    }
+    /// Waits for the server to close the connection.
+    pub async fn expect_closed(&mut self) {
+        loop {
+            match tokio::time::timeout(TIMEOUT, self.ws.next())
+                .await
+                .expect("connection still open")
+            {
+                None | Some(Err(_)) | Some(Ok(Message::Close(_))) => return,
+                Some(Ok(_)) => (),
+            }
+        }
+    }
+    pub async fn close(mut self) {
+        self.ws.close(None).await.unwrap();
+    }
+}
+
+async fn recv(ws: &mut Socket) -> ClientboundPacket {
+    loop {
+        let message = tokio::time::timeout(TIMEOUT, ws.next())
+            .await
+            .expect("timed out waiting for a packet")
+            .expect("connection closed")
+            .unwrap();
+        if let Message::Text(text) = message {
+            return serde_json::from_str(&text).unwrap();
+        }
+    }
+}
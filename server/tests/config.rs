@@ -0,0 +1,67 @@
+/*
+    This file is part of keks-meet (https://codeberg.org/metamuffin/keks-meet)
+    which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
+    Copyright (C) 2023 metamuffin <metamuffin.org>
+*/
+use keks_meet_server::config::WebrtcConfig;
+
+fn webrtc(toml: &str) -> Result<WebrtcConfig, toml::de::Error> {
+    toml::from_str(toml)
+}
+
+#[test]
+fn legacy_keys_become_ice_servers() {
+    let config = webrtc(
+        r#"
+        stun = "stun:example.org:3478"
+        turn = "turn:example.org:3478"
+        turn_user = "keksmeet"
+        turn_cred = "hunter2"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(config.ice_servers.len(), 1);
+    let server = &config.ice_servers[0];
+    assert_eq!(
+        server.urls,
+        ["stun:example.org:3478", "turn:example.org:3478"]
+    );
+    assert_eq!(server.username.as_deref(), Some("keksmeet"));
+    assert_eq!(server.credential.as_deref(), Some("hunter2"));
+
+    let config = webrtc(r#"stun = "stun:example.org:3478""#).unwrap();
+    assert_eq!(config.ice_servers[0].urls, ["stun:example.org:3478"]);
+    assert_eq!(config.ice_servers[0].username, None);
+}
+
+#[test]
+fn ice_servers() {
+    let config = webrtc(
+        r#"
+        [[ice_servers]]
+        urls = ["stun:example.org:3478"]
+        [[ice_servers]]
+        urls = ["turn:example.org:3478"]
+        username = "keksmeet"
+        credential = "hunter2"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(config.ice_servers.len(), 2);
+    assert!(webrtc("").unwrap().ice_servers.is_empty());
+}
+
+#[test]
+fn unknown_keys_are_rejected() {
+    let e = webrtc(r#"turn_password = "hunter2""#).unwrap_err();
+    assert!(e.to_string().contains("turn_password"), "{e}");
+}
+
+#[test]
+fn client_config_has_only_ice_servers() {
+    let config = webrtc(r#"stun = "stun:example.org:3478""#).unwrap();
+    assert_eq!(
+        serde_json::to_string(&config).unwrap(),
+        r#"{"ice_servers":[{"urls":["stun:example.org:3478"]}]}"#
+    );
+}
@@ -0,0 +1,144 @@
+/*
+    This file is part of keks-meet (https://codeberg.org/metamuffin/keks-meet)
+    which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
+    Copyright (C) 2023 metamuffin <metamuffin.org>
+*/
+mod common;
+
+use common::{config, serve, TestClient};
+use keks_meet_server::protocol::{
+    ClientboundPacket, DirectoryEntry, ServerboundPacket, DEFAULT_ROOM,
+};
+use std::net::SocketAddr;
+
+async fn serve_directory() -> SocketAddr {
+    let mut config = config();
+    config.features.room_directory = true;
+    serve(config).await
+}
+
+async fn publish(client: &mut TestClient, name: &str) {
+    client
+        .send(ServerboundPacket::Publish {
+            name: name.to_string(),
+            description: String::new(),
+            secret: "hunter2".to_string(),
+            room: DEFAULT_ROOM,
+        })
+        .await
+}
+
+async fn list(client: &mut TestClient) -> Vec<DirectoryEntry> {
+    client.send(ServerboundPacket::ListDirectory).await;
+    match client.recv().await {
+        ClientboundPacket::Directory { rooms } => rooms,
+        p => panic!("expected directory, got {p:?}"),
+    }
+}
+
+/// Connects two clients into the same room, consuming their join packets.
+async fn pair(addr: SocketAddr) -> (TestClient, TestClient) {
+    let mut a = TestClient::connect(addr).await;
+    a.join("room").await;
+    a.recv().await;
+    let mut b = TestClient::connect(addr).await;
+    b.join("room").await;
+    b.recv().await;
+    b.recv().await;
+    a.recv().await;
+    (a, b)
+}
+
+#[tokio::test]
+async fn publish_and_list() {
+    let addr = serve_directory().await;
+    let (mut a, _b) = pair(addr).await;
+    publish(&mut a, "lobby").await;
+    assert_eq!(
+        list(&mut a).await,
+        vec![DirectoryEntry {
+            name: "lobby".to_string(),
+            description: String::new(),
+            secret: "hunter2".to_string(),
+            hash: "room".to_string(),
+            user_count: 2,
+        }]
+    );
+    a.send(ServerboundPacket::Unpublish { room: DEFAULT_ROOM })
+        .await;
+    assert_eq!(list(&mut a).await, vec![]);
+}
+
+#[tokio::test]
+async fn only_publisher_changes_entry() {
+    let addr = serve_directory().await;
+    let (mut a, mut b) = pair(addr).await;
+    publish(&mut a, "lobby").await;
+    publish(&mut b, "spam").await;
+    b.send(ServerboundPacket::Unpublish { room: DEFAULT_ROOM })
+        .await;
+    let rooms = list(&mut b).await;
+    assert_eq!(rooms.len(), 1);
+    assert_eq!(rooms[0].name, "lobby");
+
+    // until they leave
+    a.close().await;
+    assert!(matches!(
+        b.recv().await,
+        ClientboundPacket::ClientLeave { .. }
+    ));
+    publish(&mut b, "mine").await;
+    assert_eq!(list(&mut b).await[0].name, "mine");
+}
+
+#[tokio::test]
+async fn moderated_rooms_need_moderator() {
+    let addr = serve_directory().await;
+    let mut a = TestClient::connect(addr).await;
+    a.join_moderated("room", "modhash").await;
+    a.recv().await;
+    a.recv().await;
+    let mut b = TestClient::connect(addr).await;
+    b.join("room").await;
+    for _ in 0..3 {
+        b.recv().await;
+    }
+    a.recv().await;
+
+    publish(&mut b, "spam").await;
+    assert_eq!(list(&mut b).await, vec![]);
+    publish(&mut a, "lobby").await;
+    assert_eq!(list(&mut b).await.len(), 1);
+}
+
+#[tokio::test]
+async fn disabled_by_default() {
+    let addr = serve(config()).await;
+    let mut a = TestClient::connect(addr).await;
+    a.join("room").await;
+    a.recv().await;
+    publish(&mut a, "lobby").await;
+    a.send(ServerboundPacket::ListDirectory).await;
+    a.expect_silence().await;
+}
+
+#[tokio::test]
+async fn long_entries_are_refused() {
+    let addr = serve_directory().await;
+    let (mut a, _b) = pair(addr).await;
+    a.send(ServerboundPacket::Publish {
+        name: "lobby".to_string(),
+        description: String::new(),
+        secret: "a".repeat(1 << 16),
+        room: DEFAULT_ROOM,
+    })
+    .await;
+    assert_eq!(
+        a.recv().await,
+        ClientboundPacket::Error {
+            message: "directory entry too long".to_string(),
+            room: DEFAULT_ROOM,
+        }
+    );
+    assert_eq!(list(&mut a).await, vec![]);
+}
@@ -0,0 +1,75 @@
+/*
+    This file is part of keks-meet (https://codeberg.org/metamuffin/keks-meet)
+    which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
+    Copyright (C) 2023 metamuffin <metamuffin.org>
+*/
+mod common;
+
+use common::config;
+use keks_meet_server::routes;
+
+const ROUTES: &[&str] = &[
+    "/",
+    "/room",
+    "/room/oldsecret",
+    "/favicon.ico",
+    "/assets/icons/chat.svg",
+    "/assets/start.js",
+    "/sw.js",
+    "/config.json",
+    "/overrides.css",
+    "/style.css",
+    "/version",
+    "/directory.json",
+    "/signaling",
+    "/does-not-exist",
+];
+
+const SECURITY_HEADERS: &[&str] = &[
+    "content-security-policy",
+    "strict-transport-security",
+    "referrer-policy",
+    "permissions-policy",
+    "cross-origin-opener-policy",
+    "cross-origin-embedder-policy",
+];
+
+#[tokio::test]
+async fn default_headers_on_every_route() {
+    let routes = routes(&config(), Default::default());
+    for path in ROUTES {
+        let res = warp::test::request().path(path).reply(&routes).await;
+        for header in SECURITY_HEADERS {
+            assert!(
+                res.headers().contains_key(*header),
+                "{header} missing on {path}"
+            );
+        }
+        assert_eq!(res.headers()["server"], "keks-meet");
+        let csp = res.headers()["content-security-policy"].to_str().unwrap();
+        assert!(csp.contains("script-src 'self' 'wasm-unsafe-eval';"));
+    }
+}
+
+#[tokio::test]
+async fn configured_headers_on_every_route() {
+    let mut config = config();
+    config.headers.content_security_policy = "default-src 'none'".to_string();
+    config.headers.strict_transport_security = String::new();
+    config.headers.referrer_policy = "same-origin".to_string();
+    let routes = routes(&config, Default::default());
+    for path in ROUTES {
+        let res = warp::test::request().path(path).reply(&routes).await;
+        assert_eq!(
+            res.headers()["content-security-policy"],
+            "default-src 'none'",
+            "on {path}"
+        );
+        assert_eq!(res.headers()["referrer-policy"], "same-origin", "on {path}");
+        assert!(
+            !res.headers().contains_key("strict-transport-security"),
+            "hsts not disabled on {path}"
+        );
+        assert_eq!(res.headers()["cross-origin-opener-policy"], "same-origin");
+    }
+}
@@ -0,0 +1,314 @@
+/*
+    This file is part of keks-meet (https://codeberg.org/metamuffin/keks-meet)
+    which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
+    Copyright (C) 2023 metamuffin <metamuffin.org>
+*/
+mod common;
+
+use common::{config, serve, TestClient};
+use keks_meet_server::protocol::{ClientboundPacket, ServerboundPacket, DEFAULT_ROOM};
+use std::{net::SocketAddr, time::Duration};
+
+/// Joins a room as its moderator and consumes the packets announcing that.
+async fn moderator(addr: SocketAddr, hash: &str) -> TestClient {
+    let mut client = TestClient::connect(addr).await;
+    client.join_moderated(hash, "modhash").await;
+    assert_eq!(
+        client.recv().await,
+        ClientboundPacket::ClientJoin {
+            id: client.id,
+            room: DEFAULT_ROOM,
+        }
+    );
+    assert_eq!(
+        client.recv().await,
+        ClientboundPacket::Moderator {
+            id: client.id,
+            room: DEFAULT_ROOM,
+        }
+    );
+    client
+}
+
+/// Joins a room with a moderator already in it.
+async fn member(addr: SocketAddr, hash: &str, moderator: &mut TestClient) -> TestClient {
+    let mut client = TestClient::connect(addr).await;
+    client.join(hash).await;
+    expect_member(&mut client, moderator).await;
+    client
+}
+
+async fn expect_member(client: &mut TestClient, moderator: &mut TestClient) {
+    assert_eq!(
+        client.recv().await,
+        ClientboundPacket::ClientJoin {
+            id: client.id,
+            room: DEFAULT_ROOM,
+        }
+    );
+    assert_eq!(
+        client.recv().await,
+        ClientboundPacket::ClientJoin {
+            id: moderator.id,
+            room: DEFAULT_ROOM,
+        }
+    );
+    assert_eq!(
+        client.recv().await,
+        ClientboundPacket::Moderator {
+            id: moderator.id,
+            room: DEFAULT_ROOM,
+        }
+    );
+    assert_eq!(
+        moderator.recv().await,
+        ClientboundPacket::ClientJoin {
+            id: client.id,
+            room: DEFAULT_ROOM,
+        }
+    );
+}
+
+/// Joins, retrying while the server has not yet noticed earlier disconnects.
+async fn join_when_unlocked(client: &mut TestClient, hash: &str) -> ClientboundPacket {
+    for _ in 0..50 {
+        client.join(hash).await;
+        match client.recv().await {
+            ClientboundPacket::Error { message, .. } if message == "room is locked" => {
+                tokio::time::sleep(Duration::from_millis(20)).await
+            }
+            p => return p,
+        }
+    }
+    panic!("room stayed locked")
+}
+
+#[tokio::test]
+async fn kick() {
+    let addr = serve(config()).await;
+    let mut a = moderator(addr, "room").await;
+    let mut b = member(addr, "room", &mut a).await;
+
+    a.send(ServerboundPacket::Kick {
+        id: b.id,
+        room: DEFAULT_ROOM,
+    })
+    .await;
+    assert_eq!(
+        b.recv().await,
+        ClientboundPacket::Kicked { room: DEFAULT_ROOM }
+    );
+    for client in [&mut a, &mut b] {
+        assert!(matches!(
+            client.recv().await,
+            ClientboundPacket::ClientLeave { .. }
+        ));
+    }
+
+    b.relay(None, "still here").await;
+    a.expect_silence().await;
+
+    b.join("room").await;
+    expect_member(&mut b, &mut a).await;
+}
+
+#[tokio::test]
+async fn only_moderators_moderate() {
+    let addr = serve(config()).await;
+    let mut a = moderator(addr, "room").await;
+    let mut b = member(addr, "room", &mut a).await;
+
+    b.send(ServerboundPacket::Kick {
+        id: a.id,
+        room: DEFAULT_ROOM,
+    })
+    .await;
+    b.send(ServerboundPacket::Lock {
+        locked: true,
+        room: DEFAULT_ROOM,
+    })
+    .await;
+    b.send(ServerboundPacket::Mute {
+        id: a.id,
+        muted: true,
+        room: DEFAULT_ROOM,
+    })
+    .await;
+    a.expect_silence().await;
+    b.expect_silence().await;
+}
+
+#[tokio::test]
+async fn lock() {
+    let addr = serve(config()).await;
+    let mut a = moderator(addr, "room").await;
+    let mut b = member(addr, "room", &mut a).await;
+
+    a.send(ServerboundPacket::Lock {
+        locked: true,
+        room: DEFAULT_ROOM,
+    })
+    .await;
+    for client in [&mut a, &mut b] {
+        assert_eq!(
+            client.recv().await,
+            ClientboundPacket::Locked {
+                locked: true,
+                room: DEFAULT_ROOM,
+            }
+        );
+    }
+
+    let mut c = TestClient::connect(addr).await;
+    c.join("room").await;
+    assert_eq!(
+        c.recv().await,
+        ClientboundPacket::Error {
+            message: "room is locked".to_string(),
+            room: DEFAULT_ROOM,
+        }
+    );
+    a.expect_silence().await;
+
+    // moderators still get in
+    let mut d = TestClient::connect(addr).await;
+    d.join_moderated("room", "modhash").await;
+    assert_eq!(
+        d.recv().await,
+        ClientboundPacket::ClientJoin {
+            id: d.id,
+            room: DEFAULT_ROOM,
+        }
+    );
+    d.close().await;
+
+    a.close().await;
+    b.close().await;
+
+    // the lock is gone with the last member
+    assert_eq!(
+        join_when_unlocked(&mut c, "room").await,
+        ClientboundPacket::ClientJoin {
+            id: c.id,
+            room: DEFAULT_ROOM,
+        }
+    );
+}
+
+#[tokio::test]
+async fn mute() {
+    let addr = serve(config()).await;
+    let mut a = moderator(addr, "room").await;
+    let mut b = member(addr, "room", &mut a).await;
+
+    let id = b.id;
+    for muted in [true, false] {
+        a.send(ServerboundPacket::Mute {
+            id,
+            muted,
+            room: DEFAULT_ROOM,
+        })
+        .await;
+        for client in [&mut a, &mut b] {
+            assert_eq!(
+                client.recv().await,
+                ClientboundPacket::Muted {
+                    id,
+                    muted,
+                    room: DEFAULT_ROOM,
+                }
+            );
+        }
+        b.relay(None, "hello").await;
+        if muted {
+            a.expect_silence().await;
+        } else {
+            assert_eq!(
+                a.recv().await,
+                ClientboundPacket::Message {
+                    sender: id,
+                    message: "hello".to_string(),
+                    room: DEFAULT_ROOM,
+                }
+            );
+        }
+    }
+}
+
+#[tokio::test]
+async fn other_moderator_secret_cannot_kick() {
+    let addr = serve(config()).await;
+    let mut a = moderator(addr, "room").await;
+    let mut b = TestClient::connect(addr).await;
+    b.join_moderated("room", "other").await;
+    expect_member(&mut b, &mut a).await;
+
+    b.send(ServerboundPacket::Kick {
+        id: a.id,
+        room: DEFAULT_ROOM,
+    })
+    .await;
+    a.expect_silence().await;
+    b.expect_silence().await;
+}
+
+#[tokio::test]
+async fn members_cannot_claim_room() {
+    let addr = serve(config()).await;
+    let mut a = TestClient::connect(addr).await;
+    a.join("room").await;
+    a.recv().await;
+
+    let mut b = TestClient::connect(addr).await;
+    b.join_moderated("room", "modhash").await;
+    assert!(matches!(
+        b.recv().await,
+        ClientboundPacket::ClientJoin { .. }
+    ));
+    assert!(matches!(
+        b.recv().await,
+        ClientboundPacket::ClientJoin { .. }
+    ));
+    assert!(matches!(
+        a.recv().await,
+        ClientboundPacket::ClientJoin { .. }
+    ));
+    b.send(ServerboundPacket::Kick {
+        id: a.id,
+        room: DEFAULT_ROOM,
+    })
+    .await;
+    a.expect_silence().await;
+    b.expect_silence().await;
+}
+
+#[tokio::test]
+async fn moderator_forgotten_when_empty() {
+    let addr = serve(config()).await;
+    let a = moderator(addr, "room").await;
+    a.close().await;
+
+    let mut b = TestClient::connect(addr).await;
+    for _ in 0..50 {
+        b.join_moderated("room", "other").await;
+        assert!(matches!(
+            b.recv().await,
+            ClientboundPacket::ClientJoin { .. }
+        ));
+        match b.recv().await {
+            ClientboundPacket::Moderator { id, .. } if id == b.id => return,
+            // the first moderator's room was still around
+            ClientboundPacket::ClientJoin { .. } => {
+                b.send(ServerboundPacket::Join {
+                    hash: None,
+                    moderator: None,
+                    room: DEFAULT_ROOM,
+                })
+                .await;
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            p => panic!("unexpected {p:?}"),
+        }
+    }
+    panic!("moderator hash survived the empty room")
+}
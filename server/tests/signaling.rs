@@ -0,0 +1,443 @@
+/*
+    This file is part of keks-meet (https://codeberg.org/metamuffin/keks-meet)
+    which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
+    Copyright (C) 2023 metamuffin <metamuffin.org>
+*/
+mod common;
+
+use common::{config, serve, TestClient};
+use keks_meet_server::protocol::{ClientboundPacket, ServerboundPacket, DEFAULT_ROOM};
+use std::net::SocketAddr;
+
+/// Connects a client and joins it into a room, consuming the join packets it
+/// receives for itself and everybody already there.
+async fn joined(addr: SocketAddr, hash: &str, others: &mut [&mut TestClient]) -> TestClient {
+    let mut client = TestClient::connect(addr).await;
+    client.join(hash).await;
+    assert_eq!(
+        client.recv().await,
+        ClientboundPacket::ClientJoin {
+            id: client.id,
+            room: DEFAULT_ROOM,
+        }
+    );
+    for _ in 0..others.len() {
+        assert!(matches!(
+            client.recv().await,
+            ClientboundPacket::ClientJoin { .. }
+        ));
+    }
+    for other in others {
+        assert_eq!(
+            other.recv().await,
+            ClientboundPacket::ClientJoin {
+                id: client.id,
+                room: DEFAULT_ROOM,
+            }
+        );
+    }
+    client
+}
+
+#[tokio::test]
+async fn ids_are_unique() {
+    let addr = serve(config()).await;
+    let a = TestClient::connect(addr).await;
+    let b = TestClient::connect(addr).await;
+    assert_ne!(a.id, b.id);
+}
+
+#[tokio::test]
+async fn join() {
+    let addr = serve(config()).await;
+    let mut a = TestClient::connect(addr).await;
+    a.join("room").await;
+    assert_eq!(
+        a.recv().await,
+        ClientboundPacket::ClientJoin {
+            id: a.id,
+            room: DEFAULT_ROOM,
+        }
+    );
+
+    let mut b = TestClient::connect(addr).await;
+    b.join("room").await;
+    assert_eq!(
+        b.recv().await,
+        ClientboundPacket::ClientJoin {
+            id: b.id,
+            room: DEFAULT_ROOM,
+        }
+    );
+    assert_eq!(
+        b.recv().await,
+        ClientboundPacket::ClientJoin {
+            id: a.id,
+            room: DEFAULT_ROOM,
+        }
+    );
+    assert_eq!(
+        a.recv().await,
+        ClientboundPacket::ClientJoin {
+            id: b.id,
+            room: DEFAULT_ROOM,
+        }
+    );
+    a.expect_silence().await;
+    b.expect_silence().await;
+}
+
+#[tokio::test]
+async fn leave() {
+    let addr = serve(config()).await;
+    let mut a = joined(addr, "room", &mut []).await;
+    let mut b = joined(addr, "room", &mut [&mut a]).await;
+
+    b.send(ServerboundPacket::Join {
+        hash: None,
+        moderator: None,
+        room: DEFAULT_ROOM,
+    })
+    .await;
+    assert_eq!(
+        b.recv().await,
+        ClientboundPacket::ClientLeave {
+            id: b.id,
+            room: DEFAULT_ROOM,
+        }
+    );
+    assert_eq!(
+        a.recv().await,
+        ClientboundPacket::ClientLeave {
+            id: b.id,
+            room: DEFAULT_ROOM,
+        }
+    );
+    a.expect_silence().await;
+}
+
+#[tokio::test]
+async fn disconnect_leaves() {
+    let addr = serve(config()).await;
+    let mut a = joined(addr, "room", &mut []).await;
+    let b = joined(addr, "room", &mut [&mut a]).await;
+    let b_id = b.id;
+    b.close().await;
+    assert_eq!(
+        a.recv().await,
+        ClientboundPacket::ClientLeave {
+            id: b_id,
+            room: DEFAULT_ROOM,
+        }
+    );
+    a.expect_silence().await;
+}
+
+#[tokio::test]
+async fn relay_to_recipient() {
+    let addr = serve(config()).await;
+    let mut a = joined(addr, "room", &mut []).await;
+    let mut b = joined(addr, "room", &mut [&mut a]).await;
+    let mut c = joined(addr, "room", &mut [&mut a, &mut b]).await;
+
+    a.relay(Some(b.id), "hello b").await;
+    assert_eq!(
+        b.recv().await,
+        ClientboundPacket::Message {
+            sender: a.id,
+            message: "hello b".to_string(),
+            room: DEFAULT_ROOM,
+        }
+    );
+    a.expect_silence().await;
+    c.expect_silence().await;
+}
+
+#[tokio::test]
+async fn relay_to_everyone() {
+    let addr = serve(config()).await;
+    let mut a = joined(addr, "room", &mut []).await;
+    let mut b = joined(addr, "room", &mut [&mut a]).await;
+    let mut c = joined(addr, "room", &mut [&mut a, &mut b]).await;
+    let mut outsider = joined(addr, "other room", &mut []).await;
+
+    a.relay(None, "hello all").await;
+    for client in [&mut b, &mut c] {
+        assert_eq!(
+            client.recv().await,
+            ClientboundPacket::Message {
+                sender: a.id,
+                message: "hello all".to_string(),
+                room: DEFAULT_ROOM,
+            }
+        );
+    }
+    a.expect_silence().await;
+    outsider.expect_silence().await;
+}
+
+#[tokio::test]
+async fn relay_to_other_room_is_dropped() {
+    let addr = serve(config()).await;
+    let mut a = joined(addr, "room", &mut []).await;
+    let mut b = joined(addr, "other room", &mut []).await;
+    a.relay(Some(b.id), "psst").await;
+    b.expect_silence().await;
+}
+
+#[tokio::test]
+async fn switch_rooms() {
+    let addr = serve(config()).await;
+    let mut a = joined(addr, "first", &mut []).await;
+    let mut b = joined(addr, "first", &mut [&mut a]).await;
+    let mut c = joined(addr, "second", &mut []).await;
+
+    a.join("second").await;
+    assert_eq!(
+        a.recv().await,
+        ClientboundPacket::ClientLeave {
+            id: a.id,
+            room: DEFAULT_ROOM,
+        }
+    );
+    assert_eq!(
+        b.recv().await,
+        ClientboundPacket::ClientLeave {
+            id: a.id,
+            room: DEFAULT_ROOM,
+        }
+    );
+    assert_eq!(
+        a.recv().await,
+        ClientboundPacket::ClientJoin {
+            id: a.id,
+            room: DEFAULT_ROOM,
+        }
+    );
+    assert_eq!(
+        a.recv().await,
+        ClientboundPacket::ClientJoin {
+            id: c.id,
+            room: DEFAULT_ROOM,
+        }
+    );
+    assert_eq!(
+        c.recv().await,
+        ClientboundPacket::ClientJoin {
+            id: a.id,
+            room: DEFAULT_ROOM,
+        }
+    );
+
+    a.relay(None, "hi").await;
+    assert_eq!(
+        c.recv().await,
+        ClientboundPacket::Message {
+            sender: a.id,
+            message: "hi".to_string(),
+            room: DEFAULT_ROOM,
+        }
+    );
+    b.expect_silence().await;
+}
+
+#[tokio::test]
+async fn several_rooms_on_one_connection() {
+    let addr = serve(config()).await;
+    let mut b = joined(addr, "first", &mut []).await;
+    let mut c = joined(addr, "second", &mut []).await;
+    let mut a = TestClient::connect(addr).await;
+
+    a.join_room(1, "first").await;
+    assert_eq!(
+        a.recv().await,
+        ClientboundPacket::ClientJoin { id: a.id, room: 1 }
+    );
+    assert_eq!(
+        a.recv().await,
+        ClientboundPacket::ClientJoin { id: b.id, room: 1 }
+    );
+    assert_eq!(
+        b.recv().await,
+        ClientboundPacket::ClientJoin {
+            id: a.id,
+            room: DEFAULT_ROOM
+        }
+    );
+    a.join_room(2, "second").await;
+    assert_eq!(
+        a.recv().await,
+        ClientboundPacket::ClientJoin { id: a.id, room: 2 }
+    );
+    assert_eq!(
+        a.recv().await,
+        ClientboundPacket::ClientJoin { id: c.id, room: 2 }
+    );
+    c.recv().await;
+
+    a.relay_in(2, None, "hello second").await;
+    assert_eq!(
+        c.recv().await,
+        ClientboundPacket::Message {
+            sender: a.id,
+            message: "hello second".to_string(),
+            room: DEFAULT_ROOM,
+        }
+    );
+    b.relay(None, "hello first").await;
+    assert_eq!(
+        a.recv().await,
+        ClientboundPacket::Message {
+            sender: b.id,
+            message: "hello first".to_string(),
+            room: 1,
+        }
+    );
+    b.expect_silence().await;
+
+    // leaving one room keeps the other
+    a.send(ServerboundPacket::Join {
+        hash: None,
+        moderator: None,
+        room: 1,
+    })
+    .await;
+    assert_eq!(
+        a.recv().await,
+        ClientboundPacket::ClientLeave { id: a.id, room: 1 }
+    );
+    assert_eq!(
+        b.recv().await,
+        ClientboundPacket::ClientLeave {
+            id: a.id,
+            room: DEFAULT_ROOM
+        }
+    );
+    a.relay_in(1, None, "gone").await;
+    a.relay_in(2, None, "still here").await;
+    assert_eq!(
+        c.recv().await,
+        ClientboundPacket::Message {
+            sender: a.id,
+            message: "still here".to_string(),
+            room: DEFAULT_ROOM,
+        }
+    );
+    b.expect_silence().await;
+
+    // disconnecting leaves every room
+    let a_id = a.id;
+    a.close().await;
+    assert_eq!(
+        c.recv().await,
+        ClientboundPacket::ClientLeave {
+            id: a_id,
+            room: DEFAULT_ROOM
+        }
+    );
+}
+
+#[tokio::test]
+async fn same_room_under_two_tags_is_rejected() {
+    let addr = serve(config()).await;
+    let mut a = joined(addr, "room", &mut []).await;
+    a.join_room(1, "room").await;
+    assert_eq!(
+        a.recv().await,
+        ClientboundPacket::Error {
+            message: "already in this room".to_string(),
+            room: 1,
+        }
+    );
+    a.expect_silence().await;
+}
+
+#[tokio::test]
+async fn watches() {
+    let addr = serve(config()).await;
+    let mut w = TestClient::connect(addr).await;
+    w.send(ServerboundPacket::WatchRooms(vec!["room".to_string()]))
+        .await;
+    w.expect_silence().await;
+
+    let mut a = joined(addr, "room", &mut []).await;
+    assert_eq!(
+        w.recv().await,
+        ClientboundPacket::RoomInfo {
+            hash: "room".to_string(),
+            user_count: 1
+        }
+    );
+    let b = joined(addr, "room", &mut [&mut a]).await;
+    assert_eq!(
+        w.recv().await,
+        ClientboundPacket::RoomInfo {
+            hash: "room".to_string(),
+            user_count: 2
+        }
+    );
+    b.close().await;
+    assert_eq!(
+        w.recv().await,
+        ClientboundPacket::RoomInfo {
+            hash: "room".to_string(),
+            user_count: 1
+        }
+    );
+
+    // watching an existing room reports its count immediately
+    let mut v = TestClient::connect(addr).await;
+    v.send(ServerboundPacket::WatchRooms(vec!["room".to_string()]))
+        .await;
+    assert_eq!(
+        v.recv().await,
+        ClientboundPacket::RoomInfo {
+            hash: "room".to_string(),
+            user_count: 1
+        }
+    );
+
+    // replacing the list drops the old watches
+    w.send(ServerboundPacket::WatchRooms(vec![])).await;
+    a.close().await;
+    w.expect_silence().await;
+}
+
+#[tokio::test]
+async fn malformed_input_closes_connection() {
+    let addr = serve(config()).await;
+    let mut a = joined(addr, "room", &mut []).await;
+    let mut b = joined(addr, "room", &mut [&mut a]).await;
+    b.send_raw("{\"join\": 5}").await;
+    b.expect_closed().await;
+    assert_eq!(
+        a.recv().await,
+        ClientboundPacket::ClientLeave {
+            id: b.id,
+            room: DEFAULT_ROOM,
+        }
+    );
+
+    let mut c = TestClient::connect(addr).await;
+    c.send_raw("not even json").await;
+    c.expect_closed().await;
+}
+
+#[tokio::test]
+async fn relay_outside_room_is_ignored() {
+    let addr = serve(config()).await;
+    let mut a = joined(addr, "room", &mut []).await;
+    let mut b = TestClient::connect(addr).await;
+    b.relay(None, "anyone?").await;
+    b.relay(Some(a.id), "hello?").await;
+    a.expect_silence().await;
+    // the connection survives
+    b.join("room").await;
+    assert_eq!(
+        b.recv().await,
+        ClientboundPacket::ClientJoin {
+            id: b.id,
+            room: DEFAULT_ROOM,
+        }
+    );
+}
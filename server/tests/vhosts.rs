@@ -0,0 +1,135 @@
+/*
+    This file is part of keks-meet (https://codeberg.org/metamuffin/keks-meet)
+    which is licensed under the GNU Affero General Public License (version 3); see /COPYING.
+    Copyright (C) 2023 metamuffin <metamuffin.org>
+*/
+mod common;
+
+use common::{config, serve, TestClient};
+use keks_meet_server::{
+    config::{Config, FeaturesConfig, IceServerConfig, VhostConfig, WebrtcConfig},
+    protocol::{ClientboundPacket, ServerboundPacket, DEFAULT_ROOM},
+    routes,
+};
+
+const OTHER: &str = "other.example";
+
+/// The default vhost with watches and without the directory, and [`OTHER`]
+/// the other way around, with its own look and ICE servers.
+fn vhost_config() -> Config {
+    let mut config = config();
+    let mut appearance = config.appearance.clone();
+    appearance.accent = "#3f8454".to_string();
+    config.vhosts.insert(
+        OTHER.to_string(),
+        VhostConfig {
+            features: Some(FeaturesConfig {
+                room_watches: false,
+                room_directory: true,
+            }),
+            webrtc: Some(WebrtcConfig {
+                ice_servers: vec![IceServerConfig {
+                    urls: vec!["stun:stun.other.example".to_string()],
+                    username: None,
+                    credential: None,
+                }],
+            }),
+            appearance: Some(appearance),
+        },
+    );
+    config
+}
+
+#[tokio::test]
+async fn rooms_are_separate() {
+    let addr = serve(vhost_config()).await;
+    let mut a = TestClient::connect(addr).await;
+    let mut b = TestClient::connect_host(addr, OTHER).await;
+    a.join("room").await;
+    b.join("room").await;
+    for client in [&mut a, &mut b] {
+        assert_eq!(
+            client.recv().await,
+            ClientboundPacket::ClientJoin {
+                id: client.id,
+                room: DEFAULT_ROOM,
+            }
+        );
+    }
+    a.relay(None, "hello").await;
+    a.expect_silence().await;
+    b.expect_silence().await;
+
+    // the host name is not case sensitive
+    let mut c = TestClient::connect_host(addr, "Other.Example").await;
+    c.join("room").await;
+    assert!(matches!(c.recv().await, ClientboundPacket::ClientJoin { id, .. } if id == c.id));
+    assert_eq!(
+        c.recv().await,
+        ClientboundPacket::ClientJoin {
+            id: b.id,
+            room: DEFAULT_ROOM,
+        }
+    );
+    a.expect_silence().await;
+}
+
+#[tokio::test]
+async fn watches_follow_features() {
+    let addr = serve(vhost_config()).await;
+    for host in [None, Some(OTHER)] {
+        let mut member = match host {
+            Some(host) => TestClient::connect_host(addr, host).await,
+            None => TestClient::connect(addr).await,
+        };
+        member.join("room").await;
+        member.recv().await;
+        let mut watcher = match host {
+            Some(host) => TestClient::connect_host(addr, host).await,
+            None => TestClient::connect(addr).await,
+        };
+        watcher
+            .send(ServerboundPacket::WatchRooms(vec!["room".to_string()]))
+            .await;
+        match host {
+            Some(_) => watcher.expect_silence().await,
+            None => assert_eq!(
+                watcher.recv().await,
+                ClientboundPacket::RoomInfo {
+                    hash: "room".to_string(),
+                    user_count: 1,
+                }
+            ),
+        }
+    }
+}
+
+#[tokio::test]
+async fn served_per_vhost() {
+    let routes = routes(&vhost_config(), Default::default());
+    let get = |host: &'static str, path: &'static str| {
+        warp::test::request()
+            .header("host", host)
+            .path(path)
+            .reply(&routes)
+    };
+
+    let client_config = |body: &[u8]| serde_json::from_slice::<serde_json::Value>(body).unwrap();
+    let default = client_config(get("localhost", "/config.json").await.body());
+    let other = client_config(get(OTHER, "/config.json").await.body());
+    assert_eq!(default["appearance"]["accent"], "#5e3f84");
+    assert_eq!(other["appearance"]["accent"], "#3f8454");
+    assert_eq!(
+        other["webrtc"]["ice_servers"][0]["urls"][0],
+        "stun:stun.other.example"
+    );
+    assert_ne!(default["webrtc"], other["webrtc"]);
+    assert_eq!(default["features"]["room_watches"], true);
+    assert_eq!(other["features"]["room_watches"], false);
+
+    let css = get(OTHER, "/overrides.css").await;
+    assert!(String::from_utf8_lossy(css.body()).contains("--ac: #3f8454;"));
+
+    assert_eq!(get("localhost", "/directory.json").await.status(), 404);
+    assert_eq!(get(OTHER, "/directory.json").await.status(), 200);
+}